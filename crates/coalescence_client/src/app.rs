@@ -1,29 +1,70 @@
 use std::{
+    collections::HashMap,
     fs::File,
+    future::Future,
     io,
     net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+    pin::Pin,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
+use async_io::Timer;
 use bevy::{
     app::{AppExit, PluginsState, ScheduleRunnerPlugin},
-    ecs::event::ManualEventReader,
+    ecs::{event::ManualEventReader, system::SystemParam},
     log::Level,
     prelude::*,
     tasks::{block_on, IoTaskPool, Task},
 };
+use coalescence_proto::{
+    clock::{ClockPlugin, ClockSync, NetworkClock, NetworkTick},
+    heartbeat::HeartbeatSettings,
+    input::{Input, InputPlugin, LocalInput},
+    interpolation::{
+        reset_interpolation, AppInterpolationExt, InterpolationPlugin, InterpolationTimeline,
+        Motion,
+    },
+    lag_compensation::HitTest,
+    master::{ListedServer, MasterRequest, MasterResponse, ServerFilter},
+    packet::{
+        Attack, DisconnectReason, Lobby, PlayerConnection, PlayerStats, Profile, QueuePosition,
+        Received, Resume, RoomChanged, ServerStatus, SessionResumable, StatusRequest,
+    },
+    peer::Client,
+    prediction::{reset_prediction, PredictionPlugin},
+    replication::{despawn_replicated, interest::Room, AppReplicationExt, ReplicationPlugin},
+    simulation::SimulationPlugin,
+    ConnectionBundle, NetworkStats, PacketSender, PeerDisconnected, ProtoPlugin, PROTOCOL_VERSION,
+};
 use coalescence_quinn::{
-    client::create_endpoint,
-    quinn::{ConnectError, Connection, ConnectionError, Endpoint},
-    receive_stream_driver::ReceiveStreamDriver,
-    send_stream_driver::SendStreamDriver,
-    AppEndpoint,
+    client::{create_endpoints, ClientEndpoints, ClientIdentity, IdentityError},
+    connection::{disconnect, disconnect_reason},
+    format_fingerprint,
+    master::{self, MasterError},
+    peer_fingerprint,
+    quinn::{ConnectError, ConnectionError, TransportConfig},
+    resumption::{session_cache, NewConnection, ResumptionMetrics, ResumptionStats},
+    rustls::client::ClientSessionStore,
+    transport::{TransportSettings, TransportSettingsError},
+    AppEndpoints, QuinnConnection, QuinnPlugin,
 };
-use futures_lite::future::poll_once;
+use futures_lite::future::{self, poll_once};
 use thiserror::Error;
 use tracing_log::LogTracer;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter, Registry};
 
+use crate::{
+    discovery::{DiscoveredServer, LanDiscovery, LanDiscoveryPlugin},
+    traversal::{connect_with_traversal, MasterServer},
+};
+
 #[derive(Debug, Deref, DerefMut)]
 pub struct AppContainer {
     #[deref]
@@ -37,52 +78,274 @@ impl Default for AppContainer {
     }
 }
 
-#[derive(Resource, Debug)]
+/// How long to wait between attempts to reconnect after the connection to the server dropped
+const RECONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Component, Debug)]
 struct ServerConnection {
+    /// Where the server is and which endpoints reached it, so that it can be reconnected to
+    address: String,
+    port: u16,
+    endpoints: ClientEndpoints,
     username: String,
-    connection: Connection,
-    send: SendStreamDriver,
-    receive: ReceiveStreamDriver,
+    password: Option<String>,
+    /// Where we are in the server's queue, if it was full when we joined
+    queue_position: Option<QueuePosition>,
+    /// How to take our place on the server back if the connection drops, once the server has let us in
+    resume: Option<SessionResumable>,
+    /// How well every player's connection to the server is doing, as last sent by the server
+    player_stats: Vec<PlayerConnection>,
+    /// Whether the server has let us in, rather than keeping us in its queue
+    joined: bool,
+}
+
+/// A component on the server connection while reconnecting after it dropped
+#[derive(Component, Debug)]
+struct Reconnecting {
+    /// The attempt in progress, or `None` once connected and waiting for the server to give our place back
+    task: Option<Task<Result<QuinnConnection, ConnectToServerError>>>,
+    /// Which attempt this is, starting from 1
+    attempt: u32,
+    /// When the server gives our place away, after which there's no point trying anymore
+    deadline: Instant,
+}
+
+/// The C# callbacks for when the connection to the server drops and is resumed
+#[derive(Resource, Debug, Clone, Copy)]
+struct ReconnectHandlers {
+    reconnecting: extern "C" fn(u32),
+    reconnected: extern "C" fn(),
+    failed: extern "C" fn(anyhow::Error),
 }
 
 #[derive(Debug, Error)]
 pub enum ConnectToServerError {
     #[error("Could not create a QUIC endpoint")]
     CouldNotCreateEndpoint(#[source] io::Error),
+    #[error("This host can't reach addresses of this family")]
+    UnsupportedAddressFamily,
     #[error("Could not resolve a socket address")]
     BadSocketAddress(#[source] io::Error),
+    #[error("{0} didn't resolve to any socket addresses")]
+    NoAddresses(String),
     #[error(transparent)]
     ConnectError(#[from] ConnectError),
+    #[error("Disconnected by the server: {0}")]
+    Disconnected(DisconnectReason),
     #[error(transparent)]
-    ConnectionError(#[from] ConnectionError),
+    ConnectionError(ConnectionError),
     #[error(
-        "Could not connect to any of the resolved socket addresses. See log output for details."
+        "Could not connect to any of the resolved socket addresses:{}",
+        format_failures(.0)
     )]
-    AllAddressesFailed,
+    AllAddressesFailed(Vec<AddressFailure>),
+    #[error("Error while communicating with the master server")]
+    Master(#[from] MasterError),
+    #[error("The master server rejected the request: {0}")]
+    MasterRejected(String),
+    #[error("The master server's certificate doesn't match the fingerprint it was pinned to")]
+    UntrustedMaster,
+    #[error("Timed out after {0:?}")]
+    TimedOut(Duration),
+    #[error("The connection attempt was cancelled")]
+    Cancelled,
 }
 
-// Non-send resource because of the CSharp callbacks
+/// Why connecting to one of the socket addresses that a server's address resolved to failed
 #[derive(Debug)]
+pub struct AddressFailure {
+    pub address: SocketAddr,
+    pub error: ConnectToServerError,
+}
+
+fn format_failures(failures: &[AddressFailure]) -> String {
+    failures
+        .iter()
+        .map(|failure| format!("\n'{}': {}", failure.address, failure.error))
+        .collect()
+}
+
+/// The QUIC transport parameters that the app's endpoints are created with
+#[derive(Resource, Debug, Clone)]
+struct ClientTransport(Arc<TransportConfig>);
+
+/// The session tickets that servers have sent, kept separately from the endpoints so that they survive them being
+/// recreated
+#[derive(Resource, Clone)]
+struct SessionCache(Arc<dyn ClientSessionStore>);
+
+/// The certificate that servers identify our player by
+#[derive(Resource, Debug, Clone)]
+struct PlayerIdentity(ClientIdentity);
+
+/// How connection attempts to a server's resolved socket addresses are raced against each other
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct ConnectTimeouts {
+    /// How long each attempt is given before an attempt to the next address is started alongside it
+    pub attempt_delay: Duration,
+    /// How long each attempt may take before it's given up on
+    pub attempt_timeout: Duration,
+}
+
+impl Default for ConnectTimeouts {
+    fn default() -> Self {
+        Self {
+            // As recommended by RFC 8305
+            attempt_delay: Duration::from_millis(250),
+            attempt_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl From<ConnectionError> for ConnectToServerError {
+    fn from(error: ConnectionError) -> Self {
+        match disconnect_reason(&error) {
+            Some(reason) => Self::Disconnected(reason),
+            None => Self::ConnectionError(error),
+        }
+    }
+}
+
+/// A step of connecting to a server, reported as it happens
+#[derive(Debug, Clone)]
+pub enum ConnectProgress {
+    /// Started trying to connect to one of the socket addresses that the server's address resolved to
+    Trying {
+        address: SocketAddr,
+        /// Which of the resolved socket addresses this is, starting from 1
+        attempt: u32,
+        /// How many socket addresses the server's address resolved to
+        addresses: u32,
+    },
+    /// Started trying to connect through a hole that the server punched through its NAT
+    Punching { address: SocketAddr },
+    /// Started trying to connect through a relay on the master server
+    Relaying { address: SocketAddr },
+}
+
+/// Reports connection progress from the connecting task to the main thread, or nowhere if nothing is interested
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProgressSender(Option<Sender<ConnectProgress>>);
+
+impl ProgressSender {
+    pub(crate) fn report(&self, progress: ConnectProgress) {
+        if let Some(sender) = &self.0 {
+            // The receiver is gone if the attempt was cancelled, in which case nobody cares anymore
+            let _ = sender.send(progress);
+        }
+    }
+}
+
+type ConnectProgressHandler = Box<dyn FnMut(&ConnectProgress)>;
+
+// Non-send resource because of the CSharp callbacks
 struct ConnectToServerTask {
-    task: Task<Result<ServerConnection, ConnectToServerError>>,
+    task: Task<Result<(ServerConnection, QuinnConnection), ConnectToServerError>>,
+    progress: Receiver<ConnectProgress>,
+    progress_handler: ConnectProgressHandler,
     ok_handler: extern "C" fn(),
     error_handler: extern "C" fn(anyhow::Error),
 }
 
+/// The answer to a status query, along with what the client observed while making it
+#[derive(Debug, Clone)]
+pub struct QueriedServerStatus {
+    pub status: ServerStatus,
+    /// The round-trip time to the server
+    pub ping: Duration,
+    /// The fingerprint of the certificate that the server presented
+    pub fingerprint: Option<[u8; 32]>,
+}
+
+impl QueriedServerStatus {
+    /// Whether this client can join the server
+    pub fn is_compatible(&self) -> bool {
+        self.status.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+type ServerQueryHandler = Box<dyn FnOnce(Result<QueriedServerStatus, ConnectToServerError>)>;
+
+/// A component marking a connection that was only made to query the server's status
+#[derive(Component, Debug)]
+struct StatusQuery;
+
+// Non-send resource because of the CSharp callbacks
+#[derive(Default)]
+struct ServerQueries {
+    /// Queries that are still connecting to the server
+    tasks: Vec<(
+        Task<Result<QuinnConnection, ConnectToServerError>>,
+        ServerQueryHandler,
+    )>,
+    /// Queries that are waiting for the server to answer, by the entity of their connection
+    pending: HashMap<Entity, ServerQueryHandler>,
+}
+
+/// A page of the master server's list of public servers
+#[derive(Debug, Clone)]
+pub struct ServerListPage {
+    pub servers: Vec<ListedServer>,
+    pub page: u32,
+    /// The total amount of pages of servers matching the filter
+    pub page_count: u32,
+}
+
+type ServerListHandler = Box<dyn FnOnce(Result<ServerListPage, ConnectToServerError>)>;
+
+// Non-send resource because of the CSharp callbacks
+#[derive(Default)]
+struct ServerListRequests(
+    Vec<(
+        Task<Result<ServerListPage, ConnectToServerError>>,
+        ServerListHandler,
+    )>,
+);
+
 impl AppContainer {
     pub fn new() -> Self {
         info!("AppContainer::new()");
 
         let mut app = App::new();
-        app.add_plugins(MinimalPlugins.build().disable::<ScheduleRunnerPlugin>())
-            .add_systems(
-                Update,
+        app.add_plugins((
+            MinimalPlugins.build().disable::<ScheduleRunnerPlugin>(),
+            ProtoPlugin::<Client>::default(),
+            QuinnPlugin::<Client>::default(),
+            SimulationPlugin,
+            ClockPlugin::<Client>::default(),
+            ReplicationPlugin::<Client>::default(),
+            PredictionPlugin,
+            InputPlugin::<Client>::default(),
+            InterpolationPlugin,
+            LanDiscoveryPlugin,
+        ))
+        // The same components, in the same order, as the server
+        .replicate::<Motion>()
+        .interpolate::<Motion>()
+        .init_resource::<ConnectTimeouts>()
+        .init_resource::<ResumptionMetrics>()
+        .init_resource::<CurrentRoom>()
+        .insert_resource(SessionCache(session_cache()))
+        .init_non_send_resource::<ServerQueries>()
+        .init_non_send_resource::<ServerListRequests>()
+        .add_systems(
+            Update,
+            (
+                poll_connect_to_server_task,
+                handshake,
+                (update_queue_position, report_room).chain(),
+                store_resume_token,
+                store_player_stats,
+                (handle_disconnect, poll_reconnect, finish_reconnect).chain(),
                 (
-                    poll_connect_to_server_task,
-                    handshake.run_if(resource_added::<ServerConnection>()),
-                    drive_send_stream.run_if(resource_exists::<ServerConnection>()),
-                ),
-            );
+                    poll_server_list_requests,
+                    poll_server_queries,
+                    send_status_requests,
+                    finish_server_queries,
+                )
+                    .chain(),
+            ),
+        );
 
         if app.plugins_state() != PluginsState::Cleaned {
             while app.plugins_state() == PluginsState::Adding {
@@ -117,102 +380,554 @@ impl AppContainer {
         address: &str,
         port: u16,
         username: String,
+        password: Option<String>,
+        progress_handler: impl FnMut(&ConnectProgress) + 'static,
         async_ok_handler: extern "C" fn(),
         async_error_handler: extern "C" fn(anyhow::Error),
     ) -> Result<(), ConnectToServerError> {
-        let address_port = format!("'{address}:{port}'");
-        info!("Connecting to {address_port} with username '{username}'...");
-
-        // Getting the endpoint cannot happen in the task because of needing to insert it as a resource if it's created here
-        let endpoint = match self.world.get_resource::<AppEndpoint>() {
-            Some(AppEndpoint(endpoint)) => endpoint.clone(),
-            None => {
-                let endpoint =
-                    create_endpoint().map_err(ConnectToServerError::CouldNotCreateEndpoint)?;
-                self.insert_resource(AppEndpoint(endpoint.clone()));
-                endpoint
-            }
-        };
+        info!("Connecting to '{address}:{port}' with username '{username}'...");
 
-        // `to_socket_addrs` is blocking with no async alternative, so putting it in the task makes no difference
-        let mut addresses = (address, port)
-            .to_socket_addrs()
-            .map_err(ConnectToServerError::BadSocketAddress)?;
+        let endpoints = self.endpoints()?;
+        let master = self.world.get_resource::<MasterServer>().cloned();
+        let timeouts = *self.world.resource::<ConnectTimeouts>();
+        let metrics = self.world.resource::<ResumptionMetrics>().clone();
+        let (sender, progress) = mpsc::channel();
+        let address = address.to_owned();
+        let connecting = connect_with_traversal(
+            endpoints.clone(),
+            address.clone(),
+            port,
+            master,
+            timeouts,
+            metrics,
+            ProgressSender(Some(sender)),
+        );
 
         self.app.insert_non_send_resource(ConnectToServerTask {
             task: IoTaskPool::get().spawn(async move {
-                let addresses = addresses.as_mut_slice();
+                let quinn = connecting.await?.open().await?;
 
-                if addresses.is_empty() {
-                    return Err(ConnectToServerError::BadSocketAddress(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("{address_port} resolved to 0 socket addresses"),
-                    )));
+                if quinn.in_early_data() {
+                    info!("Connection established, resuming the previous session!");
+                } else {
+                    info!("Connection established!");
                 }
 
-                info!(
-                    "Resolved {address_port} to {} socket addresses.",
-                    addresses.len()
-                );
-
-                let mut connection = None;
-
-                // `SocketAddr` implements `Ord` such that IPv4 addresses get sorted before IPv6 addresses, so we sort the
-                // given addresses and then iterate over them in reverse, meaning IPv6 addresses get prioritised.
-                addresses.sort_unstable();
-                for (i, &socket_address) in addresses.iter().rev().enumerate() {
-                    info!("Trying to connect to address #{}: '{address}'...", i + 1);
-
-                    match try_connect(&endpoint, socket_address, address).await {
-                        Ok(c) => {
-                            connection = Some(c);
-                            break;
-                        }
-                        Err(e) => error!("{e}"),
-                    };
-
-                    // Use a local function for the `?` syntax, as `try` blocks are unstable
-                    // The server_name parameter must either be a valid DNS domain name or a valid IpAddr, with the port excluded
-                    async fn try_connect(
-                        endpoint: &Endpoint,
-                        address: SocketAddr,
-                        server_name: &str,
-                    ) -> Result<Connection, ConnectToServerError> {
-                        Ok(endpoint.connect(address, server_name)?.await?)
-                    }
-                }
+                Ok((
+                    ServerConnection {
+                        address,
+                        port,
+                        endpoints,
+                        username,
+                        password,
+                        queue_position: None,
+                        resume: None,
+                        player_stats: Vec::new(),
+                        joined: false,
+                    },
+                    quinn,
+                ))
+            }),
+            progress,
+            progress_handler: Box::new(progress_handler),
+            ok_handler: async_ok_handler,
+            error_handler: async_error_handler,
+        });
 
-                let Some(connection) = connection else {
-                    return Err(ConnectToServerError::AllAddressesFailed);
-                };
+        Ok(())
+    }
 
-                let (send, receive) = connection.open_bi().await?;
+    /// Cancel the connection attempt in progress, calling its error handler with [`ConnectToServerError::Cancelled`].
+    /// Returns false if no connection attempt was in progress
+    pub fn cancel_connect(&mut self) -> bool {
+        let Some(task) = self.world.remove_non_send_resource::<ConnectToServerTask>() else {
+            return false;
+        };
 
-                info!("Connection established!");
+        info!("Cancelling the connection attempt...");
 
-                Ok(ServerConnection {
-                    username,
-                    connection,
-                    send: SendStreamDriver::new(send),
-                    receive: ReceiveStreamDriver::new(receive),
-                })
-            }),
-            ok_handler: async_ok_handler,
-            error_handler: async_error_handler,
+        // Cancelling the task drops any connections that are still being made, which closes them.
+        // If it already finished, the connection has to be closed here instead
+        if let Some(Ok((_, mut quinn))) = block_on(task.task.cancel()) {
+            quinn.disconnect(DisconnectReason::Quit);
+        }
+
+        (task.error_handler)(anyhow!(ConnectToServerError::Cancelled));
+        true
+    }
+
+    /// Set the QUIC transport parameters used for new connections. Existing connections keep their old parameters
+    pub fn set_transport_settings(
+        &mut self,
+        settings: &TransportSettings,
+    ) -> Result<(), TransportSettingsError> {
+        info!("Transport settings: {settings:?}");
+        self.insert_resource(ClientTransport(settings.to_config()?));
+        // The endpoints only take the new parameters when they're recreated
+        self.world.remove_resource::<AppEndpoints>();
+        Ok(())
+    }
+
+    /// Load the identity that servers know our player by from a PEM file, creating it if it doesn't exist yet. Until one
+    /// is loaded, a new identity is generated for as long as the app runs, so servers won't recognise the player again
+    pub fn load_identity(&mut self, path: &Path) -> Result<(), IdentityError> {
+        let identity = ClientIdentity::load_or_create(path)?;
+        info!(
+            "Loaded identity '{}'",
+            format_fingerprint(&identity.fingerprint())
+        );
+        self.insert_resource(PlayerIdentity(identity));
+        // The endpoints only present the new identity when they're recreated
+        self.world.remove_resource::<AppEndpoints>();
+        Ok(())
+    }
+
+    /// Set the callbacks for when the connection to the server drops and the app reconnects on its own.
+    /// `reconnecting` is called with the attempt number before every attempt, `reconnected` once the server has given
+    /// our place back, and `failed` if the server gave it away or couldn't be reached in time
+    pub fn set_reconnect_handlers(
+        &mut self,
+        reconnecting: extern "C" fn(u32),
+        reconnected: extern "C" fn(),
+        failed: extern "C" fn(anyhow::Error),
+    ) {
+        self.insert_resource(ReconnectHandlers {
+            reconnecting,
+            reconnected,
+            failed,
+        });
+    }
+
+    /// Set the master server used to reach servers that can't be connected to directly, or `None` to only ever connect
+    /// directly
+    pub fn set_master_server(&mut self, master: Option<MasterServer>) {
+        if let Some(master) = master {
+            self.insert_resource(master);
+        } else {
+            self.world.remove_resource::<MasterServer>();
+        }
+    }
+
+    /// Set how connection attempts to a server's resolved socket addresses are raced against each other
+    pub fn set_connect_timeouts(&mut self, timeouts: ConnectTimeouts) {
+        self.insert_resource(timeouts);
+    }
+
+    /// Set how often heartbeats are sent to the server, and how long it may go silent for before it's considered gone.
+    /// Returns false without changing anything if the timeout isn't longer than the interval
+    pub fn set_heartbeat_settings(&mut self, settings: HeartbeatSettings) -> bool {
+        if settings.interval.is_zero() || settings.timeout <= settings.interval {
+            warn!("Heartbeat timeout must be longer than the interval, ignoring {settings:?}");
+            return false;
+        }
+
+        self.insert_resource(settings);
+        true
+    }
+
+    /// Set what the local player is pressing, which is used from the next tick on until it's set again
+    pub fn set_input(&mut self, input: Input) {
+        self.world.resource_mut::<LocalInput>().0 = input;
+    }
+
+    /// Set the room our player is in, and the rooms it connects to, so that the server sends us what's relevant to it.
+    /// `None` means the player isn't in any room, e.g. because they're in the menus
+    pub fn set_room(&mut self, room: Option<String>, neighbours: Vec<String>) {
+        self.insert_resource(CurrentRoom {
+            room: room.map(Room),
+            neighbours: neighbours.into_iter().map(Room).collect(),
+        });
+    }
+
+    /// Tell the server that our player attacked in the given room, so that it checks what was hit as of the tick we were
+    /// showing the other players and creatures at. Returns false if we aren't playing on a server
+    pub fn report_attack(&mut self, room: String, test: HitTest) -> bool {
+        let Some(tick) = self.world.resource::<InterpolationTimeline>().shown_tick() else {
+            return false;
+        };
+
+        let mut query = self
+            .world
+            .query_filtered::<(&ServerConnection, &mut PacketSender<Client>), Without<Reconnecting>>();
+        let Some((_, mut sender)) = query
+            .iter_mut(&mut self.world)
+            .find(|(connection, _)| connection.joined)
+        else {
+            return false;
+        };
+
+        let result = sender.send(Attack {
+            tick,
+            room: Room(room),
+            test,
+        });
+        if let Err(e) = result {
+            error!("Error while telling the server about an attack: {e}");
+            return false;
+        }
+        true
+    }
+
+    /// Ask a server for its [`ServerStatus`] without joining it.
+    ///
+    /// Any number of queries may be in progress at once. `handler` is called during [`AppContainer::update`] once the
+    /// query finishes
+    pub fn query_server(
+        &mut self,
+        address: &str,
+        port: u16,
+        handler: impl FnOnce(Result<QueriedServerStatus, ConnectToServerError>) + 'static,
+    ) -> Result<(), ConnectToServerError> {
+        info!("Querying the status of '{address}:{port}'...");
+
+        let endpoints = self.endpoints()?;
+        let connecting = connect(
+            endpoints,
+            address,
+            port,
+            *self.world.resource::<ConnectTimeouts>(),
+            self.world.resource::<ResumptionMetrics>().clone(),
+            ProgressSender::default(),
+        )?;
+
+        // Status requests are replay-safe, so a resumed session sends one without waiting for the handshake
+        let task = IoTaskPool::get().spawn(async move { Ok(connecting.await?.open().await?) });
+
+        self.world
+            .non_send_resource_mut::<ServerQueries>()
+            .tasks
+            .push((task, Box::new(handler)));
+
+        Ok(())
+    }
+
+    /// Fetch a page of the public servers that match the filter from a master server.
+    ///
+    /// `handler` is called during [`AppContainer::update`] once the request finishes
+    pub fn fetch_server_list(
+        &mut self,
+        master: MasterServer,
+        filter: ServerFilter,
+        page: u32,
+        handler: impl FnOnce(Result<ServerListPage, ConnectToServerError>) + 'static,
+    ) -> Result<(), ConnectToServerError> {
+        info!(
+            "Fetching page {page} of the server list from '{}:{}'...",
+            master.address, master.port
+        );
+
+        let connecting = master.connect(
+            self.endpoints()?,
+            *self.world.resource::<ConnectTimeouts>(),
+            self.world.resource::<ResumptionMetrics>().clone(),
+        )?;
+
+        let task = IoTaskPool::get().spawn(async move {
+            let connection = connecting.await?;
+            let response =
+                master::request(&connection, &MasterRequest::List { filter, page }).await;
+            disconnect(&connection, DisconnectReason::Quit);
+
+            match response? {
+                MasterResponse::ServerList {
+                    servers,
+                    page,
+                    page_count,
+                } => Ok(ServerListPage {
+                    servers,
+                    page,
+                    page_count,
+                }),
+                MasterResponse::Rejected(reason) => {
+                    Err(ConnectToServerError::MasterRejected(reason))
+                }
+                response => Err(ConnectToServerError::MasterRejected(format!(
+                    "Unexpected response: {response:?}"
+                ))),
+            }
         });
 
+        self.world
+            .non_send_resource_mut::<ServerListRequests>()
+            .0
+            .push((task, Box::new(handler)));
+
+        Ok(())
+    }
+
+    /// Get the app's endpoints, creating them if they don't exist yet.
+    /// This cannot happen in a task because of needing to insert them as a resource if they're created here
+    fn endpoints(&mut self) -> Result<ClientEndpoints, ConnectToServerError> {
+        match self.world.get_resource::<AppEndpoints>() {
+            Some(AppEndpoints(endpoints)) => Ok(endpoints.clone()),
+            None => {
+                let transport = match self.world.get_resource::<ClientTransport>() {
+                    Some(ClientTransport(transport)) => transport.clone(),
+                    None => Arc::default(),
+                };
+                let SessionCache(sessions) = self.world.resource::<SessionCache>().clone();
+                let identity = match self.world.get_resource::<PlayerIdentity>() {
+                    Some(PlayerIdentity(identity)) => identity.clone(),
+                    None => {
+                        let identity = ClientIdentity::generate().map_err(|e| {
+                            ConnectToServerError::CouldNotCreateEndpoint(io::Error::new(
+                                io::ErrorKind::Other,
+                                e,
+                            ))
+                        })?;
+                        self.insert_resource(PlayerIdentity(identity.clone()));
+                        identity
+                    }
+                };
+                let endpoints = create_endpoints(transport, sessions, &identity)
+                    .map_err(ConnectToServerError::CouldNotCreateEndpoint)?;
+                if !endpoints.is_dual_stack() {
+                    info!("Dual-stack sockets aren't available, using one endpoint per address family");
+                }
+                self.insert_resource(AppEndpoints(endpoints.clone()));
+                Ok(endpoints)
+            }
+        }
+    }
+
+    /// Start listening for servers announcing themselves on the local network, if not already listening
+    pub fn start_lan_discovery(&mut self) -> io::Result<()> {
+        if !self.world.contains_resource::<LanDiscovery>() {
+            info!("Listening for servers on the LAN...");
+            let discovery = LanDiscovery::new()?;
+            self.insert_resource(discovery);
+        }
         Ok(())
     }
+
+    /// Stop listening for servers on the local network, forgetting any that were discovered
+    pub fn stop_lan_discovery(&mut self) {
+        self.world.remove_resource::<LanDiscovery>();
+    }
+
+    /// The servers discovered on the local network, most recently seen first.
+    /// Empty if the app isn't listening for them
+    pub fn discovered_servers(&self) -> Vec<&DiscoveredServer> {
+        self.world
+            .get_resource::<LanDiscovery>()
+            .map(LanDiscovery::servers)
+            .unwrap_or_default()
+    }
+
+    /// How often connections have managed to resume a previous session with 0-RTT early data
+    pub fn resumption_stats(&self) -> ResumptionStats {
+        self.world.resource::<ResumptionMetrics>().stats()
+    }
+
+    /// Where we are in the server's queue, as `(position, length)`, or `None` if we aren't queued
+    pub fn queue_position(&mut self) -> Option<(u32, u32)> {
+        self.world
+            .query::<&ServerConnection>()
+            .iter(&self.world)
+            .find_map(|connection| connection.queue_position.as_ref())
+            .map(|queue| (queue.position, queue.length))
+    }
+
+    /// Statistics about our connection to the server, or `None` if we aren't connected
+    pub fn network_stats(&mut self) -> Option<NetworkStats> {
+        self.world
+            .query_filtered::<&NetworkStats, With<ServerConnection>>()
+            .iter(&self.world)
+            .next()
+            .cloned()
+    }
+
+    /// Our estimate of the tick the server is on, or `None` until our clock has been synchronised with the server's
+    pub fn network_tick(&mut self) -> Option<NetworkTick> {
+        let now = self.world.resource::<NetworkClock>().now();
+        self.world
+            .query::<&ClockSync>()
+            .iter(&self.world)
+            .find_map(|sync| sync.server_tick(now))
+    }
+
+    /// How well every player's connection to the server is doing, including our own.
+    /// Empty if we aren't connected, or the server hasn't sent them yet
+    pub fn player_stats(&mut self) -> Vec<PlayerConnection> {
+        self.world
+            .query::<&ServerConnection>()
+            .iter(&self.world)
+            .next()
+            .map(|connection| connection.player_stats.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Resolve the given address, returning a future that races connections to the resolved socket addresses, as in
+/// [Happy Eyeballs](https://www.rfc-editor.org/rfc/rfc8305).
+///
+/// Addresses are tried alternating between IPv6 and IPv4, starting with IPv6. Each attempt gets a head start of
+/// [`ConnectTimeouts::attempt_delay`] before the next one is started alongside it, or less if it fails sooner. The first
+/// attempt to succeed wins, and the others are cancelled.
+///
+/// If there's a session to resume with the server, an attempt to its only address succeeds before the handshake has
+/// completed, so that early data can be sent. When there are several addresses, attempts still wait for the handshake,
+/// as that's the only way to tell which addresses are reachable
+pub(crate) fn connect(
+    endpoints: ClientEndpoints,
+    address: &str,
+    port: u16,
+    timeouts: ConnectTimeouts,
+    metrics: ResumptionMetrics,
+    progress: ProgressSender,
+) -> Result<impl Future<Output = Result<NewConnection, ConnectToServerError>>, ConnectToServerError>
+{
+    let address_port = format!("'{address}:{port}'");
+    let address = address.to_owned();
+
+    // `to_socket_addrs` is blocking with no async alternative, so putting it in the task makes no difference
+    let addresses: Vec<_> = (address.as_str(), port)
+        .to_socket_addrs()
+        .map_err(ConnectToServerError::BadSocketAddress)?
+        .collect();
+
+    Ok(async move {
+        if addresses.is_empty() {
+            return Err(ConnectToServerError::NoAddresses(address_port));
+        }
+
+        info!(
+            "Resolved {address_port} to {} socket addresses.",
+            addresses.len()
+        );
+
+        race(
+            &endpoints,
+            interleave(addresses),
+            &address,
+            timeouts,
+            &metrics,
+            progress,
+        )
+        .await
+    })
+}
+
+/// Order addresses alternating between IPv6 and IPv4, starting with IPv6, otherwise keeping the order they resolved in
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut ipv6, mut ipv4) = (ipv6.into_iter(), ipv4.into_iter());
+
+    let mut interleaved = Vec::with_capacity(ipv6.len() + ipv4.len());
+    loop {
+        match (ipv6.next(), ipv4.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+type ConnectAttempt<'a> =
+    Pin<Box<dyn Future<Output = Result<NewConnection, ConnectToServerError>> + Send + 'a>>;
+
+async fn race(
+    endpoints: &ClientEndpoints,
+    addresses: Vec<SocketAddr>,
+    server_name: &str,
+    timeouts: ConnectTimeouts,
+    metrics: &ResumptionMetrics,
+    progress: ProgressSender,
+) -> Result<NewConnection, ConnectToServerError> {
+    let count = addresses.len() as u32;
+    let mut remaining = addresses.into_iter().enumerate();
+    let mut attempts: Vec<(SocketAddr, ConnectAttempt<'_>)> = Vec::new();
+    let mut failures = Vec::new();
+    let mut next_attempt = Timer::never();
+
+    future::poll_fn(move |cx| loop {
+        // Start the first attempt straight away
+        let mut start_next = attempts.is_empty();
+
+        let mut i = 0;
+        while i < attempts.len() {
+            match attempts[i].1.as_mut().poll(cx) {
+                Poll::Ready(Ok(connection)) => return Poll::Ready(Ok(connection)),
+                Poll::Ready(Err(error)) => {
+                    let (address, _) = attempts.swap_remove(i);
+                    warn!("Could not connect to '{address}': {error}");
+                    failures.push(AddressFailure { address, error });
+                    // No need to wait out the delay when the current attempt has already failed
+                    start_next = true;
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+
+        if Pin::new(&mut next_attempt).poll(cx).is_ready() {
+            start_next = true;
+        }
+
+        if !start_next {
+            return Poll::Pending;
+        }
+
+        match remaining.next() {
+            Some((i, address)) => {
+                info!("Trying to connect to address #{}: '{address}'...", i + 1);
+                progress.report(ConnectProgress::Trying {
+                    address,
+                    attempt: i as u32 + 1,
+                    addresses: count,
+                });
+                // The server_name parameter must either be a valid DNS domain name or a valid IpAddr, with the port excluded
+                let attempt = async move {
+                    let endpoint = endpoints
+                        .for_address(address)
+                        .ok_or(ConnectToServerError::UnsupportedAddressFamily)?;
+                    let connecting = endpoint.connect(address, server_name)?;
+                    let connection = NewConnection::establish(connecting, metrics).await?;
+                    // With nothing to race against, a resumed session carries on in early data without waiting for the
+                    // handshake. If the handshake then fails, the connection closes with the reason, like any other drop
+                    if count == 1 {
+                        Ok(connection)
+                    } else {
+                        Ok(connection.handshake().await?.into())
+                    }
+                };
+                let timeout = timeouts.attempt_timeout;
+                attempts.push((
+                    address,
+                    Box::pin(future::or(attempt, async move {
+                        Timer::after(timeout).await;
+                        Err(ConnectToServerError::TimedOut(timeout))
+                    })),
+                ));
+                next_attempt.set_after(timeouts.attempt_delay);
+                // Loop around to poll the new attempt, so that it can wake this task
+            }
+            None if attempts.is_empty() => {
+                return Poll::Ready(Err(ConnectToServerError::AllAddressesFailed(
+                    std::mem::take(&mut failures),
+                )))
+            }
+            None => return Poll::Pending,
+        }
+    })
+    .await
 }
 
 // Needs to be an exclusive system to be able to remove the non-send ConnectToServerTask resource
 fn poll_connect_to_server_task(world: &mut World) {
     if let Some(mut task) = world.get_non_send_resource_mut::<ConnectToServerTask>() {
+        let task = &mut *task;
+        for progress in task.progress.try_iter() {
+            (task.progress_handler)(&progress);
+        }
+
         if let Some(result) = block_on(poll_once(&mut task.task)) {
             match result {
                 Ok(connection) => {
                     (task.ok_handler)();
-                    world.insert_resource(connection);
+                    world.spawn((
+                        connection,
+                        ConnectionBundle::<Client>::default(),
+                        ClockSync::default(),
+                    ));
                 }
                 // Only anyhow errors are allowed to cross the FFI boundry for simplicity
                 Err(e) => (task.error_handler)(anyhow!(e)),
@@ -222,16 +937,344 @@ fn poll_connect_to_server_task(world: &mut World) {
     }
 }
 
-fn drive_send_stream(mut connection: ResMut<ServerConnection>) {
-    if let Err(e) = connection.send.drive() {
-        error!("Error while sending data to server: {e}");
+fn handshake(
+    mut query: Query<
+        (
+            &ServerConnection,
+            Option<&Reconnecting>,
+            &mut PacketSender<Client>,
+        ),
+        Added<QuinnConnection>,
+    >,
+) {
+    for (connection, reconnecting, mut sender) in query.iter_mut() {
+        let result = match (reconnecting, &connection.resume) {
+            (Some(_), Some(resume)) => {
+                info!("Asking the server for our place back...");
+                sender.send(Resume {
+                    token: resume.token,
+                })
+            }
+            _ => {
+                info!("Initiating handshake...");
+                sender.send(Profile {
+                    protocol_version: PROTOCOL_VERSION,
+                    username: connection.username.clone(),
+                    password: connection.password.clone(),
+                })
+            }
+        };
+        if let Err(e) = result {
+            error!("Error while sending handshake to server: {e}");
+        }
+    }
+}
+
+fn store_resume_token(mut query: Query<(&mut ServerConnection, &mut Received<SessionResumable>)>) {
+    for (mut connection, mut resumables) in query.iter_mut() {
+        if let Some(resume) = resumables.buffer.drain(..).last() {
+            debug!(
+                "The server will hold our place for {:?} if the connection drops",
+                resume.grace_period
+            );
+            connection.resume = Some(resume);
+        }
+    }
+}
+
+fn store_player_stats(mut query: Query<(&mut ServerConnection, &mut Received<PlayerStats>)>) {
+    for (mut connection, mut stats) in query.iter_mut() {
+        if let Some(stats) = stats.buffer.drain(..).last() {
+            connection.player_stats = stats.players;
+        }
+    }
+}
+
+fn update_queue_position(
+    mut query: Query<(
+        &mut ServerConnection,
+        &mut Received<QueuePosition>,
+        &Received<Lobby>,
+    )>,
+) {
+    for (mut connection, mut positions, lobbies) in query.iter_mut() {
+        if let Some(position) = positions.buffer.drain(..).last() {
+            info!(
+                "The server is full, waiting in the queue at position {}/{}",
+                position.position, position.length
+            );
+            connection.queue_position = Some(position);
+        }
+
+        // The lobby is only sent once we've been let in
+        if !lobbies.buffer.is_empty() {
+            connection.joined = true;
+            if connection.queue_position.take().is_some() {
+                info!("Left the queue and joined the server");
+            }
+        }
+    }
+}
+
+/// The room our player is in, which the server sends us the entities that are relevant to
+#[derive(Resource, Debug, Default)]
+struct CurrentRoom {
+    room: Option<Room>,
+    /// The rooms that the room connects to
+    neighbours: Vec<Room>,
+}
+
+/// Tell the server which room we're in whenever it changes, and whenever it lets us in, as it only learns about rooms
+/// from us
+fn report_room(
+    room: Res<CurrentRoom>,
+    mut query: Query<(
+        &ServerConnection,
+        Option<&Reconnecting>,
+        &Received<Lobby>,
+        &mut PacketSender<Client>,
+    )>,
+) {
+    for (connection, reconnecting, lobbies, mut sender) in query.iter_mut() {
+        let changed = room.is_changed() && connection.joined && reconnecting.is_none();
+        if !changed && lobbies.buffer.is_empty() {
+            continue;
+        }
+
+        let result = sender.send(RoomChanged {
+            room: room.room.clone(),
+            neighbours: room.neighbours.clone(),
+        });
+        if let Err(e) = result {
+            error!("Error while telling the server which room we're in: {e}");
+        }
+    }
+}
+
+/// Everything needed to reconnect to the server from a system
+#[derive(SystemParam)]
+struct Reconnector<'w> {
+    master: Option<Res<'w, MasterServer>>,
+    timeouts: Res<'w, ConnectTimeouts>,
+    metrics: Res<'w, ResumptionMetrics>,
+    handlers: Option<Res<'w, ReconnectHandlers>>,
+}
+
+impl Reconnector<'_> {
+    /// Start an attempt to reconnect to the server after waiting for `delay`, telling C# about it
+    fn attempt(
+        &self,
+        connection: &ServerConnection,
+        attempt: u32,
+        delay: Duration,
+    ) -> Task<Result<QuinnConnection, ConnectToServerError>> {
+        if let Some(handlers) = &self.handlers {
+            (handlers.reconnecting)(attempt);
+        }
+
+        let connecting = connect_with_traversal(
+            connection.endpoints.clone(),
+            connection.address.clone(),
+            connection.port,
+            self.master.as_deref().cloned(),
+            *self.timeouts,
+            self.metrics.clone(),
+            ProgressSender::default(),
+        );
+        IoTaskPool::get().spawn(async move {
+            Timer::after(delay).await;
+            Ok(connecting.await?.open().await?)
+        })
+    }
+
+    fn reconnected(&self) {
+        if let Some(handlers) = &self.handlers {
+            (handlers.reconnected)();
+        }
+    }
+
+    fn failed(&self, error: ConnectToServerError) {
+        if let Some(handlers) = &self.handlers {
+            // Only anyhow errors are allowed to cross the FFI boundry for simplicity
+            (handlers.failed)(anyhow!(error));
+        }
+    }
+}
+
+/// Reconnect if the connection dropped after the server let us in, otherwise forget the server
+fn handle_disconnect(
+    mut commands: Commands,
+    mut events: EventReader<PeerDisconnected>,
+    query: Query<(&ServerConnection, Option<&Reconnecting>)>,
+    reconnector: Reconnector,
+) {
+    for PeerDisconnected { entity, reason } in events.read() {
+        let Ok((connection, reconnecting)) = query.get(*entity) else {
+            continue;
+        };
+
+        let now = Instant::now();
+        // Dropping again while getting our place back keeps the original deadline
+        let deadline = match reconnecting {
+            Some(reconnecting) => Some(reconnecting.deadline),
+            None => connection
+                .resume
+                .as_ref()
+                .map(|resume| now + resume.grace_period),
+        };
+
+        match deadline {
+            Some(deadline) if reason.is_resumable() && now < deadline => {
+                info!("Lost the connection to the server ({reason}), reconnecting...");
+                let attempt = reconnecting.map_or(1, |reconnecting| reconnecting.attempt + 1);
+                commands.entity(*entity).insert(Reconnecting {
+                    task: Some(reconnector.attempt(connection, attempt, Duration::ZERO)),
+                    attempt,
+                    deadline,
+                });
+            }
+            _ => {
+                info!("Disconnected from server: {reason}");
+                if reconnecting.is_some() {
+                    reconnector.failed(ConnectToServerError::Disconnected(reason.clone()));
+                }
+                commands.entity(*entity).despawn();
+                commands.add(despawn_replicated);
+                commands.add(reset_prediction);
+                commands.add(reset_interpolation);
+            }
+        }
+    }
+}
+
+fn poll_reconnect(
+    mut commands: Commands,
+    mut query: Query<(Entity, &ServerConnection, &mut Reconnecting)>,
+    reconnector: Reconnector,
+) {
+    for (entity, connection, mut reconnecting) in query.iter_mut() {
+        let Some(task) = &mut reconnecting.task else {
+            continue;
+        };
+        let Some(result) = block_on(poll_once(task)) else {
+            continue;
+        };
+
+        match result {
+            Ok(quinn) => {
+                info!("Reconnected to the server");
+                reconnecting.task = None;
+                commands
+                    .entity(entity)
+                    .insert((quinn, ConnectionBundle::<Client>::default()));
+            }
+            Err(e) if Instant::now() < reconnecting.deadline => {
+                warn!("Could not reconnect to the server, trying again: {e}");
+                reconnecting.attempt += 1;
+                let attempt = reconnecting.attempt;
+                reconnecting.task =
+                    Some(reconnector.attempt(connection, attempt, RECONNECT_RETRY_DELAY));
+            }
+            Err(e) => {
+                info!("Giving up on reconnecting to the server: {e}");
+                reconnector.failed(e);
+                commands.entity(entity).despawn();
+                commands.add(despawn_replicated);
+                commands.add(reset_prediction);
+                commands.add(reset_interpolation);
+            }
+        }
+    }
+}
+
+/// The server sends the lobby once it has given our place back
+fn finish_reconnect(
+    mut commands: Commands,
+    query: Query<(Entity, &Reconnecting, &Received<Lobby>)>,
+    reconnector: Reconnector,
+) {
+    for (entity, reconnecting, lobbies) in query.iter() {
+        if reconnecting.task.is_none() && !lobbies.buffer.is_empty() {
+            info!("The server gave our place back");
+            commands.entity(entity).remove::<Reconnecting>();
+            reconnector.reconnected();
+        }
+    }
+}
+
+fn poll_server_list_requests(mut requests: NonSendMut<ServerListRequests>) {
+    for (mut task, handler) in std::mem::take(&mut requests.0) {
+        match block_on(poll_once(&mut task)) {
+            None => requests.0.push((task, handler)),
+            Some(result) => handler(result),
+        }
+    }
+}
+
+fn poll_server_queries(mut commands: Commands, mut queries: NonSendMut<ServerQueries>) {
+    let queries = &mut *queries;
+    for (mut task, handler) in std::mem::take(&mut queries.tasks) {
+        match block_on(poll_once(&mut task)) {
+            None => queries.tasks.push((task, handler)),
+            Some(Ok(quinn)) => {
+                let entity = commands
+                    .spawn((StatusQuery, quinn, ConnectionBundle::<Client>::default()))
+                    .id();
+                queries.pending.insert(entity, handler);
+            }
+            Some(Err(e)) => handler(Err(e)),
+        }
     }
 }
 
-fn handshake(mut connection: ResMut<ServerConnection>) {
-    info!("Initiating handshake...");
-    let bytes = connection.username.clone().into();
-    connection.send.queue_chunk(bytes);
+fn send_status_requests(mut query: Query<&mut PacketSender<Client>, Added<StatusQuery>>) {
+    for mut sender in query.iter_mut() {
+        let request = StatusRequest {
+            protocol_version: PROTOCOL_VERSION,
+        };
+        if let Err(e) = sender.send(request) {
+            error!("Error while sending status request: {e}");
+        }
+    }
+}
+
+fn finish_server_queries(
+    mut commands: Commands,
+    mut queries: NonSendMut<ServerQueries>,
+    mut query: Query<
+        (Entity, &mut QuinnConnection, &mut Received<ServerStatus>),
+        With<StatusQuery>,
+    >,
+    mut events: EventReader<PeerDisconnected>,
+) {
+    for (entity, mut quinn, mut statuses) in query.iter_mut() {
+        let Some(status) = statuses.buffer.drain(..).last() else {
+            continue;
+        };
+
+        let status = QueriedServerStatus {
+            status,
+            ping: quinn.connection().rtt(),
+            fingerprint: peer_fingerprint(quinn.connection()),
+        };
+
+        // The connection is despawned below once it has finished closing
+        quinn.disconnect(DisconnectReason::Quit);
+        if let Some(handler) = queries.pending.remove(&entity) {
+            handler(Ok(status));
+        }
+    }
+
+    for PeerDisconnected { entity, reason } in events.read() {
+        // Only connections that closed before the server answered still have a handler
+        if let Some(handler) = queries.pending.remove(entity) {
+            handler(Err(ConnectToServerError::Disconnected(reason.clone())));
+        }
+
+        if query.contains(*entity) {
+            commands.entity(*entity).despawn();
+        }
+    }
 }
 
 /// Configures native logging permanently for the whole application. Calling this more than once will panic.
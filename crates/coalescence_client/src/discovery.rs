@@ -0,0 +1,157 @@
+//! Listens for servers announcing themselves on the local network
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use coalescence_proto::{
+    discovery::{Announcement, MAX_ANNOUNCEMENT_LENGTH},
+    packet::ServerStatus,
+    PROTOCOL_VERSION,
+};
+use coalescence_quinn::discovery::listen_socket;
+
+/// Servers that haven't announced themselves for this long are assumed to have shut down
+const SERVER_EXPIRY: Duration = Duration::from_secs(10);
+
+/// How many datagrams are read from each socket per update at most, so that a flood of them can't stall the game
+const MAX_DATAGRAMS_PER_UPDATE: usize = 64;
+
+/// A server that announced itself on the local network
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    /// The address to connect to the server on
+    pub address: SocketAddr,
+    pub status: ServerStatus,
+    /// When the server last announced itself
+    pub last_seen: Instant,
+}
+
+impl DiscoveredServer {
+    /// How long ago the server last announced itself
+    pub fn age(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
+
+    /// Whether this client can join the server
+    pub fn is_compatible(&self) -> bool {
+        self.status.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// A resource that exists while the app is listening for servers on the local network
+#[derive(Resource, Debug)]
+pub struct LanDiscovery {
+    sockets: Vec<UdpSocket>,
+    /// By the fingerprint of their certificate
+    servers: HashMap<[u8; 32], DiscoveredServer>,
+}
+
+impl LanDiscovery {
+    /// Start listening on every address family that's available, failing only if none of them are
+    pub fn new() -> io::Result<Self> {
+        let mut sockets = Vec::new();
+        let mut last_error = None;
+        for ipv6 in [true, false] {
+            match listen_socket(ipv6) {
+                Ok(socket) => sockets.push(socket),
+                Err(e) => {
+                    warn!(
+                        "Could not listen for LAN servers over {}: {e}",
+                        if ipv6 { "IPv6" } else { "IPv4" }
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if sockets.is_empty() => Err(e),
+            _ => Ok(Self {
+                sockets,
+                servers: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The servers that have been discovered, most recently seen first
+    pub fn servers(&self) -> Vec<&DiscoveredServer> {
+        let mut servers: Vec<_> = self.servers.values().collect();
+        servers.sort_unstable_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        servers
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LanDiscoveryPlugin;
+
+impl Plugin for LanDiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PreUpdate,
+            receive_announcements.run_if(resource_exists::<LanDiscovery>()),
+        );
+    }
+}
+
+fn receive_announcements(mut discovery: ResMut<LanDiscovery>) {
+    let discovery = &mut *discovery;
+    let now = Instant::now();
+    // One byte longer than any announcement, so that longer datagrams can be told apart from ones that just fit
+    let mut buffer = [0; MAX_ANNOUNCEMENT_LENGTH + 1];
+
+    for socket in &discovery.sockets {
+        for _ in 0..MAX_DATAGRAMS_PER_UPDATE {
+            let (length, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Only that datagram is lost, e.g. on Windows, where datagrams too long for the buffer are an error
+                Err(e) => {
+                    debug!("Error while listening for LAN servers: {e}");
+                    continue;
+                }
+            };
+            if length > MAX_ANNOUNCEMENT_LENGTH {
+                continue;
+            }
+
+            let Some(Announcement {
+                port,
+                fingerprint,
+                status,
+            }) = Announcement::decode(&buffer[..length])
+            else {
+                continue;
+            };
+
+            // Keeps the scope of link-local IPv6 addresses, without which they can't be connected to
+            let mut address = source;
+            address.set_port(port);
+            // Servers announcing themselves over both address families are listed once, by their IPv4 address for as
+            // long as they keep announcing on it, as it doesn't depend on the interface
+            if discovery
+                .servers
+                .get(&fingerprint)
+                .is_some_and(|server| server.address.is_ipv4() && address.is_ipv6())
+            {
+                continue;
+            }
+            discovery.servers.insert(
+                fingerprint,
+                DiscoveredServer {
+                    address,
+                    status,
+                    last_seen: now,
+                },
+            );
+        }
+    }
+
+    discovery
+        .servers
+        .retain(|_, server| now.duration_since(server.last_seen) < SERVER_EXPIRY);
+}
@@ -1,6 +1,8 @@
 use std::{
     mem::ManuallyDrop,
+    net::SocketAddr,
     ops::{Deref, DerefMut},
+    time::Duration,
 };
 
 use anyhow::anyhow;
@@ -8,9 +10,24 @@ use bevy::{
     prelude::*,
     tasks::{AsyncComputeTaskPool, ComputeTaskPool, IoTaskPool},
 };
+use coalescence_common::GameMode;
+use coalescence_proto::{
+    heartbeat::HeartbeatSettings, input::Input, lag_compensation::HitTest, master::ServerFilter,
+    stats::ConnectionQuality, PROTOCOL_VERSION,
+};
+use coalescence_quinn::{
+    parse_fingerprint,
+    transport::{CongestionController, TransportSettings},
+};
 use widestring::{U16CStr, U16CString, Utf16Str};
 
-use crate::app::{configure_logging, AppContainer};
+use crate::{
+    app::{
+        configure_logging, AppContainer, ConnectProgress, ConnectTimeouts, QueriedServerStatus,
+        ServerListPage,
+    },
+    traversal::MasterServer,
+};
 
 /// A `Box`, but only for `Sized` types, so guaranteed to always be 'thin', i.e. always 1 `usize`.
 /// Pointers to unsized types are 'fat', i.e. 2 `usize`s. The second `usize` is for len/vtable/etc.
@@ -86,9 +103,36 @@ pub enum AppConnectToServerResult {
     Err(anyhow::Error),
 }
 
+/// Which step of connecting to a server is being reported
+#[repr(u8)]
+#[derive(Debug)]
+pub enum ConnectStage {
+    /// Trying one of the socket addresses that the server's address resolved to
+    Trying,
+    /// Trying to connect through a hole that the server punched through its NAT
+    Punching,
+    /// Trying to connect through a relay on the master server
+    Relaying,
+}
+
+/// Progress made while connecting to a server.
+/// The address string is only valid until the callback that it was passed to returns
+#[repr(C)]
+#[derive(Debug)]
+pub struct ConnectProgressInfo {
+    pub stage: ConnectStage,
+    /// The socket address being connected to
+    pub address: *const u16,
+    /// Which of the resolved socket addresses this is, starting from 1, or 0 if not [`ConnectStage::Trying`]
+    pub attempt: u32,
+    /// How many socket addresses the server's address resolved to, or 0 if not [`ConnectStage::Trying`]
+    pub addresses: u32,
+}
+
 /// # Safety
 ///
-/// The given pointers must be [valid], and `address` & `username` must point to null-terminated, UTF-16 encoded strings
+/// The given pointers must be [valid], and `address` & `username` must point to null-terminated, UTF-16 encoded strings.
+/// `password` may be null if no password should be given, otherwise it must also point to a null-terminated, UTF-16 encoded string
 ///
 /// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
 #[no_mangle]
@@ -97,6 +141,8 @@ pub unsafe extern "C" fn app_connect_to_server(
     address: *const u16,
     port: u16,
     username: *const u16,
+    password: *const u16,
+    async_progress_handler: extern "C" fn(*const ConnectProgressInfo),
     async_ok_handler: extern "C" fn(),
     async_error_handler: extern "C" fn(anyhow::Error),
 ) -> AppConnectToServerResult {
@@ -109,6 +155,8 @@ pub unsafe extern "C" fn app_connect_to_server(
             &marshal_string(address),
             port,
             marshal_string(username),
+            (!password.is_null()).then(|| marshal_string(password)),
+            move |progress| call_progress_handler(async_progress_handler, progress),
             async_ok_handler,
             async_error_handler,
         ) {
@@ -118,6 +166,1026 @@ pub unsafe extern "C" fn app_connect_to_server(
     }
 }
 
+fn call_progress_handler(
+    handler: extern "C" fn(*const ConnectProgressInfo),
+    progress: &ConnectProgress,
+) {
+    let (stage, address, attempt, addresses) = match *progress {
+        ConnectProgress::Trying {
+            address,
+            attempt,
+            addresses,
+        } => (ConnectStage::Trying, address, attempt, addresses),
+        ConnectProgress::Punching { address } => (ConnectStage::Punching, address, 0, 0),
+        ConnectProgress::Relaying { address } => (ConnectStage::Relaying, address, 0, 0),
+    };
+
+    let address = U16CString::from_str_truncate(address.to_string());
+    handler(&ConnectProgressInfo {
+        stage,
+        address: address.as_ptr(),
+        attempt,
+        addresses,
+    });
+}
+
+/// Cancels the connection attempt in progress, which calls its error handler with a "cancelled" error.
+/// Returns false if no connection attempt was in progress, or the pointer is null
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_cancel_connect(app: *mut AppContainer) -> bool {
+    if app.is_null() {
+        warn!("Cannot cancel connecting on null app pointer");
+        false
+    } else {
+        (*app).cancel_connect()
+    }
+}
+
+/// Sets the callbacks for when the connection to the server drops and the app reconnects on its own.
+/// `reconnecting` is called with the attempt number before every attempt, `reconnected` once the server has given the
+/// player's place back, and `failed` if it gave it away or couldn't be reached in time
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_reconnect_handlers(
+    app: *mut AppContainer,
+    reconnecting: extern "C" fn(u32),
+    reconnected: extern "C" fn(),
+    failed: extern "C" fn(anyhow::Error),
+) {
+    if app.is_null() {
+        warn!("Cannot set the reconnect handlers of null app pointer");
+        return;
+    }
+
+    (*app).set_reconnect_handlers(reconnecting, reconnected, failed);
+}
+
+#[repr(u8)]
+#[derive(Debug)]
+pub enum AppQueryServerResult {
+    Ok,
+    AppPointerIsNull,
+    AddressPointerIsNull,
+    Err(anyhow::Error),
+}
+
+/// The answer to a status query. The strings are only valid until the callback that it was passed to returns
+#[repr(C)]
+#[derive(Debug)]
+pub struct ServerStatusInfo {
+    pub name: *const u16,
+    pub motd: *const u16,
+    pub players: u32,
+    pub max_players: u32,
+    pub protocol_version: u32,
+    /// Whether the server's protocol version matches this client's, so it can be joined
+    pub compatible: bool,
+    pub game_mode: GameMode,
+    pub password_required: bool,
+    pub ping_milliseconds: u32,
+    /// Whether the server presented a certificate, i.e. whether `certificate_fingerprint` is valid
+    pub has_fingerprint: bool,
+    /// The SHA-256 hash of the server's certificate
+    pub certificate_fingerprint: [u8; 32],
+}
+
+/// Passes the status to the handler, keeping its strings alive until the handler returns
+fn call_status_handler(
+    status: QueriedServerStatus,
+    handler: extern "C" fn(*const ServerStatusInfo),
+) {
+    let name = U16CString::from_str_truncate(&status.status.name);
+    let motd = U16CString::from_str_truncate(&status.status.motd);
+    let info = ServerStatusInfo {
+        name: name.as_ptr(),
+        motd: motd.as_ptr(),
+        players: status.status.players,
+        max_players: status.status.max_players,
+        protocol_version: status.status.protocol_version,
+        compatible: status.is_compatible(),
+        game_mode: status.status.game_mode,
+        password_required: status.status.password_required,
+        ping_milliseconds: status.ping.as_millis().try_into().unwrap_or(u32::MAX),
+        has_fingerprint: status.fingerprint.is_some(),
+        certificate_fingerprint: status.fingerprint.unwrap_or_default(),
+    };
+    handler(&info);
+}
+
+/// Asks a server for its status without joining it. Any number of queries may be in progress at once.
+/// One of the handlers is called during a later [`update_app`] call, once the query finishes
+///
+/// # Safety
+///
+/// The given pointers must be [valid], and `address` must point to a null-terminated, UTF-16 encoded string
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_query_server(
+    app: *mut AppContainer,
+    address: *const u16,
+    port: u16,
+    async_ok_handler: extern "C" fn(*const ServerStatusInfo),
+    async_error_handler: extern "C" fn(anyhow::Error),
+) -> AppQueryServerResult {
+    if app.is_null() {
+        AppQueryServerResult::AppPointerIsNull
+    } else if address.is_null() {
+        AppQueryServerResult::AddressPointerIsNull
+    } else {
+        match (*app).query_server(&marshal_string(address), port, move |result| match result {
+            Ok(status) => call_status_handler(status, async_ok_handler),
+            // Only anyhow errors are allowed to cross the FFI boundry for simplicity
+            Err(e) => async_error_handler(anyhow!(e)),
+        }) {
+            Ok(_) => AppQueryServerResult::Ok,
+            Err(e) => AppQueryServerResult::Err(anyhow!(e)),
+        }
+    }
+}
+
+/// Starts listening for servers on the local network. Returns false if the pointer is null or listening failed,
+/// in which case the reason is logged
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_start_lan_discovery(app: *mut AppContainer) -> bool {
+    if app.is_null() {
+        warn!("Cannot start LAN discovery on null app pointer");
+        return false;
+    }
+
+    match (*app).start_lan_discovery() {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Could not listen for servers on the LAN: {e}");
+            false
+        }
+    }
+}
+
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_stop_lan_discovery(app: *mut AppContainer) {
+    if !app.is_null() {
+        (*app).stop_lan_discovery();
+    }
+}
+
+/// A server that announced itself on the local network.
+/// The strings are only valid until the callback that it was passed to returns
+#[repr(C)]
+#[derive(Debug)]
+pub struct DiscoveredServerInfo {
+    /// The IP address of the server, followed by `%` and the scope ID for link-local IPv6 addresses
+    pub address: *const u16,
+    pub port: u16,
+    /// How long ago the server last announced itself
+    pub age_milliseconds: u32,
+    pub name: *const u16,
+    pub motd: *const u16,
+    pub players: u32,
+    pub max_players: u32,
+    /// Whether the server's protocol version matches this client's, so it can be joined
+    pub compatible: bool,
+    pub game_mode: GameMode,
+    pub password_required: bool,
+}
+
+/// Calls the handler once for each server discovered on the local network, most recently seen first
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_discovered_servers(
+    app: *mut AppContainer,
+    handler: extern "C" fn(*const DiscoveredServerInfo),
+) {
+    if app.is_null() {
+        warn!("Cannot get discovered servers of null app pointer");
+        return;
+    }
+
+    for server in (*app).discovered_servers() {
+        let address = match server.address {
+            SocketAddr::V6(address) if address.scope_id() != 0 => {
+                format!("{}%{}", address.ip(), address.scope_id())
+            }
+            address => address.ip().to_string(),
+        };
+        let address = U16CString::from_str_truncate(address);
+        let name = U16CString::from_str_truncate(&server.status.name);
+        let motd = U16CString::from_str_truncate(&server.status.motd);
+        let info = DiscoveredServerInfo {
+            address: address.as_ptr(),
+            port: server.address.port(),
+            age_milliseconds: server.age().as_millis().try_into().unwrap_or(u32::MAX),
+            name: name.as_ptr(),
+            motd: motd.as_ptr(),
+            players: server.status.players,
+            max_players: server.status.max_players,
+            compatible: server.is_compatible(),
+            game_mode: server.status.game_mode,
+            password_required: server.status.password_required,
+        };
+        handler(&info);
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug)]
+pub enum AppFetchServerListResult {
+    Ok,
+    AppPointerIsNull,
+    AddressPointerIsNull,
+    /// The fingerprint pointer was null, or didn't point to a fingerprint formatted as colon-separated hex bytes
+    InvalidFingerprint,
+    Err(anyhow::Error),
+}
+
+/// Which servers to include in the server list
+#[repr(C)]
+#[derive(Debug)]
+pub struct ServerListFilter {
+    /// Only include servers whose names contain this, ignoring case. May be null to include any name
+    pub name: *const u16,
+    /// Only include servers that this client can join
+    pub only_compatible: bool,
+    /// Whether to filter by `game_mode`
+    pub has_game_mode: bool,
+    /// A [`GameMode`], which is passed as its underlying value because C# could pass values that aren't one
+    pub game_mode: u8,
+    /// Only include servers with at least this many players
+    pub min_players: u32,
+    /// Only include servers that aren't full
+    pub not_full: bool,
+    /// Only include servers that don't need a password
+    pub no_password: bool,
+}
+
+/// A server in the public server list. The strings are only valid until the callback that it was passed to returns
+#[repr(C)]
+#[derive(Debug)]
+pub struct ListedServerInfo {
+    /// The IP address of the server
+    pub address: *const u16,
+    pub port: u16,
+    pub name: *const u16,
+    pub motd: *const u16,
+    pub players: u32,
+    pub max_players: u32,
+    /// Whether the server's protocol version matches this client's, so it can be joined
+    pub compatible: bool,
+    pub game_mode: GameMode,
+    pub password_required: bool,
+}
+
+/// Fetches a page of the public server list from a master server, which is only trusted if its certificate has the
+/// fingerprint `master_fingerprint`, formatted as colon-separated hex bytes like the master server logs it.
+/// One of the handlers is called during a later [`update_app`] call, once the request finishes.
+/// The ok handler is given an array of `count` servers, which is only valid until it returns
+///
+/// # Safety
+///
+/// The given pointers must be [valid], and `master_address` and `master_fingerprint` must point to null-terminated,
+/// UTF-16 encoded strings. `filter` may be null to include every server
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_fetch_server_list(
+    app: *mut AppContainer,
+    master_address: *const u16,
+    port: u16,
+    master_fingerprint: *const u16,
+    filter: *const ServerListFilter,
+    page: u32,
+    async_ok_handler: extern "C" fn(*const ListedServerInfo, usize, u32, u32),
+    async_error_handler: extern "C" fn(anyhow::Error),
+) -> AppFetchServerListResult {
+    if app.is_null() {
+        return AppFetchServerListResult::AppPointerIsNull;
+    } else if master_address.is_null() {
+        return AppFetchServerListResult::AddressPointerIsNull;
+    }
+
+    let Some(fingerprint) = marshal_fingerprint(master_fingerprint) else {
+        return AppFetchServerListResult::InvalidFingerprint;
+    };
+    let master = MasterServer {
+        address: marshal_string(master_address),
+        port,
+        fingerprint,
+    };
+
+    let filter = match filter.as_ref() {
+        Some(filter) => ServerFilter {
+            name: (!filter.name.is_null()).then(|| marshal_string(filter.name)),
+            protocol_version: filter.only_compatible.then_some(PROTOCOL_VERSION),
+            game_mode: match filter
+                .has_game_mode
+                .then(|| GameMode::try_from(filter.game_mode))
+            {
+                Some(Ok(game_mode)) => Some(game_mode),
+                Some(Err(value)) => {
+                    warn!("Cannot filter the server list by unknown game mode {value}");
+                    return AppFetchServerListResult::Err(anyhow!("Unknown game mode {value}"));
+                }
+                None => None,
+            },
+            min_players: (filter.min_players > 0).then_some(filter.min_players),
+            not_full: filter.not_full,
+            no_password: filter.no_password,
+        },
+        None => ServerFilter::default(),
+    };
+
+    let result = (*app).fetch_server_list(master, filter, page, move |result| match result {
+        Ok(page) => call_server_list_handler(page, async_ok_handler),
+        // Only anyhow errors are allowed to cross the FFI boundry for simplicity
+        Err(e) => async_error_handler(anyhow!(e)),
+    });
+
+    match result {
+        Ok(_) => AppFetchServerListResult::Ok,
+        Err(e) => AppFetchServerListResult::Err(anyhow!(e)),
+    }
+}
+
+/// Passes the page to the handler, keeping its strings alive until the handler returns
+fn call_server_list_handler(
+    page: ServerListPage,
+    handler: extern "C" fn(*const ListedServerInfo, usize, u32, u32),
+) {
+    let strings: Vec<_> = page
+        .servers
+        .iter()
+        .map(|server| {
+            (
+                U16CString::from_str_truncate(server.address.ip().to_string()),
+                U16CString::from_str_truncate(&server.status.name),
+                U16CString::from_str_truncate(&server.status.motd),
+            )
+        })
+        .collect();
+
+    let infos: Vec<_> = page
+        .servers
+        .iter()
+        .zip(&strings)
+        .map(|(server, (address, name, motd))| ListedServerInfo {
+            address: address.as_ptr(),
+            port: server.address.port(),
+            name: name.as_ptr(),
+            motd: motd.as_ptr(),
+            players: server.status.players,
+            max_players: server.status.max_players,
+            compatible: server.status.protocol_version == PROTOCOL_VERSION,
+            game_mode: server.status.game_mode,
+            password_required: server.status.password_required,
+        })
+        .collect();
+
+    handler(infos.as_ptr(), infos.len(), page.page, page.page_count);
+}
+
+/// Sets how connection attempts to a server's resolved socket addresses are raced against each other: how many
+/// milliseconds each attempt is given before the next one is started alongside it, and how many milliseconds each attempt
+/// may take before it's given up on
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_connect_timeouts(
+    app: *mut AppContainer,
+    attempt_delay_ms: u32,
+    attempt_timeout_ms: u32,
+) {
+    if app.is_null() {
+        warn!("Cannot set the connect timeouts of null app pointer");
+        return;
+    }
+
+    (*app).set_connect_timeouts(ConnectTimeouts {
+        attempt_delay: Duration::from_millis(attempt_delay_ms.into()),
+        attempt_timeout: Duration::from_millis(attempt_timeout_ms.into()),
+    });
+}
+
+/// Loads the identity that servers know the player by from the PEM file at `path`, creating it there if it doesn't exist
+/// yet. Returns false if either pointer is null, or the identity couldn't be loaded or created
+///
+/// # Safety
+///
+/// The given pointers must be [valid]. `path` must point to a null-terminated, UTF-16 encoded string
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_load_identity(app: *mut AppContainer, path: *const u16) -> bool {
+    if app.is_null() || path.is_null() {
+        warn!("Cannot load the identity with a null pointer");
+        return false;
+    }
+
+    match (*app).load_identity(marshal_string(path).as_ref()) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Could not load the identity: {e}");
+            false
+        }
+    }
+}
+
+/// Sets how many milliseconds apart heartbeats are sent to the server, and how many milliseconds it may go silent for
+/// before the connection is considered lost. Returns false if the pointer is null, or the timeout isn't longer than the
+/// interval
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_heartbeat_settings(
+    app: *mut AppContainer,
+    interval_ms: u32,
+    timeout_ms: u32,
+) -> bool {
+    if app.is_null() {
+        warn!("Cannot set the heartbeat settings of null app pointer");
+        return false;
+    }
+
+    (*app).set_heartbeat_settings(HeartbeatSettings {
+        interval: Duration::from_millis(interval_ms.into()),
+        timeout: Duration::from_millis(timeout_ms.into()),
+    })
+}
+
+/// What the local player is pressing, the same as the game's `Player.InputPackage`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InputInfo {
+    /// -1 for left, 0 for neither and 1 for right
+    pub x: i8,
+    /// -1 for down, 0 for neither and 1 for up
+    pub y: i8,
+    pub jump: bool,
+    pub throw: bool,
+    pub pick_up: bool,
+    pub map: bool,
+    pub analogue_x: f32,
+    pub analogue_y: f32,
+}
+
+impl From<&InputInfo> for Input {
+    fn from(info: &InputInfo) -> Self {
+        Self {
+            x: info.x.signum(),
+            y: info.y.signum(),
+            jump: info.jump,
+            throw: info.throw,
+            pick_up: info.pick_up,
+            map: info.map,
+            analogue_x: info.analogue_x.clamp(-1.0, 1.0),
+            analogue_y: info.analogue_y.clamp(-1.0, 1.0),
+        }
+    }
+}
+
+/// Sets what the local player is pressing, which is used from the next tick on until it's set again.
+/// Returns false if either pointer is null
+///
+/// # Safety
+///
+/// The given pointers must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_input(app: *mut AppContainer, input: *const InputInfo) -> bool {
+    if app.is_null() || input.is_null() {
+        warn!("Cannot set the input with a null pointer");
+        return false;
+    }
+
+    (*app).set_input((&*input).into());
+    true
+}
+
+/// Sets the room our player is in, and the `neighbour_count` rooms it connects to, so that the server sends us the
+/// entities that are relevant to it. If `room` is null, the player isn't in any room, e.g. because they're in the menus
+///
+/// # Safety
+///
+/// The given pointers must be [valid]. `room` must either be null or point to a null-terminated, UTF-16 encoded string,
+/// and `neighbours` must point to `neighbour_count` pointers to null-terminated, UTF-16 encoded strings
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_room(
+    app: *mut AppContainer,
+    room: *const u16,
+    neighbours: *const *const u16,
+    neighbour_count: usize,
+) {
+    if app.is_null() {
+        warn!("Cannot set the room of null app pointer");
+        return;
+    }
+
+    let room = (!room.is_null()).then(|| marshal_string(room));
+    let neighbours = if neighbours.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(neighbours, neighbour_count)
+            .iter()
+            .map(|&neighbour| marshal_string(neighbour))
+            .collect()
+    };
+    (*app).set_room(room, neighbours);
+}
+
+/// The shape of the area that an attack reaches
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum AttackShape {
+    /// A thrown weapon, reaching everything within the radius of the line from the start to the end
+    Sweep,
+    /// A bite or similar, reaching everything within the radius of the start
+    Area,
+}
+
+impl TryFrom<u8> for AttackShape {
+    /// The value that isn't an attack shape
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(AttackShape::Sweep),
+            1 => Ok(AttackShape::Area),
+            _ => Err(value),
+        }
+    }
+}
+
+/// The area that an attack reaches, in room coordinates
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AttackInfo {
+    /// An [`AttackShape`]
+    pub shape: u8,
+    pub start_x: f32,
+    pub start_y: f32,
+    /// Only used by sweeps
+    pub end_x: f32,
+    /// Only used by sweeps
+    pub end_y: f32,
+    pub radius: f32,
+}
+
+impl TryFrom<&AttackInfo> for HitTest {
+    /// The shape that isn't one
+    type Error = u8;
+
+    fn try_from(info: &AttackInfo) -> Result<Self, Self::Error> {
+        Ok(match AttackShape::try_from(info.shape)? {
+            AttackShape::Sweep => Self::Sweep {
+                from: [info.start_x, info.start_y],
+                to: [info.end_x, info.end_y],
+                radius: info.radius,
+            },
+            AttackShape::Area => Self::Area {
+                centre: [info.start_x, info.start_y],
+                radius: info.radius,
+            },
+        })
+    }
+}
+
+/// Tells the server that our player attacked in `room`, so that it checks what was hit as of what we were seeing.
+/// Returns false if either pointer is null, the shape is unknown, or we aren't playing on a server
+///
+/// # Safety
+///
+/// The given pointers must be [valid]. `room` must point to a null-terminated, UTF-16 encoded string
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_report_attack(
+    app: *mut AppContainer,
+    room: *const u16,
+    attack: *const AttackInfo,
+) -> bool {
+    if app.is_null() || room.is_null() || attack.is_null() {
+        warn!("Cannot report an attack with a null pointer");
+        return false;
+    }
+
+    let test = match HitTest::try_from(&*attack) {
+        Ok(test) => test,
+        Err(value) => {
+            warn!("Unknown attack shape {value}, ignoring the attack");
+            return false;
+        }
+    };
+    (*app).report_attack(marshal_string(room), test)
+}
+
+/// The algorithm used to decide how much data may be in flight at once
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum CongestionControllerKind {
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+impl TryFrom<u8> for CongestionControllerKind {
+    /// The value that isn't a congestion controller
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CongestionControllerKind::Cubic),
+            1 => Ok(CongestionControllerKind::NewReno),
+            2 => Ok(CongestionControllerKind::Bbr),
+            _ => Err(value),
+        }
+    }
+}
+
+/// QUIC transport parameters. Durations are in milliseconds
+#[repr(C)]
+#[derive(Debug)]
+pub struct TransportSettingsInfo {
+    /// How long a connection may go without receiving anything before it's closed. Zero means never
+    pub idle_timeout_ms: u64,
+    /// How often to send keep-alive packets on otherwise idle connections. Zero means never
+    pub keep_alive_interval_ms: u64,
+    pub max_concurrent_bidi_streams: u32,
+    pub max_concurrent_uni_streams: u32,
+    /// How many bytes of unread datagrams to buffer. Zero means datagrams are refused
+    pub datagram_receive_buffer_size: usize,
+    pub datagram_send_buffer_size: usize,
+    /// The round-trip time assumed before it has been measured
+    pub initial_rtt_ms: u64,
+    pub mtu_discovery: bool,
+    /// A [`CongestionControllerKind`], which is passed as its underlying value because C# could pass values that aren't
+    /// one
+    pub congestion_controller: u8,
+}
+
+impl TryFrom<&TransportSettingsInfo> for TransportSettings {
+    /// The congestion controller that isn't one
+    type Error = u8;
+
+    fn try_from(info: &TransportSettingsInfo) -> Result<Self, Self::Error> {
+        let non_zero = |value: u64| (value != 0).then(|| Duration::from_millis(value));
+        let congestion_controller = CongestionControllerKind::try_from(info.congestion_controller)?;
+        Ok(Self {
+            idle_timeout: Duration::from_millis(info.idle_timeout_ms),
+            keep_alive_interval: non_zero(info.keep_alive_interval_ms),
+            max_concurrent_bidi_streams: info.max_concurrent_bidi_streams,
+            max_concurrent_uni_streams: info.max_concurrent_uni_streams,
+            datagram_receive_buffer_size: (info.datagram_receive_buffer_size != 0)
+                .then_some(info.datagram_receive_buffer_size),
+            datagram_send_buffer_size: info.datagram_send_buffer_size,
+            initial_rtt: Duration::from_millis(info.initial_rtt_ms),
+            mtu_discovery: info.mtu_discovery,
+            congestion_controller: match congestion_controller {
+                CongestionControllerKind::Cubic => CongestionController::Cubic,
+                CongestionControllerKind::NewReno => CongestionController::NewReno,
+                CongestionControllerKind::Bbr => CongestionController::Bbr,
+            },
+        })
+    }
+}
+
+/// Writes the default QUIC transport parameters to `settings`, as a starting point for changing them
+///
+/// # Safety
+///
+/// The given pointer must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn default_transport_settings(settings: *mut TransportSettingsInfo) {
+    if settings.is_null() {
+        return;
+    }
+
+    let defaults = TransportSettings::default();
+    let millis = |duration: Duration| duration.as_millis() as u64;
+    *settings = TransportSettingsInfo {
+        idle_timeout_ms: millis(defaults.idle_timeout),
+        keep_alive_interval_ms: defaults.keep_alive_interval.map_or(0, millis),
+        max_concurrent_bidi_streams: defaults.max_concurrent_bidi_streams,
+        max_concurrent_uni_streams: defaults.max_concurrent_uni_streams,
+        datagram_receive_buffer_size: defaults.datagram_receive_buffer_size.unwrap_or(0),
+        datagram_send_buffer_size: defaults.datagram_send_buffer_size,
+        initial_rtt_ms: millis(defaults.initial_rtt),
+        mtu_discovery: defaults.mtu_discovery,
+        congestion_controller: match defaults.congestion_controller {
+            CongestionController::Cubic => CongestionControllerKind::Cubic,
+            CongestionController::NewReno => CongestionControllerKind::NewReno,
+            CongestionController::Bbr => CongestionControllerKind::Bbr,
+        } as u8,
+    };
+}
+
+/// Sets the QUIC transport parameters used for new connections.
+/// Returns false if the parameters are invalid, with the reason in the native log, or if either pointer is null
+///
+/// # Safety
+///
+/// The given pointers must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_transport_settings(
+    app: *mut AppContainer,
+    settings: *const TransportSettingsInfo,
+) -> bool {
+    if app.is_null() || settings.is_null() {
+        warn!("Cannot set transport settings with a null pointer");
+        return false;
+    }
+
+    let settings = match TransportSettings::try_from(&*settings) {
+        Ok(settings) => settings,
+        Err(value) => {
+            warn!("Unknown congestion controller {value}, ignoring the transport settings");
+            return false;
+        }
+    };
+
+    match (*app).set_transport_settings(&settings) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Invalid transport settings: {e}");
+            false
+        }
+    }
+}
+
+/// Sets the master server used to reach servers that can't be connected to directly, by punching holes through NAT or
+/// relaying through the master server. It's only trusted if its certificate has the fingerprint `master_fingerprint`,
+/// formatted as colon-separated hex bytes. If `master_address` is null, servers are only ever connected to directly.
+/// Returns false without changing anything if the app pointer is null or the fingerprint is invalid
+///
+/// # Safety
+///
+/// The given pointers must be [valid], and `master_address` must either be null or point to a null-terminated, UTF-16
+/// encoded string, as must `master_fingerprint` if `master_address` isn't null
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_set_master_server(
+    app: *mut AppContainer,
+    master_address: *const u16,
+    port: u16,
+    master_fingerprint: *const u16,
+) -> bool {
+    if app.is_null() {
+        warn!("Cannot set the master server of null app pointer");
+        return false;
+    }
+
+    if master_address.is_null() {
+        (*app).set_master_server(None);
+        return true;
+    }
+
+    let Some(fingerprint) = marshal_fingerprint(master_fingerprint) else {
+        warn!("Invalid master server fingerprint, keeping the previous master server");
+        return false;
+    };
+    (*app).set_master_server(Some(MasterServer {
+        address: marshal_string(master_address),
+        port,
+        fingerprint,
+    }));
+    true
+}
+
+/// How often connections have managed to resume a previous session with 0-RTT early data
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ResumptionStatsInfo {
+    /// Connections that had no session to resume
+    pub full_handshakes: u32,
+    /// Connections whose early data the server accepted
+    pub early_data_accepted: u32,
+    /// Connections that tried to resume a session, but whose early data the server rejected
+    pub early_data_rejected: u32,
+}
+
+/// Writes how often connections have managed to resume a previous session to `stats`.
+/// Returns false if either pointer is null
+///
+/// # Safety
+///
+/// The given pointers must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_resumption_stats(
+    app: *mut AppContainer,
+    stats: *mut ResumptionStatsInfo,
+) -> bool {
+    if app.is_null() || stats.is_null() {
+        warn!("Cannot get resumption stats with a null pointer");
+        return false;
+    }
+
+    let resumption = (*app).resumption_stats();
+    *stats = ResumptionStatsInfo {
+        full_handshakes: resumption.full_handshakes,
+        early_data_accepted: resumption.early_data_accepted,
+        early_data_rejected: resumption.early_data_rejected,
+    };
+    true
+}
+
+/// A rough rating of a connection, for showing to players
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum ConnectionQualityKind {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl From<ConnectionQuality> for ConnectionQualityKind {
+    fn from(quality: ConnectionQuality) -> Self {
+        match quality {
+            ConnectionQuality::Good => Self::Good,
+            ConnectionQuality::Fair => Self::Fair,
+            ConnectionQuality::Poor => Self::Poor,
+        }
+    }
+}
+
+/// Statistics about the connection to the server. Durations are in milliseconds
+#[repr(C)]
+#[derive(Debug)]
+pub struct NetworkStatsInfo {
+    pub rtt_ms: f32,
+    /// The round-trip time of heartbeats, including how long the server took to answer them
+    pub latency_ms: f32,
+    /// How much the round-trip time varies
+    pub jitter_ms: f32,
+    /// How many bytes may be in flight at once
+    pub congestion_window: u64,
+    pub lost_packets: u64,
+    /// The fraction of sent packets that were lost
+    pub loss_rate: f32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub quality: ConnectionQualityKind,
+}
+
+/// Writes statistics about the connection to the server to `stats`.
+/// Returns false if the app isn't connected, or either pointer is null
+///
+/// # Safety
+///
+/// The given pointers must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_network_stats(
+    app: *mut AppContainer,
+    stats: *mut NetworkStatsInfo,
+) -> bool {
+    if app.is_null() || stats.is_null() {
+        warn!("Cannot get network stats with a null pointer");
+        return false;
+    }
+
+    let Some(network) = (*app).network_stats() else {
+        return false;
+    };
+    *stats = NetworkStatsInfo {
+        rtt_ms: network.rtt.as_secs_f32() * 1000.0,
+        latency_ms: network.latency.as_secs_f32() * 1000.0,
+        jitter_ms: network.jitter.as_secs_f32() * 1000.0,
+        congestion_window: network.congestion_window,
+        lost_packets: network.lost_packets,
+        loss_rate: network.loss_rate(),
+        bytes_sent: network.sent.total().bytes,
+        bytes_received: network.received.total().bytes,
+        quality: network.quality().into(),
+    };
+    true
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct PlayerStatsInfo {
+    pub username: *const u16,
+    /// The round-trip time between the server and the player
+    pub ping_ms: u32,
+    pub quality: ConnectionQualityKind,
+}
+
+/// Calls the handler for every player on the server, with how well their connection is doing.
+/// The player's username is only valid during the handler
+///
+/// # Safety
+///
+/// The given pointer must be [valid] or null
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_player_stats(
+    app: *mut AppContainer,
+    handler: extern "C" fn(*const PlayerStatsInfo),
+) {
+    if app.is_null() {
+        warn!("Cannot get player stats of null app pointer");
+        return;
+    }
+
+    for player in (*app).player_stats() {
+        let username = U16CString::from_str_truncate(&player.username);
+        let info = PlayerStatsInfo {
+            username: username.as_ptr(),
+            ping_ms: player.ping.as_millis().try_into().unwrap_or(u32::MAX),
+            quality: player.quality.into(),
+        };
+        handler(&info);
+    }
+}
+
+/// Writes our estimate of the tick the server is on to `tick`.
+/// Returns false if our clock hasn't been synchronised with the server's yet, or either pointer is null
+///
+/// # Safety
+///
+/// The given pointers must be [valid]
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_network_tick(app: *mut AppContainer, tick: *mut u32) -> bool {
+    if app.is_null() || tick.is_null() {
+        warn!("Cannot get the network tick with a null pointer");
+        return false;
+    }
+
+    let Some(estimate) = (*app).network_tick() else {
+        return false;
+    };
+    *tick = estimate.0;
+    true
+}
+
+/// Returns where the app is in the server's queue, or 0 if it isn't queued or the pointer is null.
+/// If `length` isn't null, the length of the queue is written to it
+///
+/// # Safety
+///
+/// The given pointers must be [valid] or null
+///
+/// [valid]: https://doc.rust-lang.org/std/ptr/index.html#safety
+#[no_mangle]
+pub unsafe extern "C" fn app_queue_position(app: *mut AppContainer, length: *mut u32) -> u32 {
+    if app.is_null() {
+        warn!("Cannot get the queue position of null app pointer");
+        return 0;
+    }
+
+    let (position, queue_length) = (*app).queue_position().unwrap_or((0, 0));
+    if !length.is_null() {
+        *length = queue_length;
+    }
+    position
+}
+
 /// # Safety
 ///
 /// See [`Box::from_raw`]
@@ -160,6 +1228,18 @@ unsafe fn marshal_string(string: *const u16) -> String {
     string.to_string()
 }
 
+/// Parse a fingerprint formatted as colon-separated hex bytes, or `None` if the pointer is null or it isn't one
+///
+/// # Safety
+///
+/// The given pointer must either be null, or be valid and point to a null-terminated, UTF-16 encoded string
+unsafe fn marshal_fingerprint(fingerprint: *const u16) -> Option<[u8; 32]> {
+    if fingerprint.is_null() {
+        return None;
+    }
+    parse_fingerprint(&marshal_string(fingerprint))
+}
+
 /// # Safety
 ///
 /// See [`U16CString::from_raw`]
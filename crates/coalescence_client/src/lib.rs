@@ -1,2 +1,4 @@
 pub mod app;
+pub mod discovery;
 pub mod ffi;
+pub mod traversal;
@@ -0,0 +1,211 @@
+//! Reaching servers that are behind NAT, with the help of a master server.
+//!
+//! Connecting tries three paths in turn: directly to the server, then to the server after the master server has asked it
+//! to punch a hole through its NAT towards us, and finally through a relay on the master server. The same endpoints are
+//! used for the master server and the server, so that our own NAT keeps using the same mapping for both.
+//!
+//! With a master server, the direct attempt has to finish its handshake to show that it got through, so early data is
+//! only sent when there's no master server to fall back to
+
+use std::{
+    future::Future,
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use async_io::Timer;
+use bevy::prelude::*;
+use coalescence_proto::{
+    master::{MasterRequest, MasterResponse},
+    packet::DisconnectReason,
+};
+use coalescence_quinn::{
+    client::ClientEndpoints,
+    connection::disconnect,
+    master::request,
+    peer_fingerprint,
+    quinn::Connection,
+    resumption::{NewConnection, ResumptionMetrics},
+};
+use futures_lite::future;
+
+use crate::app::{connect, ConnectProgress, ConnectTimeouts, ConnectToServerError, ProgressSender};
+
+/// How long to wait for a direct connection before asking the master server for help
+const DIRECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for a connection through a punched hole before falling back to a relay.
+/// QUIC keeps resending its first packets during this time, which is what gets through once the server has punched
+const PUNCHED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The master server used to reach servers that can't be connected to directly
+#[derive(Resource, Debug, Clone)]
+pub struct MasterServer {
+    pub address: String,
+    pub port: u16,
+    /// The fingerprint of the master server's certificate, which it's only trusted if it presents
+    pub fingerprint: [u8; 32],
+}
+
+impl MasterServer {
+    /// Connect to the master server, checking that it presented the certificate it's pinned to before anything is sent
+    /// to it
+    pub fn connect(
+        &self,
+        endpoints: ClientEndpoints,
+        timeouts: ConnectTimeouts,
+        metrics: ResumptionMetrics,
+    ) -> Result<impl Future<Output = Result<Connection, ConnectToServerError>>, ConnectToServerError>
+    {
+        let connecting = connect(
+            endpoints,
+            &self.address,
+            self.port,
+            timeouts,
+            metrics,
+            ProgressSender::default(),
+        )?;
+        let fingerprint = self.fingerprint;
+
+        Ok(async move {
+            // Requests to the master server are sent on their own streams, which early data being rejected would discard,
+            // and the certificate can only be checked once the handshake is done anyway
+            let connection = connecting.await?.handshake().await?;
+            if peer_fingerprint(&connection) != Some(fingerprint) {
+                disconnect(&connection, DisconnectReason::Quit);
+                return Err(ConnectToServerError::UntrustedMaster);
+            }
+            Ok(connection)
+        })
+    }
+}
+
+/// Connect to a server, falling back to punching a hole or relaying through the master server if there is one
+pub async fn connect_with_traversal(
+    endpoints: ClientEndpoints,
+    address: String,
+    port: u16,
+    master: Option<MasterServer>,
+    timeouts: ConnectTimeouts,
+    metrics: ResumptionMetrics,
+    progress: ProgressSender,
+) -> Result<NewConnection, ConnectToServerError> {
+    let direct = connect(
+        endpoints.clone(),
+        &address,
+        port,
+        timeouts,
+        metrics.clone(),
+        progress.clone(),
+    )?;
+    let Some(master) = master else {
+        return direct.await;
+    };
+
+    let direct = async { Ok(direct.await?.handshake().await?.into()) };
+    match with_timeout(direct, DIRECT_TIMEOUT).await {
+        Ok(connection) => return Ok(connection),
+        Err(e) => {
+            info!("Could not connect to '{address}:{port}' directly ({e}), asking the master server for help...")
+        }
+    }
+
+    let master_connection = master
+        .connect(endpoints.clone(), timeouts, metrics.clone())?
+        .await?;
+    let result = connect_through_master(
+        &endpoints,
+        &master_connection,
+        &address,
+        port,
+        &metrics,
+        &progress,
+    )
+    .await;
+    disconnect(&master_connection, DisconnectReason::Quit);
+    result
+}
+
+async fn connect_through_master(
+    endpoints: &ClientEndpoints,
+    master: &Connection,
+    address: &str,
+    port: u16,
+    metrics: &ResumptionMetrics,
+    progress: &ProgressSender,
+) -> Result<NewConnection, ConnectToServerError> {
+    // The master server knows servers by the socket address they're listed under, so find which one this is.
+    // `to_socket_addrs` is blocking with no async alternative, so putting it in the task makes no difference
+    let servers: Vec<SocketAddr> = (address, port)
+        .to_socket_addrs()
+        .map_err(ConnectToServerError::BadSocketAddress)?
+        .collect();
+
+    let mut rejection = None;
+    for &server in &servers {
+        match request(master, &MasterRequest::Punch { server }).await? {
+            MasterResponse::PunchStarted { address: punched } => {
+                info!("Connecting to '{punched}' through a punched hole...");
+                progress.report(ConnectProgress::Punching { address: punched });
+                let connecting = async { connect_from(endpoints, punched, address, metrics).await };
+                match with_timeout(connecting, PUNCHED_TIMEOUT).await {
+                    Ok(connection) => return Ok(connection),
+                    Err(e) => info!(
+                        "Could not connect through a punched hole ({e}), asking for a relay..."
+                    ),
+                }
+            }
+            MasterResponse::Rejected(reason) => {
+                rejection = Some(reason);
+                continue;
+            }
+            response => return Err(unexpected(response)),
+        }
+
+        return match request(master, &MasterRequest::Relay { server }).await? {
+            MasterResponse::RelayAllocated { port } => {
+                let relay = SocketAddr::new(master.remote_address().ip(), port);
+                info!("Connecting to '{relay}' through a relay...");
+                progress.report(ConnectProgress::Relaying { address: relay });
+                connect_from(endpoints, relay, address, metrics).await
+            }
+            MasterResponse::Rejected(reason) => Err(ConnectToServerError::MasterRejected(reason)),
+            response => Err(unexpected(response)),
+        };
+    }
+
+    Err(rejection.map_or(
+        ConnectToServerError::NoAddresses(format!("'{address}:{port}'")),
+        ConnectToServerError::MasterRejected,
+    ))
+}
+
+/// Connect to the server through a punched hole or relay, waiting for the handshake to finish to show that it got through
+async fn connect_from(
+    endpoints: &ClientEndpoints,
+    address: SocketAddr,
+    server_name: &str,
+    metrics: &ResumptionMetrics,
+) -> Result<NewConnection, ConnectToServerError> {
+    let endpoint = endpoints
+        .for_address(address)
+        .ok_or(ConnectToServerError::UnsupportedAddressFamily)?;
+    let connecting = endpoint.connect(address, server_name)?;
+    let connection = NewConnection::establish(connecting, metrics).await?;
+    Ok(connection.handshake().await?.into())
+}
+
+async fn with_timeout<T>(
+    future: impl Future<Output = Result<T, ConnectToServerError>>,
+    timeout: Duration,
+) -> Result<T, ConnectToServerError> {
+    future::or(future, async {
+        Timer::after(timeout).await;
+        Err(ConnectToServerError::TimedOut(timeout))
+    })
+    .await
+}
+
+fn unexpected(response: MasterResponse) -> ConnectToServerError {
+    ConnectToServerError::MasterRejected(format!("Unexpected response: {response:?}"))
+}
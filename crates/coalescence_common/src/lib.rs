@@ -1 +1,26 @@
+use serde::{Deserialize, Serialize};
 
+/// What kind of game a server is running
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    /// Players progress through the campaign together
+    #[default]
+    Story,
+    /// Players compete against each other in arena matches
+    Arena,
+}
+
+impl TryFrom<u8> for GameMode {
+    /// The value that isn't a game mode
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(GameMode::Story),
+            1 => Ok(GameMode::Arena),
+            _ => Err(value),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! The master server keeps a list of public servers, which register themselves and then send regular heartbeats to stay
+//! listed. Clients fetch the list to find servers to join.
+//!
+//! It also helps clients reach servers that are behind NAT, by asking servers to punch holes towards clients, and by
+//! relaying packets between the two when that fails.
+//!
+//! Run it locally with `cargo run -p coalescence_master`, and point a server at it with `--master-server 127.0.0.1:7112`.
+//! Clients only trust the master server whose certificate fingerprint they were given, which is logged on startup, so a
+//! master server that's meant to last should be given a certificate with `--certificate` and `--private-key`
+
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    log::LogPlugin,
+    prelude::*,
+    tasks::IoTaskPool,
+};
+use clap::Parser;
+use coalescence_proto::master::{MasterNotification, MasterRequest, MasterResponse};
+use coalescence_quinn::{
+    certificate_fingerprint, format_fingerprint,
+    master::{read_message, write_message, MasterError, DEFAULT_MASTER_PORT},
+    quinn::{Connecting, Connection, Endpoint},
+    server::{create_config, create_endpoint, generate_certificate, load_certificate},
+    transport::TransportSettings,
+};
+use registry::{prune_registry, Registry, ServerRegistry};
+use relay::Relays;
+
+mod registry;
+mod relay;
+
+/// Master server for Rain World Coalescence, which keeps a list of public servers
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The address to listen on. The unspecified IPv6 address also accepts IPv4 connections where supported
+    #[arg(long, default_value_t = Ipv6Addr::UNSPECIFIED.into())]
+    address: IpAddr,
+    /// The port to listen on
+    #[arg(short, long, default_value_t = DEFAULT_MASTER_PORT)]
+    port: u16,
+    /// How long a server may go without sending a heartbeat before it's removed from the list
+    #[arg(long, default_value = "90s", value_parser = humantime::parse_duration)]
+    heartbeat_timeout: Duration,
+    /// The most servers that may be registered from a single IP address
+    #[arg(long, default_value_t = 8)]
+    max_servers_per_ip: usize,
+    /// The most relays that may be active at once, for clients that can't reach a server directly
+    #[arg(long, default_value_t = 64)]
+    max_relays: usize,
+    /// The most relays that may be active at once for clients from a single IP address
+    #[arg(long, default_value_t = 4)]
+    max_relays_per_ip: usize,
+    /// A PEM file with the certificate chain to present to clients, which pin its fingerprint. A new self-signed one is
+    /// generated every time the master server starts if this isn't set
+    #[arg(long, requires = "private_key")]
+    certificate: Option<PathBuf>,
+    /// A PEM file with the certificate's private key
+    #[arg(long, requires = "certificate")]
+    private_key: Option<PathBuf>,
+}
+
+#[derive(Resource, Debug)]
+struct ListenAddress(SocketAddr);
+
+/// Where to load the master server's certificate from, if it isn't generated
+#[derive(Resource, Debug)]
+struct CertificatePaths(Option<(PathBuf, PathBuf)>);
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let registry = ServerRegistry::new(cli.heartbeat_timeout, cli.max_servers_per_ip);
+
+    App::new()
+        .add_plugins((
+            LogPlugin::default(),
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_millis(100))),
+        ))
+        .insert_resource(Registry(Arc::new(Mutex::new(registry))))
+        .insert_resource(Relays::new(cli.max_relays, cli.max_relays_per_ip))
+        .insert_resource(ListenAddress(SocketAddr::new(cli.address, cli.port)))
+        .insert_resource(CertificatePaths(cli.certificate.zip(cli.private_key)))
+        .add_systems(Startup, start_listening)
+        .add_systems(Update, prune_registry)
+        .run();
+
+    ExitCode::SUCCESS
+}
+
+fn start_listening(
+    address: Res<ListenAddress>,
+    certificate: Res<CertificatePaths>,
+    registry: Res<Registry>,
+    relays: Res<Relays>,
+    mut exit: EventWriter<AppExit>,
+) {
+    // Clients pin the certificate's fingerprint rather than checking it against a CA, so a self-signed one is fine
+    let identity = match &certificate.0 {
+        Some((certificate, private_key)) => {
+            load_certificate(certificate, private_key).map_err(anyhow::Error::from)
+        }
+        None => generate_certificate(vec!["localhost".into()])
+            .map(|(certificate, key)| (vec![certificate], key))
+            .map_err(anyhow::Error::from),
+    };
+    let endpoint = identity
+        .and_then(|(chain, key)| {
+            info!(
+                "Certificate fingerprint: {}",
+                format_fingerprint(&certificate_fingerprint(&chain[0]))
+            );
+            Ok(create_config(chain, key, &TransportSettings::default())?)
+        })
+        .and_then(|config| Ok(create_endpoint(config, address.0, false)?));
+
+    let endpoint = match endpoint {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Could not listen on '{}': {e:#}", address.0);
+            exit.send(AppExit);
+            return;
+        }
+    };
+
+    info!("Master server listening on '{}'...", address.0);
+    IoTaskPool::get()
+        .spawn(accept_connections(
+            endpoint,
+            registry.clone(),
+            relays.clone(),
+        ))
+        .detach();
+}
+
+async fn accept_connections(endpoint: Endpoint, registry: Registry, relays: Relays) {
+    while let Some(connecting) = endpoint.accept().await {
+        IoTaskPool::get()
+            .spawn(handle_connection(
+                connecting,
+                registry.clone(),
+                relays.clone(),
+            ))
+            .detach();
+    }
+}
+
+async fn handle_connection(connecting: Connecting, registry: Registry, relays: Relays) {
+    let address = connecting.remote_address();
+    let connection = match connecting.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("Error while accepting connection from '{address}': {e}");
+            return;
+        }
+    };
+
+    // Each request arrives on its own stream, and streams are handled one at a time per connection
+    loop {
+        let (mut send, mut receive) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("Connection from '{address}' closed: {e}");
+                return;
+            }
+        };
+
+        let result: Result<(), MasterError> = async {
+            let request = read_message(&mut receive).await?;
+            let response = handle_request(&registry, &relays, &connection, request).await;
+            write_message(&mut send, &response).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            debug!("Error while handling request from '{address}': {e}");
+        }
+    }
+}
+
+async fn handle_request(
+    registry: &Registry,
+    relays: &Relays,
+    connection: &Connection,
+    request: MasterRequest,
+) -> MasterResponse {
+    let remote_address = canonical_address(connection.remote_address());
+    match request {
+        MasterRequest::Register { port, status } => {
+            // Servers are registered under the address that their request came from, so they can't list someone else
+            registry.0.lock().unwrap().register(
+                SocketAddr::new(remote_address.ip(), port),
+                connection.remote_address(),
+                connection.clone(),
+                status,
+            )
+        }
+        MasterRequest::Heartbeat { token, status } => {
+            registry.0.lock().unwrap().heartbeat(token, status)
+        }
+        MasterRequest::Unregister { token } => registry.0.lock().unwrap().unregister(token),
+        MasterRequest::List { filter, page } => registry.0.lock().unwrap().list(&filter, page),
+        MasterRequest::Punch { server } => {
+            let Some((observed, server_connection)) = registry.0.lock().unwrap().find(server)
+            else {
+                return MasterResponse::Rejected(format!("No server is registered at '{server}'"));
+            };
+
+            let notification = MasterNotification::Punch {
+                client: remote_address,
+            };
+            match notify(&server_connection, &notification).await {
+                Ok(()) => MasterResponse::PunchStarted {
+                    address: canonical_address(observed),
+                },
+                Err(e) => MasterResponse::Rejected(format!("Could not reach the server: {e}")),
+            }
+        }
+        MasterRequest::Relay { server } => {
+            let Some((observed, server_connection)) = registry.0.lock().unwrap().find(server)
+            else {
+                return MasterResponse::Rejected(format!("No server is registered at '{server}'"));
+            };
+
+            let port = match relays.allocate(canonical_address(observed), remote_address.ip()) {
+                Ok(port) => port,
+                Err(e) => return MasterResponse::Rejected(format!("Could not start a relay: {e}")),
+            };
+
+            match notify(&server_connection, &MasterNotification::Relay { port }).await {
+                Ok(()) => MasterResponse::RelayAllocated { port },
+                Err(e) => MasterResponse::Rejected(format!("Could not reach the server: {e}")),
+            }
+        }
+    }
+}
+
+/// Send a notification to a registered server on its own unidirectional stream
+async fn notify(
+    connection: &Connection,
+    notification: &MasterNotification,
+) -> Result<(), MasterError> {
+    let mut send = connection.open_uni().await?;
+    write_message(&mut send, notification).await
+}
+
+/// IPv4 servers connecting to a dual-stack socket have their address mapped into IPv6, which is undone here so that
+/// IPv4-only clients can still connect to them
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, |v4| {
+            SocketAddr::new(IpAddr::V4(v4), address.port())
+        }),
+        IpAddr::V4(_) => address,
+    }
+}
@@ -0,0 +1,396 @@
+//! The list of registered servers
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use coalescence_proto::{
+    master::{ListedServer, MasterResponse, ServerFilter, SERVERS_PER_PAGE},
+    packet::ServerStatus,
+};
+use coalescence_quinn::quinn::Connection;
+
+#[derive(Debug)]
+struct RegisteredServer<C> {
+    /// The address that players connect to the server on
+    address: SocketAddr,
+    /// The address that the server's registration came from, which is where its NAT forwards packets from
+    observed: SocketAddr,
+    /// The connection the server registered over, used to send it notifications
+    connection: C,
+    status: ServerStatus,
+    last_heartbeat: Instant,
+}
+
+/// The registered servers, which are generic over their connections so that they can be tested without any
+#[derive(Debug)]
+pub struct ServerRegistry<C = Connection> {
+    servers: HashMap<u64, RegisteredServer<C>>,
+    /// How long a server may go without sending a heartbeat before it's removed
+    timeout: Duration,
+    /// The most servers that may be registered from a single IP address
+    max_per_ip: usize,
+}
+
+impl<C: Clone> ServerRegistry<C> {
+    pub fn new(timeout: Duration, max_per_ip: usize) -> Self {
+        Self {
+            servers: HashMap::new(),
+            timeout,
+            max_per_ip,
+        }
+    }
+
+    /// How often servers should send heartbeats, leaving room for a couple to be lost
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.timeout / 3
+    }
+
+    /// Register a server under `address`, whose registration came from `observed`
+    pub fn register(
+        &mut self,
+        address: SocketAddr,
+        observed: SocketAddr,
+        connection: C,
+        status: ServerStatus,
+    ) -> MasterResponse {
+        // A server that restarted re-registers from the same address, so replace its old entry rather than rejecting it
+        self.servers.retain(|_, server| server.address != address);
+
+        let from_ip = self
+            .servers
+            .values()
+            .filter(|server| server.address.ip() == address.ip())
+            .count();
+        if from_ip >= self.max_per_ip {
+            return MasterResponse::Rejected(format!(
+                "At most {} servers may be registered from the same IP address",
+                self.max_per_ip
+            ));
+        }
+
+        let token = loop {
+            let token = rand::random();
+            if !self.servers.contains_key(&token) {
+                break token;
+            }
+        };
+
+        info!("Registered server '{}' at '{address}'", status.name);
+        self.servers.insert(
+            token,
+            RegisteredServer {
+                address,
+                observed,
+                connection,
+                status,
+                last_heartbeat: Instant::now(),
+            },
+        );
+
+        MasterResponse::Registered {
+            token,
+            heartbeat_interval: self.heartbeat_interval(),
+        }
+    }
+
+    pub fn heartbeat(&mut self, token: u64, status: ServerStatus) -> MasterResponse {
+        match self.servers.get_mut(&token) {
+            Some(server) => {
+                server.status = status;
+                server.last_heartbeat = Instant::now();
+                MasterResponse::Ok
+            }
+            None => MasterResponse::UnknownToken,
+        }
+    }
+
+    pub fn unregister(&mut self, token: u64) -> MasterResponse {
+        match self.servers.remove(&token) {
+            Some(server) => {
+                info!(
+                    "Unregistered server '{}' at '{}'",
+                    server.status.name, server.address
+                );
+                MasterResponse::Ok
+            }
+            None => MasterResponse::UnknownToken,
+        }
+    }
+
+    pub fn list(&self, filter: &ServerFilter, page: u32) -> MasterResponse {
+        let mut servers: Vec<_> = self
+            .servers
+            .values()
+            .filter(|server| filter.matches(&server.status))
+            .collect();
+
+        // Busiest servers first, so that players find each other, then by name so that pages are stable
+        servers.sort_unstable_by(|a, b| {
+            b.status
+                .players
+                .cmp(&a.status.players)
+                .then_with(|| a.status.name.cmp(&b.status.name))
+                .then_with(|| a.address.cmp(&b.address))
+        });
+
+        let page_count = servers.len().div_ceil(SERVERS_PER_PAGE) as u32;
+        let servers = servers
+            .into_iter()
+            .skip(page as usize * SERVERS_PER_PAGE)
+            .take(SERVERS_PER_PAGE)
+            .map(|server| ListedServer {
+                address: server.address,
+                status: server.status.clone(),
+            })
+            .collect();
+
+        MasterResponse::ServerList {
+            servers,
+            page,
+            page_count,
+        }
+    }
+
+    /// Find the registered server with the given listed address, returning the address its registration came from and the
+    /// connection it registered over
+    pub fn find(&self, address: SocketAddr) -> Option<(SocketAddr, C)> {
+        self.servers
+            .values()
+            .find(|server| server.address == address)
+            .map(|server| (server.observed, server.connection.clone()))
+    }
+
+    /// Remove servers that have stopped sending heartbeats
+    fn prune(&mut self) {
+        let timeout = self.timeout;
+        self.servers.retain(|_, server| {
+            let alive = server.last_heartbeat.elapsed() < timeout;
+            if !alive {
+                info!(
+                    "Server '{}' at '{}' timed out",
+                    server.status.name, server.address
+                );
+            }
+            alive
+        });
+    }
+}
+
+/// The registry, shared between the ECS world and the tasks that handle requests
+#[derive(Resource, Debug, Clone)]
+pub struct Registry(pub Arc<Mutex<ServerRegistry>>);
+
+pub fn prune_registry(time: Res<Time>, mut timer: Local<Option<Timer>>, registry: Res<Registry>) {
+    let timer =
+        timer.get_or_insert_with(|| Timer::new(Duration::from_secs(1), TimerMode::Repeating));
+    if timer.tick(time.delta()).just_finished() {
+        registry.0.lock().unwrap().prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use coalescence_common::GameMode;
+    use coalescence_proto::PROTOCOL_VERSION;
+
+    use super::*;
+
+    fn address(last: u8, port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), port)
+    }
+
+    fn status(name: &str, players: u32) -> ServerStatus {
+        ServerStatus {
+            name: name.to_owned(),
+            motd: String::new(),
+            players,
+            max_players: 16,
+            protocol_version: PROTOCOL_VERSION,
+            game_mode: GameMode::Story,
+            password_required: false,
+        }
+    }
+
+    fn registry() -> ServerRegistry<()> {
+        ServerRegistry::new(Duration::from_secs(60), 8)
+    }
+
+    fn register(
+        registry: &mut ServerRegistry<()>,
+        address: SocketAddr,
+        status: ServerStatus,
+    ) -> u64 {
+        match registry.register(address, address, (), status) {
+            MasterResponse::Registered { token, .. } => token,
+            response => panic!("Registration failed: {response:?}"),
+        }
+    }
+
+    fn list(registry: &ServerRegistry<()>, filter: &ServerFilter, page: u32) -> (Vec<String>, u32) {
+        match registry.list(filter, page) {
+            MasterResponse::ServerList {
+                servers,
+                page_count,
+                ..
+            } => (
+                servers
+                    .into_iter()
+                    .map(|server| server.status.name)
+                    .collect(),
+                page_count,
+            ),
+            response => panic!("Listing failed: {response:?}"),
+        }
+    }
+
+    #[test]
+    fn lists_busiest_servers_first_then_by_name() {
+        let mut registry = registry();
+        register(&mut registry, address(1, 7110), status("Bravo", 2));
+        register(&mut registry, address(2, 7110), status("Alpha", 2));
+        register(&mut registry, address(3, 7110), status("Charlie", 5));
+
+        let (names, page_count) = list(&registry, &ServerFilter::default(), 0);
+        assert_eq!(names, ["Charlie", "Alpha", "Bravo"]);
+        assert_eq!(page_count, 1);
+    }
+
+    #[test]
+    fn filters_servers() {
+        let mut registry = registry();
+        register(&mut registry, address(1, 7110), status("Story Time", 3));
+        register(
+            &mut registry,
+            address(2, 7110),
+            ServerStatus {
+                game_mode: GameMode::Arena,
+                ..status("Arena Brawl", 1)
+            },
+        );
+        register(&mut registry, address(3, 7110), status("Full House", 16));
+        register(
+            &mut registry,
+            address(4, 7110),
+            ServerStatus {
+                password_required: true,
+                ..status("Private", 0)
+            },
+        );
+        register(
+            &mut registry,
+            address(5, 7110),
+            ServerStatus {
+                protocol_version: PROTOCOL_VERSION + 1,
+                ..status("From The Future", 0)
+            },
+        );
+
+        let filtered = |filter: ServerFilter| list(&registry, &filter, 0).0;
+        assert_eq!(
+            filtered(ServerFilter {
+                name: Some("STORY".into()),
+                ..Default::default()
+            }),
+            ["Story Time"]
+        );
+        assert_eq!(
+            filtered(ServerFilter {
+                game_mode: Some(GameMode::Arena),
+                ..Default::default()
+            }),
+            ["Arena Brawl"]
+        );
+        assert_eq!(
+            filtered(ServerFilter {
+                min_players: Some(2),
+                not_full: true,
+                ..Default::default()
+            }),
+            ["Story Time"]
+        );
+        assert!(!filtered(ServerFilter {
+            no_password: true,
+            ..Default::default()
+        })
+        .contains(&"Private".to_owned()));
+        assert!(!filtered(ServerFilter::compatible()).contains(&"From The Future".to_owned()));
+    }
+
+    #[test]
+    fn paginates_servers() {
+        let mut registry = ServerRegistry::new(Duration::from_secs(60), usize::MAX);
+        let count = SERVERS_PER_PAGE * 2 + 1;
+        for i in 0..count {
+            register(
+                &mut registry,
+                address(1, 7000 + i as u16),
+                status(&format!("Server {i:03}"), 0),
+            );
+        }
+
+        let (first, page_count) = list(&registry, &ServerFilter::default(), 0);
+        let (second, _) = list(&registry, &ServerFilter::default(), 1);
+        let (third, _) = list(&registry, &ServerFilter::default(), 2);
+        let (past_end, _) = list(&registry, &ServerFilter::default(), 3);
+
+        assert_eq!(page_count, 3);
+        assert_eq!(first.len(), SERVERS_PER_PAGE);
+        assert_eq!(second.len(), SERVERS_PER_PAGE);
+        assert_eq!(third, [format!("Server {:03}", count - 1)]);
+        assert!(past_end.is_empty());
+        assert_eq!(first[0], "Server 000");
+        assert_eq!(second[0], format!("Server {SERVERS_PER_PAGE:03}"));
+    }
+
+    #[test]
+    fn limits_servers_per_ip() {
+        let mut registry = ServerRegistry::new(Duration::from_secs(60), 2);
+        register(&mut registry, address(1, 7110), status("First", 0));
+        register(&mut registry, address(1, 7111), status("Second", 0));
+
+        let third = registry.register(address(1, 7112), address(1, 7112), (), status("Third", 0));
+        assert!(matches!(third, MasterResponse::Rejected(_)));
+
+        // Re-registering from the same address replaces the old entry instead
+        register(&mut registry, address(1, 7110), status("First Again", 0));
+        let (names, _) = list(&registry, &ServerFilter::default(), 0);
+        assert_eq!(names, ["First Again", "Second"]);
+    }
+
+    #[test]
+    fn expires_servers_without_heartbeats() {
+        let mut registry = ServerRegistry::new(Duration::ZERO, 8);
+        let token = register(&mut registry, address(1, 7110), status("Gone", 0));
+
+        registry.prune();
+        assert!(list(&registry, &ServerFilter::default(), 0).0.is_empty());
+        assert!(matches!(
+            registry.heartbeat(token, status("Gone", 0)),
+            MasterResponse::UnknownToken
+        ));
+        assert!(registry.find(address(1, 7110)).is_none());
+    }
+
+    #[test]
+    fn keeps_servers_that_send_heartbeats() {
+        let mut registry = registry();
+        let token = register(&mut registry, address(1, 7110), status("Alive", 0));
+
+        assert!(matches!(
+            registry.heartbeat(token, status("Alive", 4)),
+            MasterResponse::Ok
+        ));
+        registry.prune();
+
+        assert_eq!(list(&registry, &ServerFilter::default(), 0).0, ["Alive"]);
+        assert!(matches!(registry.unregister(token), MasterResponse::Ok));
+        assert!(list(&registry, &ServerFilter::default(), 0).0.is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+//! Relays forward QUIC packets between a client and a server that couldn't reach each other directly, even after punching
+//! holes through their NATs.
+//!
+//! Each relay gets its own UDP socket. The server is told to punch a hole towards it, and the client connects to it as if
+//! it were the server. Packets from the server are forwarded to the client, and packets from the client to the server
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::{Async, Timer};
+use bevy::{prelude::*, tasks::IoTaskPool};
+use coalescence_quinn::{bind_socket, IPV6_WILDCARD};
+use futures_lite::future;
+
+/// How long a relay may go without forwarding any packets before it's closed
+const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Large enough for any QUIC packet
+const MAX_PACKET_LENGTH: usize = u16::MAX as usize;
+
+#[derive(Debug, Default)]
+struct ActiveRelays {
+    total: usize,
+    by_client: HashMap<IpAddr, usize>,
+}
+
+/// Counts the active relays, so that there can't be too many at once, or too many for a single client
+#[derive(Resource, Debug, Clone)]
+pub struct Relays {
+    active: Arc<Mutex<ActiveRelays>>,
+    max: usize,
+    max_per_client: usize,
+}
+
+impl Relays {
+    pub fn new(max: usize, max_per_client: usize) -> Self {
+        Self {
+            active: Arc::default(),
+            max,
+            max_per_client,
+        }
+    }
+
+    /// Start a relay between the server at the given address and a client at the given IP, returning the port that the
+    /// client should connect to
+    pub fn allocate(&self, server: SocketAddr, client: IpAddr) -> io::Result<u16> {
+        self.acquire(client)?;
+
+        let result = bind_socket(IPV6_WILDCARD, false)
+            .and_then(Async::new)
+            .and_then(|socket| Ok((socket.get_ref().local_addr()?.port(), socket)));
+        let (port, socket) = match result {
+            Ok(bound) => bound,
+            Err(e) => {
+                self.release(client);
+                return Err(e);
+            }
+        };
+
+        let relays = self.clone();
+        IoTaskPool::get()
+            .spawn(async move {
+                forward(socket, server, client).await;
+                relays.release(client);
+            })
+            .detach();
+
+        Ok(port)
+    }
+
+    fn acquire(&self, client: IpAddr) -> io::Result<()> {
+        let active = &mut *self.active.lock().unwrap();
+        if active.total >= self.max {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Too many relays are active",
+            ));
+        }
+
+        let from_client = active.by_client.entry(client).or_default();
+        if *from_client >= self.max_per_client {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "At most {} relays may be active for the same IP address",
+                    self.max_per_client
+                ),
+            ));
+        }
+
+        *from_client += 1;
+        active.total += 1;
+        Ok(())
+    }
+
+    fn release(&self, client: IpAddr) {
+        let mut active = self.active.lock().unwrap();
+        active.total -= 1;
+        if let Some(from_client) = active.by_client.get_mut(&client) {
+            *from_client -= 1;
+            if *from_client == 0 {
+                active.by_client.remove(&client);
+            }
+        }
+    }
+}
+
+async fn forward(socket: Async<UdpSocket>, server: SocketAddr, client_ip: IpAddr) {
+    // The socket is dual-stack, so IPv4 addresses must be mapped into IPv6 to be compared with or sent to
+    let server = mapped(server);
+    let client_ip = mapped(SocketAddr::new(client_ip, 0)).ip();
+    let mut client = None;
+    let mut buffer = vec![0; MAX_PACKET_LENGTH];
+
+    info!(
+        "Relay to '{server}' opened on port {:?}",
+        socket.get_ref().local_addr().map(|a| a.port())
+    );
+
+    // Only forwarded packets keep the relay open, so that it can't be kept open by anyone else sending it packets
+    let mut idle_deadline = Instant::now() + RELAY_IDLE_TIMEOUT;
+    loop {
+        let received = future::or(async { Some(socket.recv_from(&mut buffer).await) }, async {
+            Timer::at(idle_deadline).await;
+            None
+        })
+        .await;
+
+        let (length, source) = match received {
+            Some(Ok(received)) => received,
+            Some(Err(e)) => {
+                debug!("Error while relaying packets to '{server}': {e}");
+                continue;
+            }
+            None => break,
+        };
+
+        // The client's port may have been changed by its NAT, so the first packet from its IP decides which port it's on,
+        // and anyone else is ignored
+        let destination = if source == server {
+            client
+        } else if source.ip() == client_ip && *client.get_or_insert(source) == source {
+            Some(server)
+        } else {
+            None
+        };
+
+        if let Some(destination) = destination {
+            idle_deadline = Instant::now() + RELAY_IDLE_TIMEOUT;
+            if let Err(e) = socket.send_to(&buffer[..length], destination).await {
+                debug!("Error while relaying packets to '{destination}': {e}");
+            }
+        }
+    }
+
+    info!("Relay to '{server}' closed after being idle for {RELAY_IDLE_TIMEOUT:?}");
+}
+
+fn mapped(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.to_ipv6_mapped()), address.port()),
+        IpAddr::V6(_) => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn limits_relays_per_client() {
+        let relays = Relays::new(3, 2);
+        let first = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        assert!(relays.acquire(first).is_ok());
+        assert!(relays.acquire(first).is_ok());
+        assert!(relays.acquire(first).is_err());
+        assert!(relays.acquire(second).is_ok());
+        assert!(
+            relays.acquire(second).is_err(),
+            "only 3 may be active in total"
+        );
+
+        relays.release(first);
+        assert!(relays.acquire(second).is_ok());
+        assert!(relays.acquire(first).is_err());
+        relays.release(second);
+        relays.release(second);
+        relays.release(first);
+        assert_eq!(relays.active.lock().unwrap().total, 0);
+        assert!(relays.active.lock().unwrap().by_client.is_empty());
+    }
+}
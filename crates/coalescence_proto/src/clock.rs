@@ -0,0 +1,423 @@
+//! Clock synchronisation, so that both peers agree on which simulation tick it is.
+//!
+//! Ticks are numbered by the server, starting from when it started. The client estimates the offset between its clock and
+//! the server's the same way as [NTP]: it sends a [`TimeRequest`] stamped with its own time, and the server answers with a
+//! [`TimeResponse`] stamped with its time. Assuming both legs of the round trip took as long as each other, the server's
+//! time was halfway through the round trip when it answered.
+//!
+//! Clocks drift apart over time, so the client keeps sampling, and fits a line through the samples with the shortest
+//! round trips, as those are the least likely to have been delayed on one leg more than the other.
+//!
+//! The client's simulation runs ahead of the server's, by however long its [input](crate::input) takes to reach the
+//! server plus a margin of [`INPUT_MARGIN_TICKS`], so that the input for a tick arrives before the server simulates it
+//!
+//! [NTP]: https://www.rfc-editor.org/rfc/rfc5905
+
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    app::{App, Plugin, PostUpdate, PreUpdate},
+    ecs::{
+        component::Component,
+        schedule::IntoSystemConfigs,
+        system::{Commands, Query, Res, ResMut, Resource},
+    },
+    log::{debug, error, info},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    packet::{Received, TimeRequest, TimeResponse},
+    peer::{Client, Server},
+    simulation::FixedTimestep,
+    PacketSender, ReceivePackets, SendPackets,
+};
+
+/// How many samples the client keeps, dropping the oldest
+const MAX_SAMPLES: usize = 32;
+/// How many samples are taken in quick succession once connected, to get a first estimate quickly
+const INITIAL_SAMPLES: usize = 8;
+const INITIAL_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+/// How many ticks the client's simulation may drift from the estimated server tick before it jumps to it
+const MAX_TICK_ERROR: u32 = 2;
+/// How many ticks early the client's input should reach the server, so that it still arrives in time when it's delayed
+/// a little more than usual
+pub const INPUT_MARGIN_TICKS: u32 = 2;
+/// The most that clocks are believed to drift apart, as a fraction of the time passed. Quartz clocks stay well within
+/// this, so fitting a steeper line than this means the samples are too noisy to trust
+const MAX_DRIFT: f64 = 0.001;
+/// The most ticks per second a server may run at
+pub const MAX_TICK_RATE: u32 = 240;
+
+/// The simulation tick that a peer is on. Ticks are numbered from when the server started, so a packet stamped with a
+/// tick means the same moment to both peers
+#[derive(
+    Resource,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub struct NetworkTick(pub u32);
+
+/// A monotonic clock that counts from when the app started, which is what [`TimeRequest`]s and [`TimeResponse`]s are
+/// stamped with
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct NetworkClock {
+    start: Instant,
+}
+
+impl Default for NetworkClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl NetworkClock {
+    pub fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// How many simulation ticks the server runs per second
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickRate(pub u32);
+
+impl TickRate {
+    /// How long each tick lasts. A rate of zero is treated as one tick per second
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(1) / self.0.max(1)
+    }
+}
+
+/// When the server's current tick started, by its [`NetworkClock`]
+#[derive(Resource, Debug, Default, Clone, Copy)]
+struct TickStarted(Duration);
+
+/// A single round trip of a [`TimeRequest`] and [`TimeResponse`]
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    /// The client's time halfway through the round trip, in seconds
+    local: f64,
+    /// How far ahead the server's clock was of the client's, in seconds
+    offset: f64,
+    /// How long the round trip took, in seconds
+    rtt: f64,
+}
+
+/// The server's tick as of its latest [`TimeResponse`]
+#[derive(Debug, Clone, Copy)]
+struct TickAnchor {
+    tick: NetworkTick,
+    /// When the tick started, by the server's clock, in seconds
+    started: f64,
+    tick_duration: f64,
+}
+
+/// A component on the client's connection to the server, which estimates the server's clock and tick
+#[derive(Debug, Component)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+    /// How many requests have been sent, including ones that were never answered
+    requests: usize,
+    next_request: Duration,
+    /// The fitted offset as `(offset at local time zero, drift)`, in seconds and seconds per second
+    fit: Option<(f64, f64)>,
+    anchor: Option<TickAnchor>,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(MAX_SAMPLES),
+            requests: 0,
+            next_request: Duration::ZERO,
+            fit: None,
+            anchor: None,
+        }
+    }
+}
+
+impl ClockSync {
+    /// Whether the server has answered enough requests for the estimates to be usable
+    pub fn is_synced(&self) -> bool {
+        self.fit.is_some() && self.anchor.is_some()
+    }
+
+    /// How far ahead the server's clock is of ours at the given local time, in seconds. Negative if it's behind
+    pub fn offset(&self, local: Duration) -> Option<f64> {
+        let (offset, drift) = self.fit?;
+        Some(offset + drift * local.as_secs_f64())
+    }
+
+    /// The estimated drift between the clocks, in seconds per second
+    pub fn drift(&self) -> Option<f64> {
+        self.fit.map(|(_, drift)| drift)
+    }
+
+    /// The shortest round trip out of the samples that are kept
+    pub fn best_rtt(&self) -> Option<Duration> {
+        self.samples
+            .iter()
+            .map(|sample| sample.rtt)
+            .min_by(f64::total_cmp)
+            .map(Duration::from_secs_f64)
+    }
+
+    /// The server's time at the given local time, in seconds
+    pub fn server_time(&self, local: Duration) -> Option<f64> {
+        Some(local.as_secs_f64() + self.offset(local)?)
+    }
+
+    /// The tick the server is on at the given local time
+    pub fn server_tick(&self, local: Duration) -> Option<NetworkTick> {
+        self.server_tick_progress(local).map(|(tick, _)| tick)
+    }
+
+    /// The tick the server is on at the given local time, and how long ago that tick started
+    pub fn server_tick_progress(&self, local: Duration) -> Option<(NetworkTick, Duration)> {
+        let anchor = self.anchor?;
+        // The anchor can't be ahead of the server, so never go back before it
+        let elapsed = (self.server_time(local)? - anchor.started).max(0.0);
+        let ticks = (elapsed / anchor.tick_duration).floor();
+        let progress = elapsed - ticks * anchor.tick_duration;
+        Some((
+            NetworkTick(anchor.tick.0.wrapping_add(ticks as u32)),
+            // Rounding can leave the progress a hair below zero
+            Duration::try_from_secs_f64(progress.max(0.0)).unwrap_or_default(),
+        ))
+    }
+
+    /// The server's tick rate, as of its latest [`TimeResponse`]
+    pub fn tick_rate(&self) -> Option<TickRate> {
+        self.anchor.map(|anchor| {
+            TickRate(((1.0 / anchor.tick_duration).round() as u32).clamp(1, MAX_TICK_RATE))
+        })
+    }
+
+    fn record(&mut self, response: &TimeResponse, now: Duration) {
+        let sent = response.client_time.as_secs_f64();
+        let received = now.as_secs_f64();
+        if received < sent {
+            // Can't have been sent in answer to one of our requests
+            return;
+        }
+
+        let sample = ClockSample {
+            local: (sent + received) / 2.0,
+            offset: response.server_time.as_secs_f64() - (sent + received) / 2.0,
+            rtt: received - sent,
+        };
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.fit = self.fit_samples();
+
+        let valid_durations = TickRate(MAX_TICK_RATE).duration()..=TickRate(1).duration();
+        if !valid_durations.contains(&response.tick_duration) {
+            // Every tick estimate divides by this, so keep the last anchor that made sense
+            debug!(
+                "Ignoring tick duration of {:?} from server",
+                response.tick_duration
+            );
+            return;
+        }
+        self.anchor = Some(TickAnchor {
+            tick: response.tick,
+            started: response.tick_started.as_secs_f64(),
+            tick_duration: response.tick_duration.as_secs_f64(),
+        });
+    }
+
+    /// Fit a line through the better half of the samples, by least squares
+    fn fit_samples(&self) -> Option<(f64, f64)> {
+        let mut best: Vec<_> = self.samples.iter().collect();
+        best.sort_by(|a, b| a.rtt.total_cmp(&b.rtt));
+        best.truncate(best.len().div_ceil(2));
+
+        let count = best.len() as f64;
+        let mean_local = best.iter().map(|sample| sample.local).sum::<f64>() / count;
+        let mean_offset = best.iter().map(|sample| sample.offset).sum::<f64>() / count;
+
+        let spread: f64 = best
+            .iter()
+            .map(|sample| (sample.local - mean_local).powi(2))
+            .sum();
+        let drift = if spread > 0.0 {
+            let covariance: f64 = best
+                .iter()
+                .map(|sample| (sample.local - mean_local) * (sample.offset - mean_offset))
+                .sum();
+            (covariance / spread).clamp(-MAX_DRIFT, MAX_DRIFT)
+        } else {
+            0.0
+        };
+
+        (!best.is_empty()).then(|| (mean_offset - drift * mean_local, drift))
+    }
+}
+
+/// Synchronises the peer's clock and [`NetworkTick`]. Needs the [`SimulationPlugin`](crate::simulation::SimulationPlugin),
+/// and on the server, a [`TickRate`] resource
+#[derive(Debug)]
+pub struct ClockPlugin<P>(PhantomData<P>);
+
+impl<P> Default for ClockPlugin<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl Plugin for ClockPlugin<Server> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkClock>()
+            .init_resource::<NetworkTick>()
+            .init_resource::<TickStarted>()
+            .add_systems(PreUpdate, answer_time_requests.after(ReceivePackets));
+    }
+}
+
+impl Plugin for ClockPlugin<Client> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkClock>()
+            .init_resource::<NetworkTick>()
+            .add_systems(
+                PreUpdate,
+                (record_time_responses, estimate_server_tick)
+                    .chain()
+                    .after(ReceivePackets),
+            )
+            .add_systems(PostUpdate, send_time_requests.before(SendPackets));
+    }
+}
+
+/// Move on to the next tick, at the start of every [`NetworkUpdate`](crate::simulation::NetworkUpdate)
+pub(crate) fn advance_tick(
+    clock: Res<NetworkClock>,
+    timestep: Res<FixedTimestep>,
+    mut tick: ResMut<NetworkTick>,
+    started: Option<ResMut<TickStarted>>,
+) {
+    tick.0 = tick.0.wrapping_add(1);
+    // Only the server tells the other peer when its ticks started
+    if let Some(mut started) = started {
+        started.0 = clock.now().saturating_sub(timestep.overstep());
+    }
+}
+
+// Answered as soon as they're received, so that the time the server spends simulating isn't mistaken for network delay
+// on the way there
+fn answer_time_requests(
+    clock: Res<NetworkClock>,
+    tick: Res<NetworkTick>,
+    started: Res<TickStarted>,
+    rate: Res<TickRate>,
+    mut query: Query<(&mut Received<TimeRequest>, &mut PacketSender<Server>)>,
+) {
+    for (mut requests, mut sender) in query.iter_mut() {
+        for request in requests.buffer.drain(..) {
+            let response = TimeResponse {
+                client_time: request.client_time,
+                server_time: clock.now(),
+                tick: *tick,
+                tick_started: started.0,
+                tick_duration: rate.duration(),
+            };
+            if let Err(e) = sender.send(response) {
+                error!("Error while answering a time request: {e}");
+            }
+        }
+    }
+}
+
+fn send_time_requests(
+    clock: Res<NetworkClock>,
+    mut query: Query<(&mut ClockSync, &mut PacketSender<Client>)>,
+) {
+    let now = clock.now();
+    for (mut sync, mut sender) in query.iter_mut() {
+        if now < sync.next_request {
+            continue;
+        }
+
+        if let Err(e) = sender.send(TimeRequest { client_time: now }) {
+            error!("Error while sending a time request: {e}");
+            continue;
+        }
+
+        sync.requests += 1;
+        let interval = if sync.requests < INITIAL_SAMPLES {
+            INITIAL_SAMPLE_INTERVAL
+        } else {
+            SAMPLE_INTERVAL
+        };
+        sync.next_request = now + interval;
+    }
+}
+
+fn record_time_responses(
+    clock: Res<NetworkClock>,
+    mut query: Query<(&mut ClockSync, &mut Received<TimeResponse>)>,
+) {
+    let now = clock.now();
+    for (mut sync, mut responses) in query.iter_mut() {
+        for response in responses.buffer.drain(..) {
+            sync.record(&response, now);
+        }
+    }
+}
+
+/// Adopt the server's tick rate, and jump to the server's tick if the simulation has drifted too far from it.
+/// Otherwise, the tick is left to the [fixed timestep](crate::simulation), so that it moves on smoothly
+fn estimate_server_tick(
+    mut commands: Commands,
+    clock: Res<NetworkClock>,
+    rate: Option<Res<TickRate>>,
+    mut tick: ResMut<NetworkTick>,
+    mut timestep: ResMut<FixedTimestep>,
+    query: Query<&ClockSync>,
+) {
+    let now = clock.now();
+    let Some((sync, (estimate, progress))) = query.iter().find_map(|sync| {
+        let tick_duration = sync.tick_rate()?.duration();
+        let lead = sync.best_rtt()? / 2 + tick_duration * INPUT_MARGIN_TICKS;
+        Some((sync, sync.server_tick_progress(now + lead)?))
+    }) else {
+        return;
+    };
+
+    if let Some(server_rate) = sync.tick_rate() {
+        if rate.as_deref() != Some(&server_rate) {
+            info!(
+                "Simulating at the server's tick rate of {} Hz",
+                server_rate.0
+            );
+            commands.insert_resource(server_rate);
+        }
+    }
+
+    let error = estimate.0.wrapping_sub(tick.0) as i32;
+    if error.unsigned_abs() > MAX_TICK_ERROR {
+        debug!(
+            "The simulation is {error} ticks behind where it should be, jumping to tick {}",
+            estimate.0
+        );
+        *tick = estimate;
+        timestep.set_overstep(progress);
+    }
+}
@@ -0,0 +1,41 @@
+//! The messages that servers announce themselves on the local network with
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    packet::ServerStatus,
+    serde::{deserialize, serialize},
+    Error,
+};
+
+/// The longest that an encoded announcement may be. Announcements are sent as single datagrams, so this keeps them from
+/// being fragmented on any network that IPv6 works on
+pub const MAX_ANNOUNCEMENT_LENGTH: usize = 1200;
+
+/// Prefixed to every announcement, so that unrelated traffic on the discovery port can be ignored cheaply
+const MAGIC: [u8; 4] = *b"RWCo";
+
+/// Periodically sent by servers on the local network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    /// The port that the server accepts connections on. The address is taken from where the announcement was sent from
+    pub port: u16,
+    /// The fingerprint of the server's certificate, which tells apart the announcements of different servers, and tells
+    /// which ones come from the same server over different address families
+    pub fingerprint: [u8; 32],
+    pub status: ServerStatus,
+}
+
+impl Announcement {
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(serialize(self)?);
+        Ok(bytes)
+    }
+
+    /// Decode an announcement, returning `None` if the bytes aren't one
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(&MAGIC)?;
+        deserialize(bytes).ok()
+    }
+}
@@ -0,0 +1,168 @@
+//! Heartbeats that both peers send each other, to measure the latency between them and to notice when the other
+//! peer has gone silent.
+//!
+//! Every [interval](HeartbeatSettings::interval), each peer sends a [`Ping`], which the other answers with a [`Pong`]
+//! once it gets round to it. As pings are answered during the update after they're received, the latency they measure
+//! includes the time the remote peer spent simulating, unlike the transport's round-trip time. If nothing at all is
+//! received from a peer for the [timeout](HeartbeatSettings::timeout), it's disconnected with
+//! [`DisconnectReason::Timeout`]
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    ecs::{
+        component::Component,
+        entity::Entity,
+        event::EventWriter,
+        system::{Query, Res, Resource},
+    },
+    log::error,
+};
+
+use crate::{
+    packet::{DisconnectReason, Ping, Pong, Received},
+    peer::{Bidirectional, Outbound, Peer},
+    DisconnectPeer, NetworkStats, PacketReceiver, PacketSender,
+};
+
+/// How often heartbeats are sent, and how long a peer may go silent for before it's disconnected
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatSettings {
+    pub interval: Duration,
+    /// How long a peer may go without sending anything before it's considered gone.
+    /// Should be several intervals long, so that a few lost pings aren't mistaken for a dead peer
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A component that keeps track of the heartbeats sent to and received from the peer on the same entity
+#[derive(Debug, Component)]
+pub struct Heartbeat {
+    /// When anything was last received from the peer
+    last_heard: Instant,
+    /// How many bytes had been received from the peer as of `last_heard`
+    received_bytes: u64,
+    next_ping: Instant,
+    next_sequence: u32,
+    /// The pings that haven't been answered yet, oldest first
+    in_flight: VecDeque<(u32, Instant)>,
+    /// Set once the peer has timed out, so that it's only disconnected once
+    timed_out: bool,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            last_heard: now,
+            received_bytes: 0,
+            next_ping: now,
+            next_sequence: 0,
+            in_flight: VecDeque::new(),
+            timed_out: false,
+        }
+    }
+}
+
+impl Heartbeat {
+    /// How long it has been since anything was received from the peer
+    pub fn silence(&self) -> Duration {
+        self.last_heard.elapsed()
+    }
+
+    /// Match a pong up with the ping it answers, returning the ping's round-trip time.
+    /// Pings sent before it are given up on, as pongs can't overtake each other by a whole interval
+    fn answer(&mut self, sequence: u32, now: Instant) -> Option<Duration> {
+        let index = self
+            .in_flight
+            .iter()
+            .position(|&(sent, _)| sent == sequence)?;
+        let (_, sent_at) = self.in_flight.drain(..=index).last()?;
+        Some(now - sent_at)
+    }
+}
+
+pub(crate) fn heartbeat<P: Peer>(
+    settings: Res<HeartbeatSettings>,
+    mut query: Query<(
+        Entity,
+        &mut Heartbeat,
+        &mut PacketSender<P>,
+        &PacketReceiver,
+        &Received<Ping>,
+        &Received<Pong>,
+        &mut NetworkStats,
+    )>,
+    mut disconnects: EventWriter<DisconnectPeer>,
+) where
+    Bidirectional: Outbound<P>,
+{
+    let now = Instant::now();
+    for (entity, mut heartbeat, mut sender, receiver, pings, pongs, mut stats) in query.iter_mut() {
+        if heartbeat.timed_out {
+            continue;
+        }
+
+        // Any packet shows the peer is still there, not just heartbeats
+        let received_bytes = receiver.traffic().total().bytes;
+        if received_bytes != heartbeat.received_bytes {
+            heartbeat.received_bytes = received_bytes;
+            heartbeat.last_heard = now;
+        }
+
+        if now - heartbeat.last_heard >= settings.timeout {
+            heartbeat.timed_out = true;
+            disconnects.send(DisconnectPeer {
+                entity,
+                reason: DisconnectReason::Timeout,
+            });
+            continue;
+        }
+
+        for ping in pings.iter() {
+            let pong = Pong {
+                sequence: ping.sequence,
+            };
+            if let Err(e) = sender.send(pong) {
+                error!("Error while answering a ping: {e}");
+            }
+        }
+
+        for pong in pongs.iter() {
+            if let Some(latency) = heartbeat.answer(pong.sequence, now) {
+                stats.record_latency(latency);
+            }
+        }
+
+        // Pings that haven't been answered within the timeout never will be
+        while heartbeat
+            .in_flight
+            .front()
+            .is_some_and(|&(_, sent_at)| now - sent_at >= settings.timeout)
+        {
+            heartbeat.in_flight.pop_front();
+        }
+
+        if now >= heartbeat.next_ping {
+            let sequence = heartbeat.next_sequence;
+            if let Err(e) = sender.send(Ping { sequence }) {
+                error!("Error while sending a ping: {e}");
+                continue;
+            }
+            heartbeat.next_sequence = sequence.wrapping_add(1);
+            heartbeat.in_flight.push_back((sequence, now));
+            heartbeat.next_ping = now + settings.interval;
+        }
+    }
+}
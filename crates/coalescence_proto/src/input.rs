@@ -0,0 +1,467 @@
+//! Player input, sampled from the game once per tick.
+//!
+//! Clients run their simulation a little ahead of the server's (see [`clock`](crate::clock)), so that the input for a
+//! tick reaches the server before it simulates that tick. Every tick, clients send a [`PlayerInput`] packet over the
+//! unreliable channel, with their input for the tick and for the [`InputSettings::redundancy`] ticks before it. A lost
+//! packet is never resent, but the inputs in it still arrive with the next ones.
+//!
+//! The server buffers each client's inputs by tick in their [`PlayerInputs`], ignoring any it already has, and takes the
+//! one for each tick as it's simulated. When a client's input for a tick hasn't arrived in time, the [`MissingInput`]
+//! policy decides what they're assumed to have pressed instead
+
+use std::{collections::VecDeque, marker::PhantomData};
+
+use bevy::{
+    app::{App, Plugin, PostUpdate, PreUpdate},
+    ecs::{
+        component::Component,
+        entity::Entity,
+        schedule::{IntoSystemConfigs, SystemSet},
+        system::{Local, Query, Res, Resource},
+    },
+    log::{error, warn},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{ClockSync, NetworkTick},
+    packet::{PlayerInput, Received},
+    peer::{Client, Server},
+    prediction::InputHistory,
+    replication::snapshot::is_newer,
+    simulation::{AdvanceTick, NetworkUpdate},
+    PacketSender, ReceivePackets, SendPackets,
+};
+
+/// How many ticks of input are kept, which is also as far back as the client can resimulate
+pub const INPUT_HISTORY_LENGTH: usize = 128;
+/// The most inputs that a single [`PlayerInput`] packet may hold
+pub const MAX_INPUTS_PER_PACKET: usize = 32;
+
+/// What a player is pressing during a tick, the same as the game's `Player.InputPackage`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    /// The horizontal direction held: -1 for left, 0 for neither and 1 for right
+    pub x: i8,
+    /// The vertical direction held: -1 for down, 0 for neither and 1 for up
+    pub y: i8,
+    pub jump: bool,
+    pub throw: bool,
+    pub pick_up: bool,
+    pub map: bool,
+    /// The direction of the analogue stick, for controllers, with each axis between -1 and 1
+    pub analogue_x: f32,
+    pub analogue_y: f32,
+}
+
+impl Input {
+    /// The same input with every value in its valid range, as clients can send anything
+    pub fn sanitized(self) -> Self {
+        let axis = |value: f32| {
+            if value.is_finite() {
+                value.clamp(-1.0, 1.0)
+            } else {
+                0.0
+            }
+        };
+        Self {
+            x: self.x.signum(),
+            y: self.y.signum(),
+            analogue_x: axis(self.analogue_x),
+            analogue_y: axis(self.analogue_y),
+            ..self
+        }
+    }
+}
+
+/// Inputs by the tick they were for, oldest first
+#[derive(Debug, Default, Clone)]
+pub struct InputBuffer {
+    inputs: VecDeque<(NetworkTick, Input)>,
+}
+
+impl InputBuffer {
+    /// Set the input for the tick, replacing any that was already there. Only the latest
+    /// [`INPUT_HISTORY_LENGTH`] ticks are kept
+    pub fn insert(&mut self, tick: NetworkTick, input: Input) {
+        // Most inputs are for a tick after every other, so search from the back
+        let index = self
+            .inputs
+            .iter()
+            .rposition(|&(existing, _)| !is_newer(existing, tick));
+        match index {
+            Some(index) if self.inputs[index].0 == tick => self.inputs[index].1 = input,
+            Some(index) => self.inputs.insert(index + 1, (tick, input)),
+            None => self.inputs.push_front((tick, input)),
+        }
+
+        while self.inputs.len() > INPUT_HISTORY_LENGTH {
+            self.inputs.pop_front();
+        }
+    }
+
+    pub fn get(&self, tick: NetworkTick) -> Option<Input> {
+        self.inputs
+            .iter()
+            .rev()
+            .find(|&&(existing, _)| existing == tick)
+            .map(|&(_, input)| input)
+    }
+
+    /// The input for the tick, or the latest one before it if the tick has none
+    pub fn latest_at(&self, tick: NetworkTick) -> Option<Input> {
+        self.inputs
+            .iter()
+            .rev()
+            .find(|&&(existing, _)| !is_newer(existing, tick))
+            .map(|&(_, input)| input)
+    }
+
+    /// The inputs for the given tick and the ones after it, oldest first
+    pub fn since(&self, tick: NetworkTick) -> impl Iterator<Item = (NetworkTick, Input)> + '_ {
+        self.inputs
+            .iter()
+            .filter(move |&&(existing, _)| !is_newer(tick, existing))
+            .copied()
+    }
+
+    pub fn latest(&self) -> Option<(NetworkTick, Input)> {
+        self.inputs.back().copied()
+    }
+
+    /// Forget the inputs for the given tick and the ones before it
+    pub fn discard_until(&mut self, tick: NetworkTick) {
+        while self
+            .inputs
+            .front()
+            .is_some_and(|&(existing, _)| !is_newer(existing, tick))
+        {
+            self.inputs.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.inputs.clear();
+    }
+}
+
+/// The input most recently sampled from the game, which the next tick is simulated with. Set by the host app
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct LocalInput(pub Input);
+
+/// What the server assumes a client pressed during a tick that their input didn't arrive in time for
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingInput {
+    /// Keep holding whatever they were pressing during the previous tick, which is usually right, as inputs rarely
+    /// change from one tick to the next
+    #[default]
+    RepeatLast,
+    /// Let go of everything
+    Neutral,
+}
+
+/// How clients send their input
+#[derive(Resource, Debug, Clone)]
+pub struct InputSettings {
+    /// How many ticks before the current one each [`PlayerInput`] packet repeats the inputs of, so that up to this many
+    /// packets in a row can be lost without the server missing an input
+    pub redundancy: usize,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        Self { redundancy: 8 }
+    }
+}
+
+/// The system set in [`NetworkUpdate`] that takes each client's input for the tick on the server. Simulation systems
+/// that read [`PlayerInputs::current`] should run after it
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct ReadInputs;
+
+/// A component on the server's client connections, holding the client's inputs for the ticks that haven't been
+/// simulated yet
+#[derive(Component, Debug, Default)]
+pub struct PlayerInputs {
+    buffer: InputBuffer,
+    /// The latest tick that an input has been taken for
+    consumed: Option<NetworkTick>,
+    current: Input,
+    /// Recent ticks that the client's input didn't arrive in time for
+    missed: VecDeque<NetworkTick>,
+    /// How many ticks the client's input didn't arrive in time for
+    pub missing: u64,
+    /// How many of those inputs arrived after all
+    pub late: u64,
+}
+
+impl PlayerInputs {
+    /// The client's input for the tick being simulated
+    pub fn current(&self) -> Input {
+        self.current
+    }
+
+    /// Buffer the inputs in a packet, which are for the ticks up to and including the packet's
+    fn receive_packet(&mut self, packet: PlayerInput) {
+        let first = packet.tick.0.wrapping_sub(packet.inputs.len() as u32);
+        for (offset, input) in packet.inputs.into_iter().enumerate() {
+            let input_tick = NetworkTick(first.wrapping_add(offset as u32 + 1));
+            self.receive(input_tick, input);
+        }
+    }
+
+    fn receive(&mut self, tick: NetworkTick, input: Input) {
+        if self
+            .consumed
+            .is_some_and(|consumed| !is_newer(tick, consumed))
+        {
+            if let Some(index) = self.missed.iter().position(|&missed| missed == tick) {
+                self.missed.remove(index);
+                self.late += 1;
+            }
+            return;
+        }
+
+        if self.buffer.get(tick).is_none() {
+            self.buffer.insert(tick, input.sanitized());
+        }
+    }
+
+    fn consume(&mut self, tick: NetworkTick, policy: MissingInput) {
+        match self.buffer.get(tick) {
+            Some(input) => self.current = input,
+            None => {
+                self.missing += 1;
+                if self.missed.len() == INPUT_HISTORY_LENGTH {
+                    self.missed.pop_front();
+                }
+                self.missed.push_back(tick);
+                if policy == MissingInput::Neutral {
+                    self.current = Input::default();
+                }
+            }
+        }
+        self.buffer.discard_until(tick);
+        self.consumed = Some(tick);
+    }
+}
+
+/// Sends player input from clients to the server. Needs the [`SimulationPlugin`](crate::simulation::SimulationPlugin),
+/// and on clients the [`PredictionPlugin`](crate::prediction::PredictionPlugin), which records the inputs that are sent
+#[derive(Debug)]
+pub struct InputPlugin<P>(PhantomData<P>);
+
+impl<P> Default for InputPlugin<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl Plugin for InputPlugin<Server> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MissingInput>()
+            .add_systems(PreUpdate, receive_inputs.after(ReceivePackets))
+            .add_systems(
+                NetworkUpdate,
+                consume_inputs.in_set(ReadInputs).after(AdvanceTick),
+            );
+    }
+}
+
+impl Plugin for InputPlugin<Client> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputSettings>()
+            .add_systems(PostUpdate, send_inputs.before(SendPackets));
+    }
+}
+
+/// Send the latest inputs whenever a new tick has been simulated
+fn send_inputs(
+    tick: Res<NetworkTick>,
+    history: Res<InputHistory>,
+    settings: Res<InputSettings>,
+    mut query: Query<(&ClockSync, &mut PacketSender<Client>)>,
+    mut sent: Local<Option<NetworkTick>>,
+) {
+    let tick = *tick;
+    if *sent == Some(tick) || history.0.get(tick).is_none() {
+        return;
+    }
+    *sent = Some(tick);
+
+    // The history skips ticks when the simulation jumps, but the packet needs an input for every tick, so fill them in
+    // the same way as the prediction does
+    let count = settings.redundancy.min(MAX_INPUTS_PER_PACKET - 1) as u32 + 1;
+    let inputs: Vec<_> = (0..count)
+        .rev()
+        .filter_map(|offset| {
+            history
+                .0
+                .latest_at(NetworkTick(tick.0.wrapping_sub(offset)))
+        })
+        .collect();
+
+    for (sync, mut sender) in query.iter_mut() {
+        if !sync.is_synced() {
+            continue;
+        }
+
+        let packet = PlayerInput {
+            tick,
+            inputs: inputs.clone(),
+        };
+        if let Err(e) = sender.send(packet) {
+            error!("Error while sending input: {e}");
+        }
+    }
+}
+
+fn receive_inputs(
+    tick: Res<NetworkTick>,
+    mut query: Query<(Entity, &mut PlayerInputs, &mut Received<PlayerInput>)>,
+) {
+    // Clients run ahead of the server, but not this far
+    let furthest = NetworkTick(tick.0.wrapping_add(INPUT_HISTORY_LENGTH as u32));
+    for (entity, mut inputs, mut received) in query.iter_mut() {
+        for packet in received.buffer.drain(..) {
+            if packet.inputs.len() > MAX_INPUTS_PER_PACKET || is_newer(packet.tick, furthest) {
+                warn!("Client on entity {entity:?} sent invalid input, ignoring it");
+                continue;
+            }
+
+            inputs.receive_packet(packet);
+        }
+    }
+}
+
+fn consume_inputs(
+    tick: Res<NetworkTick>,
+    policy: Res<MissingInput>,
+    mut query: Query<&mut PlayerInputs>,
+) {
+    for mut inputs in query.iter_mut() {
+        inputs.consume(*tick, *policy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(x: i8) -> Input {
+        Input {
+            x,
+            ..Input::default()
+        }
+    }
+
+    fn packet(tick: u32, xs: &[i8]) -> PlayerInput {
+        PlayerInput {
+            tick: NetworkTick(tick),
+            inputs: xs.iter().copied().map(input).collect(),
+        }
+    }
+
+    #[test]
+    fn buffer_keeps_inputs_in_tick_order_across_wraparound() {
+        let mut buffer = InputBuffer::default();
+        buffer.insert(NetworkTick(0), input(0));
+        buffer.insert(NetworkTick(u32::MAX), input(-1));
+        buffer.insert(NetworkTick(1), input(1));
+        buffer.insert(NetworkTick(u32::MAX - 1), input(-1));
+        buffer.insert(NetworkTick(u32::MAX - 1), input(-2));
+
+        let ticks: Vec<_> = buffer
+            .since(NetworkTick(u32::MAX - 1))
+            .map(|(tick, input)| (tick.0, input.x))
+            .collect();
+        assert_eq!(ticks, [(u32::MAX - 1, -2), (u32::MAX, -1), (0, 0), (1, 1)]);
+        assert_eq!(buffer.latest(), Some((NetworkTick(1), input(1))));
+        assert_eq!(buffer.latest_at(NetworkTick(5)), Some(input(1)));
+    }
+
+    #[test]
+    fn maps_redundant_inputs_to_their_ticks() {
+        let mut inputs = PlayerInputs::default();
+        inputs.receive_packet(packet(10, &[-1, 0, 1]));
+
+        for (tick, x) in [(8, -1), (9, 0), (10, 1)] {
+            inputs.consume(NetworkTick(tick), MissingInput::RepeatLast);
+            assert_eq!(inputs.current(), input(x));
+        }
+        assert_eq!(inputs.missing, 0);
+    }
+
+    #[test]
+    fn keeps_the_first_input_for_each_tick() {
+        let mut inputs = PlayerInputs::default();
+        // Out of order, and repeating some of the same ticks with different inputs, which are ignored
+        inputs.receive_packet(packet(11, &[1, 1, 1]));
+        inputs.receive_packet(packet(10, &[-1, -1, -1]));
+        inputs.receive_packet(packet(11, &[0, 0, 0]));
+
+        for (tick, x) in [(8, -1), (9, 1), (10, 1), (11, 1)] {
+            inputs.consume(NetworkTick(tick), MissingInput::Neutral);
+            assert_eq!(inputs.current(), input(x));
+        }
+        assert_eq!(inputs.missing, 0);
+        assert_eq!(inputs.late, 0);
+    }
+
+    #[test]
+    fn counts_inputs_that_arrive_after_their_tick_as_late() {
+        let mut inputs = PlayerInputs::default();
+        inputs.receive_packet(packet(4, &[1]));
+        inputs.consume(NetworkTick(4), MissingInput::RepeatLast);
+        inputs.consume(NetworkTick(5), MissingInput::RepeatLast);
+        assert_eq!(inputs.missing, 1);
+
+        // Only counted once, however many packets repeat it, and not used for a tick that's already been simulated
+        inputs.receive_packet(packet(6, &[-1, -1]));
+        inputs.receive_packet(packet(7, &[-1, -1, -1]));
+        assert_eq!(inputs.late, 1);
+        assert_eq!(inputs.current(), input(1));
+
+        // Inputs that weren't missed aren't late
+        inputs.receive_packet(packet(4, &[0]));
+        assert_eq!(inputs.late, 1);
+
+        inputs.consume(NetworkTick(6), MissingInput::RepeatLast);
+        assert_eq!(inputs.current(), input(-1));
+    }
+
+    #[test]
+    fn repeats_the_last_input_when_one_is_missing() {
+        let mut inputs = PlayerInputs::default();
+        inputs.receive_packet(packet(u32::MAX - 1, &[1]));
+        inputs.receive_packet(packet(1, &[-1]));
+
+        for (tick, x) in [(u32::MAX - 1, 1), (u32::MAX, 1), (0, 1), (1, -1)] {
+            inputs.consume(NetworkTick(tick), MissingInput::RepeatLast);
+            assert_eq!(inputs.current(), input(x));
+        }
+        assert_eq!(inputs.missing, 2);
+
+        inputs.receive_packet(packet(0, &[0, 0]));
+        assert_eq!(inputs.late, 2);
+    }
+
+    #[test]
+    fn lets_go_of_everything_when_an_input_is_missing() {
+        let mut inputs = PlayerInputs::default();
+        inputs.receive_packet(packet(u32::MAX - 1, &[1]));
+        inputs.receive_packet(packet(1, &[-1]));
+
+        for (tick, x) in [(u32::MAX - 1, 1), (u32::MAX, 0), (0, 0), (1, -1)] {
+            inputs.consume(NetworkTick(tick), MissingInput::Neutral);
+            assert_eq!(inputs.current(), input(x));
+        }
+        assert_eq!(inputs.missing, 2);
+
+        // An input from before the wraparound is still in the past
+        inputs.receive_packet(packet(u32::MAX, &[1]));
+        assert_eq!(inputs.late, 1);
+        assert_eq!(inputs.current(), input(-1));
+    }
+}
@@ -0,0 +1,504 @@
+//! Interpolation of remote entities on clients, so that they move smoothly however unevenly snapshots arrive.
+//!
+//! Showing replicated entities exactly as of the latest snapshot would make them stutter whenever a packet is delayed or
+//! lost, so instead the client keeps the recent values of each [interpolated](AppInterpolationExt::interpolate)
+//! component by the tick they were for, and shows the [`Interpolated`] value between the two ticks either side of a
+//! point [`InterpolationTimeline::delay`] behind the estimated server time. Components holding a velocity as well as a
+//! position, such as [`Motion`], have both interpolated, so that the two stay consistent with each other.
+//!
+//! The delay needs to be long enough that the snapshot after the point being shown has usually arrived already, so it's
+//! adapted to how late snapshots arrive and how much that varies, gradually so that entities don't visibly speed up or
+//! slow down. When the next snapshot still hasn't arrived, entities carry on along their latest motion for up to
+//! [`InterpolationSettings::max_extrapolation`], and then stop until it does.
+//!
+//! Entities that the client [predicts](crate::prediction) are shown as predicted instead
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{
+    app::{App, Plugin, PreUpdate},
+    ecs::{
+        component::Component,
+        entity::Entity,
+        query::{With, Without},
+        schedule::{IntoSystemConfigs, SystemSet},
+        system::{Commands, Query, Res, ResMut, Resource},
+        world::World,
+    },
+    math::Vec2,
+    time::Time,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{ClockSync, NetworkClock, NetworkTick},
+    prediction::Predicted,
+    replication::{snapshot::is_newer, ApplySnapshots, NetworkEntity, SnapshotBuffer},
+};
+
+/// How many ticks of values are kept for each entity, which is well over the longest delay
+const INTERPOLATION_BUFFER_LENGTH: usize = 64;
+/// How strongly each snapshot's lateness affects the running estimates of it, the same as RTP's jitter estimate
+const LATENESS_GAIN: f64 = 1.0 / 16.0;
+/// How much faster or slower than real time the shown point may move while the delay adapts
+const MAX_TIME_STRETCH: f64 = 0.1;
+
+/// The system set in [`PreUpdate`] that updates the [`Interpolated`] values, after the latest snapshots are applied
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct InterpolateEntities;
+
+/// A component type that can be interpolated
+pub trait Interpolate: Component + Clone {
+    /// The value part of the way from this one to `towards`, where `t` is between 0 and 1. Values of `t` above 1
+    /// continue past `towards` in the same direction, to extrapolate. Types that can't be interpolated can return
+    /// whichever of the two values is closer
+    fn interpolate(&self, towards: &Self, t: f32) -> Self;
+}
+
+/// Where an entity is in its room and how fast it's moving, which are interpolated together so that they agree
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Motion {
+    /// The same as the game's room coordinates
+    pub position: [f32; 2],
+    /// How far the entity moves each tick
+    pub velocity: [f32; 2],
+}
+
+impl Interpolate for Motion {
+    fn interpolate(&self, towards: &Self, t: f32) -> Self {
+        let lerp =
+            |from: [f32; 2], to: [f32; 2]| Vec2::from(from).lerp(Vec2::from(to), t).to_array();
+        Self {
+            position: lerp(self.position, towards.position),
+            velocity: lerp(self.velocity, towards.velocity),
+        }
+    }
+}
+
+/// How remote entities are interpolated
+#[derive(Resource, Debug, Clone)]
+pub struct InterpolationSettings {
+    /// The shortest that the delay can adapt down to
+    pub min_delay: Duration,
+    /// The longest that the delay can adapt up to, however unreliable the connection is
+    pub max_delay: Duration,
+    /// How many times the variation in snapshots' lateness to add to the delay. Higher values make it less likely that
+    /// entities need to be extrapolated, at the cost of showing them further in the past
+    pub jitter_margin: f64,
+    /// How long entities may carry on along their latest motion when the next snapshot hasn't arrived
+    pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(500),
+            jitter_margin: 3.0,
+            max_extrapolation: Duration::from_millis(100),
+        }
+    }
+}
+
+/// The point in the server's timeline that remote entities are shown as of
+#[derive(Resource, Debug, Default)]
+pub struct InterpolationTimeline {
+    /// How far behind the estimated server time entities are shown, in seconds
+    delay: Option<f64>,
+    /// The running average of how long after their tick started snapshots are applied, in seconds
+    lateness: Option<f64>,
+    /// The running average of how far snapshots' lateness is from the average, in seconds
+    jitter: f64,
+    /// The latest snapshot that has been recorded
+    recorded: Option<NetworkTick>,
+    /// Whether a new snapshot was applied this update, which the values need recording for
+    new_snapshot: Option<NetworkTick>,
+    /// The tick being shown, and how far through it as a fraction of a tick, or `None` before the clock is synchronised
+    shown: Option<(NetworkTick, f64)>,
+    /// How long each tick lasts, in seconds
+    tick_duration: f64,
+}
+
+impl InterpolationTimeline {
+    /// How far behind the estimated server time entities are shown
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay.map(Duration::from_secs_f64)
+    }
+
+    /// The tick that remote entities are being shown as of, or `None` before the clock is synchronised
+    pub fn shown_tick(&self) -> Option<NetworkTick> {
+        self.shown.map(|(tick, _)| tick)
+    }
+
+    /// How much the time it takes snapshots to be applied varies
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    /// How many ticks the shown point is after the given tick, which is negative if it's before it
+    fn ticks_since(&self, tick: NetworkTick) -> Option<f64> {
+        let (shown, progress) = self.shown?;
+        Some(shown.0.wrapping_sub(tick.0) as i32 as f64 + progress)
+    }
+
+    /// Update the estimates of how late snapshots arrive with one that was `lateness` seconds after its tick started
+    fn record_lateness(&mut self, lateness: f64) {
+        let Some(average) = self.lateness else {
+            self.lateness = Some(lateness);
+            return;
+        };
+        self.jitter += ((lateness - average).abs() - self.jitter) * LATENESS_GAIN;
+        self.lateness = Some(average + (lateness - average) * LATENESS_GAIN);
+    }
+
+    /// How far behind the server entities should be shown to usually have the snapshot after the shown point
+    fn target_delay(&self, settings: &InterpolationSettings) -> f64 {
+        let lateness = self.lateness.unwrap_or_default();
+        let target = lateness + self.jitter * settings.jitter_margin + self.tick_duration;
+        target.clamp(
+            settings.min_delay.as_secs_f64(),
+            settings.max_delay.as_secs_f64(),
+        )
+    }
+}
+
+/// The value of a component to show for a remote entity, between the values from the snapshots either side of the
+/// shown point
+#[derive(Component, Debug, Clone)]
+pub struct Interpolated<C> {
+    pub value: C,
+}
+
+/// The values that an interpolated component had as of recent ticks, oldest first
+#[derive(Component, Debug)]
+struct InterpolationHistory<C> {
+    values: VecDeque<(NetworkTick, C)>,
+}
+
+impl<C: Interpolate> InterpolationHistory<C> {
+    fn push(&mut self, tick: NetworkTick, value: C) {
+        if self
+            .values
+            .back()
+            .is_some_and(|&(latest, _)| !is_newer(tick, latest))
+        {
+            return;
+        }
+
+        if self.values.len() == INTERPOLATION_BUFFER_LENGTH {
+            self.values.pop_front();
+        }
+        self.values.push_back((tick, value));
+    }
+
+    /// The value as of the shown point, or `None` if there's nothing to show yet
+    fn sample(&mut self, timeline: &InterpolationTimeline, max_extrapolation: f64) -> Option<C> {
+        let since = |tick| timeline.ticks_since(tick);
+
+        // Forget the values that are too old to be either side of the shown point again
+        while self.values.len() > 2 && since(self.values[1].0).is_some_and(|since| since >= 0.0) {
+            self.values.pop_front();
+        }
+
+        let (before, after) = match self.values.len() {
+            0 => return None,
+            1 => return Some(self.values[0].1.clone()),
+            _ => (&self.values[0], &self.values[1]),
+        };
+        let before_since = since(before.0)?;
+        // The entity was only just spawned, so it has nothing to show from before then
+        if before_since < 0.0 {
+            return Some(before.1.clone());
+        }
+
+        let gap = after.0 .0.wrapping_sub(before.0 .0) as f64;
+        let after_since = since(after.0)?;
+        if after_since <= 0.0 {
+            return Some(before.1.interpolate(&after.1, (before_since / gap) as f32));
+        }
+
+        // The next snapshot is late, so carry on from the latest value in the same direction
+        let max_ticks = max_extrapolation / timeline.tick_duration;
+        let t = 1.0 + after_since.min(max_ticks) / gap;
+        Some(before.1.interpolate(&after.1, t as f32))
+    }
+}
+
+/// Registers component types for interpolation
+pub trait AppInterpolationExt {
+    /// Interpolate the component type on replicated entities that aren't [`Predicted`]. It should also be
+    /// [replicated](crate::replication::AppReplicationExt::replicate), or it will never change
+    fn interpolate<C: Interpolate>(&mut self) -> &mut Self;
+}
+
+impl AppInterpolationExt for App {
+    fn interpolate<C: Interpolate>(&mut self) -> &mut Self {
+        self.add_systems(
+            PreUpdate,
+            (record::<C>, sample::<C>)
+                .chain()
+                .in_set(InterpolateEntities)
+                .after(update_timeline),
+        )
+    }
+}
+
+/// Interpolates remote entities on clients. Needs the [`ReplicationPlugin`](crate::replication::ReplicationPlugin) and
+/// the [`ClockPlugin`](crate::clock::ClockPlugin)
+#[derive(Debug, Default)]
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InterpolationSettings>()
+            .init_resource::<InterpolationTimeline>()
+            .configure_sets(PreUpdate, InterpolateEntities.after(ApplySnapshots))
+            .add_systems(PreUpdate, update_timeline.in_set(InterpolateEntities));
+    }
+}
+
+/// Measure how late the latest snapshot was, and move the shown point on
+fn update_timeline(
+    clock: Res<NetworkClock>,
+    time: Res<Time>,
+    buffer: Res<SnapshotBuffer>,
+    settings: Res<InterpolationSettings>,
+    query: Query<&ClockSync>,
+    mut timeline: ResMut<InterpolationTimeline>,
+) {
+    timeline.new_snapshot = None;
+    let now = clock.now();
+    let Some((sync, tick_duration)) = query
+        .iter()
+        .find_map(|sync| Some((sync, sync.tick_rate()?.duration().as_secs_f64())))
+    else {
+        timeline.shown = None;
+        return;
+    };
+    let Some((server_tick, progress)) = sync.server_tick_progress(now) else {
+        timeline.shown = None;
+        return;
+    };
+    timeline.tick_duration = tick_duration;
+
+    if let Some(latest) = buffer.latest() {
+        if timeline.recorded != Some(latest) {
+            timeline.recorded = Some(latest);
+            timeline.new_snapshot = Some(latest);
+
+            let ticks = server_tick.0.wrapping_sub(latest.0) as i32 as f64;
+            timeline.record_lateness(ticks * tick_duration + progress.as_secs_f64());
+        }
+    }
+
+    // Stretch time a little rather than jumping, so that entities keep moving smoothly while the delay adapts
+    let target = timeline.target_delay(&settings);
+    let delay = match timeline.delay {
+        Some(delay) => {
+            let max_change = MAX_TIME_STRETCH * time.delta_seconds_f64();
+            delay + (target - delay).clamp(-max_change, max_change)
+        }
+        None => target,
+    };
+    timeline.delay = Some(delay);
+
+    timeline.shown = sync
+        .server_tick_progress(now.saturating_sub(Duration::from_secs_f64(delay)))
+        .map(|(tick, progress)| (tick, progress.as_secs_f64() / tick_duration));
+}
+
+/// Record the values of the component as of the snapshot that was just applied
+fn record<C: Interpolate>(
+    mut commands: Commands,
+    timeline: Res<InterpolationTimeline>,
+    mut query: Query<
+        (Entity, &C, Option<&mut InterpolationHistory<C>>),
+        (With<NetworkEntity>, Without<Predicted>),
+    >,
+) {
+    let Some(tick) = timeline.new_snapshot else {
+        return;
+    };
+
+    for (entity, value, history) in query.iter_mut() {
+        match history {
+            Some(mut history) => history.push(tick, value.clone()),
+            None => {
+                commands.entity(entity).insert((
+                    InterpolationHistory {
+                        values: VecDeque::from([(tick, value.clone())]),
+                    },
+                    Interpolated {
+                        value: value.clone(),
+                    },
+                ));
+            }
+        }
+    }
+}
+
+fn sample<C: Interpolate>(
+    settings: Res<InterpolationSettings>,
+    timeline: Res<InterpolationTimeline>,
+    mut query: Query<(&mut InterpolationHistory<C>, &mut Interpolated<C>), Without<Predicted>>,
+) {
+    let max_extrapolation = settings.max_extrapolation.as_secs_f64();
+    for (mut history, mut interpolated) in query.iter_mut() {
+        if let Some(value) = history.sample(&timeline, max_extrapolation) {
+            interpolated.value = value;
+        }
+    }
+}
+
+/// Forget how late snapshots have been arriving, e.g. after leaving the server, as the next server's connection won't
+/// be the same
+pub fn reset_interpolation(world: &mut World) {
+    if let Some(mut timeline) = world.get_resource_mut::<InterpolationTimeline>() {
+        *timeline = InterpolationTimeline::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_DURATION: f64 = 1.0 / 60.0;
+
+    fn motion(x: f32) -> Motion {
+        Motion {
+            position: [x, 0.0],
+            velocity: [1.0, 0.0],
+        }
+    }
+
+    fn history(values: &[(u32, f32)]) -> InterpolationHistory<Motion> {
+        InterpolationHistory {
+            values: values
+                .iter()
+                .map(|&(tick, x)| (NetworkTick(tick), motion(x)))
+                .collect(),
+        }
+    }
+
+    fn shown_at(tick: u32, progress: f64) -> InterpolationTimeline {
+        InterpolationTimeline {
+            shown: Some((NetworkTick(tick), progress)),
+            tick_duration: TICK_DURATION,
+            ..Default::default()
+        }
+    }
+
+    fn sampled_x(
+        history: &mut InterpolationHistory<Motion>,
+        timeline: &InterpolationTimeline,
+    ) -> f32 {
+        // Long enough to never be reached, unless the test is about it
+        history.sample(timeline, 1.0).unwrap().position[0]
+    }
+
+    #[test]
+    fn interpolates_position_and_velocity() {
+        let from = Motion {
+            position: [0.0, 10.0],
+            velocity: [2.0, 0.0],
+        };
+        let to = Motion {
+            position: [4.0, 20.0],
+            velocity: [4.0, -2.0],
+        };
+        let halfway = from.interpolate(&to, 0.5);
+        assert_eq!(halfway.position, [2.0, 15.0]);
+        assert_eq!(halfway.velocity, [3.0, -1.0]);
+        assert_eq!(from.interpolate(&to, 1.5).position, [6.0, 25.0]);
+    }
+
+    #[test]
+    fn interpolates_between_the_ticks_either_side() {
+        let mut history = history(&[(10, 0.0), (12, 2.0), (14, 6.0)]);
+        assert_eq!(sampled_x(&mut history, &shown_at(11, 0.5)), 1.5);
+        assert_eq!(history.values.len(), 3);
+
+        // The first value is forgotten once the shown point has passed the second
+        assert_eq!(sampled_x(&mut history, &shown_at(13, 0.0)), 4.0);
+        assert_eq!(history.values.len(), 2);
+    }
+
+    #[test]
+    fn interpolates_across_tick_wraparound() {
+        let mut history = history(&[(u32::MAX, 0.0), (1, 2.0)]);
+        assert_eq!(sampled_x(&mut history, &shown_at(0, 0.5)), 1.5);
+    }
+
+    #[test]
+    fn extrapolates_for_at_most_max_extrapolation() {
+        let max_extrapolation = 2.0 * TICK_DURATION;
+        let mut history = history(&[(10, 0.0), (12, 2.0)]);
+
+        let x = history
+            .sample(&shown_at(13, 0.0), max_extrapolation)
+            .unwrap()
+            .position[0];
+        assert!((x - 3.0).abs() < 1e-4, "{x}");
+
+        // Stops where the limit is reached, however late the next snapshot is
+        for tick in [14, 20, 100] {
+            let x = history
+                .sample(&shown_at(tick, 0.0), max_extrapolation)
+                .unwrap()
+                .position[0];
+            assert!((x - 4.0).abs() < 1e-4, "{x}");
+        }
+    }
+
+    #[test]
+    fn shows_newly_spawned_entities_as_they_are() {
+        let mut history = history(&[(10, 5.0)]);
+        assert_eq!(sampled_x(&mut history, &shown_at(8, 0.0)), 5.0);
+        assert_eq!(sampled_x(&mut history, &shown_at(12, 0.0)), 5.0);
+
+        // Spawned after the shown point, so there's nothing before it to interpolate from
+        history.push(NetworkTick(11), motion(6.0));
+        assert_eq!(sampled_x(&mut history, &shown_at(9, 0.5)), 5.0);
+
+        let mut empty = InterpolationHistory::<Motion> {
+            values: VecDeque::new(),
+        };
+        assert!(empty.sample(&shown_at(10, 0.0), 1.0).is_none());
+    }
+
+    #[test]
+    fn adapts_the_delay_to_jitter() {
+        let settings = InterpolationSettings::default();
+        let mut timeline = shown_at(0, 0.0);
+
+        // Snapshots that always arrive just as late only need the shortest delay
+        for _ in 0..100 {
+            timeline.record_lateness(0.02);
+        }
+        assert!(timeline.jitter() < Duration::from_millis(1));
+        assert_eq!(
+            timeline.target_delay(&settings),
+            settings.min_delay.as_secs_f64()
+        );
+
+        // Ones whose lateness varies need a margin for it
+        for i in 0..200 {
+            timeline.record_lateness(if i % 2 == 0 { 0.02 } else { 0.12 });
+        }
+        assert!(timeline.jitter() > Duration::from_millis(40));
+        let target = timeline.target_delay(&settings);
+        assert!(
+            target > 0.15 && target < settings.max_delay.as_secs_f64(),
+            "{target}"
+        );
+
+        // But never more than the longest delay
+        for i in 0..200 {
+            timeline.record_lateness(if i % 2 == 0 { 0.0 } else { 2.0 });
+        }
+        assert_eq!(
+            timeline.target_delay(&settings),
+            settings.max_delay.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,477 @@
+//! Lag compensation for hit detection on the server, so that attacks that hit on a player's screen hit on the server.
+//!
+//! Clients show other players and creatures some time in the past, because of their latency and
+//! [interpolation](crate::interpolation), so by the time the server hears about a spear throw or a bite, its target has
+//! already moved on. Instead, the server records the [`Hitbox`] of every entity that has one at the end of each tick in
+//! the [`RewindHistory`], and clients stamp each [`Attack`] with the tick they were seeing. The server checks the attack
+//! against the hitboxes as of that tick, and sends an [`AttackResolved`] event with what it hit.
+//!
+//! Attacks are only checked in the room the attacker's player is in, and must start within reach of the attacker's own
+//! hitbox as of the tick they're checked as of, which is found by the player's [`Owner`]. Nothing in this crate sets
+//! hitboxes, as it doesn't simulate the game: whatever does on the server should keep the [`Hitbox`] of every creature
+//! up to date in [`NetworkUpdate`], before [`RecordHitboxes`], and give the player each client controls an [`Owner`].
+//!
+//! How far back an attack can be checked is capped at [`LagCompensationSettings::max_rewind`], so that players with a
+//! very high latency can't hit things that everyone else has long seen move out of the way. Attacks from further back
+//! are checked against the oldest allowed tick instead
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{
+    app::{App, Plugin, PreUpdate},
+    ecs::{
+        component::Component,
+        entity::Entity,
+        event::{Event, EventWriter},
+        schedule::{IntoSystemConfigs, SystemSet},
+        system::{Query, Res, ResMut, Resource},
+    },
+    log::warn,
+    math::Vec2,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{NetworkTick, TickRate},
+    packet::{Attack, Received},
+    replication::{interest::Room, snapshot::is_newer, Owner},
+    simulation::{AdvanceTick, NetworkUpdate},
+    ReceivePackets,
+};
+
+/// How many ticks of hitboxes are kept, which is the furthest that attacks can be rewound
+pub const REWIND_HISTORY_LENGTH: usize = 64;
+/// The largest radius that an attack or a body chunk may have, which is far larger than any creature
+const MAX_RADIUS: f32 = 500.0;
+/// The longest line that a sweep may cover, which is well over how far a thrown weapon moves in a tick
+const MAX_SWEEP_LENGTH: f32 = 1000.0;
+/// How far from the edge of the attacker's own hitbox an attack may start, which is well over the reach of any creature
+/// and allows for the attacker having moved since the tick the attack is checked as of
+const MAX_REACH: f32 = 300.0;
+
+/// The system set in [`NetworkUpdate`] that records every entity's [`Hitbox`] at the end of the tick. Simulation
+/// systems that move hitboxes should run before it
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct RecordHitboxes;
+
+/// A circle making up part of a creature's body, the same as the game's `BodyChunk`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BodyChunk {
+    /// Where the centre of the chunk is in its room
+    pub position: [f32; 2],
+    pub radius: f32,
+}
+
+/// A component holding the body chunks that attacks can hit an entity by
+#[derive(Component, Debug, Default, Clone, PartialEq)]
+pub struct Hitbox {
+    pub chunks: Vec<BodyChunk>,
+}
+
+/// The area that an attack reaches
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HitTest {
+    /// A thrown weapon, which hits whatever is within `radius` of the line it moved along from `from` to `to`
+    Sweep {
+        from: [f32; 2],
+        to: [f32; 2],
+        radius: f32,
+    },
+    /// A bite or other attack that hits whatever is within `radius` of `centre`
+    Area { centre: [f32; 2], radius: f32 },
+}
+
+impl HitTest {
+    /// Whether the test is one that could happen in the game, as clients can send anything
+    pub fn is_valid(&self) -> bool {
+        let finite = |point: &[f32; 2]| point.iter().all(|value| value.is_finite());
+        let radius = |radius: f32| (0.0..=MAX_RADIUS).contains(&radius);
+        match self {
+            HitTest::Sweep {
+                from,
+                to,
+                radius: r,
+            } => {
+                finite(from)
+                    && finite(to)
+                    && radius(*r)
+                    && Vec2::from(*from).distance(Vec2::from(*to)) <= MAX_SWEEP_LENGTH
+            }
+            HitTest::Area { centre, radius: r } => finite(centre) && radius(*r),
+        }
+    }
+
+    /// Where the attack starts from
+    pub fn origin(&self) -> [f32; 2] {
+        match *self {
+            HitTest::Sweep { from, .. } => from,
+            HitTest::Area { centre, .. } => centre,
+        }
+    }
+
+    /// How far into the attack the chunk is hit, for sorting what was hit by which the attack reaches first, or `None`
+    /// if it isn't hit
+    pub fn hits(&self, chunk: &BodyChunk) -> Option<f32> {
+        let position = Vec2::from(chunk.position);
+        match *self {
+            HitTest::Sweep { from, to, radius } => {
+                let from = Vec2::from(from);
+                let direction = Vec2::from(to) - from;
+                let along = if direction == Vec2::ZERO {
+                    0.0
+                } else {
+                    ((position - from).dot(direction) / direction.length_squared()).clamp(0.0, 1.0)
+                };
+                let closest = from + direction * along;
+                (closest.distance(position) <= radius + chunk.radius)
+                    .then(|| along * direction.length())
+            }
+            HitTest::Area { centre, radius } => {
+                let distance = Vec2::from(centre).distance(position);
+                (distance <= radius + chunk.radius).then_some(distance)
+            }
+        }
+    }
+}
+
+/// An entity's hitbox as of a recorded tick
+#[derive(Debug, Clone)]
+struct RecordedHitbox {
+    entity: Entity,
+    /// The client connection that controls the entity
+    owner: Option<Entity>,
+    room: Option<Room>,
+    hitbox: Hitbox,
+}
+
+/// The hitboxes of every entity as of recent ticks, oldest first
+#[derive(Resource, Debug, Default)]
+pub struct RewindHistory {
+    ticks: VecDeque<(NetworkTick, Vec<RecordedHitbox>)>,
+}
+
+impl RewindHistory {
+    fn push(&mut self, tick: NetworkTick, hitboxes: Vec<RecordedHitbox>) {
+        if self.ticks.len() == REWIND_HISTORY_LENGTH {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back((tick, hitboxes));
+    }
+
+    /// The earliest tick that hitboxes are known for that isn't before the given one, or the latest one if the given
+    /// tick is after every recorded one
+    pub fn nearest(&self, tick: NetworkTick) -> Option<NetworkTick> {
+        self.ticks
+            .iter()
+            .find(|&&(recorded, _)| !is_newer(tick, recorded))
+            .or_else(|| self.ticks.back())
+            .map(|&(recorded, _)| recorded)
+    }
+
+    fn get(&self, tick: NetworkTick) -> Option<&[RecordedHitbox]> {
+        self.ticks
+            .iter()
+            .find(|(recorded, _)| *recorded == tick)
+            .map(|(_, hitboxes)| &hitboxes[..])
+    }
+
+    /// Whether the point is within reach of the hitbox of an entity in the room that the client connection owned as of
+    /// the tick
+    pub fn within_reach(
+        &self,
+        tick: NetworkTick,
+        owner: Entity,
+        room: &Room,
+        point: [f32; 2],
+    ) -> bool {
+        let point = Vec2::from(point);
+        self.get(tick)
+            .into_iter()
+            .flatten()
+            .filter(|recorded| {
+                recorded.owner == Some(owner) && recorded.room.as_ref() == Some(room)
+            })
+            .flat_map(|recorded| &recorded.hitbox.chunks)
+            .any(|chunk| Vec2::from(chunk.position).distance(point) <= chunk.radius + MAX_REACH)
+    }
+
+    /// The entities in the room that the test by the attacking client connection hits as of the tick, in the order that
+    /// the attack reaches them, leaving out the attacker's own. Returns `None` if no hitboxes were recorded for the tick
+    pub fn check(
+        &self,
+        tick: NetworkTick,
+        attacker: Entity,
+        room: &Room,
+        test: &HitTest,
+    ) -> Option<Vec<Entity>> {
+        let mut hits: Vec<_> = self
+            .get(tick)?
+            .iter()
+            .filter(|recorded| recorded.room.as_ref() == Some(room))
+            .filter(|recorded| recorded.owner != Some(attacker))
+            .filter_map(|recorded| {
+                let reached = recorded
+                    .hitbox
+                    .chunks
+                    .iter()
+                    .filter_map(|chunk| test.hits(chunk))
+                    .min_by(f32::total_cmp)?;
+                Some((reached, recorded.entity))
+            })
+            .collect();
+        hits.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        Some(hits.into_iter().map(|(_, entity)| entity).collect())
+    }
+
+    pub fn clear(&mut self) {
+        self.ticks.clear();
+    }
+}
+
+/// How attacks are lag compensated
+#[derive(Resource, Debug, Clone)]
+pub struct LagCompensationSettings {
+    /// The furthest back that attacks can be checked
+    pub max_rewind: Duration,
+}
+
+impl Default for LagCompensationSettings {
+    fn default() -> Self {
+        Self {
+            max_rewind: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Sent when the server has checked what a client's attack hit
+#[derive(Event, Debug, Clone)]
+pub struct AttackResolved {
+    /// The connection of the client that attacked
+    pub attacker: Entity,
+    /// The tick that the attack was checked as of, after capping how far it was rewound
+    pub tick: NetworkTick,
+    pub room: Room,
+    pub test: HitTest,
+    /// The entities that were hit, in the order that the attack reaches them, other than the attacker's own
+    pub hits: Vec<Entity>,
+}
+
+/// Checks clients' attacks against the hitboxes they were seeing on the server. Needs the
+/// [`SimulationPlugin`](crate::simulation::SimulationPlugin)
+#[derive(Debug, Default)]
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RewindHistory>()
+            .init_resource::<LagCompensationSettings>()
+            .add_event::<AttackResolved>()
+            .add_systems(
+                NetworkUpdate,
+                record_hitboxes.in_set(RecordHitboxes).after(AdvanceTick),
+            )
+            .add_systems(PreUpdate, resolve_attacks.after(ReceivePackets));
+    }
+}
+
+fn record_hitboxes(
+    tick: Res<NetworkTick>,
+    query: Query<(Entity, &Hitbox, Option<&Room>, Option<&Owner>)>,
+    mut history: ResMut<RewindHistory>,
+) {
+    let hitboxes = query
+        .iter()
+        .map(|(entity, hitbox, room, owner)| RecordedHitbox {
+            entity,
+            owner: owner.map(|owner| owner.0),
+            room: room.cloned(),
+            hitbox: hitbox.clone(),
+        })
+        .collect();
+    history.push(*tick, hitboxes);
+}
+
+fn resolve_attacks(
+    tick: Res<NetworkTick>,
+    tick_rate: Res<TickRate>,
+    settings: Res<LagCompensationSettings>,
+    history: Res<RewindHistory>,
+    mut query: Query<(Entity, &mut Received<Attack>, Option<&Room>)>,
+    mut events: EventWriter<AttackResolved>,
+) {
+    let max_rewind = (settings.max_rewind.as_secs_f64() / tick_rate.duration().as_secs_f64())
+        .round()
+        .min(REWIND_HISTORY_LENGTH as f64) as u32;
+    let oldest = NetworkTick(tick.0.wrapping_sub(max_rewind));
+
+    for (attacker, mut attacks, room) in query.iter_mut() {
+        for attack in attacks.buffer.drain(..) {
+            if !attack.test.is_valid() {
+                warn!("Client on entity {attacker:?} sent an invalid attack, ignoring it");
+                continue;
+            }
+            if room != Some(&attack.room) {
+                warn!("Client on entity {attacker:?} attacked in a room it isn't in, ignoring it");
+                continue;
+            }
+
+            // Clients can't have seen ticks that the server hasn't simulated yet
+            let claimed = if is_newer(attack.tick, *tick) {
+                *tick
+            } else if is_newer(oldest, attack.tick) {
+                oldest
+            } else {
+                attack.tick
+            };
+            let Some(rewound) = history.nearest(claimed) else {
+                continue;
+            };
+            if !history.within_reach(rewound, attacker, &attack.room, attack.test.origin()) {
+                warn!("Client on entity {attacker:?} attacked from out of its reach, ignoring it");
+                continue;
+            }
+            let Some(hits) = history.check(rewound, attacker, &attack.room, &attack.test) else {
+                continue;
+            };
+
+            events.send(AttackResolved {
+                attacker,
+                tick: rewound,
+                room: attack.room,
+                test: attack.test,
+                hits,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::world::World;
+
+    use super::*;
+
+    fn chunk(x: f32, y: f32, radius: f32) -> BodyChunk {
+        BodyChunk {
+            position: [x, y],
+            radius,
+        }
+    }
+
+    fn recorded(entity: Entity, owner: Option<Entity>, room: &str, x: f32) -> RecordedHitbox {
+        RecordedHitbox {
+            entity,
+            owner,
+            room: Some(Room(room.into())),
+            hitbox: Hitbox {
+                chunks: vec![chunk(x, 0.0, 5.0)],
+            },
+        }
+    }
+
+    #[test]
+    fn sweep_hits_along_its_line() {
+        let sweep = HitTest::Sweep {
+            from: [0.0, 0.0],
+            to: [100.0, 0.0],
+            radius: 1.0,
+        };
+        assert_eq!(sweep.hits(&chunk(50.0, 5.0, 4.0)), Some(50.0));
+        assert_eq!(sweep.hits(&chunk(50.0, 10.0, 4.0)), None);
+        // Past the end of the line only counts within reach of the end
+        assert_eq!(sweep.hits(&chunk(103.0, 0.0, 2.0)), Some(100.0));
+        assert_eq!(sweep.hits(&chunk(110.0, 0.0, 2.0)), None);
+    }
+
+    #[test]
+    fn sweep_without_length_acts_like_area() {
+        let sweep = HitTest::Sweep {
+            from: [10.0, 10.0],
+            to: [10.0, 10.0],
+            radius: 2.0,
+        };
+        assert_eq!(sweep.hits(&chunk(13.0, 10.0, 1.0)), Some(0.0));
+        assert_eq!(sweep.hits(&chunk(14.0, 10.0, 1.0)), None);
+    }
+
+    #[test]
+    fn area_hits_by_distance() {
+        let area = HitTest::Area {
+            centre: [0.0, 0.0],
+            radius: 10.0,
+        };
+        assert_eq!(area.hits(&chunk(3.0, 4.0, 1.0)), Some(5.0));
+        assert_eq!(area.hits(&chunk(30.0, 40.0, 39.0)), None);
+    }
+
+    #[test]
+    fn invalid_tests_are_rejected() {
+        let sweep = |to: f32, radius: f32| HitTest::Sweep {
+            from: [0.0, 0.0],
+            to: [to, 0.0],
+            radius,
+        };
+        assert!(sweep(100.0, 10.0).is_valid());
+        assert!(!sweep(f32::NAN, 10.0).is_valid());
+        assert!(!sweep(100.0, -1.0).is_valid());
+        assert!(!sweep(100.0, MAX_RADIUS + 1.0).is_valid());
+        assert!(!sweep(MAX_SWEEP_LENGTH + 1.0, 10.0).is_valid());
+    }
+
+    #[test]
+    fn nearest_finds_the_closest_recorded_tick() {
+        let mut history = RewindHistory::default();
+        assert_eq!(history.nearest(NetworkTick(5)), None);
+
+        for tick in 10..20 {
+            history.push(NetworkTick(tick), Vec::new());
+        }
+        assert_eq!(history.nearest(NetworkTick(5)), Some(NetworkTick(10)));
+        assert_eq!(history.nearest(NetworkTick(15)), Some(NetworkTick(15)));
+        assert_eq!(history.nearest(NetworkTick(25)), Some(NetworkTick(19)));
+
+        for tick in 20..20 + REWIND_HISTORY_LENGTH as u32 {
+            history.push(NetworkTick(tick), Vec::new());
+        }
+        assert_eq!(history.nearest(NetworkTick(15)), Some(NetworkTick(20)));
+    }
+
+    #[test]
+    fn check_sorts_hits_and_skips_the_attacker() {
+        let mut world = World::new();
+        let [attacker, player, near, far, elsewhere] = [(); 5].map(|_| world.spawn_empty().id());
+
+        let mut history = RewindHistory::default();
+        history.push(
+            NetworkTick(1),
+            vec![
+                recorded(far, None, "SU_A01", 80.0),
+                recorded(player, Some(attacker), "SU_A01", 0.0),
+                recorded(near, None, "SU_A01", 40.0),
+                recorded(elsewhere, None, "SU_A02", 40.0),
+            ],
+        );
+
+        let room = Room("SU_A01".into());
+        let sweep = HitTest::Sweep {
+            from: [0.0, 0.0],
+            to: [100.0, 0.0],
+            radius: 1.0,
+        };
+        assert_eq!(
+            history.check(NetworkTick(1), attacker, &room, &sweep),
+            Some(vec![near, far])
+        );
+        assert_eq!(history.check(NetworkTick(2), attacker, &room, &sweep), None);
+
+        assert!(history.within_reach(NetworkTick(1), attacker, &room, [100.0, 0.0]));
+        assert!(!history.within_reach(NetworkTick(1), attacker, &room, [400.0, 0.0]));
+        assert!(!history.within_reach(NetworkTick(1), near, &room, [40.0, 0.0]));
+        assert!(!history.within_reach(
+            NetworkTick(1),
+            attacker,
+            &Room("SU_A02".into()),
+            [0.0, 0.0]
+        ));
+    }
+}
@@ -1,18 +1,36 @@
 pub mod channel;
+pub mod clock;
+pub mod discovery;
+pub mod heartbeat;
+pub mod input;
+pub mod interpolation;
 mod is;
+pub mod lag_compensation;
+pub mod master;
 pub mod packet;
 pub mod peer;
 mod plugin;
+pub mod prediction;
+pub mod replication;
 pub mod serde;
+pub mod simulation;
+pub mod stats;
 
 pub use is::Is;
 pub use packet::{PacketReceiver, PacketSender, ReceiveError};
-pub use plugin::{ConnectionBundle, ProtoPlugin, ReceivePackets, SendPackets};
+pub use plugin::{
+    ConnectionBundle, DisconnectPeer, PeerDisconnected, ProtoPlugin, ReceivePackets, SendPackets,
+};
 pub use serde::ByteQueue;
+pub use stats::NetworkStats;
 
 use serde::SerdeError;
 use thiserror::Error;
 
+/// The version of the protocol implemented by this crate.
+/// Incremented whenever a change is made that prevents peers on different versions from understanding each other
+pub const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct Error(ErrorKind);
@@ -0,0 +1,129 @@
+//! The messages exchanged with the master server, which keeps a list of public servers for clients to browse.
+//!
+//! Unlike packets, these aren't sent over a long-lived stream. Each request is sent on its own bidirectional stream, which
+//! the master server answers with a single response before finishing the stream.
+//!
+//! The master server also helps clients reach servers that are behind NAT. Since registered servers keep their connection
+//! to the master server open, it can send them [`MasterNotification`]s on unidirectional streams, asking them to punch a
+//! hole through their NAT towards a client, or towards a relay that forwards packets between the two.
+
+use std::{net::SocketAddr, time::Duration};
+
+use coalescence_common::GameMode;
+use serde::{Deserialize, Serialize};
+
+use crate::{packet::ServerStatus, PROTOCOL_VERSION};
+
+/// The maximum size of a serialized request or response, so that peers can't be made to buffer unbounded amounts of data
+pub const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+/// How many servers are listed in each page of a [`MasterResponse::ServerList`]
+pub const SERVERS_PER_PAGE: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MasterRequest {
+    /// Add the sending server to the list. Its address is taken from the connection that sent the request
+    Register {
+        /// The port that the server accepts players on
+        port: u16,
+        status: ServerStatus,
+    },
+    /// Keep a registered server in the list, and update its status
+    Heartbeat { token: u64, status: ServerStatus },
+    /// Remove a registered server from the list, e.g. because it's shutting down
+    Unregister { token: u64 },
+    /// Fetch a page of the servers matching the filter, starting from 0
+    List { filter: ServerFilter, page: u32 },
+    /// Ask the registered server with the given listed address to punch a hole through its NAT towards the sender
+    Punch { server: SocketAddr },
+    /// Ask for a relay that forwards packets between the sender and the registered server with the given listed address,
+    /// for when punching a hole fails
+    Relay { server: SocketAddr },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MasterResponse {
+    Registered {
+        /// Identifies the server in later heartbeats
+        token: u64,
+        /// How often heartbeats must be sent for the server to stay in the list
+        heartbeat_interval: Duration,
+    },
+    /// The heartbeat or unregistration was accepted
+    Ok,
+    /// The token isn't known, e.g. because the server missed too many heartbeats. It should register again
+    UnknownToken,
+    ServerList {
+        servers: Vec<ListedServer>,
+        page: u32,
+        /// The total amount of pages of servers matching the filter
+        page_count: u32,
+    },
+    /// The server is punching a hole towards the sender, which should now connect to the server on the given address.
+    /// This is the address that the master server sees the server's connection coming from, which may differ from its
+    /// listed address if its NAT doesn't preserve ports
+    PunchStarted { address: SocketAddr },
+    /// A relay to the server is ready on the given port of the master server, which the sender should now connect to
+    RelayAllocated { port: u16 },
+    /// The request couldn't be handled, for the given reason
+    Rejected(String),
+}
+
+/// Sent by the master server to registered servers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MasterNotification {
+    /// A client at the given address wants to connect, so the server should send packets towards it to open its NAT
+    Punch { client: SocketAddr },
+    /// A relay has been set up on the given port of the master server, so the server should send packets towards it to
+    /// open its NAT
+    Relay { port: u16 },
+}
+
+/// A server in the master server's list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListedServer {
+    /// The address that players connect to the server on
+    pub address: SocketAddr,
+    pub status: ServerStatus,
+}
+
+/// Which servers to include in a [`MasterRequest::List`]. Fields that are `None` or `false` don't filter anything
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerFilter {
+    /// Only include servers whose names contain this, ignoring case
+    pub name: Option<String>,
+    /// Only include servers with this protocol version
+    pub protocol_version: Option<u32>,
+    pub game_mode: Option<GameMode>,
+    /// Only include servers with at least this many players
+    pub min_players: Option<u32>,
+    /// Only include servers that aren't full
+    pub not_full: bool,
+    /// Only include servers that don't need a password
+    pub no_password: bool,
+}
+
+impl ServerFilter {
+    /// A filter for servers that this version of the protocol can join
+    pub fn compatible() -> Self {
+        Self {
+            protocol_version: Some(PROTOCOL_VERSION),
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, status: &ServerStatus) -> bool {
+        let name_matches = self.name.as_ref().map_or(true, |name| {
+            status.name.to_lowercase().contains(&name.to_lowercase())
+        });
+
+        name_matches
+            && self
+                .protocol_version
+                .map_or(true, |version| status.protocol_version == version)
+            && self.game_mode.map_or(true, |mode| status.game_mode == mode)
+            && self.min_players.map_or(true, |min| status.players >= min)
+            && !(self.not_full && status.players >= status.max_players)
+            && !(self.no_password && status.password_required)
+    }
+}
@@ -1,15 +1,26 @@
 //! The different types of packets that are defined by the protocol
 #![allow(non_snake_case)]
 
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
 use bevy::{
-    ecs::{component::Component, query::QueryData},
+    ecs::{bundle::Bundle, component::Component, query::QueryData},
     prelude::{Deref, DerefMut},
 };
+use coalescence_common::GameMode;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    channel::{Channel, Ordered},
+    channel::{Channel, Ordered, Unreliable},
+    clock::NetworkTick,
+    input::Input,
+    lag_compensation::HitTest,
     peer::{Bidirectional, ClientToServer, Direction, ServerToClient},
+    replication::{interest::Room, snapshot::Fragment, NetworkEntity},
+    stats::ConnectionQuality,
 };
 
 mod header;
@@ -17,7 +28,7 @@ mod packet_receiver;
 mod packet_sender;
 
 pub(crate) use header::OrderedHeader;
-pub(crate) use packet_receiver::receive;
+pub(crate) use packet_receiver::{clear_received, receive};
 pub use packet_receiver::{PacketReceiver, ReceiveError};
 pub use packet_sender::PacketSender;
 
@@ -30,6 +41,15 @@ macro_rules! all_packets {
             $( $packet($packet), )+
         }
 
+        impl AnyPacket {
+            /// The name of the packet's type
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $( Self::$packet(_) => stringify!($packet), )+
+                }
+            }
+        }
+
         $(
             impl From<$packet> for AnyPacket {
                 fn from(packet: $packet) -> Self {
@@ -55,11 +75,58 @@ macro_rules! all_packets {
                     )+
                 }
             }
+
+            fn clear(&mut self) {
+                $(
+                    self.$packet.buffer.clear();
+                )+
+            }
+        }
+
+        /// A bundle of a [`Received`] buffer for every packet type
+        #[derive(Debug, Bundle)]
+        pub(crate) struct ReceivedPacketsBundle {
+            $(
+                $packet: Received<$packet>,
+            )+
+        }
+
+        impl Default for ReceivedPacketsBundle {
+            fn default() -> Self {
+                Self {
+                    $(
+                        $packet: Received::default(),
+                    )+
+                }
+            }
         }
     };
 }
 
-all_packets!(Profile, Lobby, PlayerJoined, PlayerLeft, Disconnect);
+all_packets!(
+    Profile,
+    Lobby,
+    PlayerJoined,
+    PlayerLeft,
+    QueuePosition,
+    StatusRequest,
+    ServerStatus,
+    Disconnect,
+    SessionResumable,
+    Resume,
+    PlayerStats,
+    Ping,
+    Pong,
+    TimeRequest,
+    TimeResponse,
+    SnapshotFragment,
+    ReliableSnapshot,
+    SnapshotAck,
+    OwnedEntity,
+    RoomChanged,
+    PlayerInput,
+    Attack
+);
 
 /// A component holding a buffer for packets of the specified type that have been received from the peer
 #[derive(Debug, Component, Deref, DerefMut)]
@@ -82,11 +149,20 @@ pub trait Packet: Into<AnyPacket> {
 
     /// The direction between the client and server that this packet is valid to be sent over
     type Direction: Direction;
+
+    /// Whether this packet is safe for the receiver to handle more than once, so that it may be sent in 0-RTT early data,
+    /// which an attacker can replay. Packets that aren't replay-safe are held back until the handshake has completed
+    const REPLAY_SAFE: bool = false;
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Profile {
+    /// The [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION) of the client. This comes first, so that servers can still read
+    /// it if the rest of the profile changes in later versions
+    pub protocol_version: u32,
     pub username: String,
+    /// The server's password, if it has one
+    pub password: Option<String>,
 }
 
 impl Packet for Profile {
@@ -115,17 +191,363 @@ impl Packet for PlayerJoined {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct PlayerLeft;
+pub struct PlayerLeft {
+    pub username: String,
+}
 
 impl Packet for PlayerLeft {
     type Channel = Ordered;
     type Direction = ServerToClient;
 }
 
+/// Sent to clients that are waiting for a player slot on a full server, whenever their place in the queue changes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuePosition {
+    /// The client's place in the queue, starting from 1
+    pub position: u32,
+    /// How many clients are in the queue in total
+    pub length: u32,
+}
+
+impl Packet for QueuePosition {
+    type Channel = Ordered;
+    type Direction = ServerToClient;
+}
+
+/// Sent instead of a [`Profile`] by clients that only want to know about the server, without joining it.
+/// The server answers with a [`ServerStatus`], after which the client closes the connection
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Disconnect;
+pub struct StatusRequest {
+    /// The [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION) of the client
+    pub protocol_version: u32,
+}
+
+impl Packet for StatusRequest {
+    type Channel = Ordered;
+    type Direction = ClientToServer;
+    // Only asks for information, so answering it twice does no harm
+    const REPLAY_SAFE: bool = true;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub name: String,
+    /// The message of the day
+    pub motd: String,
+    pub players: u32,
+    pub max_players: u32,
+    /// The [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION) of the server.
+    /// Clients can only join servers with the same version as their own
+    pub protocol_version: u32,
+    pub game_mode: GameMode,
+    /// Whether a password must be given in the [`Profile`] to join
+    pub password_required: bool,
+}
+
+impl Packet for ServerStatus {
+    type Channel = Ordered;
+    type Direction = ServerToClient;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Disconnect {
+    pub reason: DisconnectReason,
+}
 
 impl Packet for Disconnect {
     type Channel = Ordered;
     type Direction = Bidirectional;
 }
+
+/// A secret that lets a player who lost their connection take their place on the server back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ResumeToken(pub [u8; 16]);
+
+/// Sent to players once they've been let into the server, so that they can reconnect if their connection drops
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionResumable {
+    pub token: ResumeToken,
+    /// How long the server holds the player's place for after their connection drops
+    pub grace_period: Duration,
+}
+
+impl Packet for SessionResumable {
+    type Channel = Ordered;
+    type Direction = ServerToClient;
+}
+
+/// Sent instead of a [`Profile`] by clients reconnecting after their connection dropped, to take back their place.
+/// The server answers with a [`Lobby`], or disconnects the client with [`DisconnectReason::SessionExpired`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resume {
+    pub token: ResumeToken,
+}
+
+impl Packet for Resume {
+    type Channel = Ordered;
+    type Direction = ClientToServer;
+}
+
+/// How well a player's connection to the server is doing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerConnection {
+    pub username: String,
+    /// The round-trip time between the server and the player
+    pub ping: Duration,
+    pub quality: ConnectionQuality,
+}
+
+/// Sent to players every so often, so that they can see how well everyone's connection is doing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub players: Vec<PlayerConnection>,
+}
+
+impl Packet for PlayerStats {
+    type Channel = Ordered;
+    type Direction = ServerToClient;
+}
+
+/// Sent by both peers every [heartbeat interval](crate::heartbeat::HeartbeatSettings::interval), to show they're
+/// still there and to measure the latency between them. Answered with a [`Pong`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ping {
+    pub sequence: u32,
+}
+
+impl Packet for Ping {
+    type Channel = Unreliable;
+    type Direction = Bidirectional;
+    // Answering it twice only sends an extra pong
+    const REPLAY_SAFE: bool = true;
+}
+
+/// The answer to a [`Ping`], with the same sequence number
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pong {
+    pub sequence: u32,
+}
+
+impl Packet for Pong {
+    type Channel = Unreliable;
+    type Direction = Bidirectional;
+    const REPLAY_SAFE: bool = true;
+}
+
+/// Sent by clients to find out the offset between their clock and the server's, see [`clock`](crate::clock)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimeRequest {
+    /// The client's [`NetworkClock`](crate::clock::NetworkClock) time when the request was sent
+    pub client_time: Duration,
+}
+
+impl Packet for TimeRequest {
+    type Channel = Unreliable;
+    type Direction = ClientToServer;
+    // Only asks for information, so answering it twice does no harm
+    const REPLAY_SAFE: bool = true;
+}
+
+/// The answer to a [`TimeRequest`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimeResponse {
+    /// The client's time from the request
+    pub client_time: Duration,
+    /// The server's [`NetworkClock`](crate::clock::NetworkClock) time when it answered
+    pub server_time: Duration,
+    /// The tick the server was on when it answered
+    pub tick: NetworkTick,
+    /// When that tick started, by the server's clock
+    pub tick_started: Duration,
+    /// How long each of the server's ticks lasts
+    pub tick_duration: Duration,
+}
+
+impl Packet for TimeResponse {
+    type Channel = Unreliable;
+    type Direction = ServerToClient;
+}
+
+/// A piece of a [`Snapshot`](crate::replication::snapshot::Snapshot) of the replicated entities that's a delta from
+/// one the client has acknowledged, see [`replication`](crate::replication)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotFragment {
+    pub fragment: Fragment,
+}
+
+impl Packet for SnapshotFragment {
+    type Channel = Unreliable;
+    type Direction = ServerToClient;
+}
+
+/// A piece of a [`Snapshot`](crate::replication::snapshot::Snapshot) of the replicated entities sent reliably, either
+/// a full one for clients that have no snapshot to apply a delta to, or a delta too big to send over datagrams
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReliableSnapshot {
+    pub fragment: Fragment,
+}
+
+impl Packet for ReliableSnapshot {
+    type Channel = Ordered;
+    type Direction = ServerToClient;
+}
+
+/// Sent by clients when they apply a snapshot, so the server can send the next one as a delta from it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotAck {
+    pub tick: NetworkTick,
+}
+
+impl Packet for SnapshotAck {
+    type Channel = Unreliable;
+    type Direction = ClientToServer;
+    // Acknowledging a snapshot twice changes nothing
+    const REPLAY_SAFE: bool = true;
+}
+
+/// Sent to clients when the replicated entity they control changes, so that they
+/// [predict](crate::prediction::Predicted) it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OwnedEntity {
+    /// The entity the client controls, or `None` if it doesn't control any
+    pub entity: Option<NetworkEntity>,
+}
+
+impl Packet for OwnedEntity {
+    type Channel = Ordered;
+    type Direction = ServerToClient;
+}
+
+/// Sent by clients when their player moves to another room, so that they're sent the entities that are relevant to them,
+/// see [`interest`](crate::replication::interest)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomChanged {
+    /// The room the player is now in, or `None` if they've left the game world, e.g. for the menus
+    pub room: Option<Room>,
+    /// The rooms that the new room connects to
+    pub neighbours: Vec<Room>,
+}
+
+impl Packet for RoomChanged {
+    type Channel = Ordered;
+    type Direction = ClientToServer;
+}
+
+/// Why a connection between two peers was closed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// The peer closed the connection normally, e.g. because the player quit
+    Quit,
+    /// The connection was lost without the peer saying why, e.g. because of a network error
+    ConnectionLost,
+    /// The player or their address is banned from the server
+    Banned {
+        /// Why the player was banned, if a reason was given
+        reason: Option<String>,
+        /// When the ban expires, or `None` if the ban is permanent
+        expires: Option<SystemTime>,
+    },
+    /// The server only accepts players on its allow list, and this player is not on it
+    NotAllowed,
+    /// The peer took too long to respond
+    Timeout,
+    /// The server is too busy to accept new connections right now
+    ServerBusy,
+    /// The peer sent more data than it is allowed to
+    RateLimited,
+    /// The server requires a password, and the player didn't give the right one
+    WrongPassword,
+    /// The server has no free player slots, and isn't queueing players or its queue is full
+    ServerFull,
+    /// The player tried to reconnect, but the server had already given up their place
+    SessionExpired,
+    /// The client didn't present a certificate for the server to identify the player by
+    MissingIdentity,
+    /// Another player on the server is already using the username
+    UsernameTaken,
+    /// The client's [`PROTOCOL_VERSION`](crate::PROTOCOL_VERSION) is different from the server's
+    IncompatibleVersion {
+        /// The server's protocol version
+        server_version: u32,
+    },
+}
+
+impl DisconnectReason {
+    /// Whether the connection dropped by accident, so that the player may be able to reconnect and resume their session
+    pub fn is_resumable(&self) -> bool {
+        matches!(self, Self::ConnectionLost | Self::Timeout)
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Quit => f.write_str("The connection was closed"),
+            DisconnectReason::ConnectionLost => f.write_str("The connection was lost"),
+            DisconnectReason::Banned { reason, expires } => {
+                f.write_str("You are banned from this server")?;
+                if let Some(expires) = expires {
+                    write!(f, " until {}", humantime::format_rfc3339_seconds(*expires))?;
+                }
+                if let Some(reason) = reason {
+                    write!(f, ": {reason}")?;
+                }
+                Ok(())
+            }
+            DisconnectReason::NotAllowed => f.write_str("You are not on this server's allow list"),
+            DisconnectReason::Timeout => f.write_str("The connection timed out"),
+            DisconnectReason::ServerBusy => {
+                f.write_str("The server is too busy to accept new connections, try again later")
+            }
+            DisconnectReason::RateLimited => f.write_str("Too much data was sent too quickly"),
+            DisconnectReason::WrongPassword => f.write_str("Incorrect password"),
+            DisconnectReason::ServerFull => f.write_str("The server is full"),
+            DisconnectReason::SessionExpired => {
+                f.write_str("Could not reconnect, as the server has already given your place away")
+            }
+            DisconnectReason::MissingIdentity => f.write_str(
+                "The server could not identify your player, as no identity was presented",
+            ),
+            DisconnectReason::UsernameTaken => {
+                f.write_str("Someone on this server is already using that username")
+            }
+            DisconnectReason::IncompatibleVersion { server_version } => write!(
+                f,
+                "The server uses protocol version {server_version}, which is incompatible with this version of the mod"
+            ),
+        }
+    }
+}
+
+/// Sent by clients every tick with their player's input, along with the inputs for the ticks before it, so that the
+/// server still gets them if a few packets are lost
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerInput {
+    /// The tick of the last input
+    pub tick: NetworkTick,
+    /// The inputs for consecutive ticks, oldest first
+    pub inputs: Vec<Input>,
+}
+
+impl Packet for PlayerInput {
+    type Channel = Unreliable;
+    type Direction = ClientToServer;
+    // Inputs for ticks the server already has are discarded
+    const REPLAY_SAFE: bool = true;
+}
+
+/// Sent by clients when their player attacks, so that the server can check what it hit as of the tick they were seeing,
+/// see [`lag_compensation`](crate::lag_compensation)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Attack {
+    /// The tick that the other players and creatures were being shown as of when the player attacked
+    pub tick: NetworkTick,
+    /// The room the attack happened in
+    pub room: Room,
+    pub test: HitTest,
+}
+
+impl Packet for Attack {
+    type Channel = Ordered;
+    type Direction = ClientToServer;
+}
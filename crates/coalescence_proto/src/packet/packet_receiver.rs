@@ -11,6 +11,7 @@ use bytes::Bytes;
 use crate::{
     channel::{Channel, Ordered, Unordered, Unreliable},
     serde::{deserialize, deserialize_from, ByteQueue},
+    stats::Traffic,
     Error, Is,
 };
 
@@ -35,6 +36,8 @@ pub struct PacketReceiver {
     unordered_buffer: Vec<Bytes>,
     /// Packets received from the unordered-unreliable channel, that haven't been deserialized yet
     unreliable_buffer: Vec<Bytes>,
+    /// Everything received over the connection's lifetime
+    traffic: Traffic,
 }
 
 impl PacketReceiver {
@@ -48,6 +51,13 @@ impl PacketReceiver {
     /// over the course of the calls corresponds exactly to the order the bytes were received in, with no gaps or reordering.
     /// This guarantee does not apply to the other channels.
     pub fn receive<C: Channel>(&mut self, bytes: Bytes) {
+        let channel = self.traffic.channel_mut::<C>();
+        channel.bytes += bytes.len() as u64;
+        // Ordered packets may be split across several calls, so they're counted once they've been deserialized instead
+        if !C::is::<Ordered>() {
+            channel.packets += 1;
+        }
+
         if C::is::<Ordered>() {
             self.ordered_queue.push(bytes);
         } else if C::is::<Unordered>() {
@@ -59,6 +69,19 @@ impl PacketReceiver {
         }
     }
 
+    /// Everything received over the connection's lifetime, including packets that haven't been deserialized yet
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Discard all received bytes that haven't been deserialized into packets yet
+    pub fn clear(&mut self) {
+        self.ordered_queue.clear();
+        self.pending_ordered_header = None;
+        self.unordered_buffer.clear();
+        self.unreliable_buffer.clear();
+    }
+
     /// Poll for packets received from the ordered-reliable channel.
     ///
     /// Returns Ok(Some(AnyPacket)) if a packet was successfully deserialized.
@@ -100,6 +123,8 @@ impl PacketReceiver {
                     "Deserializing packet didn't read all of its bytes"
                 );
 
+                self.traffic.ordered.packets += 1;
+
                 Ok(Some(packet))
             }
             Err(e) => {
@@ -122,6 +147,7 @@ pub(crate) fn receive(
         let PacketReceiver {
             unordered_buffer,
             unreliable_buffer,
+            traffic,
             ..
         } = &mut *receiver;
 
@@ -129,8 +155,11 @@ pub(crate) fn receive(
             .drain(..)
             .chain(unreliable_buffer.drain(..))
         {
-            match deserialize(&packets) {
-                Ok(packet) => buffers.receive(packet),
+            match deserialize::<AnyPacket>(&packets) {
+                Ok(packet) => {
+                    traffic.record_packet_type(packet.name());
+                    buffers.receive(packet);
+                }
                 Err(error) => {
                     errors.send(ReceiveError { entity, error });
                 }
@@ -140,7 +169,10 @@ pub(crate) fn receive(
         // Deserialize any ordered packets that are available
         while let Some(result) = receiver.poll_ordered_reliable().transpose() {
             match result {
-                Ok(packet) => buffers.receive(packet),
+                Ok(packet) => {
+                    receiver.traffic.record_packet_type(packet.name());
+                    buffers.receive(packet);
+                }
                 Err(error) => {
                     errors.send(ReceiveError { entity, error });
                 }
@@ -148,3 +180,10 @@ pub(crate) fn receive(
         }
     }
 }
+
+/// Discard any received packets that weren't handled during the update they were received in
+pub(crate) fn clear_received(mut query: Query<ReceivedPackets>) {
+    for mut buffers in query.iter_mut() {
+        buffers.clear();
+    }
+}
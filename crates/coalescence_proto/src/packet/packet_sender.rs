@@ -7,6 +7,7 @@ use crate::{
     channel::{Channel, Ordered, Unordered, Unreliable},
     peer::Outbound,
     serde::{serialize_into, serialized_size},
+    stats::Traffic,
     Error, Is,
 };
 
@@ -17,12 +18,24 @@ use super::{AnyPacket, OrderedHeader, Packet};
 /// The generic type parameter `P` is the type of *this* peer, not the remote peer that packets are sent to
 #[derive(Debug, Component)]
 pub struct PacketSender<P> {
-    ordered_buffer: Vec<Bytes>,
-    unordered_buffer: Vec<Bytes>,
-    unreliable_buffer: Vec<Bytes>,
+    ordered_buffer: ChannelBuffer,
+    unordered_buffer: ChannelBuffer,
+    unreliable_buffer: ChannelBuffer,
+    /// Whether the connection is still sending 0-RTT early data, during which only replay-safe packets may be taken
+    early_data: bool,
+    /// Everything sent over the connection's lifetime
+    traffic: Traffic,
     peer: PhantomData<P>,
 }
 
+/// The serialized packets waiting to be sent over a single channel
+#[derive(Debug, Default)]
+struct ChannelBuffer {
+    packets: Vec<Bytes>,
+    /// The index of the first buffered packet that isn't replay-safe, if any
+    first_unsafe: Option<usize>,
+}
+
 impl<P> Default for PacketSender<P> {
     fn default() -> Self {
         Self::new()
@@ -35,11 +48,19 @@ impl<P> PacketSender<P> {
             ordered_buffer: default(),
             unordered_buffer: default(),
             unreliable_buffer: default(),
+            early_data: false,
+            traffic: default(),
             peer: PhantomData,
         }
     }
 
-    fn buffer_for_channel<C: Channel>(&mut self) -> &mut Vec<Bytes> {
+    /// Set whether the connection is still sending 0-RTT early data. While it is, packets that aren't
+    /// [replay-safe](Packet::REPLAY_SAFE) stay buffered, along with everything sent after them on the same channel
+    pub fn set_early_data(&mut self, early_data: bool) {
+        self.early_data = early_data;
+    }
+
+    fn buffer_for_channel<C: Channel>(&mut self) -> &mut ChannelBuffer {
         if C::is::<Ordered>() {
             &mut self.ordered_buffer
         } else if C::is::<Unordered>() {
@@ -71,13 +92,40 @@ impl<P> PacketSender<P> {
 
         serialize_into(&mut bytes, &packet)?;
 
-        self.buffer_for_channel::<T::Channel>().push(bytes.into());
+        let channel = self.traffic.channel_mut::<T::Channel>();
+        channel.bytes += bytes.len() as u64;
+        channel.packets += 1;
+        self.traffic.record_packet_type(packet.name());
+
+        let buffer = self.buffer_for_channel::<T::Channel>();
+        if !T::REPLAY_SAFE && buffer.first_unsafe.is_none() {
+            buffer.first_unsafe = Some(buffer.packets.len());
+        }
+        buffer.packets.push(bytes.into());
 
         Ok(())
     }
 
-    /// Take all of the bytes currently buffered to be sent over the specified channel
+    /// Everything sent over the connection's lifetime, including packets that are still buffered
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Take all of the bytes currently buffered to be sent over the specified channel.
+    /// During early data, only the packets before the first one that isn't replay-safe are taken
     pub fn take_bytes<C: Channel>(&mut self) -> Vec<Bytes> {
-        std::mem::take(self.buffer_for_channel::<C>())
+        let early_data = self.early_data;
+        let buffer = self.buffer_for_channel::<C>();
+        match buffer.first_unsafe {
+            Some(first_unsafe) if early_data => {
+                let held = buffer.packets.split_off(first_unsafe);
+                buffer.first_unsafe = Some(0);
+                std::mem::replace(&mut buffer.packets, held)
+            }
+            _ => {
+                buffer.first_unsafe = None;
+                std::mem::take(&mut buffer.packets)
+            }
+        }
     }
 }
@@ -1,23 +1,59 @@
 use std::marker::PhantomData;
 
 use bevy::{
-    app::{App, Plugin, Update},
+    app::{App, Last, Plugin, PostUpdate, PreUpdate},
     ecs::{
         bundle::Bundle,
+        entity::Entity,
+        event::Event,
         schedule::{IntoSystemConfigs, SystemSet},
     },
 };
 
 use crate::{
-    packet::{receive, PacketReceiver, PacketSender},
-    peer::Peer,
+    heartbeat::{heartbeat, Heartbeat, HeartbeatSettings},
+    packet::{
+        clear_received, receive, DisconnectReason, PacketReceiver, PacketSender,
+        ReceivedPacketsBundle,
+    },
+    peer::{Bidirectional, Outbound, Peer},
+    stats::{update_traffic_stats, NetworkStats},
+    ReceiveError,
 };
 
-#[derive(Debug, Bundle, Default)]
+#[derive(Debug, Bundle)]
 pub struct ConnectionBundle<P: Peer> {
     sender: PacketSender<P>,
     receiver: PacketReceiver,
-    // received_packets: ReceivedPackets,
+    received_packets: ReceivedPacketsBundle,
+    stats: NetworkStats,
+    heartbeat: Heartbeat,
+}
+
+impl<P: Peer> Default for ConnectionBundle<P> {
+    fn default() -> Self {
+        Self {
+            sender: PacketSender::new(),
+            receiver: PacketReceiver::new(),
+            received_packets: ReceivedPacketsBundle::default(),
+            stats: NetworkStats::default(),
+            heartbeat: Heartbeat::default(),
+        }
+    }
+}
+
+/// An event that requests the connection on the given entity be closed, telling the remote peer why
+#[derive(Debug, Event)]
+pub struct DisconnectPeer {
+    pub entity: Entity,
+    pub reason: DisconnectReason,
+}
+
+/// An event that is sent whenever the connection on the given entity has been closed, by either peer
+#[derive(Debug, Event)]
+pub struct PeerDisconnected {
+    pub entity: Entity,
+    pub reason: DisconnectReason,
 }
 
 #[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
@@ -29,8 +65,29 @@ pub struct ReceivePackets;
 #[derive(Debug)]
 pub struct ProtoPlugin<P>(PhantomData<P>);
 
-impl<P: Peer> Plugin for ProtoPlugin<P> {
+impl<P> Default for ProtoPlugin<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<P: Peer> Plugin for ProtoPlugin<P>
+where
+    Bidirectional: Outbound<P>,
+{
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, receive.in_set(ReceivePackets));
+        app.init_resource::<HeartbeatSettings>()
+            .add_event::<ReceiveError>()
+            .add_event::<DisconnectPeer>()
+            .add_event::<PeerDisconnected>()
+            .add_systems(PreUpdate, receive.in_set(ReceivePackets))
+            .add_systems(
+                PostUpdate,
+                (
+                    heartbeat::<P>.before(SendPackets),
+                    update_traffic_stats::<P>.after(SendPackets),
+                ),
+            )
+            .add_systems(Last, clear_received);
     }
 }
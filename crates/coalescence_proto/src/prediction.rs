@@ -0,0 +1,476 @@
+//! Client-side prediction of the local player, with rollback when the server disagrees.
+//!
+//! Waiting for the server to say where the player has moved would put a round trip between pressing a button and seeing
+//! anything happen, so the client simulates the entities marked [`Predicted`] itself, in the [`PredictedUpdate`]
+//! schedule, using the [`LocalInput`] of each tick. The entity that the server says the client
+//! [owns](crate::replication::Owner) is marked automatically. The input and the [predicted](AppPredictionExt::predict) components
+//! are recorded for every tick.
+//!
+//! Snapshots from the server are for ticks that the client has already predicted. When one arrives, the predicted
+//! components are compared with what was predicted for its tick. If they match, the prediction carries on. If not, the
+//! client rolls back to the server's state, and resimulates every tick since then with the recorded inputs. The
+//! [`Smoothed`] copy of each component then catches up with the corrected value over
+//! [`PredictionSettings::correction_time`], so that mispredictions don't make the player visibly snap to a new place.
+//!
+//! The schedule is only run by clients. The server simulates the same systems in
+//! [`NetworkUpdate`](crate::simulation::NetworkUpdate) as usual
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{
+    app::{App, Plugin, PostUpdate, PreUpdate},
+    ecs::{
+        component::Component,
+        entity::Entity,
+        query::With,
+        schedule::{IntoSystemConfigs, Schedule, ScheduleLabel, SystemSet},
+        system::{Local, Query, Res, ResMut, Resource},
+        world::{Mut, World},
+    },
+    log::{debug, warn},
+    time::Time,
+};
+
+use crate::{
+    clock::NetworkTick,
+    input::{Input, InputBuffer, LocalInput, INPUT_HISTORY_LENGTH},
+    replication::{
+        snapshot::{is_newer, HISTORY_LENGTH},
+        ApplySnapshots, SnapshotBuffer,
+    },
+    simulation::{AdvanceTick, NetworkUpdate},
+};
+
+/// The schedule that predicted entities are simulated in, once per tick, and again for every tick being resimulated
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PredictedUpdate;
+
+/// The system set in [`NetworkUpdate`] that records the tick's input and runs [`PredictedUpdate`]
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct PredictTick;
+
+/// A marker component for the entities that the client predicts, such as the local player's slugcat. Given to the entity
+/// the client owns on the server, and can be given to others by hand
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Predicted;
+
+/// A component type that can be predicted
+pub trait Predict: Component + Clone + PartialEq {
+    /// The value part of the way from this one to `towards`, where `t` is between 0 and 1. Used to smooth out
+    /// mispredictions, so types that can't be blended can return `towards`
+    fn blend(&self, towards: &Self, t: f32) -> Self;
+}
+
+/// The input that the tick being simulated should use
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct CurrentInput(pub Input);
+
+/// The local player's input for recent ticks
+#[derive(Resource, Debug, Default)]
+pub struct InputHistory(pub InputBuffer);
+
+/// How mispredictions are corrected
+#[derive(Resource, Debug, Clone)]
+pub struct PredictionSettings {
+    /// How long the [`Smoothed`] value takes to catch up after a misprediction
+    pub correction_time: Duration,
+}
+
+impl Default for PredictionSettings {
+    fn default() -> Self {
+        Self {
+            correction_time: Duration::from_millis(250),
+        }
+    }
+}
+
+/// A copy of a predicted component for showing to the player, which catches up smoothly after mispredictions instead of
+/// jumping straight to the corrected value
+#[derive(Component, Debug, Clone)]
+pub struct Smoothed<C> {
+    pub value: C,
+    /// How much longer until the value has caught up
+    correcting: Duration,
+}
+
+/// The values that a predicted component had as of recent ticks, oldest first
+#[derive(Component, Debug)]
+struct PredictionHistory<C> {
+    values: VecDeque<(NetworkTick, C)>,
+}
+
+impl<C> PredictionHistory<C> {
+    fn get(&self, tick: NetworkTick) -> Option<&C> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(existing, _)| *existing == tick)
+            .map(|(_, value)| value)
+    }
+
+    /// Record the value for the tick, forgetting any for the ticks after it, as they're now out of date
+    fn insert(&mut self, tick: NetworkTick, value: C) {
+        while self
+            .values
+            .back()
+            .is_some_and(|&(existing, _)| !is_newer(tick, existing))
+        {
+            self.values.pop_back();
+        }
+
+        if self.values.len() == HISTORY_LENGTH {
+            self.values.pop_front();
+        }
+        self.values.push_back((tick, value));
+    }
+}
+
+/// The functions for predicting a single component type, without knowing the type
+#[derive(Debug, Clone, Copy)]
+struct PredictedComponent {
+    /// Record the current values of the component as of the tick
+    save: fn(&mut World, NetworkTick),
+    /// Whether any entity's current value of the component differs from the one predicted for the tick
+    mispredicted: fn(&mut World, NetworkTick) -> bool,
+    /// Go back to the values predicted for the tick
+    restore: fn(&mut World, NetworkTick),
+    /// Start smoothing out a misprediction
+    correct: fn(&mut World, Duration),
+}
+
+impl PredictedComponent {
+    fn new<C: Predict>() -> Self {
+        Self {
+            save: save::<C>,
+            mispredicted: |world, tick| {
+                world
+                    .query_filtered::<(&C, Option<&PredictionHistory<C>>), With<Predicted>>()
+                    .iter(world)
+                    .any(|(value, history)| {
+                        history.and_then(|history| history.get(tick)) != Some(value)
+                    })
+            },
+            restore: |world, tick| {
+                for (mut value, history) in world
+                    .query_filtered::<(&mut C, &PredictionHistory<C>), With<Predicted>>()
+                    .iter_mut(world)
+                {
+                    if let Some(predicted) = history.get(tick) {
+                        *value = predicted.clone();
+                    }
+                }
+            },
+            correct: |world, correction_time| {
+                for mut smoothed in world
+                    .query_filtered::<&mut Smoothed<C>, With<Predicted>>()
+                    .iter_mut(world)
+                {
+                    smoothed.correcting = correction_time;
+                }
+            },
+        }
+    }
+}
+
+fn save<C: Predict>(world: &mut World, tick: NetworkTick) {
+    let mut new = Vec::new();
+    for (entity, value, history) in world
+        .query_filtered::<(Entity, &C, Option<&mut PredictionHistory<C>>), With<Predicted>>()
+        .iter_mut(world)
+    {
+        match history {
+            Some(mut history) => history.insert(tick, value.clone()),
+            None => new.push((entity, value.clone())),
+        }
+    }
+
+    for (entity, value) in new {
+        world.entity_mut(entity).insert((
+            PredictionHistory {
+                values: VecDeque::from([(tick, value.clone())]),
+            },
+            Smoothed {
+                value,
+                correcting: Duration::ZERO,
+            },
+        ));
+    }
+}
+
+/// The component types registered for prediction
+#[derive(Resource, Debug, Default)]
+struct PredictionRegistry {
+    components: Vec<PredictedComponent>,
+}
+
+/// Registers component types for prediction
+pub trait AppPredictionExt {
+    /// Predict the component type on [`Predicted`] entities. It should also be
+    /// [replicated](crate::replication::AppReplicationExt::replicate), so that there's something to compare the
+    /// prediction with
+    fn predict<C: Predict>(&mut self) -> &mut Self;
+}
+
+impl AppPredictionExt for App {
+    fn predict<C: Predict>(&mut self) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(PredictionRegistry::default)
+            .components
+            .push(PredictedComponent::new::<C>());
+        self.add_systems(PostUpdate, smooth::<C>)
+    }
+}
+
+/// Predicts the local player on clients. Needs the [`ReplicationPlugin`](crate::replication::ReplicationPlugin) and
+/// the [`SimulationPlugin`](crate::simulation::SimulationPlugin)
+#[derive(Debug, Default)]
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PredictionRegistry>()
+            .init_resource::<PredictionSettings>()
+            .init_resource::<LocalInput>()
+            .init_resource::<CurrentInput>()
+            .init_resource::<InputHistory>()
+            .add_schedule(Schedule::new(PredictedUpdate))
+            .add_systems(
+                NetworkUpdate,
+                (record_input, run_predicted_update, save_predicted)
+                    .chain()
+                    .in_set(PredictTick)
+                    .after(AdvanceTick),
+            )
+            .add_systems(PreUpdate, rollback.after(ApplySnapshots));
+    }
+}
+
+fn record_input(
+    tick: Res<NetworkTick>,
+    local: Res<LocalInput>,
+    mut current: ResMut<CurrentInput>,
+    mut history: ResMut<InputHistory>,
+) {
+    history.0.insert(*tick, local.0);
+    current.0 = local.0;
+}
+
+fn run_predicted_update(world: &mut World) {
+    world.run_schedule(PredictedUpdate);
+}
+
+fn save_predicted(world: &mut World) {
+    let tick = *world.resource::<NetworkTick>();
+    world.resource_scope(|world, registry: Mut<PredictionRegistry>| {
+        for component in &registry.components {
+            (component.save)(world, tick);
+        }
+    });
+}
+
+/// Check the prediction against the latest snapshot from the server once it has been applied, which leaves the predicted
+/// entities in the state the server says they were in as of the snapshot's tick
+fn rollback(world: &mut World, mut checked: Local<Option<NetworkTick>>) {
+    let Some(authoritative) = world.resource::<SnapshotBuffer>().latest() else {
+        return;
+    };
+    if *checked == Some(authoritative) {
+        return;
+    }
+    *checked = Some(authoritative);
+    reconcile(world, authoritative);
+}
+
+/// Compare the predicted entities, which have just been given the server's state as of the `authoritative` tick, with
+/// what was predicted for that tick, and resimulate from it if they differ
+fn reconcile(world: &mut World, authoritative: NetworkTick) {
+    let now = *world.resource::<NetworkTick>();
+    let components = world.resource::<PredictionRegistry>().components.clone();
+
+    // The server is as far along as we are, so there's nothing to predict yet
+    if !is_newer(now, authoritative) {
+        for component in &components {
+            (component.save)(world, authoritative);
+        }
+        return;
+    }
+
+    let mispredicted = components
+        .iter()
+        .any(|component| (component.mispredicted)(world, authoritative));
+    if !mispredicted {
+        for component in &components {
+            (component.restore)(world, now);
+        }
+        return;
+    }
+
+    let ticks = now.0.wrapping_sub(authoritative.0);
+    let correction_time = world.resource::<PredictionSettings>().correction_time;
+    for component in &components {
+        (component.save)(world, authoritative);
+        (component.correct)(world, correction_time);
+    }
+
+    if ticks as usize > INPUT_HISTORY_LENGTH {
+        warn!("The server's state is {ticks} ticks old, too old to resimulate from, so the prediction starts over from it");
+        return;
+    }
+
+    debug!(
+        "Mispredicted tick {}, resimulating {ticks} ticks",
+        authoritative.0
+    );
+    for offset in 1..=ticks {
+        let tick = NetworkTick(authoritative.0.wrapping_add(offset));
+        // Missing inputs are assumed to have been held, as they were when the tick was first predicted
+        let input = world
+            .resource::<InputHistory>()
+            .0
+            .latest_at(tick)
+            .unwrap_or_default();
+        *world.resource_mut::<NetworkTick>() = tick;
+        world.resource_mut::<CurrentInput>().0 = input;
+        world.run_schedule(PredictedUpdate);
+        for component in &components {
+            (component.save)(world, tick);
+        }
+    }
+}
+
+/// Move the smoothed value towards the current one, arriving at it when the correction time runs out
+fn smooth<C: Predict>(time: Res<Time>, mut query: Query<(&C, &mut Smoothed<C>)>) {
+    let delta = time.delta();
+    for (value, mut smoothed) in query.iter_mut() {
+        if smoothed.correcting <= delta {
+            smoothed.correcting = Duration::ZERO;
+            smoothed.value = value.clone();
+            continue;
+        }
+
+        let t = delta.as_secs_f32() / smoothed.correcting.as_secs_f32();
+        smoothed.value = smoothed.value.blend(value, t);
+        smoothed.correcting -= delta;
+    }
+}
+
+/// Forget the local player's recorded inputs, e.g. after leaving the server, as the next server's ticks won't line up
+/// with them
+pub fn reset_prediction(world: &mut World) {
+    if let Some(mut history) = world.get_resource_mut::<InputHistory>() {
+        history.0.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Component, Debug, Clone, PartialEq)]
+    struct Position(i32);
+
+    impl Predict for Position {
+        fn blend(&self, towards: &Self, _t: f32) -> Self {
+            towards.clone()
+        }
+    }
+
+    fn walk(input: Res<CurrentInput>, mut query: Query<&mut Position, With<Predicted>>) {
+        for mut position in query.iter_mut() {
+            position.0 += input.0.x as i32;
+        }
+    }
+
+    /// A world with one predicted entity at the origin, which has predicted the given ticks of walking right
+    fn predicted(ticks: u32) -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(PredictionRegistry {
+            components: vec![PredictedComponent::new::<Position>()],
+        });
+        world.init_resource::<PredictionSettings>();
+        world.init_resource::<CurrentInput>();
+        world.init_resource::<InputHistory>();
+        world.init_resource::<NetworkTick>();
+        let mut schedule = Schedule::new(PredictedUpdate);
+        schedule.add_systems(walk);
+        world.add_schedule(schedule);
+
+        let entity = world.spawn((Predicted, Position(0))).id();
+        save_predicted(&mut world);
+        for tick in 1..=ticks {
+            let input = Input {
+                x: 1,
+                ..Input::default()
+            };
+            *world.resource_mut::<NetworkTick>() = NetworkTick(tick);
+            world
+                .resource_mut::<InputHistory>()
+                .0
+                .insert(NetworkTick(tick), input);
+            world.resource_mut::<CurrentInput>().0 = input;
+            world.run_schedule(PredictedUpdate);
+            save_predicted(&mut world);
+        }
+        (world, entity)
+    }
+
+    fn correcting(world: &World, entity: Entity) -> Duration {
+        world.get::<Smoothed<Position>>(entity).unwrap().correcting
+    }
+
+    #[test]
+    fn correct_prediction_carries_on() {
+        let (mut world, entity) = predicted(5);
+        // The snapshot for tick 3 agrees with what was predicted
+        *world.get_mut::<Position>(entity).unwrap() = Position(3);
+        reconcile(&mut world, NetworkTick(3));
+
+        assert_eq!(world.get::<Position>(entity), Some(&Position(5)));
+        assert_eq!(correcting(&world, entity), Duration::ZERO);
+    }
+
+    #[test]
+    fn misprediction_resimulates_from_the_server_state() {
+        let (mut world, entity) = predicted(5);
+        // Something pushed the player on the server
+        *world.get_mut::<Position>(entity).unwrap() = Position(10);
+        reconcile(&mut world, NetworkTick(3));
+
+        assert_eq!(world.get::<Position>(entity), Some(&Position(12)));
+        assert_eq!(*world.resource::<NetworkTick>(), NetworkTick(5));
+        assert!(correcting(&world, entity) > Duration::ZERO);
+
+        // The resimulated ticks replace the mispredicted ones
+        let history = world.get::<PredictionHistory<Position>>(entity).unwrap();
+        assert_eq!(history.get(NetworkTick(3)), Some(&Position(10)));
+        assert_eq!(history.get(NetworkTick(5)), Some(&Position(12)));
+    }
+
+    #[test]
+    fn resimulation_uses_recorded_inputs() {
+        let (mut world, entity) = predicted(5);
+        let back = Input {
+            x: -1,
+            ..Input::default()
+        };
+        world
+            .resource_mut::<InputHistory>()
+            .0
+            .insert(NetworkTick(5), back);
+        *world.get_mut::<Position>(entity).unwrap() = Position(0);
+        reconcile(&mut world, NetworkTick(3));
+
+        assert_eq!(world.get::<Position>(entity), Some(&Position(0)));
+    }
+
+    #[test]
+    fn too_old_state_is_taken_as_is() {
+        let (mut world, entity) = predicted(5);
+        *world.resource_mut::<NetworkTick>() = NetworkTick(1000);
+        *world.get_mut::<Position>(entity).unwrap() = Position(10);
+        reconcile(&mut world, NetworkTick(3));
+
+        assert_eq!(world.get::<Position>(entity), Some(&Position(10)));
+        assert_eq!(*world.resource::<NetworkTick>(), NetworkTick(1000));
+    }
+}
@@ -0,0 +1,785 @@
+//! Server-authoritative replication of entities and their components.
+//!
+//! The server gives every entity marked [`Replicated`] a [`NetworkEntity`] ID, and every tick takes a snapshot of the
+//! [registered](AppReplicationExt::replicate) components of every replicated entity. Clients are sent a
+//! [`Snapshot`](snapshot::Snapshot) of what changed since the latest tick they acknowledged, over the unreliable channel,
+//! so that a lost snapshot is never resent and instead the next one covers its changes too. Clients spawn an entity of
+//! their own for each replicated one, and keep it up to date. Both peers map between their own entities and network IDs
+//! with the [`NetworkEntityMap`]. Which replicated entities each client is sent is decided by its
+//! [interest](interest).
+//!
+//! Replicated entities that a client controls, such as its player's slugcat, are given an [`Owner`] on the server. The
+//! client is told which entity it owns, and marks it [`Predicted`].
+//!
+//! Clients that have nothing to compare a delta to, whether because they've just joined, they've resumed their session,
+//! or they've gone so long without acknowledging a snapshot that the server no longer has the one they last did, are sent
+//! a full snapshot of every replicated entity over the ordered channel instead. Deltas that would take too many datagrams
+//! to send are sent over the ordered channel too, still against the tick the client last acknowledged
+
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    sync::Arc,
+};
+
+use bevy::{
+    app::{App, Plugin, PostUpdate, PreUpdate},
+    ecs::{
+        component::{Component, Tick},
+        entity::Entity,
+        query::{With, Without},
+        schedule::{IntoSystemConfigs, SystemSet},
+        system::{Commands, Local, Query, Res, ResMut, Resource},
+        world::{EntityRef, EntityWorldMut, Mut, World},
+    },
+    log::{debug, error, warn},
+};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    clock::NetworkTick,
+    packet::{OwnedEntity, Received, ReliableSnapshot, SnapshotAck, SnapshotFragment},
+    peer::{Client, Server},
+    prediction::Predicted,
+    serde::{deserialize, serialize},
+    Error, PacketSender, ReceivePackets, SendPackets,
+};
+
+use self::{
+    interest::{
+        record_rooms, EntityRooms, Interest, Neighbours, RelevanceHistory, RelevantSets, Room,
+        Viewer,
+    },
+    snapshot::{
+        fragment, is_newer, EntityState, Fragment, Reassembly, Snapshot, SnapshotHistory, View,
+        WorldState, MAX_UNRELIABLE_FRAGMENTS, RELIABLE_FRAGMENT_LENGTH, UNRELIABLE_FRAGMENT_LENGTH,
+    },
+};
+
+pub mod interest;
+pub mod snapshot;
+
+/// How many component types can be registered for replication
+pub const MAX_REPLICATED_COMPONENTS: usize = u16::MAX as usize;
+
+/// The system set in [`PreUpdate`] that applies the snapshots received from the server on clients
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct ApplySnapshots;
+
+/// A marker component for entities on the server that should be replicated to clients
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Replicated;
+
+/// A component on replicated entities on the server that a client controls, holding the client's connection. Clients
+/// own at most one entity, so if several have the same owner, one of them is picked
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner(pub Entity);
+
+/// The replicated entity that the local player controls, as told by the server, which is [`Predicted`] once it has
+/// been replicated
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct LocalOwned(pub Option<NetworkEntity>);
+
+/// The ID of a replicated entity, which is the same for every peer, unlike its [`Entity`]
+#[derive(
+    Component, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct NetworkEntity(pub u32);
+
+/// The ID of a component type registered for replication. Component types are numbered in the order they're registered
+/// in, so both peers must register the same types in the same order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ReplicationId(pub u16);
+
+/// Maps between network IDs and the [`Entity`]s that a peer uses for them
+#[derive(Resource, Debug, Default)]
+pub struct NetworkEntityMap {
+    entities: HashMap<NetworkEntity, Entity>,
+    /// The next ID for the server to give out
+    next: u32,
+}
+
+impl NetworkEntityMap {
+    pub fn get(&self, network: NetworkEntity) -> Option<Entity> {
+        self.entities.get(&network).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    fn allocate(&mut self, entity: Entity) -> NetworkEntity {
+        let network = NetworkEntity(self.next);
+        self.next = self.next.wrapping_add(1);
+        self.entities.insert(network, entity);
+        network
+    }
+}
+
+/// The functions for replicating a single component type, without knowing the type
+#[derive(Debug, Clone, Copy)]
+struct ReplicatedComponent {
+    name: &'static str,
+    contains: fn(&EntityRef) -> bool,
+    changed: fn(&EntityRef, Tick, Tick) -> bool,
+    serialize: fn(&EntityRef) -> Option<Result<Vec<u8>, Error>>,
+    write: fn(&mut EntityWorldMut, &[u8]) -> Result<(), Error>,
+    remove: fn(&mut EntityWorldMut),
+}
+
+impl ReplicatedComponent {
+    fn new<C: Component + Serialize + DeserializeOwned>() -> Self {
+        Self {
+            name: std::any::type_name::<C>(),
+            contains: |entity| entity.contains::<C>(),
+            changed: |entity, last_run, this_run| {
+                entity
+                    .get_change_ticks::<C>()
+                    .is_some_and(|ticks| ticks.is_changed(last_run, this_run))
+            },
+            serialize: |entity| entity.get::<C>().map(serialize),
+            write: |entity, data| {
+                entity.insert(deserialize::<C>(data)?);
+                Ok(())
+            },
+            remove: |entity| {
+                entity.remove::<C>();
+            },
+        }
+    }
+}
+
+/// The component types registered for replication
+#[derive(Resource, Debug, Default)]
+pub struct ReplicationRegistry {
+    components: Vec<ReplicatedComponent>,
+}
+
+impl ReplicationRegistry {
+    fn get(&self, id: ReplicationId) -> Option<&ReplicatedComponent> {
+        self.components.get(id.0 as usize)
+    }
+
+    /// Serialize the entity's registered components, reusing the data from its previous state for any that haven't
+    /// changed since then
+    fn state(
+        &self,
+        entity: &EntityRef,
+        previous: Option<&EntityState>,
+        last_run: Tick,
+        this_run: Tick,
+    ) -> EntityState {
+        let mut state = EntityState::with_capacity(previous.map_or(0, HashMap::len));
+
+        for (index, component) in self.components.iter().enumerate() {
+            let id = ReplicationId(index as u16);
+            if !(component.contains)(entity) {
+                continue;
+            }
+
+            let unchanged = previous
+                .and_then(|previous| previous.get(&id))
+                .filter(|_| !(component.changed)(entity, last_run, this_run));
+            if let Some(data) = unchanged {
+                state.insert(id, data.clone());
+                continue;
+            }
+
+            match (component.serialize)(entity) {
+                Some(Ok(data)) => {
+                    state.insert(id, Bytes::from(data));
+                }
+                Some(Err(e)) => error!("Error while serializing '{}': {e}", component.name),
+                None => {}
+            }
+        }
+
+        state
+    }
+}
+
+/// Registers component types for replication
+pub trait AppReplicationExt {
+    /// Replicate the component type from the server to clients. Both peers must register the same component types in the
+    /// same order
+    fn replicate<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self;
+}
+
+impl AppReplicationExt for App {
+    fn replicate<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        let mut registry = self
+            .world
+            .get_resource_or_insert_with(ReplicationRegistry::default);
+        assert!(
+            registry.components.len() < MAX_REPLICATED_COMPONENTS,
+            "At most {MAX_REPLICATED_COMPONENTS} component types can be replicated, so '{}' can't be",
+            std::any::type_name::<C>()
+        );
+        registry.components.push(ReplicatedComponent::new::<C>());
+        self
+    }
+}
+
+/// A component on the server's client connections that should be sent replicated entities.
+/// Inserting it again starts the client over with a full snapshot
+#[derive(Component, Debug, Default)]
+pub struct ReplicationClient {
+    /// The latest tick that the client has acknowledged receiving a snapshot for
+    acked: Option<NetworkTick>,
+    /// The tick of the snapshot the client was last sent over the ordered channel, until it acknowledges that or a later
+    /// one. As it's sent reliably, deltas can be sent against it without waiting for the acknowledgement
+    pending_reliable: Option<NetworkTick>,
+    relevance: RelevanceHistory,
+    /// The entity the client was last told it owns
+    owned: Option<NetworkEntity>,
+}
+
+impl ReplicationClient {
+    /// The tick that the client's next snapshot should be a delta from
+    fn baseline(&self) -> Option<NetworkTick> {
+        self.pending_reliable.or(self.acked)
+    }
+
+    fn acknowledge(&mut self, tick: NetworkTick) {
+        if self
+            .baseline()
+            .map_or(true, |baseline| is_newer(tick, baseline))
+        {
+            self.acked = Some(tick);
+            self.pending_reliable = None;
+        }
+    }
+}
+
+/// The tick a delta is from, or `None` for a full snapshot, and the IDs of the sets of entities relevant to the client
+/// now and as of then. Clients with the same entities relevant as of a tick share the same set, so its ID stands for its
+/// contents
+type DeltaKey = (Option<NetworkTick>, Option<u64>, Option<u64>);
+
+/// A serialized snapshot split into fragments for the channel it's sent over
+#[derive(Debug, Clone)]
+enum EncodedSnapshot {
+    Unreliable(Vec<Fragment>),
+    Reliable(Vec<Fragment>),
+}
+
+/// The server's record of what it has replicated
+#[derive(Resource, Debug, Default)]
+struct ServerReplication {
+    /// The network IDs of the entities that were in the latest snapshot
+    entities: HashMap<Entity, NetworkEntity>,
+    history: SnapshotHistory,
+    relevant_sets: RelevantSets,
+}
+
+/// The client's record of the snapshots it has received
+#[derive(Resource, Debug, Default)]
+pub struct SnapshotBuffer {
+    history: SnapshotHistory,
+    /// Deltas that have only had some of their fragments arrive so far
+    deltas: Reassembly,
+    /// Snapshots sent over the ordered channel that have only had some of their fragments arrive so far
+    reliable: Reassembly,
+}
+
+impl SnapshotBuffer {
+    /// The tick of the latest snapshot that has been applied, which the replicated entities are up to date with
+    pub fn latest(&self) -> Option<NetworkTick> {
+        self.history.latest().map(|(tick, _)| tick)
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+        self.deltas.clear();
+        self.reliable.clear();
+    }
+}
+
+/// Replicates entities from the server to clients. Needs the [`ClockPlugin`](crate::clock::ClockPlugin)
+#[derive(Debug)]
+pub struct ReplicationPlugin<P>(PhantomData<P>);
+
+impl<P> Default for ReplicationPlugin<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl Plugin for ReplicationPlugin<Server> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationRegistry>()
+            .init_resource::<NetworkEntityMap>()
+            .init_resource::<ServerReplication>()
+            .add_systems(PreUpdate, record_rooms.after(ReceivePackets))
+            .add_systems(
+                PostUpdate,
+                (assign_network_entities, replicate, send_owned_entities)
+                    .chain()
+                    .before(SendPackets),
+            );
+    }
+}
+
+impl Plugin for ReplicationPlugin<Client> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationRegistry>()
+            .init_resource::<NetworkEntityMap>()
+            .init_resource::<SnapshotBuffer>()
+            .init_resource::<LocalOwned>()
+            .add_systems(
+                PreUpdate,
+                (apply_snapshots, mark_owned)
+                    .chain()
+                    .in_set(ApplySnapshots)
+                    .after(ReceivePackets),
+            );
+    }
+}
+
+fn assign_network_entities(
+    mut commands: Commands,
+    query: Query<Entity, (With<Replicated>, Without<NetworkEntity>)>,
+    mut map: ResMut<NetworkEntityMap>,
+) {
+    for entity in query.iter() {
+        let network = map.allocate(entity);
+        commands.entity(entity).insert(network);
+    }
+}
+
+// Needs to be an exclusive system to be able to read every registered component of an entity without knowing its type
+fn replicate(world: &mut World, mut last_run: Local<Tick>) {
+    let tick = *world.resource::<NetworkTick>();
+    for (mut client, mut acks) in world
+        .query::<(&mut ReplicationClient, &mut Received<SnapshotAck>)>()
+        .iter_mut(world)
+    {
+        for ack in acks.buffer.drain(..) {
+            // Clients can't have received snapshots from the future
+            if !is_newer(ack.tick, tick) {
+                client.acknowledge(ack.tick);
+            }
+        }
+    }
+
+    let mut replication = world
+        .remove_resource::<ServerReplication>()
+        .unwrap_or_default();
+    // Snapshots are only taken once per tick, however many times the app updates in between
+    if replication
+        .history
+        .latest()
+        .is_some_and(|(latest, _)| latest == tick)
+    {
+        world.insert_resource(replication);
+        return;
+    }
+
+    let this_run = world.read_change_tick();
+    let (state, rooms) = take_snapshot(world, &mut replication, *last_run, this_run);
+    *last_run = this_run;
+
+    let state = Arc::new(state);
+    replication.history.push(tick, state.clone());
+
+    // Relevance is decided before sending anything, as the rules only need to read the clients
+    let mut interests = world.query::<(Entity, &Interest, Option<&Room>, Option<&Neighbours>)>();
+    let mut relevant: HashMap<Entity, HashSet<NetworkEntity>> = interests
+        .iter(world)
+        .map(|(entity, interest, room, neighbours)| {
+            let viewer = Viewer { room, neighbours };
+            (entity, rooms.relevant(interest.rule(), &viewer))
+        })
+        .collect();
+
+    // Clients that acknowledged the same tick and have the same entities relevant are sent the same delta
+    let mut deltas: HashMap<DeltaKey, EncodedSnapshot> = HashMap::new();
+    replication.relevant_sets.clear();
+    for (entity, mut client, mut sender) in world
+        .query::<(Entity, &mut ReplicationClient, &mut PacketSender<Server>)>()
+        .iter_mut(world)
+    {
+        let client = &mut *client;
+        let now = client.relevance.push(
+            tick,
+            relevant.remove(&entity),
+            &mut replication.relevant_sets,
+        );
+        let view = View {
+            state: &state,
+            relevant: now.as_ref().map(|now| &*now.entities),
+        };
+
+        // Clients whose baseline is too old to still be in the history start over from nothing
+        let baseline = client.baseline().and_then(|baseline| {
+            let then = client.relevance.get(baseline)?;
+            let base = View {
+                state: replication.history.get(baseline)?,
+                relevant: then.map(|then| &*then.entities),
+            };
+            Some((baseline, base, then.map(|then| then.id)))
+        });
+        let key = (
+            baseline.map(|(baseline, _, _)| baseline),
+            now.as_ref().map(|now| now.id),
+            baseline.and_then(|(_, _, then)| then),
+        );
+        let encoded = deltas
+            .entry(key)
+            .or_insert_with(|| {
+                let snapshot =
+                    view.delta(tick, baseline.map(|(baseline, base, _)| (baseline, base)));
+                encode_fragments(&snapshot)
+            })
+            .clone();
+
+        let result = match encoded {
+            EncodedSnapshot::Unreliable(fragments) => fragments
+                .into_iter()
+                .try_for_each(|fragment| sender.send(SnapshotFragment { fragment })),
+            EncodedSnapshot::Reliable(fragments) => {
+                if baseline.is_none() {
+                    debug!("Sending a full snapshot for tick {}", tick.0);
+                    client.acked = None;
+                }
+                client.pending_reliable = Some(tick);
+                fragments
+                    .into_iter()
+                    .try_for_each(|fragment| sender.send(ReliableSnapshot { fragment }))
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Error while sending snapshot: {e}");
+        }
+    }
+
+    world.insert_resource(replication);
+}
+
+/// Tell clients which entity they own whenever it changes
+fn send_owned_entities(
+    owned: Query<(&NetworkEntity, &Owner)>,
+    mut clients: Query<(Entity, &mut ReplicationClient, &mut PacketSender<Server>)>,
+) {
+    let owners: HashMap<Entity, NetworkEntity> = owned
+        .iter()
+        .map(|(&network, owner)| (owner.0, network))
+        .collect();
+    for (entity, mut client, mut sender) in clients.iter_mut() {
+        let entity = owners.get(&entity).copied();
+        if client.owned == entity {
+            continue;
+        }
+
+        client.owned = entity;
+        if let Err(e) = sender.send(OwnedEntity { entity }) {
+            error!("Error while telling a client which entity it owns: {e}");
+        }
+    }
+}
+
+/// Serialize the registered components of every replicated entity and note which rooms they're in, and forget the
+/// entities that are no longer replicated
+fn take_snapshot(
+    world: &mut World,
+    replication: &mut ServerReplication,
+    last_run: Tick,
+    this_run: Tick,
+) -> (WorldState, EntityRooms) {
+    let mut replicated =
+        world.query_filtered::<(Entity, &NetworkEntity, Option<&Room>), With<Replicated>>();
+    let registry = world.resource::<ReplicationRegistry>();
+    let previous = replication.history.latest().map(|(_, state)| state.clone());
+
+    let mut state = WorldState::default();
+    let mut rooms = EntityRooms::default();
+    let mut seen = HashMap::with_capacity(replication.entities.len());
+    for (entity, &network, room) in replicated.iter(world) {
+        let previous = previous
+            .as_ref()
+            .and_then(|previous| previous.entities.get(&network));
+        let components = registry.state(&world.entity(entity), previous, last_run, this_run);
+        state.entities.insert(network, components);
+        rooms.insert(network, room);
+        seen.insert(entity, network);
+    }
+
+    let gone: Vec<_> = replication
+        .entities
+        .drain()
+        .filter(|(entity, _)| !seen.contains_key(entity))
+        .collect();
+    replication.entities = seen;
+
+    for (entity, network) in gone {
+        world
+            .resource_mut::<NetworkEntityMap>()
+            .entities
+            .remove(&network);
+        // Entities that are still around but no longer replicated get a new ID if they're replicated again
+        if let Some(mut entity) = world.get_entity_mut(entity) {
+            entity.remove::<NetworkEntity>();
+        }
+    }
+
+    (state, rooms)
+}
+
+/// Serialize a snapshot and split it into fragments. Deltas go over the unreliable channel if they fit in few enough
+/// datagrams, and everything else over the ordered channel
+fn encode_fragments(snapshot: &Snapshot) -> EncodedSnapshot {
+    let bytes = match serialize(snapshot) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error while serializing snapshot: {e}");
+            return EncodedSnapshot::Unreliable(Vec::new());
+        }
+    };
+
+    let unreliable = snapshot.baseline.and_then(|_| {
+        fragment(
+            snapshot.tick,
+            &bytes,
+            UNRELIABLE_FRAGMENT_LENGTH,
+            MAX_UNRELIABLE_FRAGMENTS,
+        )
+    });
+    match unreliable {
+        Some(fragments) => EncodedSnapshot::Unreliable(fragments),
+        None => EncodedSnapshot::Reliable(
+            fragment(
+                snapshot.tick,
+                &bytes,
+                RELIABLE_FRAGMENT_LENGTH,
+                u16::MAX as usize,
+            )
+            .unwrap_or_default(),
+        ),
+    }
+}
+
+// Needs to be an exclusive system to be able to spawn entities and write components without knowing their types
+fn apply_snapshots(world: &mut World) {
+    let mut received: Vec<(Entity, Vec<u8>)> = Vec::new();
+    world.resource_scope(|world, mut buffer: Mut<SnapshotBuffer>| {
+        for (entity, mut reliable, mut deltas) in world
+            .query::<(
+                Entity,
+                &mut Received<ReliableSnapshot>,
+                &mut Received<SnapshotFragment>,
+            )>()
+            .iter_mut(world)
+        {
+            for packet in reliable.buffer.drain(..) {
+                if let Some(bytes) = buffer.reliable.insert(packet.fragment) {
+                    received.push((entity, bytes));
+                }
+            }
+            for packet in deltas.buffer.drain(..) {
+                if let Some(bytes) = buffer.deltas.insert(packet.fragment) {
+                    received.push((entity, bytes));
+                }
+            }
+        }
+    });
+    if received.is_empty() {
+        return;
+    }
+
+    world.resource_scope(|world, registry: Mut<ReplicationRegistry>| {
+        world.resource_scope(|world, mut map: Mut<NetworkEntityMap>| {
+            world.resource_scope(|world, mut buffer: Mut<SnapshotBuffer>| {
+                let mut acks = HashMap::new();
+                for (connection, bytes) in received {
+                    let snapshot = match deserialize::<Snapshot>(&bytes) {
+                        Ok(snapshot) => snapshot,
+                        Err(e) => {
+                            error!("Error while deserializing snapshot: {e}");
+                            continue;
+                        }
+                    };
+
+                    let tick = snapshot.tick;
+                    if apply_snapshot(world, &registry, &mut map, &mut buffer, snapshot) {
+                        acks.insert(connection, tick);
+                    }
+                }
+
+                if let Some(latest) = buffer.latest() {
+                    buffer.deltas.discard_until(latest);
+                }
+
+                for (connection, tick) in acks {
+                    let Some(mut sender) = world.get_mut::<PacketSender<Client>>(connection) else {
+                        continue;
+                    };
+                    if let Err(e) = sender.send(SnapshotAck { tick }) {
+                        error!("Error while acknowledging snapshot: {e}");
+                    }
+                }
+            });
+        });
+    });
+}
+
+/// Apply the snapshot to the replicated entities if it's newer than the latest one and its baseline is known, returning
+/// whether it was applied
+fn apply_snapshot(
+    world: &mut World,
+    registry: &ReplicationRegistry,
+    map: &mut NetworkEntityMap,
+    buffer: &mut SnapshotBuffer,
+    snapshot: Snapshot,
+) -> bool {
+    let empty = Arc::new(WorldState::default());
+    let previous = buffer
+        .history
+        .latest()
+        .map_or_else(|| empty.clone(), |(_, state)| state.clone());
+
+    let base = match snapshot.baseline {
+        None => {
+            debug!("Received a full snapshot for tick {}", snapshot.tick.0);
+            &empty
+        }
+        Some(baseline) => {
+            let newer = buffer
+                .latest()
+                .is_some_and(|latest| is_newer(snapshot.tick, latest));
+            match buffer.history.get(baseline) {
+                Some(base) if newer => base,
+                _ => return false,
+            }
+        }
+    };
+    let Some(state) = base.apply(&snapshot) else {
+        warn!(
+            "Received a snapshot for tick {} that doesn't fit its baseline",
+            snapshot.tick.0
+        );
+        return false;
+    };
+
+    if snapshot.baseline.is_none() {
+        buffer.history.clear();
+        // Entities can be left over from before the client's session was resumed
+        let stale: Vec<_> = map
+            .entities
+            .keys()
+            .filter(|network| !state.entities.contains_key(network))
+            .copied()
+            .collect();
+        for network in stale {
+            if let Some(entity) = map.entities.remove(&network) {
+                world.despawn(entity);
+            }
+        }
+    }
+
+    update_entities(world, registry, map, &previous, &state);
+    buffer.history.push(snapshot.tick, Arc::new(state));
+    true
+}
+
+/// Bring the replicated entities from the previous state up to date with the new one
+fn update_entities(
+    world: &mut World,
+    registry: &ReplicationRegistry,
+    map: &mut NetworkEntityMap,
+    previous: &WorldState,
+    state: &WorldState,
+) {
+    for network in previous.entities.keys() {
+        if state.entities.contains_key(network) {
+            continue;
+        }
+        if let Some(entity) = map.entities.remove(network) {
+            world.despawn(entity);
+        }
+    }
+
+    for (&network, components) in &state.entities {
+        let entity = match map.get(network) {
+            Some(entity) => entity,
+            None => {
+                let entity = world.spawn(network).id();
+                map.entities.insert(network, entity);
+                entity
+            }
+        };
+        let Some(mut entity) = world.get_entity_mut(entity) else {
+            continue;
+        };
+        let old = previous.entities.get(&network);
+        // Predicted entities have moved on from the previous snapshot, so they're given every component back to check
+        // the prediction against
+        let predicted = entity.contains::<Predicted>();
+
+        for (id, data) in components {
+            if !predicted && old.and_then(|old| old.get(id)) == Some(data) {
+                continue;
+            }
+            let Some(replicated) = registry.get(*id) else {
+                warn!("Received unregistered replicated component {id:?}");
+                continue;
+            };
+            if let Err(e) = (replicated.write)(&mut entity, data) {
+                error!("Error while deserializing '{}': {e}", replicated.name);
+            }
+        }
+
+        for id in old.into_iter().flat_map(HashMap::keys) {
+            if components.contains_key(id) {
+                continue;
+            }
+            if let Some(replicated) = registry.get(*id) {
+                (replicated.remove)(&mut entity);
+            }
+        }
+    }
+}
+
+/// Mark the entity the server says we own as [`Predicted`], and only that one
+fn mark_owned(
+    mut commands: Commands,
+    mut owned: ResMut<LocalOwned>,
+    mut received: Query<&mut Received<OwnedEntity>>,
+    map: Res<NetworkEntityMap>,
+    predicted: Query<Entity, (With<Predicted>, With<NetworkEntity>)>,
+) {
+    for mut packets in received.iter_mut() {
+        if let Some(packet) = packets.buffer.drain(..).last() {
+            owned.0 = packet.entity;
+        }
+    }
+
+    let entity = owned.0.and_then(|network| map.get(network));
+    for other in predicted.iter().filter(|&other| Some(other) != entity) {
+        commands.entity(other).remove::<Predicted>();
+    }
+    if let Some(entity) = entity.filter(|&entity| !predicted.contains(entity)) {
+        commands.entity(entity).insert(Predicted);
+    }
+}
+
+/// Despawn every entity that was replicated from the server, e.g. after leaving it
+pub fn despawn_replicated(world: &mut World) {
+    if let Some(mut map) = world.get_resource_mut::<NetworkEntityMap>() {
+        let entities: Vec<_> = map.entities.drain().map(|(_, entity)| entity).collect();
+        for entity in entities {
+            world.despawn(entity);
+        }
+    }
+    if let Some(mut buffer) = world.get_resource_mut::<SnapshotBuffer>() {
+        buffer.clear();
+    }
+    if let Some(mut owned) = world.get_resource_mut::<LocalOwned>() {
+        owned.0 = None;
+    }
+}
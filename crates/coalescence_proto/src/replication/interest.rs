@@ -0,0 +1,313 @@
+//! Interest management, so that clients are only sent the replicated entities that are relevant to them.
+//!
+//! Rain World is split into rooms, and a player mostly only needs to know about the room they're in and the ones next to
+//! it. Replicated entities are put in a room with the [`Room`] component, and clients tell the server which room they're
+//! in, and which rooms it connects to, with a [`RoomChanged`] packet. The rooms a client says its room connects to only
+//! decide what that client is sent, as the server has no map to check them against. Each client's [`Interest`] decides
+//! which rooms are relevant to it. Entities that become relevant are spawned on the client, and ones that stop being relevant are
+//! despawned, the same as if they'd been spawned or despawned on the server.
+//!
+//! Entities without a room, such as ones holding the state of the whole game, are relevant to every client, and so is
+//! everything for clients without an [`Interest`]
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use bevy::{
+    ecs::{
+        component::Component,
+        entity::Entity,
+        query::With,
+        system::{Commands, Query},
+    },
+    log::warn,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::NetworkTick,
+    packet::{Received, RoomChanged},
+};
+
+use super::{snapshot::HISTORY_LENGTH, NetworkEntity, ReplicationClient};
+
+/// The most rooms that a client may say a room connects to
+const MAX_NEIGHBOURS: usize = 64;
+const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+/// The room that a replicated entity is in, or that the player on a client connection is in, by the room's name
+#[derive(Component, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Room(pub String);
+
+/// A component on the server's client connections holding the rooms that connect to the one the client's player is in,
+/// as reported by the client. Replaced whenever the client changes rooms
+#[derive(Component, Debug, Default, Clone)]
+pub struct Neighbours(pub HashSet<Room>);
+
+/// What a [`RelevanceRule`] knows about the client it's deciding for
+#[derive(Debug, Clone, Copy)]
+pub struct Viewer<'a> {
+    /// The room the client's player is in, or `None` if they aren't in one, e.g. because they're still in the menus
+    pub room: Option<&'a Room>,
+    /// The rooms that the client says connect to its room
+    pub neighbours: Option<&'a Neighbours>,
+}
+
+impl Viewer<'_> {
+    pub fn is_neighbour(&self, room: &Room) -> bool {
+        self.neighbours
+            .is_some_and(|neighbours| neighbours.0.contains(room))
+    }
+}
+
+/// Decides which rooms' entities are relevant to a client
+pub trait RelevanceRule: Debug + Send + Sync + 'static {
+    fn is_relevant(&self, viewer: &Viewer, room: &Room) -> bool;
+}
+
+/// Only the room the client's player is in is relevant
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SameRoom;
+
+impl RelevanceRule for SameRoom {
+    fn is_relevant(&self, viewer: &Viewer, room: &Room) -> bool {
+        viewer.room == Some(room)
+    }
+}
+
+/// The room the client's player is in and the rooms that connect to it are relevant, so that creatures coming through
+/// a pipe are already there when they arrive
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AdjacentRooms;
+
+impl RelevanceRule for AdjacentRooms {
+    fn is_relevant(&self, viewer: &Viewer, room: &Room) -> bool {
+        viewer
+            .room
+            .is_some_and(|own| own == room || viewer.is_neighbour(room))
+    }
+}
+
+/// Every room is relevant, e.g. for spectators
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Everything;
+
+impl RelevanceRule for Everything {
+    fn is_relevant(&self, _viewer: &Viewer, _room: &Room) -> bool {
+        true
+    }
+}
+
+/// A component on the server's client connections deciding which replicated entities they're sent
+#[derive(Component, Debug)]
+pub struct Interest(Box<dyn RelevanceRule>);
+
+impl Interest {
+    pub fn new(rule: impl RelevanceRule) -> Self {
+        Self(Box::new(rule))
+    }
+
+    pub fn rule(&self) -> &dyn RelevanceRule {
+        &*self.0
+    }
+}
+
+/// The replicated entities in each room, as of the latest snapshot
+#[derive(Debug, Default)]
+pub(crate) struct EntityRooms {
+    rooms: HashMap<Room, Vec<NetworkEntity>>,
+    /// Entities that aren't in any room
+    roomless: Vec<NetworkEntity>,
+}
+
+impl EntityRooms {
+    pub(crate) fn insert(&mut self, entity: NetworkEntity, room: Option<&Room>) {
+        match room {
+            Some(room) => match self.rooms.get_mut(room) {
+                Some(entities) => entities.push(entity),
+                None => {
+                    self.rooms.insert(room.clone(), vec![entity]);
+                }
+            },
+            None => self.roomless.push(entity),
+        }
+    }
+
+    /// The entities that are relevant to the viewer according to the rule
+    pub(crate) fn relevant(
+        &self,
+        rule: &dyn RelevanceRule,
+        viewer: &Viewer,
+    ) -> HashSet<NetworkEntity> {
+        let mut relevant: HashSet<_> = self.roomless.iter().copied().collect();
+        for (room, entities) in &self.rooms {
+            if rule.is_relevant(viewer, room) {
+                relevant.extend(entities);
+            }
+        }
+        relevant
+    }
+}
+
+/// A set of relevant entities, which clients with the same entities relevant share, along with its ID
+#[derive(Debug, Clone)]
+pub(crate) struct RelevantSet {
+    /// Unique to this set, so that it stands for the set's contents in place of comparing them
+    pub(crate) id: u64,
+    pub(crate) entities: Arc<HashSet<NetworkEntity>>,
+}
+
+/// The relevant sets handed out for the tick being sent, so that clients with the same entities relevant share one
+#[derive(Debug, Default)]
+pub(crate) struct RelevantSets {
+    next_id: u64,
+    current: Vec<RelevantSet>,
+}
+
+impl RelevantSets {
+    /// Start sending a new tick, after which sets are no longer shared with the ones handed out before
+    pub(crate) fn clear(&mut self) {
+        self.current.clear();
+    }
+}
+
+/// Which entities were relevant to a client as of recent ticks, so that the server knows what the client has been sent
+/// as of each tick a delta could be sent from
+#[derive(Debug, Default)]
+pub(crate) struct RelevanceHistory {
+    /// The relevant entities as of each tick, oldest first, or `None` for ticks when every entity was relevant
+    ticks: VecDeque<(NetworkTick, Option<RelevantSet>)>,
+}
+
+impl RelevanceHistory {
+    /// Record the entities relevant as of the tick. They're shared with another client's as of the same tick, from
+    /// `sets`, or else this client's as of the previous tick, if they're the same, so that clients with the same
+    /// entities relevant hold the same set
+    pub(crate) fn push(
+        &mut self,
+        tick: NetworkTick,
+        relevant: Option<HashSet<NetworkEntity>>,
+        sets: &mut RelevantSets,
+    ) -> Option<RelevantSet> {
+        let relevant = relevant.map(|relevant| {
+            if let Some(same) = sets.current.iter().find(|same| *same.entities == relevant) {
+                return same.clone();
+            }
+
+            let relevant = match self.ticks.back() {
+                Some((_, Some(previous))) if *previous.entities == relevant => previous.clone(),
+                _ => {
+                    let id = sets.next_id;
+                    sets.next_id += 1;
+                    RelevantSet {
+                        id,
+                        entities: Arc::new(relevant),
+                    }
+                }
+            };
+            sets.current.push(relevant.clone());
+            relevant
+        });
+
+        if self.ticks.len() == HISTORY_LENGTH {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back((tick, relevant.clone()));
+        relevant
+    }
+
+    /// The entities relevant as of the tick, or `None` if it's too old to be remembered
+    pub(crate) fn get(&self, tick: NetworkTick) -> Option<Option<&RelevantSet>> {
+        self.ticks
+            .iter()
+            .rev()
+            .find(|(relevant_tick, _)| *relevant_tick == tick)
+            .map(|(_, relevant)| relevant.as_ref())
+    }
+}
+
+/// Move clients' players into the rooms they say they've entered
+pub(crate) fn record_rooms(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Received<RoomChanged>), With<ReplicationClient>>,
+) {
+    for (entity, mut changes) in query.iter_mut() {
+        let Some(change) = changes.buffer.drain(..).last() else {
+            continue;
+        };
+
+        let Some(room) = change.room else {
+            commands.entity(entity).remove::<(Room, Neighbours)>();
+            continue;
+        };
+
+        let valid = change.neighbours.len() <= MAX_NEIGHBOURS
+            && std::iter::once(&room)
+                .chain(&change.neighbours)
+                .all(|room| !room.0.is_empty() && room.0.len() <= MAX_ROOM_NAME_LENGTH);
+        if !valid {
+            warn!("Client on entity {entity:?} sent an invalid room, ignoring it");
+            continue;
+        }
+
+        let neighbours = Neighbours(change.neighbours.into_iter().collect());
+        commands.entity(entity).insert((room, neighbours));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entities(ids: &[u32]) -> HashSet<NetworkEntity> {
+        ids.iter().copied().map(NetworkEntity).collect()
+    }
+
+    #[test]
+    fn clients_with_the_same_entities_share_a_set() {
+        let (mut a, mut b) = (RelevanceHistory::default(), RelevanceHistory::default());
+        let mut sets = RelevantSets::default();
+        let first = a.push(NetworkTick(1), Some(entities(&[1, 2])), &mut sets);
+        let second = b.push(NetworkTick(1), Some(entities(&[2, 1])), &mut sets);
+        assert_eq!(first.unwrap().id, second.unwrap().id);
+
+        sets.clear();
+        let first = a.push(NetworkTick(2), Some(entities(&[1])), &mut sets);
+        let second = b.push(NetworkTick(2), Some(entities(&[1, 2])), &mut sets);
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(first.id, second.id);
+        // Unchanged from the previous tick
+        assert_eq!(second.id, a.get(NetworkTick(1)).flatten().unwrap().id);
+
+        // Equal to a set from an earlier tick, but not shared with it, so it needs its own ID
+        sets.clear();
+        let third = b
+            .push(NetworkTick(3), Some(entities(&[1])), &mut sets)
+            .unwrap();
+        assert_ne!(third.id, first.id);
+        assert_ne!(third.id, second.id);
+    }
+
+    #[test]
+    fn neighbours_only_count_for_their_own_viewer() {
+        let own = Room("SU_A01".into());
+        let next = Room("SU_A02".into());
+        let neighbours = Neighbours(HashSet::from([next.clone()]));
+        let viewer = Viewer {
+            room: Some(&own),
+            neighbours: Some(&neighbours),
+        };
+        let other = Viewer {
+            room: Some(&own),
+            neighbours: None,
+        };
+
+        assert!(AdjacentRooms.is_relevant(&viewer, &next));
+        assert!(!AdjacentRooms.is_relevant(&other, &next));
+        assert!(AdjacentRooms.is_relevant(&other, &own));
+        assert!(!SameRoom.is_relevant(&viewer, &next));
+    }
+}
@@ -0,0 +1,556 @@
+//! Snapshots of the replicated world, and the deltas between them.
+//!
+//! The server keeps the serialized state of every replicated component for the last [`HISTORY_LENGTH`] ticks. Each
+//! client is sent a [`Snapshot`] holding only what changed since the last tick it acknowledged, and components whose
+//! serialized size stayed the same are sent as patches of the bytes that changed, which for most components means only
+//! the fields that changed. Snapshots are split into [`Fragment`]s that fit in a datagram
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::NetworkTick;
+
+use super::{NetworkEntity, ReplicationId};
+
+/// How many ticks of snapshots the server keeps to use as baselines, and clients keep to apply deltas to
+pub const HISTORY_LENGTH: usize = 128;
+/// How many bytes of a snapshot go in each fragment sent over the unreliable channel, leaving room for the fragment's own
+/// fields within the smallest datagram that QUIC guarantees
+pub const UNRELIABLE_FRAGMENT_LENGTH: usize = 1024;
+/// The most fragments that a snapshot is split into over the unreliable channel. Bigger snapshots are too likely to have
+/// a fragment lost, so they're sent reliably instead
+pub const MAX_UNRELIABLE_FRAGMENTS: usize = 32;
+/// How many bytes of a snapshot go in each fragment sent over the ordered channel, well within its framing's limit
+pub const RELIABLE_FRAGMENT_LENGTH: usize = 32 * 1024;
+/// How many snapshots a client reassembles at once before giving up on the oldest
+const MAX_PARTIAL_SNAPSHOTS: usize = 16;
+/// The encoded size of a [`BytePatch`] without its bytes, so that changes that are close together are patched together
+const PATCH_OVERHEAD: usize = 12;
+
+/// The serialized components of a single entity
+pub(crate) type EntityState = HashMap<ReplicationId, Bytes>;
+
+/// The serialized components of every replicated entity as of a tick
+#[derive(Debug, Default, Clone)]
+pub(crate) struct WorldState {
+    pub(crate) entities: HashMap<NetworkEntity, EntityState>,
+}
+
+/// A change to a run of bytes of a serialized component
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BytePatch {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ComponentChange {
+    /// The component's entire serialized data, for components that are new or changed size
+    Full(Vec<u8>),
+    /// Changes to the component's serialized data in the baseline
+    Patch(Vec<BytePatch>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentDelta {
+    pub id: ReplicationId,
+    pub change: ComponentChange,
+}
+
+/// The changes to one replicated entity since the baseline. Entities that aren't in the baseline are spawned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityDelta {
+    pub entity: NetworkEntity,
+    /// Components that were inserted or changed
+    pub changed: Vec<ComponentDelta>,
+    pub removed: Vec<ReplicationId>,
+}
+
+/// The state of the replicated world as of a tick, relative to a baseline the client already has
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: NetworkTick,
+    /// The tick this snapshot is a delta from, or `None` for a full snapshot, which replaces everything the client has
+    pub baseline: Option<NetworkTick>,
+    pub entities: Vec<EntityDelta>,
+    pub despawns: Vec<NetworkEntity>,
+}
+
+/// A piece of a serialized [`Snapshot`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fragment {
+    pub tick: NetworkTick,
+    pub index: u16,
+    pub count: u16,
+    pub bytes: Vec<u8>,
+}
+
+/// A client's view of a [`WorldState`], with only the entities that are [relevant](super::interest) to it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct View<'a> {
+    pub(crate) state: &'a WorldState,
+    /// The entities that are relevant to the client, or `None` if they all are
+    pub(crate) relevant: Option<&'a HashSet<NetworkEntity>>,
+}
+
+impl<'a> View<'a> {
+    fn is_relevant(&self, entity: NetworkEntity) -> bool {
+        self.relevant
+            .map_or(true, |relevant| relevant.contains(&entity))
+    }
+
+    fn get(&self, entity: NetworkEntity) -> Option<&'a EntityState> {
+        self.state
+            .entities
+            .get(&entity)
+            .filter(|_| self.is_relevant(entity))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (NetworkEntity, &'a EntityState)> + '_ {
+        self.state
+            .entities
+            .iter()
+            .map(|(&entity, components)| (entity, components))
+            .filter(|&(entity, _)| self.is_relevant(entity))
+    }
+
+    /// The snapshot that turns `baseline` into this view, or turns nothing into it if there's no baseline
+    pub(crate) fn delta(
+        &self,
+        tick: NetworkTick,
+        baseline: Option<(NetworkTick, View)>,
+    ) -> Snapshot {
+        let mut entities = Vec::new();
+        for (entity, components) in self.iter() {
+            let old = baseline.and_then(|(_, base)| base.get(entity));
+            let changed: Vec<_> = components
+                .iter()
+                .filter_map(|(&id, new)| {
+                    let change = match old.and_then(|old| old.get(&id)) {
+                        Some(old) if old == new => return None,
+                        Some(old) => diff(old, new),
+                        None => ComponentChange::Full(new.to_vec()),
+                    };
+                    Some(ComponentDelta { id, change })
+                })
+                .collect();
+            let removed: Vec<_> = old
+                .into_iter()
+                .flat_map(|old| old.keys())
+                .filter(|id| !components.contains_key(id))
+                .copied()
+                .collect();
+
+            // Entities that are new need sending even without components, so that they're spawned
+            if old.is_none() || !changed.is_empty() || !removed.is_empty() {
+                entities.push(EntityDelta {
+                    entity,
+                    changed,
+                    removed,
+                });
+            }
+        }
+
+        // Entities that stopped being relevant are despawned, the same as ones that were despawned on the server
+        let despawns = baseline
+            .into_iter()
+            .flat_map(|(_, base)| base.iter())
+            .map(|(entity, _)| entity)
+            .filter(|&entity| self.get(entity).is_none())
+            .collect();
+
+        Snapshot {
+            tick,
+            baseline: baseline.map(|(baseline, _)| baseline),
+            entities,
+            despawns,
+        }
+    }
+}
+
+impl WorldState {
+    /// Apply a snapshot that has this state as its baseline, returning the new state.
+    /// Returns `None` if a patch doesn't fit the component it's for
+    pub(crate) fn apply(&self, snapshot: &Snapshot) -> Option<WorldState> {
+        let mut state = match snapshot.baseline {
+            Some(_) => self.clone(),
+            None => WorldState::default(),
+        };
+
+        for entity in &snapshot.despawns {
+            state.entities.remove(entity);
+        }
+
+        for delta in &snapshot.entities {
+            let components = state.entities.entry(delta.entity).or_default();
+            for id in &delta.removed {
+                components.remove(id);
+            }
+
+            for component in &delta.changed {
+                let bytes = match &component.change {
+                    ComponentChange::Full(data) => Bytes::from(data.clone()),
+                    ComponentChange::Patch(patches) => {
+                        patch(components.get(&component.id)?, patches)?.into()
+                    }
+                };
+                components.insert(component.id, bytes);
+            }
+        }
+
+        Some(state)
+    }
+}
+
+/// Describe how `new` differs from `old`, as patches if they're the same size and that's smaller
+fn diff(old: &[u8], new: &[u8]) -> ComponentChange {
+    if old.len() != new.len() {
+        return ComponentChange::Full(new.to_vec());
+    }
+
+    let mut patches: Vec<BytePatch> = Vec::new();
+    let mut index = 0;
+    while index < new.len() {
+        if old[index] == new[index] {
+            index += 1;
+            continue;
+        }
+
+        let start = index;
+        while index < new.len() && old[index] != new[index] {
+            index += 1;
+        }
+
+        // Merge runs that are close enough that a separate patch would cost more than resending the bytes between them
+        match patches.last_mut() {
+            Some(last) if start - (last.offset as usize + last.bytes.len()) <= PATCH_OVERHEAD => {
+                let last_start = last.offset as usize;
+                last.bytes = new[last_start..index].to_vec();
+            }
+            _ => patches.push(BytePatch {
+                offset: start as u32,
+                bytes: new[start..index].to_vec(),
+            }),
+        }
+    }
+
+    let patched_len: usize = patches
+        .iter()
+        .map(|patch| PATCH_OVERHEAD + patch.bytes.len())
+        .sum();
+    if patched_len < new.len() {
+        ComponentChange::Patch(patches)
+    } else {
+        ComponentChange::Full(new.to_vec())
+    }
+}
+
+fn patch(old: &[u8], patches: &[BytePatch]) -> Option<Vec<u8>> {
+    let mut new = old.to_vec();
+    for patch in patches {
+        let start = patch.offset as usize;
+        new.get_mut(start..start.checked_add(patch.bytes.len())?)?
+            .copy_from_slice(&patch.bytes);
+    }
+    Some(new)
+}
+
+/// Split a serialized snapshot into fragments of at most `length` bytes, or `None` if it would take more than `max`
+pub(crate) fn fragment(
+    tick: NetworkTick,
+    bytes: &[u8],
+    length: usize,
+    max: usize,
+) -> Option<Vec<Fragment>> {
+    let count = bytes.len().div_ceil(length).max(1);
+    if count > max.min(u16::MAX as usize) {
+        return None;
+    }
+
+    let fragments = (0..count)
+        .map(|index| Fragment {
+            tick,
+            index: index as u16,
+            count: count as u16,
+            bytes: bytes[index * length..bytes.len().min((index + 1) * length)].to_vec(),
+        })
+        .collect();
+    Some(fragments)
+}
+
+/// The states of the replicated world as of recent ticks, oldest first
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotHistory {
+    states: VecDeque<(NetworkTick, Arc<WorldState>)>,
+}
+
+impl SnapshotHistory {
+    pub(crate) fn push(&mut self, tick: NetworkTick, state: Arc<WorldState>) {
+        if self.states.len() == HISTORY_LENGTH {
+            self.states.pop_front();
+        }
+        self.states.push_back((tick, state));
+    }
+
+    pub(crate) fn get(&self, tick: NetworkTick) -> Option<&Arc<WorldState>> {
+        self.states
+            .iter()
+            .rev()
+            .find(|(state_tick, _)| *state_tick == tick)
+            .map(|(_, state)| state)
+    }
+
+    pub(crate) fn latest(&self) -> Option<(NetworkTick, &Arc<WorldState>)> {
+        self.states.back().map(|(tick, state)| (*tick, state))
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.states.clear();
+    }
+}
+
+/// A snapshot that some fragments have been received for
+#[derive(Debug)]
+struct PartialSnapshot {
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+/// Puts snapshots back together from their fragments
+#[derive(Debug, Default)]
+pub(crate) struct Reassembly {
+    partial: HashMap<NetworkTick, PartialSnapshot>,
+}
+
+impl Reassembly {
+    /// Add a fragment, returning the serialized snapshot once all of its fragments have been received
+    pub(crate) fn insert(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        let count = fragment.count as usize;
+        if fragment.index as usize >= count {
+            return None;
+        }
+
+        if !self.partial.contains_key(&fragment.tick) && self.partial.len() >= MAX_PARTIAL_SNAPSHOTS
+        {
+            let oldest = *self.partial.keys().min_by_key(|tick| tick.0)?;
+            self.partial.remove(&oldest);
+        }
+
+        let partial = self
+            .partial
+            .entry(fragment.tick)
+            .or_insert_with(|| PartialSnapshot {
+                fragments: vec![None; count],
+                missing: count,
+            });
+        if partial.fragments.len() != count {
+            return None;
+        }
+
+        let slot = &mut partial.fragments[fragment.index as usize];
+        if slot.is_none() {
+            *slot = Some(fragment.bytes);
+            partial.missing -= 1;
+        }
+
+        if partial.missing > 0 {
+            return None;
+        }
+
+        let partial = self.partial.remove(&fragment.tick)?;
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    /// Forget snapshots up to the given tick, which are too old to be applied anymore
+    pub(crate) fn discard_until(&mut self, tick: NetworkTick) {
+        self.partial.retain(|partial, _| is_newer(*partial, tick));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.partial.clear();
+    }
+}
+
+/// Whether tick `a` comes after tick `b`, allowing for the tick number wrapping around
+pub(crate) fn is_newer(a: NetworkTick, b: NetworkTick) -> bool {
+    (a.0.wrapping_sub(b.0) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(entities: &[(u32, &[(u16, &[u8])])]) -> WorldState {
+        let entities = entities
+            .iter()
+            .map(|&(entity, components)| {
+                let components = components
+                    .iter()
+                    .map(|&(id, bytes)| (ReplicationId(id), Bytes::copy_from_slice(bytes)))
+                    .collect();
+                (NetworkEntity(entity), components)
+            })
+            .collect();
+        WorldState { entities }
+    }
+
+    fn view(state: &WorldState) -> View {
+        View {
+            state,
+            relevant: None,
+        }
+    }
+
+    #[test]
+    fn diff_patches_changed_runs() {
+        let old = [0u8; 64];
+        let mut new = old;
+        new[3] = 1;
+        new[40..44].copy_from_slice(&[1, 2, 3, 4]);
+
+        let ComponentChange::Patch(patches) = diff(&old, &new) else {
+            panic!("expected a patch");
+        };
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patch(&old, &patches).unwrap(), new);
+    }
+
+    #[test]
+    fn diff_merges_nearby_runs() {
+        let old = [0u8; 64];
+        let mut new = old;
+        new[10] = 1;
+        new[14] = 1;
+
+        let ComponentChange::Patch(patches) = diff(&old, &new) else {
+            panic!("expected a patch");
+        };
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patch(&old, &patches).unwrap(), new);
+    }
+
+    #[test]
+    fn diff_sends_full_data_when_smaller() {
+        assert!(matches!(diff(&[0; 4], &[1; 4]), ComponentChange::Full(_)));
+        assert!(matches!(diff(&[0; 4], &[0; 5]), ComponentChange::Full(_)));
+    }
+
+    #[test]
+    fn patch_rejects_out_of_bounds() {
+        let patches = [BytePatch {
+            offset: 3,
+            bytes: vec![1, 2],
+        }];
+        assert!(patch(&[0; 4], &patches).is_none());
+
+        let patches = [BytePatch {
+            offset: u32::MAX,
+            bytes: vec![1],
+        }];
+        assert!(patch(&[0; 4], &patches).is_none());
+    }
+
+    #[test]
+    fn fragment_splits_and_limits() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let fragments = fragment(NetworkTick(7), &bytes, 100, 3).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.count == 3));
+        assert_eq!(fragments[2].bytes.len(), 56);
+
+        assert!(fragment(NetworkTick(7), &bytes, 100, 2).is_none());
+        // Empty snapshots still take a fragment, so that they're received
+        assert_eq!(fragment(NetworkTick(7), &[], 100, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reassembly_handles_out_of_order_and_duplicates() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let fragments = fragment(NetworkTick(7), &bytes, 100, 3).unwrap();
+
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.insert(fragments[2].clone()).is_none());
+        assert!(reassembly.insert(fragments[0].clone()).is_none());
+        assert!(reassembly.insert(fragments[2].clone()).is_none());
+        assert_eq!(reassembly.insert(fragments[1].clone()).unwrap(), bytes);
+
+        // A late duplicate starts a new snapshot rather than completing one
+        assert!(reassembly.insert(fragments[1].clone()).is_none());
+    }
+
+    #[test]
+    fn reassembly_rejects_inconsistent_fragments() {
+        let mut reassembly = Reassembly::default();
+        let piece = |index, count| Fragment {
+            tick: NetworkTick(1),
+            index,
+            count,
+            bytes: vec![index as u8],
+        };
+        assert!(reassembly.insert(piece(2, 2)).is_none());
+        assert!(reassembly.insert(piece(0, 2)).is_none());
+        // Disagrees with the first fragment on how many there are
+        assert!(reassembly.insert(piece(1, 3)).is_none());
+        assert_eq!(reassembly.insert(piece(1, 2)).unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn apply_delta_recreates_state() {
+        let old = state(&[(1, &[(0, &[0; 32]), (1, &[1, 2, 3])]), (2, &[(0, &[5; 8])])]);
+        let mut changed = [0; 32];
+        changed[20] = 9;
+        let new = state(&[(1, &[(0, &changed)]), (3, &[(0, &[7; 4]), (2, &[8])])]);
+
+        let snapshot = view(&new).delta(NetworkTick(2), Some((NetworkTick(1), view(&old))));
+        assert_eq!(snapshot.baseline, Some(NetworkTick(1)));
+        assert_eq!(snapshot.despawns, vec![NetworkEntity(2)]);
+        assert_eq!(old.apply(&snapshot).unwrap().entities, new.entities);
+
+        let full = view(&new).delta(NetworkTick(2), None);
+        assert_eq!(full.baseline, None);
+        assert_eq!(old.apply(&full).unwrap().entities, new.entities);
+    }
+
+    #[test]
+    fn apply_delta_leaves_out_irrelevant_entities() {
+        let old = state(&[(1, &[(0, &[1])]), (2, &[(0, &[2])])]);
+        let relevant = HashSet::from([NetworkEntity(1)]);
+        let now = View {
+            state: &old,
+            relevant: Some(&relevant),
+        };
+
+        let snapshot = now.delta(NetworkTick(2), Some((NetworkTick(1), view(&old))));
+        assert_eq!(snapshot.despawns, vec![NetworkEntity(2)]);
+        let applied = old.apply(&snapshot).unwrap();
+        assert_eq!(applied.entities.len(), 1);
+        assert!(applied.entities.contains_key(&NetworkEntity(1)));
+    }
+
+    #[test]
+    fn apply_rejects_mismatched_patch() {
+        let old = state(&[(1, &[(0, &[0; 2])])]);
+        let snapshot = Snapshot {
+            tick: NetworkTick(2),
+            baseline: Some(NetworkTick(1)),
+            entities: vec![EntityDelta {
+                entity: NetworkEntity(1),
+                changed: vec![ComponentDelta {
+                    id: ReplicationId(0),
+                    change: ComponentChange::Patch(vec![BytePatch {
+                        offset: 1,
+                        bytes: vec![1, 2],
+                    }]),
+                }],
+                removed: Vec::new(),
+            }],
+            despawns: Vec::new(),
+        };
+        assert!(old.apply(&snapshot).is_none());
+    }
+}
@@ -0,0 +1,117 @@
+//! A fixed-timestep schedule for the network simulation, so that it runs at the server's [`TickRate`] no matter how
+//! often the host app updates.
+//!
+//! Every update, the time that has passed is added to an accumulator, and [`NetworkUpdate`] is run once for every whole
+//! tick that has accumulated, which may be zero times if the host updates faster than the tick rate. If the host falls
+//! so far behind that it would have to run more than [`FixedTimestep::max_catch_up`] ticks at once, the rest are skipped
+//! rather than making the next update take even longer.
+//!
+//! The server's tick rate comes from its config, and clients adopt it from the server once their
+//! [clock](crate::clock) has been synchronised. Until then, clients don't run any ticks
+
+use std::time::Duration;
+
+use bevy::{
+    app::{App, MainScheduleOrder, Plugin, PreUpdate},
+    ecs::{
+        schedule::{IntoSystemConfigs, Schedule, ScheduleLabel, SystemSet},
+        system::Resource,
+        world::World,
+    },
+    log::warn,
+    time::{Real, Time},
+};
+
+use crate::clock::{advance_tick, TickRate};
+
+/// The schedule that the network simulation runs in, once per tick
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NetworkUpdate;
+
+/// Runs [`NetworkUpdate`] as many times as needed to catch up to the present
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+struct RunNetworkUpdate;
+
+/// The system set in [`NetworkUpdate`] that moves the [`NetworkTick`](crate::clock::NetworkTick) on at the start of
+/// every tick. Simulation systems should run after it
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct AdvanceTick;
+
+/// How much time has accumulated towards the next tick
+#[derive(Resource, Debug)]
+pub struct FixedTimestep {
+    accumulated: Duration,
+    /// The most ticks that are run in a single update when the host has fallen behind
+    pub max_catch_up: u32,
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl FixedTimestep {
+    pub fn new(max_catch_up: u32) -> Self {
+        Self {
+            accumulated: Duration::ZERO,
+            max_catch_up,
+        }
+    }
+
+    /// How long ago the current tick was due to start. Once all of the ticks that were due have been run, this is less
+    /// than a tick
+    pub fn overstep(&self) -> Duration {
+        self.accumulated
+    }
+
+    /// Start counting towards the next tick as if the current one started the given time ago
+    pub(crate) fn set_overstep(&mut self, overstep: Duration) {
+        self.accumulated = overstep;
+    }
+}
+
+/// Adds the [`NetworkUpdate`] schedule, running it between [`PreUpdate`] and the rest of the main schedule, so that it sees
+/// the packets received during the update and the packets it sends go out at the end of it
+#[derive(Debug, Default)]
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FixedTimestep>()
+            .add_schedule(Schedule::new(NetworkUpdate))
+            .add_schedule(Schedule::new(RunNetworkUpdate))
+            .add_systems(RunNetworkUpdate, run_network_update)
+            .add_systems(NetworkUpdate, advance_tick.in_set(AdvanceTick));
+
+        app.world
+            .resource_mut::<MainScheduleOrder>()
+            .insert_after(PreUpdate, RunNetworkUpdate);
+    }
+}
+
+fn run_network_update(world: &mut World) {
+    let Some(rate) = world.get_resource::<TickRate>().copied() else {
+        return;
+    };
+    let tick = rate.duration();
+    let delta = world.resource::<Time<Real>>().delta();
+
+    let mut timestep = world.resource_mut::<FixedTimestep>();
+    timestep.accumulated += delta;
+    let due = (timestep.accumulated.as_nanos() / tick.as_nanos()) as u32;
+    let ticks = due.min(timestep.max_catch_up);
+    if due > ticks {
+        warn!(
+            "The simulation fell {} ticks behind, skipping them",
+            due - ticks
+        );
+        timestep.accumulated = tick * ticks + timestep.accumulated.saturating_sub(tick * due);
+    }
+
+    for _ in 0..ticks {
+        let mut timestep = world.resource_mut::<FixedTimestep>();
+        timestep.accumulated -= tick;
+        world.run_schedule(NetworkUpdate);
+    }
+}
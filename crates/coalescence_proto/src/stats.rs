@@ -0,0 +1,176 @@
+//! Statistics about how well a connection is performing, and how much is being sent over it.
+
+use std::{collections::HashMap, time::Duration};
+
+use bevy::ecs::{component::Component, system::Query};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    channel::{Channel, Ordered, Unordered, Unreliable},
+    packet::{PacketReceiver, PacketSender},
+    peer::Peer,
+    Is,
+};
+
+/// Round-trip times up to this are good enough for any game mode
+const GOOD_RTT: Duration = Duration::from_millis(100);
+/// Round-trip times beyond this are noticeable even with prediction
+const FAIR_RTT: Duration = Duration::from_millis(250);
+const GOOD_JITTER: Duration = Duration::from_millis(20);
+const GOOD_LOSS_RATE: f32 = 0.01;
+const FAIR_LOSS_RATE: f32 = 0.05;
+
+/// How much has been sent or received over a single channel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelTraffic {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+/// How much has been sent or received over a connection, in one direction
+#[derive(Debug, Default, Clone)]
+pub struct Traffic {
+    pub ordered: ChannelTraffic,
+    pub unordered: ChannelTraffic,
+    pub unreliable: ChannelTraffic,
+    /// How many packets of each type, by the name of the type
+    pub packets_by_type: HashMap<&'static str, u64>,
+}
+
+impl Traffic {
+    pub fn channel<C: Channel>(&self) -> &ChannelTraffic {
+        if C::is::<Ordered>() {
+            &self.ordered
+        } else if C::is::<Unordered>() {
+            &self.unordered
+        } else if C::is::<Unreliable>() {
+            &self.unreliable
+        } else {
+            unreachable!("There should only be 3 channel types: Ordered, Unordered and Unreliable, but an unexpected fourth channel type exists: '{}'", std::any::type_name::<C>())
+        }
+    }
+
+    pub(crate) fn channel_mut<C: Channel>(&mut self) -> &mut ChannelTraffic {
+        if C::is::<Ordered>() {
+            &mut self.ordered
+        } else if C::is::<Unordered>() {
+            &mut self.unordered
+        } else if C::is::<Unreliable>() {
+            &mut self.unreliable
+        } else {
+            unreachable!("There should only be 3 channel types: Ordered, Unordered and Unreliable, but an unexpected fourth channel type exists: '{}'", std::any::type_name::<C>())
+        }
+    }
+
+    pub(crate) fn record_packet_type(&mut self, name: &'static str) {
+        *self.packets_by_type.entry(name).or_default() += 1;
+    }
+
+    /// The traffic over all channels combined
+    pub fn total(&self) -> ChannelTraffic {
+        ChannelTraffic {
+            bytes: self.ordered.bytes + self.unordered.bytes + self.unreliable.bytes,
+            packets: self.ordered.packets + self.unordered.packets + self.unreliable.packets,
+        }
+    }
+}
+
+/// A rough rating of a connection, for showing to players
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ConnectionQuality {
+    #[default]
+    Good,
+    Fair,
+    Poor,
+}
+
+/// A component with statistics about the connection on the same entity, updated every tick.
+///
+/// The transport fills in the round-trip time, congestion window and packet loss, as only it can measure them
+#[derive(Debug, Default, Clone, Component)]
+pub struct NetworkStats {
+    /// The latest estimate of the round-trip time
+    pub rtt: Duration,
+    /// The round-trip time of the latest [heartbeat](crate::heartbeat), which unlike `rtt` includes how long the
+    /// remote peer took to get round to answering it
+    pub latency: Duration,
+    /// How much the latency varies between heartbeats, smoothed as in RFC 3550
+    pub jitter: Duration,
+    /// How many bytes may be in flight at once
+    pub congestion_window: u64,
+    /// How many UDP packets the transport has sent over the connection's lifetime
+    pub sent_packets: u64,
+    /// How many of the sent UDP packets were lost
+    pub lost_packets: u64,
+    pub sent: Traffic,
+    pub received: Traffic,
+}
+
+impl NetworkStats {
+    /// Record the latency measured by a heartbeat, updating the jitter. The transport's round-trip time can't be used
+    /// for this, as it's already smoothed, which hides most of the variation
+    pub fn record_latency(&mut self, latency: Duration) {
+        // The first sample has nothing to vary from
+        if !self.latency.is_zero() {
+            let difference = (latency.as_secs_f64() - self.latency.as_secs_f64()).abs();
+            let jitter = self.jitter.as_secs_f64();
+            self.jitter = Duration::from_secs_f64(jitter + (difference - jitter) / 16.0);
+        }
+        self.latency = latency;
+    }
+
+    /// The fraction of sent UDP packets that were lost
+    pub fn loss_rate(&self) -> f32 {
+        if self.sent_packets == 0 {
+            0.0
+        } else {
+            self.lost_packets as f32 / self.sent_packets as f32
+        }
+    }
+
+    pub fn quality(&self) -> ConnectionQuality {
+        let loss_rate = self.loss_rate();
+        if self.rtt <= GOOD_RTT && self.jitter <= GOOD_JITTER && loss_rate <= GOOD_LOSS_RATE {
+            ConnectionQuality::Good
+        } else if self.rtt <= FAIR_RTT && loss_rate <= FAIR_LOSS_RATE {
+            ConnectionQuality::Fair
+        } else {
+            ConnectionQuality::Poor
+        }
+    }
+}
+
+pub(crate) fn update_traffic_stats<P: Peer>(
+    mut query: Query<(&PacketSender<P>, &PacketReceiver, &mut NetworkStats)>,
+) {
+    for (sender, receiver, mut stats) in query.iter_mut() {
+        let stats = &mut *stats;
+        stats.sent.clone_from(sender.traffic());
+        stats.received.clone_from(receiver.traffic());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_follows_latency_variation() {
+        let mut stats = NetworkStats::default();
+        stats.record_latency(Duration::from_millis(50));
+        assert_eq!(stats.jitter, Duration::ZERO);
+
+        for i in 0..32 {
+            let latency = if i % 2 == 0 { 30 } else { 70 };
+            stats.record_latency(Duration::from_millis(latency));
+        }
+        let varying = stats.jitter;
+        assert!(varying > Duration::from_millis(30), "{varying:?}");
+
+        // Steady latencies are samples too, so the jitter settles back down
+        for _ in 0..64 {
+            stats.record_latency(Duration::from_millis(70));
+        }
+        assert!(stats.jitter < varying / 10, "{:?}", stats.jitter);
+    }
+}
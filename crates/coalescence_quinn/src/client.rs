@@ -1,26 +1,207 @@
-use std::sync::Arc;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+};
 
-use quinn::Endpoint;
+use bevy::log::{debug, info, warn};
+use quinn::{Endpoint, TransportConfig};
+use rcgen::RcgenError;
+use rustls::{
+    client::{ClientSessionStore, Resumption},
+    Certificate, PrivateKey,
+};
+use socket2::SockRef;
+use thiserror::Error;
 
-use crate::NoServerVerification;
+use crate::{
+    bind_socket, certificate_fingerprint,
+    server::{load_certificate, LoadCertificateError},
+    NoServerVerification, IPV4_WILDCARD, IPV6_WILDCARD,
+};
 
-pub fn create_config() -> quinn::ClientConfig {
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error(transparent)]
+    Load(#[from] LoadCertificateError),
+    #[error(transparent)]
+    Generate(#[from] RcgenError),
+    #[error("Could not save the identity to '{}'", .0.display())]
+    Save(Box<Path>, #[source] io::Error),
+}
+
+/// The self-signed certificate that a client presents to servers, whose [fingerprint](certificate_fingerprint)
+/// servers identify the player by. Unlike a username, it can't be claimed by anyone else, as only the client holds its
+/// private key
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub chain: Vec<Certificate>,
+    pub private_key: PrivateKey,
+}
+
+impl ClientIdentity {
+    /// Generate a new identity, which servers see as a different player from any other
+    pub fn generate() -> Result<Self, RcgenError> {
+        Self::from_rcgen(&generate_player_certificate()?)
+    }
+
+    fn from_rcgen(certificate: &rcgen::Certificate) -> Result<Self, RcgenError> {
+        Ok(Self {
+            chain: vec![Certificate(certificate.serialize_der()?)],
+            private_key: PrivateKey(certificate.serialize_private_key_der()),
+        })
+    }
+
+    /// Load the identity from a PEM file holding both the certificate and its private key, or generate one and save it
+    /// there if the file doesn't exist, so that the player keeps the same identity from then on
+    pub fn load_or_create(path: &Path) -> Result<Self, IdentityError> {
+        if path.exists() {
+            let (chain, private_key) = load_certificate(path, path)?;
+            return Ok(Self { chain, private_key });
+        }
+
+        let certificate = generate_player_certificate()?;
+        let pem = certificate.serialize_pem()? + &certificate.serialize_private_key_pem();
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // Anyone who can read the private key can play as the player
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(pem.as_bytes()))
+            .map_err(|e| IdentityError::Save(path.into(), e))?;
+        info!("Generated a new identity at '{}'", path.display());
+
+        Ok(Self::from_rcgen(&certificate)?)
+    }
+
+    /// The fingerprint that servers identify the player by
+    pub fn fingerprint(&self) -> [u8; 32] {
+        certificate_fingerprint(&self.chain[0])
+    }
+}
+
+fn generate_player_certificate() -> Result<rcgen::Certificate, RcgenError> {
+    rcgen::generate_simple_self_signed(["coalescence-player".to_owned()])
+}
+
+/// Create the client configuration, keeping the session tickets that servers send in the given cache so that later
+/// connections can resume their sessions with 0-RTT early data. If an identity is given, it's presented to servers
+pub fn create_config(
+    sessions: Arc<dyn ClientSessionStore>,
+    identity: Option<&ClientIdentity>,
+) -> Result<quinn::ClientConfig, rustls::Error> {
     // Exactly the same as `with_safe_defaults()` but with TLS 1.2 disabled (Quic requires TLS 1.3)
-    let mut crypto = rustls::ClientConfig::builder()
+    let builder = rustls::ClientConfig::builder()
         .with_safe_default_cipher_suites()
         .with_safe_default_kx_groups()
         .with_protocol_versions(&[&rustls::version::TLS13])
         .unwrap()
-        .with_custom_certificate_verifier(Arc::new(NoServerVerification))
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(Arc::new(NoServerVerification));
+    let mut crypto = match identity {
+        Some(identity) => {
+            builder.with_client_auth_cert(identity.chain.clone(), identity.private_key.clone())?
+        }
+        None => builder.with_no_client_auth(),
+    };
     crypto.enable_early_data = true;
+    crypto.resumption = Resumption::store(sessions);
+
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+/// The endpoints that a client connects from, one per address family.
+/// Where dual-stack sockets work, both families share the same endpoint
+#[derive(Debug, Clone)]
+pub struct ClientEndpoints {
+    ipv6: Option<Endpoint>,
+    ipv4: Option<Endpoint>,
+}
+
+impl ClientEndpoints {
+    /// The endpoint to connect to the given address from, or `None` if this host can't use its address family
+    pub fn for_address(&self, address: SocketAddr) -> Option<&Endpoint> {
+        if address.is_ipv6() {
+            self.ipv6.as_ref()
+        } else {
+            self.ipv4.as_ref()
+        }
+    }
+
+    /// Whether both address families share a single dual-stack endpoint
+    pub fn is_dual_stack(&self) -> bool {
+        match (&self.ipv6, &self.ipv4) {
+            (Some(ipv6), Some(ipv4)) => ipv6.local_addr().ok() == ipv4.local_addr().ok(),
+            _ => false,
+        }
+    }
+}
+
+/// Create the client's endpoints, preferring a single dual-stack endpoint, and otherwise falling back to one endpoint per
+/// address family. Fails only if neither address family can be used.
+///
+/// The session cache outlives the endpoints, so that sessions can still be resumed after they're recreated
+pub fn create_endpoints(
+    transport: Arc<TransportConfig>,
+    sessions: Arc<dyn ClientSessionStore>,
+    identity: &ClientIdentity,
+) -> io::Result<ClientEndpoints> {
+    let mut config = create_config(sessions, Some(identity))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    config.transport_config(transport);
+    let with_config = |socket| create_endpoint(socket, config.clone());
+
+    match dual_stack_socket() {
+        Ok(socket) => {
+            let endpoint = with_config(socket)?;
+            return Ok(ClientEndpoints {
+                ipv6: Some(endpoint.clone()),
+                ipv4: Some(endpoint),
+            });
+        }
+        Err(e) => debug!("Could not bind a dual-stack socket, using one per address family: {e}"),
+    }
+
+    let ipv6 = bind_socket(IPV6_WILDCARD, true).and_then(with_config);
+    let ipv4 = bind_socket(IPV4_WILDCARD, false).and_then(with_config);
+    match (ipv6, ipv4) {
+        (Err(e), Err(_)) => Err(e),
+        (ipv6, ipv4) => {
+            if let Err(e) = &ipv6 {
+                warn!("Could not bind an IPv6 socket, so only IPv4 servers can be reached: {e}");
+            }
+            if let Err(e) = &ipv4 {
+                warn!("Could not bind an IPv4 socket, so only IPv6 servers can be reached: {e}");
+            }
+            Ok(ClientEndpoints {
+                ipv6: ipv6.ok(),
+                ipv4: ipv4.ok(),
+            })
+        }
+    }
+}
 
-    quinn::ClientConfig::new(Arc::new(crypto))
+/// Bind a dual-stack socket, checking that it really is dual-stack, as some platforms silently ignore the option
+fn dual_stack_socket() -> io::Result<std::net::UdpSocket> {
+    let socket = bind_socket(IPV6_WILDCARD, false)?;
+    if SockRef::from(&socket).only_v6()? {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The socket is IPv6-only despite asking for dual-stack",
+        ));
+    }
+    Ok(socket)
 }
 
-pub fn create_endpoint() -> std::io::Result<Endpoint> {
-    let mut endpoint = crate::client(crate::IPV6_WILDCARD)?;
-    let config = create_config();
+fn create_endpoint(
+    socket: std::net::UdpSocket,
+    config: quinn::ClientConfig,
+) -> io::Result<Endpoint> {
+    let mut endpoint = crate::client(socket)?;
     endpoint.set_default_client_config(config);
     Ok(endpoint)
 }
@@ -0,0 +1,368 @@
+use std::{
+    marker::PhantomData,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Mutex,
+    },
+};
+
+use bevy::{
+    prelude::*,
+    tasks::{block_on, IoTaskPool, Task},
+};
+use bytes::Bytes;
+use coalescence_proto::{
+    channel::{Ordered, Unreliable},
+    packet::{Disconnect, DisconnectReason},
+    peer::Peer,
+    serde::{deserialize, serialize},
+    DisconnectPeer, NetworkStats, PacketReceiver, PacketSender, PeerDisconnected, ReceivePackets,
+    SendPackets,
+};
+use futures_lite::future::poll_once;
+use quinn::{Connection, ConnectionError, RecvStream, SendStream, VarInt, ZeroRttAccepted};
+
+use crate::{
+    receive_stream_driver::ReceiveStreamDriver, resumption::ResumptionMetrics,
+    send_stream_driver::SendStreamDriver,
+};
+
+/// The application error code used when closing a connection with a [`DisconnectReason`]
+pub const DISCONNECT_ERROR_CODE: VarInt = VarInt::from_u32(0);
+
+/// The maximum amount of bytes to read from the ordered-reliable stream at once
+const MAX_CHUNK_LENGTH: usize = u16::MAX as usize;
+
+/// How many datagrams may wait to be received before more are dropped, if the app falls behind reading them
+const DATAGRAM_QUEUE_LENGTH: usize = 1024;
+
+/// How much data was received from a connection during a single update, before any of it was deserialized
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InboundTraffic {
+    pub bytes: usize,
+    /// The amount of stream chunks and datagrams that the bytes arrived in
+    pub messages: usize,
+}
+
+/// A component holding a QUIC connection to a remote peer, and the drivers for its streams
+#[derive(Debug, Component)]
+pub struct QuinnConnection {
+    connection: Connection,
+    send: SendStreamDriver,
+    receive: ReceiveStreamDriver,
+    received: InboundTraffic,
+    closed: Task<ConnectionError>,
+    /// Datagrams carrying packets from the unreliable channel, which the reader task sends as soon as they arrive.
+    /// Only in a mutex so that the component is `Sync`, as it's only ever accessed mutably
+    datagrams: Mutex<Receiver<Bytes>>,
+    /// Reads datagrams until the connection closes. Dropping it stops reading them
+    _datagram_reader: Task<()>,
+    /// Set when we close the connection ourselves, so we know why it was closed once it has finished closing
+    local_reason: Option<DisconnectReason>,
+    /// Set while the connection is resuming a session, until the server has accepted or rejected its early data
+    early_data: Option<EarlyData>,
+}
+
+#[derive(Debug)]
+enum EarlyDataState {
+    /// Waiting to find out whether the server accepted the early data, or `None` if the handshake failed instead
+    Pending(Task<Option<bool>>),
+    /// The server rejected the early data, which discards the stream it was sent on, so a new one is being opened
+    Reopening(Task<Result<(SendStream, RecvStream), ConnectionError>>),
+}
+
+#[derive(Debug)]
+struct EarlyData {
+    state: EarlyDataState,
+    /// Everything sent on the stream so far, in case it has to be sent again on a new stream
+    sent: Vec<Bytes>,
+}
+
+impl QuinnConnection {
+    pub fn new(connection: Connection, send: SendStream, receive: RecvStream) -> Self {
+        let closed = {
+            let connection = connection.clone();
+            IoTaskPool::get().spawn(async move { connection.closed().await })
+        };
+
+        let (sender, datagrams) = mpsc::sync_channel(DATAGRAM_QUEUE_LENGTH);
+        Self {
+            datagrams: Mutex::new(datagrams),
+            _datagram_reader: read_datagrams(connection.clone(), sender),
+            connection,
+            send: SendStreamDriver::new(send),
+            receive: ReceiveStreamDriver::new(receive),
+            received: InboundTraffic::default(),
+            closed,
+            local_reason: None,
+            early_data: None,
+        }
+    }
+
+    /// Mark the connection as sending 0-RTT early data until the server accepts or rejects it.
+    /// Only replay-safe packets are sent until then, and they're sent again if the server rejects them
+    pub fn with_early_data(
+        mut self,
+        accepted: ZeroRttAccepted,
+        metrics: ResumptionMetrics,
+    ) -> Self {
+        let connection = self.connection.clone();
+        let task = IoTaskPool::get().spawn(async move {
+            let accepted = accepted.await;
+            // Early data also counts as rejected when the handshake fails, which isn't the server rejecting it
+            if connection.close_reason().is_some() {
+                return None;
+            }
+            metrics.record_early_data(accepted);
+            Some(accepted)
+        });
+        self.early_data = Some(EarlyData {
+            state: EarlyDataState::Pending(task),
+            sent: Vec::new(),
+        });
+        self
+    }
+
+    /// Whether the handshake is still in progress, so only replay-safe packets can be sent
+    pub fn in_early_data(&self) -> bool {
+        matches!(
+            self.early_data,
+            Some(EarlyData {
+                state: EarlyDataState::Pending(_),
+                ..
+            })
+        )
+    }
+
+    /// Move early data along, finishing it once the server has accepted it or a new stream has replaced the rejected one
+    fn poll_early_data(&mut self) {
+        let Some(early_data) = &mut self.early_data else {
+            return;
+        };
+
+        match &mut early_data.state {
+            EarlyDataState::Pending(task) => match block_on(poll_once(task)) {
+                Some(Some(true)) => self.early_data = None,
+                // The connection closing is picked up by `poll_closed_connections`, with the reason the handshake failed
+                Some(None) => {
+                    warn!(
+                        "The handshake with '{}' failed while resuming its session",
+                        self.connection.remote_address()
+                    );
+                    self.early_data = None;
+                }
+                Some(Some(false)) => {
+                    debug!("The server rejected our early data, resending it once the handshake completes...");
+                    let connection = self.connection.clone();
+                    early_data.state = EarlyDataState::Reopening(
+                        IoTaskPool::get().spawn(async move { connection.open_bi().await }),
+                    );
+                }
+                None => {}
+            },
+            EarlyDataState::Reopening(task) => match block_on(poll_once(task)) {
+                Some(Ok((send, receive))) => {
+                    self.send = SendStreamDriver::new(send);
+                    self.receive = ReceiveStreamDriver::new(receive);
+                    self.send.queue_chunks(std::mem::take(&mut early_data.sent));
+                    self.early_data = None;
+                }
+                // The connection closing is picked up by `poll_closed_connections`
+                Some(Err(e)) => {
+                    debug!("Could not reopen the stream after early data was rejected: {e}");
+                    self.early_data = None;
+                }
+                None => {}
+            },
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// How much data was received from this connection during the current update
+    pub fn received_this_update(&self) -> InboundTraffic {
+        self.received
+    }
+
+    /// Close the connection, telling the remote peer why it was closed
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        disconnect(&self.connection, reason.clone());
+        self.local_reason = Some(reason);
+    }
+}
+
+/// Spawn a task that reads datagrams from the connection until it closes, so that every datagram that arrives between
+/// updates is ready to be received at once
+fn read_datagrams(connection: Connection, sender: SyncSender<Bytes>) -> Task<()> {
+    IoTaskPool::get().spawn(async move {
+        // The connection closing is picked up by `poll_closed_connections`
+        while let Ok(datagram) = connection.read_datagram().await {
+            match sender.try_send(datagram) {
+                Ok(()) => {}
+                // Datagrams are unreliable anyway, so the app falling behind only loses the latest ones
+                Err(TrySendError::Full(_)) => {}
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+    })
+}
+
+/// Close the given connection, telling the remote peer why it was closed
+///
+/// The reason is sent as a serialized [`Disconnect`] packet in the QUIC `CONNECTION_CLOSE` frame, rather than over a stream,
+/// because closing a connection discards any stream data that hasn't been sent yet
+pub fn disconnect(connection: &Connection, reason: DisconnectReason) {
+    let packet = Disconnect { reason };
+    let bytes = serialize(&packet).unwrap_or_else(|e| {
+        error!("Error while serializing disconnect reason: {e}");
+        Vec::new()
+    });
+    connection.close(DISCONNECT_ERROR_CODE, &bytes);
+}
+
+/// Get the reason that the remote peer gave for closing the connection, if any
+pub fn disconnect_reason(error: &ConnectionError) -> Option<DisconnectReason> {
+    match error {
+        ConnectionError::ApplicationClosed(close) if close.error_code == DISCONNECT_ERROR_CODE => {
+            deserialize::<Disconnect>(&close.reason)
+                .map(|packet| packet.reason)
+                .ok()
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, SystemSet, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct ReceiveBytes;
+
+/// Integrates Quinn with the protocol implementation, moving bytes between [`QuinnConnection`]s and the
+/// [`PacketSender`]s and [`PacketReceiver`]s on the same entities
+#[derive(Debug)]
+pub struct QuinnPlugin<P>(PhantomData<P>);
+
+impl<P> Default for QuinnPlugin<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<P: Peer> Plugin for QuinnPlugin<P> {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            PreUpdate,
+            (
+                poll_closed_connections,
+                receive_bytes,
+                update_transport_stats,
+            )
+                .chain()
+                .in_set(ReceiveBytes)
+                .before(ReceivePackets),
+        )
+        .add_systems(
+            PostUpdate,
+            (send_bytes::<P>, handle_disconnect_requests)
+                .chain()
+                .in_set(SendPackets),
+        );
+    }
+}
+
+fn receive_bytes(mut query: Query<(Entity, &mut QuinnConnection, &mut PacketReceiver)>) {
+    for (entity, mut quinn, mut receiver) in query.iter_mut() {
+        quinn.received = InboundTraffic::default();
+        while let Some(result) = quinn.receive.try_receive(MAX_CHUNK_LENGTH, true) {
+            match result {
+                Ok(Some(chunk)) => {
+                    quinn.received.bytes += chunk.bytes.len();
+                    quinn.received.messages += 1;
+                    receiver.receive::<Ordered>(chunk.bytes);
+                }
+                // The stream was finished by the remote peer, which only happens when the connection is closing
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("Error while receiving data on entity {entity:?}: {e}");
+                    break;
+                }
+            }
+        }
+
+        let quinn = &mut *quinn;
+        let datagrams = quinn.datagrams.get_mut().unwrap();
+        for datagram in datagrams.try_iter() {
+            quinn.received.bytes += datagram.len();
+            quinn.received.messages += 1;
+            receiver.receive::<Unreliable>(datagram);
+        }
+    }
+}
+
+fn update_transport_stats(mut query: Query<(&QuinnConnection, &mut NetworkStats)>) {
+    for (quinn, mut stats) in query.iter_mut() {
+        let path = quinn.connection.stats().path;
+        stats.rtt = path.rtt;
+        stats.congestion_window = path.cwnd;
+        stats.sent_packets = path.sent_packets;
+        stats.lost_packets = path.lost_packets;
+    }
+}
+
+fn send_bytes<P: Peer>(mut query: Query<(Entity, &mut QuinnConnection, &mut PacketSender<P>)>) {
+    for (entity, mut quinn, mut sender) in query.iter_mut() {
+        quinn.poll_early_data();
+        sender.set_early_data(quinn.in_early_data());
+
+        // Each unreliable packet gets a datagram of its own, which may be lost, but is never held up by other packets
+        for datagram in sender.take_bytes::<Unreliable>() {
+            if let Err(e) = quinn.connection.send_datagram(datagram) {
+                debug!("Error while sending a datagram on entity {entity:?}: {e}");
+            }
+        }
+
+        let chunks = sender.take_bytes::<Ordered>();
+        if let Some(early_data) = &mut quinn.early_data {
+            early_data.sent.extend(chunks.iter().cloned());
+            // The old stream is useless once early data has been rejected, so wait for the new one
+            if let EarlyDataState::Reopening(_) = early_data.state {
+                continue;
+            }
+        }
+
+        quinn.send.queue_chunks(chunks);
+        if let Err(e) = quinn.send.drive() {
+            debug!("Error while sending data on entity {entity:?}: {e}");
+        }
+    }
+}
+
+fn handle_disconnect_requests(
+    mut events: EventReader<DisconnectPeer>,
+    mut query: Query<&mut QuinnConnection>,
+) {
+    for DisconnectPeer { entity, reason } in events.read() {
+        if let Ok(mut quinn) = query.get_mut(*entity) {
+            quinn.disconnect(reason.clone());
+        }
+    }
+}
+
+fn poll_closed_connections(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut QuinnConnection)>,
+    mut events: EventWriter<PeerDisconnected>,
+) {
+    for (entity, mut quinn) in query.iter_mut() {
+        if let Some(error) = block_on(poll_once(&mut quinn.closed)) {
+            let reason = match &error {
+                ConnectionError::LocallyClosed => quinn.local_reason.take(),
+                error => disconnect_reason(error),
+            }
+            .unwrap_or(DisconnectReason::ConnectionLost);
+
+            commands.entity(entity).remove::<QuinnConnection>();
+            events.send(PeerDisconnected { entity, reason });
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! UDP sockets for discovering servers on the local network.
+//!
+//! Servers periodically send an [`Announcement`] to the discovery port, using broadcast on IPv4 and multicast on IPv6,
+//! and clients listen for them on that port. This is separate from QUIC, as QUIC can't broadcast.
+//!
+//! Over IPv6, both sides only use the interface that the OS picks by default for multicast, as listing every interface
+//! isn't possible without more platform-specific code. Servers on a LAN that the default interface isn't connected to
+//! are still found over IPv4, as broadcasts go out on every interface.
+//!
+//! [`Announcement`]: coalescence_proto::discovery::Announcement
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{DEFAULT_PORT, IPV4_WILDCARD, IPV6_WILDCARD};
+
+/// The port that servers send announcements to, and that clients listen for them on
+pub const DISCOVERY_PORT: u16 = DEFAULT_PORT + 1;
+
+/// The link-local IPv6 multicast group that announcements are sent to
+pub const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7110);
+
+/// Where to send announcements for the given address family
+pub fn announce_address(ipv6: bool) -> SocketAddr {
+    if ipv6 {
+        SocketAddr::new(IpAddr::V6(DISCOVERY_MULTICAST_V6), DISCOVERY_PORT)
+    } else {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DISCOVERY_PORT)
+    }
+}
+
+/// Create a non-blocking socket for sending announcements to [`announce_address`]
+pub fn announce_socket(ipv6: bool) -> io::Result<UdpSocket> {
+    let socket = if ipv6 {
+        let socket = crate::bind_socket(IPV6_WILDCARD, true)?;
+        socket.set_multicast_loop_v6(true)?;
+        socket
+    } else {
+        let socket = crate::bind_socket(IPV4_WILDCARD, false)?;
+        socket.set_broadcast(true)?;
+        socket
+    };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Create a non-blocking socket that receives announcements for the given address family.
+///
+/// The port is shared, so that multiple clients on the same machine can all listen at once
+pub fn listen_socket(ipv6: bool) -> io::Result<UdpSocket> {
+    let (domain, address) = if ipv6 {
+        (Domain::IPV6, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    } else {
+        (Domain::IPV4, IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if ipv6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&SocketAddr::new(address, DISCOVERY_PORT).into())?;
+    if ipv6 {
+        // Interface 0 lets the OS pick the default interface
+        socket.join_multicast_v6(&DISCOVERY_MULTICAST_V6, 0)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
@@ -1,20 +1,34 @@
 use std::{
+    fmt::Write,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
 };
 
 use bevy::prelude::Resource;
-use quinn::{Endpoint, EndpointConfig, ServerConfig};
+use quinn::{Connection, Endpoint, EndpointConfig, ServerConfig};
+use ring::digest::{digest, SHA256};
 use runtime::BevyTasksRuntime;
-use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, DistinguishedName,
+};
+use socket2::{Domain, Protocol, Socket, Type};
 
+pub use connection::{InboundTraffic, QuinnConnection, QuinnPlugin, ReceiveBytes};
 pub use quinn;
+pub use rustls;
 
 pub mod client;
+pub mod connection;
+pub mod discovery;
+pub mod master;
 pub mod receive_stream_driver;
+pub mod resumption;
 mod runtime;
 pub mod send_stream_driver;
 pub mod server;
+pub mod transport;
 
 pub const DEFAULT_PORT: u16 = 7110;
 
@@ -60,22 +74,39 @@ pub const fn ipv6_default_port(addr: Ipv6Addr) -> SocketAddr {
 }
 
 #[derive(Debug, Resource)]
-pub struct AppEndpoint(pub Endpoint);
+pub struct AppEndpoints(pub client::ClientEndpoints);
+
+/// Bind a UDP socket to the given address.
+///
+/// If the address is IPv6, `v6_only` controls whether the socket is restricted to IPv6, or is dual-stack and so also
+/// allows communication with IPv4 addresses. Platforms differ in which of these is the default, so it is always set explicitly
+pub fn bind_socket(local_addr: SocketAddr, v6_only: bool) -> std::io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(local_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if local_addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.bind(&local_addr.into())?;
+    Ok(socket.into())
+}
 
-pub fn client(local_addr: SocketAddr) -> std::io::Result<Endpoint> {
+pub fn client(socket: std::net::UdpSocket) -> std::io::Result<Endpoint> {
     Endpoint::new(
         EndpointConfig::default(),
         None,
-        std::net::UdpSocket::bind(local_addr)?,
+        socket,
         Arc::new(BevyTasksRuntime),
     )
 }
 
-pub fn server(config: ServerConfig, local_addr: SocketAddr) -> std::io::Result<Endpoint> {
+pub fn server(config: ServerConfig, socket: std::net::UdpSocket) -> std::io::Result<Endpoint> {
     Endpoint::new(
         EndpointConfig::default(),
         Some(config),
-        std::net::UdpSocket::bind(local_addr)?,
+        socket,
         Arc::new(BevyTasksRuntime),
     )
 }
@@ -96,3 +127,71 @@ impl ServerCertVerifier for NoServerVerification {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+/// Accepts any certificate that a client presents, without requiring one. Players' certificates are self-signed, so
+/// there's nothing to check them against, but presenting one still proves that the client holds its private key, so its
+/// [fingerprint](peer_fingerprint) can be trusted to identify the player
+#[derive(Debug)]
+pub struct AnyClientCertificate;
+
+impl ClientCertVerifier for AnyClientCertificate {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _: &Certificate,
+        _: &[Certificate],
+        _: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// The SHA-256 hash of a certificate, used to identify servers with self-signed certificates
+pub fn certificate_fingerprint(certificate: &Certificate) -> [u8; 32] {
+    digest(&SHA256, &certificate.0)
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digests are always 32 bytes long")
+}
+
+/// The fingerprint of the certificate that the remote peer presented during the TLS handshake, if it presented one
+pub fn peer_fingerprint(connection: &Connection) -> Option<[u8; 32]> {
+    let chain = connection
+        .peer_identity()?
+        .downcast::<Vec<Certificate>>()
+        .ok()?;
+    chain.first().map(certificate_fingerprint)
+}
+
+/// Format a fingerprint as colon-separated hex bytes, e.g. `AB:CD:...`
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    let mut formatted = String::with_capacity(fingerprint.len() * 3);
+    for (i, byte) in fingerprint.iter().enumerate() {
+        if i > 0 {
+            formatted.push(':');
+        }
+        let _ = write!(formatted, "{byte:02X}");
+    }
+    formatted
+}
+
+/// Parse a fingerprint formatted by [`format_fingerprint`], ignoring case, or `None` if it isn't one
+pub fn parse_fingerprint(formatted: &str) -> Option<[u8; 32]> {
+    let mut fingerprint = [0; 32];
+    let mut bytes = formatted.trim().split(':');
+    for byte in &mut fingerprint {
+        let hex = bytes.next()?;
+        if hex.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(hex, 16).ok()?;
+    }
+    bytes.next().is_none().then_some(fingerprint)
+}
@@ -0,0 +1,80 @@
+//! Sending and answering requests to the master server over QUIC, one request per bidirectional stream
+
+use std::{net::SocketAddr, time::Duration};
+
+use async_io::Timer;
+use coalescence_proto::{
+    master::{MasterRequest, MasterResponse, MAX_MESSAGE_LENGTH},
+    serde::{deserialize, serialize},
+};
+use futures_lite::future;
+use quinn::{Connection, Endpoint, ReadToEndError, RecvStream, SendStream, WriteError};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::DEFAULT_PORT;
+
+/// The port that the master server listens on by default
+pub const DEFAULT_MASTER_PORT: u16 = DEFAULT_PORT + 2;
+
+/// How long to keep sending packets towards a peer when punching a hole through NAT
+const PUNCH_DURATION: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Error)]
+pub enum MasterError {
+    #[error(transparent)]
+    Connection(#[from] quinn::ConnectionError),
+    #[error(transparent)]
+    Write(#[from] WriteError),
+    #[error(transparent)]
+    Read(#[from] ReadToEndError),
+    #[error("Could not serialize or deserialize a message")]
+    Serde(#[from] coalescence_proto::Error),
+}
+
+/// Send a request to the master server and wait for its response
+pub async fn request(
+    connection: &Connection,
+    request: &MasterRequest,
+) -> Result<MasterResponse, MasterError> {
+    let (mut send, mut receive) = connection.open_bi().await?;
+    write_message(&mut send, request).await?;
+    read_message(&mut receive).await
+}
+
+/// Read a single message from the stream, which must be finished by the sender afterwards
+pub async fn read_message<T: DeserializeOwned>(receive: &mut RecvStream) -> Result<T, MasterError> {
+    let bytes = receive.read_to_end(MAX_MESSAGE_LENGTH).await?;
+    Ok(deserialize(&bytes)?)
+}
+
+/// Write a single message to the stream, and then finish it
+pub async fn write_message<T: Serialize>(
+    send: &mut SendStream,
+    message: &T,
+) -> Result<(), MasterError> {
+    let bytes = serialize(message)?;
+    send.write_all(&bytes).await?;
+    send.finish().await?;
+    Ok(())
+}
+
+/// Punch a hole through our NAT towards the given address, so that packets from it are let through.
+///
+/// This starts a connection attempt to the address, which is abandoned after a few seconds. The attempt is expected to
+/// fail, as it only exists so that the endpoint sends packets to the address
+pub async fn punch(endpoint: &Endpoint, address: SocketAddr) {
+    let Ok(connecting) = endpoint.connect(address, "punch") else {
+        return;
+    };
+
+    future::or(
+        async {
+            let _ = connecting.await;
+        },
+        async {
+            Timer::after(PUNCH_DURATION).await;
+        },
+    )
+    .await;
+}
@@ -0,0 +1,139 @@
+//! TLS session resumption and 0-RTT early data.
+//!
+//! Servers give clients session tickets, which clients keep in a cache so that reconnecting to the same server can skip
+//! most of the handshake, and send packets in 0-RTT early data before the handshake has even completed. Early data can be
+//! replayed by an attacker, so only [replay-safe](coalescence_proto::packet::Packet::REPLAY_SAFE) packets are sent in it
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use bevy::prelude::*;
+use quinn::{Connecting, Connection, ConnectionError, ZeroRttAccepted};
+use rustls::client::{ClientSessionMemoryCache, ClientSessionStore};
+
+use crate::QuinnConnection;
+
+/// How many servers' session tickets the client remembers
+pub const SESSION_CACHE_SIZE: usize = 64;
+
+/// Create a cache for the session tickets that servers send, to share between all of a client's endpoints
+pub fn session_cache() -> Arc<dyn ClientSessionStore> {
+    Arc::new(ClientSessionMemoryCache::new(SESSION_CACHE_SIZE))
+}
+
+/// Counts how often connections manage to resume a previous session. Cloning it shares the counts
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ResumptionMetrics(Arc<ResumptionCounters>);
+
+#[derive(Debug, Default)]
+struct ResumptionCounters {
+    full_handshakes: AtomicU32,
+    accepted: AtomicU32,
+    rejected: AtomicU32,
+}
+
+/// A snapshot of [`ResumptionMetrics`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResumptionStats {
+    /// Connections that had no session to resume
+    pub full_handshakes: u32,
+    /// Connections whose early data the server accepted
+    pub early_data_accepted: u32,
+    /// Connections that tried to resume a session, but whose early data the server rejected
+    pub early_data_rejected: u32,
+}
+
+impl ResumptionStats {
+    /// The fraction of connections that resumed a session with early data, or `None` if there haven't been any
+    pub fn success_rate(&self) -> Option<f32> {
+        let total = self.full_handshakes + self.early_data_accepted + self.early_data_rejected;
+        (total > 0).then(|| self.early_data_accepted as f32 / total as f32)
+    }
+}
+
+impl ResumptionMetrics {
+    pub fn stats(&self) -> ResumptionStats {
+        ResumptionStats {
+            full_handshakes: self.0.full_handshakes.load(Ordering::Relaxed),
+            early_data_accepted: self.0.accepted.load(Ordering::Relaxed),
+            early_data_rejected: self.0.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_full_handshake(&self) {
+        self.0.full_handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_early_data(&self, accepted: bool) {
+        let counter = if accepted {
+            &self.0.accepted
+        } else {
+            &self.0.rejected
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection that may still be completing its handshake, if it's resuming a session with 0-RTT early data
+pub struct NewConnection {
+    connection: Connection,
+    early_data: Option<(ZeroRttAccepted, ResumptionMetrics)>,
+}
+
+impl From<Connection> for NewConnection {
+    fn from(connection: Connection) -> Self {
+        Self {
+            connection,
+            early_data: None,
+        }
+    }
+}
+
+impl NewConnection {
+    /// Finish connecting, straight away if there's a session to resume, and otherwise once the handshake has completed
+    pub async fn establish(
+        connecting: Connecting,
+        metrics: &ResumptionMetrics,
+    ) -> Result<Self, ConnectionError> {
+        match connecting.into_0rtt() {
+            Ok((connection, accepted)) => Ok(Self {
+                connection,
+                early_data: Some((accepted, metrics.clone())),
+            }),
+            Err(connecting) => {
+                let connection = connecting.await?;
+                metrics.record_full_handshake();
+                Ok(connection.into())
+            }
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Wait for the handshake to complete, which also shows whether the server is reachable at all
+    pub async fn handshake(self) -> Result<Connection, ConnectionError> {
+        if let Some((accepted, metrics)) = self.early_data {
+            let accepted = accepted.await;
+            if let Some(error) = self.connection.close_reason() {
+                return Err(error);
+            }
+            metrics.record_early_data(accepted);
+        }
+        Ok(self.connection)
+    }
+
+    /// Open the stream that packets are sent over. If the handshake hasn't completed yet, only replay-safe packets are
+    /// sent until it has
+    pub async fn open(self) -> Result<QuinnConnection, ConnectionError> {
+        let (send, receive) = self.connection.open_bi().await?;
+        let quinn = QuinnConnection::new(self.connection, send, receive);
+        Ok(match self.early_data {
+            Some((accepted, metrics)) => quinn.with_early_data(accepted, metrics),
+            None => quinn,
+        })
+    }
+}
@@ -1,8 +1,15 @@
+use std::{fs::File, io::BufReader, net::SocketAddr, path::Path, sync::Arc};
+
 use quinn::Endpoint;
 use rcgen::RcgenError;
 use rustls::{Certificate, PrivateKey};
 use thiserror::Error;
 
+use crate::{
+    transport::{TransportSettings, TransportSettingsError},
+    AnyClientCertificate,
+};
+
 #[derive(Debug, Error)]
 pub enum CreateEndpointError {
     #[error(transparent)]
@@ -11,6 +18,18 @@ pub enum CreateEndpointError {
     Rustls(#[from] rustls::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Transport(#[from] TransportSettingsError),
+}
+
+#[derive(Debug, Error)]
+pub enum LoadCertificateError {
+    #[error("Could not read '{}'", .0.display())]
+    Io(Box<Path>, #[source] std::io::Error),
+    #[error("'{}' does not contain any PEM-encoded certificates", .0.display())]
+    NoCertificates(Box<Path>),
+    #[error("'{}' does not contain a PEM-encoded private key", .0.display())]
+    NoPrivateKey(Box<Path>),
 }
 
 pub fn generate_certificate(
@@ -23,14 +42,75 @@ pub fn generate_certificate(
     ))
 }
 
-pub fn create_endpoint() -> Result<Endpoint, CreateEndpointError> {
-    let (certificate, private_key) = generate_certificate(vec!["::1".into()])?;
+/// Load a certificate chain and its private key from PEM files
+pub fn load_certificate(
+    certificate_path: &Path,
+    private_key_path: &Path,
+) -> Result<(Vec<Certificate>, PrivateKey), LoadCertificateError> {
+    let read_pem = |path: &Path| {
+        File::open(path)
+            .and_then(|file| rustls_pemfile::read_all(&mut BufReader::new(file)))
+            .map_err(|e| LoadCertificateError::Io(path.into(), e))
+    };
 
-    let server_config = quinn::ServerConfig::with_single_cert(vec![certificate], private_key)?;
-    // let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    // transport_config.max_concurrent_uni_streams(0_u8.into());
+    let certificates: Vec<_> = read_pem(certificate_path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
 
-    let endpoint = crate::server(server_config, crate::IPV6_WILDCARD_DEFAULT_PORT)?;
+    if certificates.is_empty() {
+        return Err(LoadCertificateError::NoCertificates(
+            certificate_path.into(),
+        ));
+    }
+
+    let private_key = read_pem(private_key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| LoadCertificateError::NoPrivateKey(private_key_path.into()))?;
+
+    Ok((certificates, private_key))
+}
+
+pub fn create_config(
+    certificate_chain: Vec<Certificate>,
+    private_key: PrivateKey,
+    transport: &TransportSettings,
+) -> Result<quinn::ServerConfig, CreateEndpointError> {
+    // Exactly the same as `with_single_cert()`, but handing out session tickets so that clients can resume their sessions
+    // without the server having to remember them, and asking clients for the certificates that identify their players
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_client_cert_verifier(Arc::new(AnyClientCertificate))
+        .with_single_cert(certificate_chain, private_key)?;
+    crypto.max_early_data_size = u32::MAX;
+    crypto.ticketer = rustls::Ticketer::new()?;
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(transport.to_config()?);
+    Ok(server_config)
+}
 
+/// Create a server endpoint listening on the given address.
+///
+/// If the address is IPv6, `v6_only` controls whether the socket also accepts IPv4 connections
+pub fn create_endpoint(
+    config: quinn::ServerConfig,
+    local_addr: SocketAddr,
+    v6_only: bool,
+) -> Result<Endpoint, CreateEndpointError> {
+    let socket = crate::bind_socket(local_addr, v6_only)?;
+    let endpoint = crate::server(config, socket)?;
     Ok(endpoint)
 }
@@ -0,0 +1,100 @@
+//! QUIC transport parameters, shared by the server's config file and the client
+
+use std::{sync::Arc, time::Duration};
+
+use quinn::{
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+    IdleTimeout, MtuDiscoveryConfig, TransportConfig, VarInt, VarIntBoundsExceeded,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// The algorithm used to decide how much data may be in flight at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CongestionController {
+    #[default]
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+#[derive(Debug, Error)]
+pub enum TransportSettingsError {
+    #[error("`idle_timeout` is too long")]
+    IdleTimeoutTooLong(#[source] VarIntBoundsExceeded),
+}
+
+/// Tunable QUIC transport parameters. The defaults are the same as Quinn's
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSettings {
+    /// How long a connection may go without receiving anything before it's closed. Zero means never
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    /// How often to send keep-alive packets on otherwise idle connections, so that they don't time out, if at all
+    #[serde(with = "humantime_serde")]
+    pub keep_alive_interval: Option<Duration>,
+    /// How many bidirectional streams the peer may have open at once
+    pub max_concurrent_bidi_streams: u32,
+    /// How many unidirectional streams the peer may have open at once
+    pub max_concurrent_uni_streams: u32,
+    /// How many bytes of unread datagrams to buffer before dropping them, or `None` to refuse datagrams
+    pub datagram_receive_buffer_size: Option<usize>,
+    /// How many bytes of unsent datagrams to buffer before dropping the oldest
+    pub datagram_send_buffer_size: usize,
+    /// The round-trip time assumed before it has been measured
+    #[serde(with = "humantime_serde")]
+    pub initial_rtt: Duration,
+    /// Whether to probe for a larger maximum packet size than the minimum that QUIC guarantees
+    pub mtu_discovery: bool,
+    pub congestion_controller: CongestionController,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(30),
+            keep_alive_interval: None,
+            max_concurrent_bidi_streams: 100,
+            max_concurrent_uni_streams: 100,
+            datagram_receive_buffer_size: Some(1_250_000),
+            datagram_send_buffer_size: 1024 * 1024,
+            initial_rtt: Duration::from_millis(333),
+            mtu_discovery: true,
+            congestion_controller: CongestionController::default(),
+        }
+    }
+}
+
+impl TransportSettings {
+    pub fn to_config(&self) -> Result<Arc<TransportConfig>, TransportSettingsError> {
+        let idle_timeout = IdleTimeout::try_from(self.idle_timeout)
+            .map_err(TransportSettingsError::IdleTimeoutTooLong)?;
+
+        let mut config = TransportConfig::default();
+        config
+            .max_idle_timeout(Some(idle_timeout))
+            .keep_alive_interval(self.keep_alive_interval)
+            .max_concurrent_bidi_streams(VarInt::from_u32(self.max_concurrent_bidi_streams))
+            .max_concurrent_uni_streams(VarInt::from_u32(self.max_concurrent_uni_streams))
+            .datagram_receive_buffer_size(self.datagram_receive_buffer_size)
+            .datagram_send_buffer_size(self.datagram_send_buffer_size)
+            .initial_rtt(self.initial_rtt)
+            .mtu_discovery_config(self.mtu_discovery.then(MtuDiscoveryConfig::default));
+
+        match self.congestion_controller {
+            CongestionController::Cubic => {
+                config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            CongestionController::NewReno => {
+                config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            CongestionController::Bbr => {
+                config.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+        };
+
+        Ok(Arc::new(config))
+    }
+}
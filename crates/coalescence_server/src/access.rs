@@ -0,0 +1,331 @@
+//! Ban and allow lists, keyed by player ID, IP address or CIDR range.
+//!
+//! The lists are loaded from a TOML file, which is polled for changes and reloaded while the server is running:
+//!
+//! ```toml
+//! [[ban]]
+//! player = "3F:A2:...:7C"
+//! reason = "Griefing"
+//! expires = "2026-11-01T00:00:00Z"
+//!
+//! [[ban]]
+//! cidr = "203.0.113.0/24"
+//!
+//! [[allow]]
+//! ip = "2001:db8::1"
+//! ```
+//!
+//! Players are identified by the SHA-256 fingerprint of the certificate that their client presents during the TLS
+//! handshake, which is logged when they join, as colon-separated hex bytes. Their username can't be used, as anyone
+//! can pick any username. Ban reasons are cut short at [`MAX_BAN_REASON_LENGTH`] bytes, so that they fit in the packet
+//! that closes the connection.
+//!
+//! The allow list is checked separately for addresses and players: if it contains any `ip` or `cidr` entries, incoming
+//! connections must match one of them, and if it contains any `player` entries, players must match one of them.
+//! Expired entries are ignored.
+
+use std::{
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use bevy::prelude::*;
+use coalescence_proto::{packet::DisconnectReason, DisconnectPeer};
+use coalescence_quinn::QuinnConnection;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::ClientConnection;
+
+/// The path that the access lists are loaded from if no other path is specified
+pub const DEFAULT_ACCESS_LIST_PATH: &str = "access.toml";
+
+/// The longest ban reason that is sent to banned players. Connections are closed with a single packet, which can't be
+/// fragmented, so longer reasons would be cut off mid-character by QUIC instead
+pub const MAX_BAN_REASON_LENGTH: usize = 512;
+
+/// How often to check the access list file for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AccessLists {
+    #[serde(default, rename = "ban")]
+    pub bans: Vec<AccessRule>,
+    #[serde(default, rename = "allow")]
+    pub allows: Vec<AccessRule>,
+}
+
+/// A single entry in a ban or allow list
+#[derive(Debug, Deserialize)]
+pub struct AccessRule {
+    #[serde(flatten)]
+    pub target: AccessTarget,
+    /// Why the entry was added. For bans, this is sent to the banned player
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// When the entry stops applying, or `None` if it applies forever
+    #[serde(default, with = "humantime_serde")]
+    pub expires: Option<SystemTime>,
+}
+
+/// What an [`AccessRule`] applies to
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTarget {
+    Player(String),
+    Ip(IpAddr),
+    Cidr(IpNet),
+}
+
+impl AccessTarget {
+    fn is_address(&self) -> bool {
+        matches!(self, AccessTarget::Ip(_) | AccessTarget::Cidr(_))
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            AccessTarget::Player(_) => false,
+            AccessTarget::Ip(target) => canonical_ip(*target) == ip,
+            AccessTarget::Cidr(target) => target.contains(&ip),
+        }
+    }
+
+    fn matches_player(&self, player: &str) -> bool {
+        match self {
+            AccessTarget::Player(target) => target.eq_ignore_ascii_case(player),
+            AccessTarget::Ip(_) | AccessTarget::Cidr(_) => false,
+        }
+    }
+}
+
+impl AccessRule {
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.expires.map_or(true, |expires| expires > now)
+    }
+
+    fn ban_reason(&self) -> DisconnectReason {
+        DisconnectReason::Banned {
+            reason: self.reason.clone(),
+            expires: self.expires,
+        }
+    }
+}
+
+impl AccessLists {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read access lists from '{}'", path.display()))?;
+        let mut lists: Self = toml::from_str(&contents)
+            .with_context(|| format!("Could not parse access lists from '{}'", path.display()))?;
+
+        for reason in lists.bans.iter_mut().filter_map(|ban| ban.reason.as_mut()) {
+            if reason.len() > MAX_BAN_REASON_LENGTH {
+                warn!(
+                    "Ban reason '{reason}' is longer than {MAX_BAN_REASON_LENGTH} bytes, shortening it"
+                );
+                let mut end = MAX_BAN_REASON_LENGTH;
+                while !reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+                reason.truncate(end);
+            }
+        }
+
+        Ok(lists)
+    }
+
+    /// Check whether a connection from the given address is allowed
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), DisconnectReason> {
+        let ip = canonical_ip(ip);
+        self.check(|target| target.matches_ip(ip), AccessTarget::is_address)
+    }
+
+    /// Check whether the given player is allowed to join
+    pub fn check_player(&self, player: &str) -> Result<(), DisconnectReason> {
+        self.check(
+            |target| target.matches_player(player),
+            |target| !target.is_address(),
+        )
+    }
+
+    fn check(
+        &self,
+        matches: impl Fn(&AccessTarget) -> bool,
+        same_kind: impl Fn(&AccessTarget) -> bool,
+    ) -> Result<(), DisconnectReason> {
+        let now = SystemTime::now();
+
+        if let Some(ban) = self
+            .bans
+            .iter()
+            .find(|rule| rule.is_active(now) && matches(&rule.target))
+        {
+            return Err(ban.ban_reason());
+        }
+
+        let mut allows = self
+            .allows
+            .iter()
+            .filter(|rule| rule.is_active(now) && same_kind(&rule.target))
+            .peekable();
+
+        if allows.peek().is_some() && !allows.any(|rule| matches(&rule.target)) {
+            return Err(DisconnectReason::NotAllowed);
+        }
+
+        Ok(())
+    }
+}
+
+/// IPv4 clients connecting to a dual-stack socket have their address mapped into IPv6, which is undone here
+/// so that rules for IPv4 addresses still match them
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// The access lists, shared between the ECS world and the tasks that accept incoming connections
+#[derive(Resource, Debug, Clone, Default)]
+pub struct AccessControl(Arc<RwLock<AccessLists>>);
+
+impl AccessControl {
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), DisconnectReason> {
+        self.0.read().unwrap().check_ip(ip)
+    }
+
+    pub fn check_player(&self, player: &str) -> Result<(), DisconnectReason> {
+        self.0.read().unwrap().check_player(player)
+    }
+}
+
+/// Where the access lists are loaded from, and when they were last modified
+#[derive(Resource, Debug)]
+pub struct AccessListFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    timer: Timer,
+}
+
+impl AccessListFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            modified: None,
+            timer: Timer::new(RELOAD_INTERVAL, TimerMode::Repeating),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AccessPlugin {
+    pub path: PathBuf,
+}
+
+impl Default for AccessPlugin {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_ACCESS_LIST_PATH.into(),
+        }
+    }
+}
+
+impl Plugin for AccessPlugin {
+    fn build(&self, app: &mut App) {
+        let mut file = AccessListFile::new(self.path.clone());
+        let access = AccessControl::default();
+
+        match fs::metadata(&file.path) {
+            Ok(metadata) => match AccessLists::load(&file.path) {
+                Ok(lists) => {
+                    info!(
+                        "Loaded {} bans and {} allow list entries from '{}'",
+                        lists.bans.len(),
+                        lists.allows.len(),
+                        file.path.display()
+                    );
+                    file.modified = metadata.modified().ok();
+                    *access.0.write().unwrap() = lists;
+                }
+                Err(e) => error!("{e:#}"),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => info!(
+                "No access lists found at '{}', all players are allowed",
+                file.path.display()
+            ),
+            Err(e) => error!(
+                "Could not read access lists from '{}': {e}",
+                file.path.display()
+            ),
+        }
+
+        app.insert_resource(file)
+            .insert_resource(access)
+            .add_systems(Update, reload_access_lists);
+    }
+}
+
+/// Reload the access lists whenever the file changes, and disconnect anyone who is no longer allowed
+fn reload_access_lists(
+    time: Res<Time>,
+    mut file: ResMut<AccessListFile>,
+    access: Res<AccessControl>,
+    query: Query<(Entity, &ClientConnection, &QuinnConnection)>,
+    mut disconnect: EventWriter<DisconnectPeer>,
+) {
+    if !file.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let modified = fs::metadata(&file.path).and_then(|metadata| metadata.modified());
+    let modified = match modified {
+        Ok(modified) => Some(modified),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(_) => return,
+    };
+
+    if modified == file.modified {
+        return;
+    }
+    file.modified = modified;
+
+    let lists = if modified.is_some() {
+        match AccessLists::load(&file.path) {
+            Ok(lists) => lists,
+            Err(e) => {
+                error!("{e:#}. Keeping the previous access lists");
+                return;
+            }
+        }
+    } else {
+        AccessLists::default()
+    };
+
+    info!(
+        "Reloaded {} bans and {} allow list entries from '{}'",
+        lists.bans.len(),
+        lists.allows.len(),
+        file.path.display()
+    );
+    *access.0.write().unwrap() = lists;
+
+    for (entity, client, quinn) in query.iter() {
+        let mut result = access.check_ip(quinn.connection().remote_address().ip());
+        if let (Ok(()), Some(profile)) = (&result, client.handshake.profile()) {
+            result = access.check_player(&profile.player);
+        }
+
+        if let Err(reason) = result {
+            info!(
+                "Disconnecting client ID '{}' as they are no longer allowed: {reason}",
+                quinn.connection().stable_id()
+            );
+            disconnect.send(DisconnectPeer { entity, reason });
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! The server's configuration, loaded from a TOML file and then overridden by any command-line flags.
+
+use std::{
+    fmt::Write,
+    fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use bevy::{log::Level, prelude::*};
+use clap::{Parser, ValueEnum};
+use coalescence_common::GameMode;
+use coalescence_proto::{
+    clock::MAX_TICK_RATE,
+    heartbeat::HeartbeatSettings,
+    input::MissingInput,
+    lag_compensation::{LagCompensationSettings, REWIND_HISTORY_LENGTH},
+    replication::interest::{AdjacentRooms, Everything, Interest, SameRoom},
+};
+use coalescence_quinn::{
+    parse_fingerprint,
+    rustls::{Certificate, PrivateKey},
+    server::{generate_certificate, load_certificate},
+    transport::TransportSettings,
+    DEFAULT_PORT,
+};
+use serde::{de::IntoDeserializer, Deserialize};
+
+use crate::{access::DEFAULT_ACCESS_LIST_PATH, rate_limit::RateLimits, slots::FullBehaviour};
+
+/// The path that the config file is loaded from if no other path is specified
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_MOTD_LENGTH: usize = 512;
+
+/// Dedicated server for Rain World Coalescence
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// The config file to load. Flags given on the command line override values from the file
+    #[arg(short, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Which address families to listen on
+    #[arg(long)]
+    bind: Option<BindAddresses>,
+    /// The IPv4 address to listen on
+    #[arg(long, value_name = "ADDRESS")]
+    ipv4_address: Option<Ipv4Addr>,
+    /// The IPv6 address to listen on
+    #[arg(long, value_name = "ADDRESS")]
+    ipv6_address: Option<Ipv6Addr>,
+    /// The port to listen on
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// How many times per second to update the simulation
+    #[arg(long)]
+    tick_rate: Option<u32>,
+    /// The most ticks to run at once to catch up after the server has fallen behind, skipping any more than that
+    #[arg(long)]
+    max_catch_up_ticks: Option<u32>,
+    /// Which replicated entities players are sent
+    #[arg(long)]
+    relevance: Option<Relevance>,
+    /// What to assume players pressed when their input arrives too late: "repeat-last" or "neutral"
+    #[arg(long, value_parser = parse_missing_input)]
+    missing_input: Option<MissingInput>,
+    /// The furthest back in time that players' attacks are checked for hits, e.g. "200ms"
+    #[arg(long, value_name = "DURATION", value_parser = humantime_serde::re::humantime::parse_duration)]
+    max_rewind: Option<Duration>,
+    /// How many players may be connected at once
+    #[arg(long)]
+    max_players: Option<usize>,
+    /// How many of the player slots only admins may take
+    #[arg(long)]
+    reserved_slots: Option<usize>,
+    /// What to do with players that join while the server is full
+    #[arg(long)]
+    full_behaviour: Option<FullBehaviour>,
+    /// How many players may wait in the queue for a slot to free up
+    #[arg(long)]
+    max_queue_length: Option<usize>,
+    /// How long to hold the place of a player whose connection dropped, e.g. "30s". Zero disables reconnecting
+    #[arg(long, value_name = "DURATION", value_parser = humantime_serde::re::humantime::parse_duration)]
+    reconnect_grace_period: Option<Duration>,
+    /// How often to send heartbeats to players, e.g. "1s"
+    #[arg(long, value_name = "DURATION", value_parser = humantime_serde::re::humantime::parse_duration)]
+    heartbeat_interval: Option<Duration>,
+    /// How long a player may go without sending anything before they're disconnected, e.g. "10s"
+    #[arg(long, value_name = "DURATION", value_parser = humantime_serde::re::humantime::parse_duration)]
+    heartbeat_timeout: Option<Duration>,
+    /// The name of the server, shown to players before they join
+    #[arg(long)]
+    name: Option<String>,
+    /// The message of the day, shown to players before they join
+    #[arg(long)]
+    motd: Option<String>,
+    /// What kind of game the server runs: "story" or "arena"
+    #[arg(long, value_parser = parse_game_mode)]
+    game_mode: Option<GameMode>,
+    /// Announce the server to players on the local network
+    #[arg(long)]
+    lan_discovery: bool,
+    /// The master server to register with, as `host:port`, so that the server appears in the public server list
+    #[arg(long, value_name = "ADDRESS")]
+    master_server: Option<String>,
+    /// The fingerprint of the master server's certificate, which it's only trusted if it presents
+    #[arg(long, value_name = "FINGERPRINT")]
+    master_fingerprint: Option<String>,
+    /// A password that players must give to join
+    #[arg(long)]
+    password: Option<String>,
+    /// A PEM file containing the server's TLS certificate chain. A self-signed certificate is generated if not given
+    #[arg(long, value_name = "PATH", requires = "private_key")]
+    certificate: Option<PathBuf>,
+    /// A PEM file containing the private key for the server's TLS certificate
+    #[arg(long, value_name = "PATH", requires = "certificate")]
+    private_key: Option<PathBuf>,
+    /// The file containing the server's ban and allow lists
+    #[arg(long, value_name = "PATH")]
+    access_list: Option<PathBuf>,
+    /// The most verbose level of log messages to print
+    #[arg(long)]
+    log_level: Option<LogLevel>,
+}
+
+/// Parse a game mode the same way as it is written in the config file
+fn parse_game_mode(value: &str) -> Result<GameMode, serde::de::value::Error> {
+    GameMode::deserialize(value.into_deserializer())
+}
+
+/// Parse a missing input policy the same way as it is written in the config file
+fn parse_missing_input(value: &str) -> Result<MissingInput, serde::de::value::Error> {
+    MissingInput::deserialize(value.into_deserializer())
+}
+
+/// Which address families the server listens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BindAddresses {
+    Ipv4,
+    Ipv6,
+    #[default]
+    Both,
+}
+
+/// Which replicated entities players are sent, by the room that they're in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Relevance {
+    /// Entities in the player's room and the rooms next to it
+    #[default]
+    AdjacentRooms,
+    /// Only entities in the player's room
+    SameRoom,
+    /// Every entity, wherever the player is
+    Everything,
+}
+
+impl Relevance {
+    pub fn interest(self) -> Interest {
+        match self {
+            Relevance::AdjacentRooms => Interest::new(AdjacentRooms),
+            Relevance::SameRoom => Interest::new(SameRoom),
+            Relevance::Everything => Interest::new(Everything),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: BindAddresses,
+    pub ipv4_address: Ipv4Addr,
+    pub ipv6_address: Ipv6Addr,
+    pub port: u16,
+    pub tick_rate: u32,
+    /// The most ticks to run at once to catch up after the server has fallen behind. Any more than that are skipped
+    pub max_catch_up_ticks: u32,
+    /// Which replicated entities players are sent
+    pub relevance: Relevance,
+    /// What to assume players pressed during ticks that their input didn't arrive in time for
+    pub missing_input: MissingInput,
+    /// The furthest back in time that players' attacks are checked for hits, to make up for their latency
+    #[serde(with = "humantime_serde")]
+    pub max_rewind: Duration,
+    pub max_players: usize,
+    pub reserved_slots: usize,
+    pub full_behaviour: FullBehaviour,
+    pub max_queue_length: usize,
+    /// How long to hold the place of a player whose connection dropped, so that they can reconnect and resume their
+    /// session. Zero disables resuming
+    #[serde(with = "humantime_serde")]
+    pub reconnect_grace_period: Duration,
+    /// How often to send heartbeats to players, to measure their latency
+    #[serde(with = "humantime_serde")]
+    pub heartbeat_interval: Duration,
+    /// How long a player may go without sending anything before they're disconnected as timed out
+    #[serde(with = "humantime_serde")]
+    pub heartbeat_timeout: Duration,
+    /// The IDs of players that may take reserved slots, and skip ahead of other players in the queue. These are the
+    /// fingerprints of the players' certificates that are logged when they join, not their usernames, which anyone can
+    /// pick
+    pub admins: Vec<String>,
+    pub name: String,
+    pub motd: String,
+    pub game_mode: GameMode,
+    /// Whether to announce the server to players on the local network
+    pub lan_discovery: bool,
+    /// The master server to register with, as `host:port`. The server isn't publicly listed if this isn't set
+    pub master_server: Option<String>,
+    /// The fingerprint of the master server's certificate, as logged by the master server when it starts. Required if
+    /// `master_server` is set, so that nobody else can pretend to be the master server
+    pub master_fingerprint: Option<String>,
+    pub password: Option<String>,
+    pub certificate: Option<PathBuf>,
+    pub private_key: Option<PathBuf>,
+    pub access_list: PathBuf,
+    pub log_level: LogLevel,
+    pub rate_limits: RateLimits,
+    pub transport: TransportSettings,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: BindAddresses::default(),
+            ipv4_address: Ipv4Addr::UNSPECIFIED,
+            ipv6_address: Ipv6Addr::UNSPECIFIED,
+            port: DEFAULT_PORT,
+            tick_rate: 60,
+            max_catch_up_ticks: 8,
+            relevance: Relevance::default(),
+            missing_input: MissingInput::default(),
+            max_rewind: LagCompensationSettings::default().max_rewind,
+            max_players: 16,
+            reserved_slots: 0,
+            full_behaviour: FullBehaviour::default(),
+            max_queue_length: 32,
+            reconnect_grace_period: Duration::from_secs(30),
+            heartbeat_interval: HeartbeatSettings::default().interval,
+            heartbeat_timeout: HeartbeatSettings::default().timeout,
+            admins: Vec::new(),
+            name: "Rain World Coalescence Server".into(),
+            motd: String::new(),
+            game_mode: GameMode::default(),
+            lan_discovery: false,
+            master_server: None,
+            master_fingerprint: None,
+            password: None,
+            certificate: None,
+            private_key: None,
+            access_list: DEFAULT_ACCESS_LIST_PATH.into(),
+            log_level: LogLevel::default(),
+            rate_limits: RateLimits::default(),
+            transport: TransportSettings::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Load the config from the file and command-line flags, validating it.
+    ///
+    /// A missing config file is only an error if its path was given explicitly
+    pub fn load() -> anyhow::Result<Self> {
+        let cli = Cli::parse();
+
+        let mut config = match &cli.config {
+            Some(path) => Self::load_file(path)?,
+            None => match Self::load_file(Path::new(DEFAULT_CONFIG_PATH)) {
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+                {
+                    Self::default()
+                }
+                result => result?,
+            },
+        };
+
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn load_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file '{}'", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Could not parse config file '{}'", path.display()))
+    }
+
+    fn apply_cli(&mut self, cli: Cli) {
+        macro_rules! apply {
+            ($( $field:ident ),+) => {
+                $(
+                    if let Some(value) = cli.$field {
+                        self.$field = value;
+                    }
+                )+
+            };
+        }
+
+        apply!(
+            bind,
+            ipv4_address,
+            ipv6_address,
+            port,
+            tick_rate,
+            max_catch_up_ticks,
+            relevance,
+            missing_input,
+            max_rewind,
+            max_players,
+            reserved_slots,
+            full_behaviour,
+            max_queue_length,
+            reconnect_grace_period,
+            heartbeat_interval,
+            heartbeat_timeout,
+            name,
+            motd,
+            game_mode,
+            access_list,
+            log_level
+        );
+
+        if cli.lan_discovery {
+            self.lan_discovery = true;
+        }
+
+        if cli.master_server.is_some() {
+            self.master_server = cli.master_server;
+        }
+
+        if cli.master_fingerprint.is_some() {
+            self.master_fingerprint = cli.master_fingerprint;
+        }
+
+        if cli.password.is_some() {
+            self.password = cli.password;
+        }
+
+        if cli.certificate.is_some() {
+            self.certificate = cli.certificate;
+            self.private_key = cli.private_key;
+        }
+    }
+
+    /// Check that all values are in range, collecting every problem into a single error
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        if self.port == 0 {
+            problems.push("`port` must not be 0".to_owned());
+        }
+
+        if !(1..=MAX_TICK_RATE).contains(&self.tick_rate) {
+            problems.push(format!(
+                "`tick_rate` must be between 1 and {MAX_TICK_RATE}, but is {}",
+                self.tick_rate
+            ));
+        }
+
+        if self.max_catch_up_ticks == 0 {
+            problems.push("`max_catch_up_ticks` must be at least 1".to_owned());
+        }
+
+        let max_rewind_ticks = self.max_rewind.as_secs_f64() * self.tick_rate as f64;
+        if max_rewind_ticks > REWIND_HISTORY_LENGTH as f64 {
+            problems.push(format!(
+                "`max_rewind` must be at most {REWIND_HISTORY_LENGTH} ticks, but is {max_rewind_ticks:.0}"
+            ));
+        }
+
+        if self.max_players == 0 {
+            problems.push("`max_players` must be at least 1".to_owned());
+        }
+
+        if self.reserved_slots > self.max_players {
+            problems.push(format!(
+                "`reserved_slots` must be at most `max_players` ({}), but is {}",
+                self.max_players, self.reserved_slots
+            ));
+        }
+
+        if self.heartbeat_interval.is_zero() {
+            problems.push("`heartbeat_interval` must not be zero".to_owned());
+        } else if self.heartbeat_timeout <= self.heartbeat_interval {
+            problems.push(format!(
+                "`heartbeat_timeout` must be longer than `heartbeat_interval` ({:?}), but is {:?}",
+                self.heartbeat_interval, self.heartbeat_timeout
+            ));
+        }
+
+        if self.name.trim().is_empty() {
+            problems.push("`name` must not be empty".to_owned());
+        } else if self.name.chars().count() > MAX_NAME_LENGTH {
+            problems.push(format!(
+                "`name` must be at most {MAX_NAME_LENGTH} characters long"
+            ));
+        }
+
+        if self.motd.chars().count() > MAX_MOTD_LENGTH {
+            problems.push(format!(
+                "`motd` must be at most {MAX_MOTD_LENGTH} characters long"
+            ));
+        }
+
+        if self.password.as_deref().is_some_and(str::is_empty) {
+            problems.push(
+                "`password` must not be empty. Remove it to allow joining without a password"
+                    .to_owned(),
+            );
+        }
+
+        if let Err(e) = self.transport.to_config() {
+            problems.push(format!("Invalid `transport` settings: {e}"));
+        }
+
+        if let Some(master) = &self.master_server {
+            let has_port = master
+                .rsplit_once(':')
+                .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+            if !has_port {
+                problems.push(format!(
+                    "`master_server` must be of the form `host:port`, but is '{master}'"
+                ));
+            }
+
+            match &self.master_fingerprint {
+                None => problems.push(
+                    "`master_server` is set, so `master_fingerprint` must be set too".to_owned(),
+                ),
+                Some(fingerprint) if parse_fingerprint(fingerprint).is_none() => {
+                    problems.push(format!(
+                        "`master_fingerprint` must be 32 colon-separated hex bytes, but is '{fingerprint}'"
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+
+        match (&self.certificate, &self.private_key) {
+            (Some(_), None) => {
+                problems.push("`certificate` is set, so `private_key` must be set too".to_owned())
+            }
+            (None, Some(_)) => {
+                problems.push("`private_key` is set, so `certificate` must be set too".to_owned())
+            }
+            (Some(certificate), Some(private_key)) => {
+                if let Err(e) = load_certificate(certificate, private_key) {
+                    problems.push(format!("{:#}", anyhow::Error::from(e)));
+                }
+            }
+            (None, None) => {}
+        }
+
+        let limits = &self.rate_limits;
+        if limits.attempts_per_ip == 0 || limits.max_pending_handshakes == 0 {
+            problems.push(
+                "`rate_limits.attempts_per_ip` and `rate_limits.max_pending_handshakes` must be at least 1"
+                    .to_owned(),
+            );
+        }
+
+        if limits.inbound_bytes_burst < limits.inbound_bytes_per_second
+            || limits.inbound_messages_burst < limits.inbound_messages_per_second
+        {
+            problems.push("Inbound bursts in `rate_limits` must be at least as large as their per-second limits".to_owned());
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let mut message = String::from("Invalid configuration:");
+        for problem in problems {
+            let _ = write!(message, "\n  - {problem}");
+        }
+        bail!(message)
+    }
+
+    /// The addresses to listen on, and whether each should be restricted to IPv6 if it is an IPv6 address
+    pub fn listen_addresses(&self) -> Vec<(SocketAddr, bool)> {
+        let ipv4 = (SocketAddr::new(self.ipv4_address.into(), self.port), false);
+        let ipv6 = SocketAddr::new(self.ipv6_address.into(), self.port);
+        match self.bind {
+            BindAddresses::Ipv4 => vec![ipv4],
+            BindAddresses::Ipv6 => vec![(ipv6, true)],
+            // Bind to both families separately, as dual-stack sockets aren't supported everywhere
+            BindAddresses::Both => vec![ipv4, (ipv6, true)],
+        }
+    }
+
+    pub fn heartbeat_settings(&self) -> HeartbeatSettings {
+        HeartbeatSettings {
+            interval: self.heartbeat_interval,
+            timeout: self.heartbeat_timeout,
+        }
+    }
+
+    pub fn lag_compensation_settings(&self) -> LagCompensationSettings {
+        LagCompensationSettings {
+            max_rewind: self.max_rewind,
+        }
+    }
+
+    /// Load the configured TLS certificate, or generate a self-signed one if none was configured
+    pub fn identity(&self) -> anyhow::Result<(Vec<Certificate>, PrivateKey)> {
+        match (&self.certificate, &self.private_key) {
+            (Some(certificate), Some(private_key)) => {
+                Ok(load_certificate(certificate, private_key)?)
+            }
+            _ => {
+                let (certificate, private_key) = generate_certificate(vec!["::1".into()])
+                    .context("Could not generate a self-signed certificate")?;
+                Ok((vec![certificate], private_key))
+            }
+        }
+    }
+}
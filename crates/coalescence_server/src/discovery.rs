@@ -0,0 +1,131 @@
+//! Announces the server on the local network, so that players can find it without typing its address
+
+use std::{io, net::UdpSocket, time::Duration};
+
+use bevy::prelude::*;
+use coalescence_proto::discovery::{Announcement, MAX_ANNOUNCEMENT_LENGTH};
+use coalescence_quinn::discovery::{announce_address, announce_socket};
+
+use crate::{
+    config::{BindAddresses, ServerConfig},
+    status::current_status,
+    ClientConnection, ClientHandshakeState, QuinnServerConfig,
+};
+
+/// How often the server announces itself
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The sockets that announcements are sent from, and when to send the next one
+#[derive(Resource, Debug)]
+struct LanAnnouncer {
+    sockets: Vec<(UdpSocket, bool)>,
+    timer: Timer,
+    /// Whether it has been logged that the message of the day is left out of announcements for being too long
+    warned_too_long: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct LanDiscoveryPlugin;
+
+impl Plugin for LanDiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, start_announcing)
+            .add_systems(Update, announce.run_if(resource_exists::<LanAnnouncer>()));
+    }
+}
+
+fn start_announcing(mut commands: Commands, config: Res<ServerConfig>) {
+    if !config.lan_discovery {
+        return;
+    }
+
+    let families: &[bool] = match config.bind {
+        BindAddresses::Ipv4 => &[false],
+        BindAddresses::Ipv6 => &[true],
+        BindAddresses::Both => &[false, true],
+    };
+
+    let sockets: Vec<_> = families
+        .iter()
+        .filter_map(|&ipv6| match announce_socket(ipv6) {
+            Ok(socket) => Some((socket, ipv6)),
+            Err(e) => {
+                warn!(
+                    "Could not create a socket for announcing the server on the LAN to '{}': {e}",
+                    announce_address(ipv6)
+                );
+                None
+            }
+        })
+        .collect();
+
+    if sockets.is_empty() {
+        return;
+    }
+
+    info!("Announcing the server on the LAN...");
+    let mut timer = Timer::new(ANNOUNCE_INTERVAL, TimerMode::Repeating);
+    // Announce straight away, rather than after the first interval
+    timer.tick(ANNOUNCE_INTERVAL);
+    commands.insert_resource(LanAnnouncer {
+        sockets,
+        timer,
+        warned_too_long: false,
+    });
+}
+
+fn announce(
+    time: Res<Time>,
+    mut announcer: ResMut<LanAnnouncer>,
+    config: Res<ServerConfig>,
+    quinn_config: Res<QuinnServerConfig>,
+    query: Query<&ClientConnection>,
+) {
+    if !announcer.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let players = query
+        .iter()
+        .filter(|client| matches!(client.handshake, ClientHandshakeState::Finished(_)))
+        .count();
+
+    let mut announcement = Announcement {
+        port: config.port,
+        fingerprint: quinn_config.fingerprint,
+        status: current_status(&config, players),
+    };
+
+    let mut bytes = match announcement.encode() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error while serializing LAN announcement: {e}");
+            return;
+        }
+    };
+
+    // Clients still see the message of the day when they query the server
+    if bytes.len() > MAX_ANNOUNCEMENT_LENGTH {
+        if !announcer.warned_too_long {
+            warn!("The LAN announcement is longer than {MAX_ANNOUNCEMENT_LENGTH} bytes, leaving the message of the day out of it");
+            announcer.warned_too_long = true;
+        }
+        announcement.status.motd.clear();
+        bytes = match announcement.encode() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Error while serializing LAN announcement: {e}");
+                return;
+            }
+        };
+    }
+
+    for (socket, ipv6) in &announcer.sockets {
+        match socket.send_to(&bytes, announce_address(*ipv6)) {
+            Ok(_) => {}
+            // Announcements are sent often, so dropping one when the socket is busy doesn't matter
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => debug!("Error while announcing the server on the LAN: {e}"),
+        }
+    }
+}
@@ -1,90 +1,340 @@
-use std::{borrow::Cow, time::Duration};
+use std::{
+    collections::HashSet,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
 
-use bevy::{app::ScheduleRunnerPlugin, log::LogPlugin, prelude::*, tasks::IoTaskPool};
+use access::{AccessControl, AccessPlugin};
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    log::LogPlugin,
+    prelude::*,
+    tasks::IoTaskPool,
+};
+use coalescence_proto::{
+    clock::{ClockPlugin, TickRate},
+    input::{InputPlugin, PlayerInputs},
+    interpolation::Motion,
+    lag_compensation::{AttackResolved, LagCompensationPlugin},
+    packet::{DisconnectReason, Profile, Received},
+    peer::Server,
+    replication::{AppReplicationExt, ReplicationClient, ReplicationPlugin},
+    simulation::{FixedTimestep, SimulationPlugin},
+    ConnectionBundle, DisconnectPeer, PeerDisconnected, ProtoPlugin, PROTOCOL_VERSION,
+};
 use coalescence_quinn::{
-    quinn::{Connecting, Connection, Endpoint},
-    receive_stream_driver::ReceiveStreamDriver,
-    send_stream_driver::SendStreamDriver,
-    server::create_endpoint,
-    AppEndpoint,
+    certificate_fingerprint, client,
+    connection::disconnect,
+    format_fingerprint, peer_fingerprint,
+    quinn::{self, Connecting, Endpoint},
+    resumption::session_cache,
+    server::{create_config, create_endpoint},
+    QuinnConnection, QuinnPlugin,
 };
-use crossbeam::channel::{Receiver, Sender};
+use config::ServerConfig;
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+use discovery::LanDiscoveryPlugin;
+use futures_lite::future;
+use master::{MasterRegistrationPlugin, ServerEndpoints};
+use rate_limit::{
+    ConnectionAttempts, HandshakeDeadline, InboundRateLimiter, PendingHandshakeGuard,
+    PendingHandshakes, RateLimitPlugin, RateLimits,
+};
+use resume::{AwaitingReconnect, Resumable, ResumePlugin, ResumeTokens};
+use slots::{admit_players, SlotsPlugin, WaitingQueue};
+use stats::PlayerStatsPlugin;
+use status::answer_status_queries;
+
+mod access;
+mod config;
+mod discovery;
+mod master;
+mod rate_limit;
+mod resume;
+mod slots;
+mod stats;
+mod status;
 
 #[derive(Debug, Default)]
 enum ClientHandshakeState {
     #[default]
-    ExpectingUsername,
+    ExpectingProfile,
+    /// Sent the server's status instead of joining, and will close the connection once it has received it
+    Queried,
+    /// Waiting in the queue for a player slot to free up
+    Queued(ClientProfile),
     Finished(ClientProfile),
 }
 
+impl ClientHandshakeState {
+    /// The client's profile, if it has finished the handshake
+    fn profile(&self) -> Option<&ClientProfile> {
+        match self {
+            ClientHandshakeState::ExpectingProfile | ClientHandshakeState::Queried => None,
+            ClientHandshakeState::Queued(profile) | ClientHandshakeState::Finished(profile) => {
+                Some(profile)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct ClientProfile {
     pub username: String,
+    /// The fingerprint of the client's certificate, which players are banned and allowed by, as they can't choose it
+    /// like their username
+    pub player: String,
 }
 
-#[derive(Component, Debug)]
+#[derive(Component, Debug, Default)]
 struct ClientConnection {
-    connection: Connection,
     handshake: ClientHandshakeState,
-    send: SendStreamDriver,
-    receive: ReceiveStreamDriver,
 }
 
+/// A connection that has finished the QUIC handshake, and the time it was first accepted at
+type NewClientConnection = (QuinnConnection, Instant);
+
+#[derive(Resource, Debug)]
+struct NewClientConnectionReceiver(Receiver<NewClientConnection>);
+
+/// The QUIC configuration that the server's endpoints are created with
 #[derive(Resource, Debug)]
-struct NewClientConnectionReceiver(Receiver<ClientConnection>);
+struct QuinnServerConfig {
+    config: quinn::ServerConfig,
+    /// The fingerprint of the server's certificate, which players can use to check that they're connecting to the right server
+    fingerprint: [u8; 32],
+}
+
+fn main() -> ExitCode {
+    // Logging isn't set up until the app is built, so configuration errors are printed directly
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let identity = config.identity().and_then(|(chain, key)| {
+        let fingerprint = certificate_fingerprint(&chain[0]);
+        Ok(QuinnServerConfig {
+            config: create_config(chain, key, &config.transport)?,
+            fingerprint,
+        })
+    });
+    let quinn_config = match identity {
+        Ok(quinn_config) => quinn_config,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-fn main() {
     App::new()
+        .insert_resource(config.rate_limits.clone())
+        .insert_resource(config.heartbeat_settings())
+        .insert_resource(TickRate(config.tick_rate))
+        .insert_resource(FixedTimestep::new(config.max_catch_up_ticks))
+        .insert_resource(config.missing_input)
+        .insert_resource(config.lag_compensation_settings())
+        .insert_resource(quinn_config)
         .add_plugins((
-            LogPlugin::default(),
+            LogPlugin {
+                level: config.log_level.into(),
+                ..default()
+            },
             MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
-                1.0 / 60.0, // Run server at 60 updates/s
+                1.0 / config.tick_rate as f64,
             ))),
+            ProtoPlugin::<Server>::default(),
+            QuinnPlugin::<Server>::default(),
+            SimulationPlugin,
+            ClockPlugin::<Server>::default(),
+            (
+                ReplicationPlugin::<Server>::default(),
+                InputPlugin::<Server>::default(),
+                LagCompensationPlugin,
+            ),
+            AccessPlugin {
+                path: config.access_list.clone(),
+            },
+            RateLimitPlugin,
+            SlotsPlugin,
+            ResumePlugin,
+            PlayerStatsPlugin,
+            LanDiscoveryPlugin,
+            MasterRegistrationPlugin,
         ))
+        // The same components, in the same order, as clients
+        .replicate::<Motion>()
+        .insert_resource(config)
         .add_systems(Startup, start_listening)
         .add_systems(
             Update,
-            (poll_new_client_connections, drive_send_streams, handshake),
+            (
+                poll_new_client_connections,
+                (
+                    answer_status_queries,
+                    handshake,
+                    admit_players,
+                    start_replication,
+                )
+                    .chain(),
+                despawn_disconnected_clients,
+                log_attacks,
+            ),
         )
         .run();
+
+    ExitCode::SUCCESS
 }
 
-fn start_listening(mut commands: Commands) {
+fn start_listening(
+    mut commands: Commands,
+    config: Res<ServerConfig>,
+    quinn_config: Res<QuinnServerConfig>,
+    access: Res<AccessControl>,
+    limits: Res<RateLimits>,
+    mut endpoints: ResMut<ServerEndpoints>,
+    mut exit: EventWriter<AppExit>,
+) {
+    info!("Starting server '{}'...", config.name);
+    info!(
+        "Certificate fingerprint: {}",
+        format_fingerprint(&quinn_config.fingerprint)
+    );
+
     let (sender, receiver) = crossbeam::channel::bounded(16);
     commands.insert_resource(NewClientConnectionReceiver(receiver));
 
-    let endpoint = create_endpoint().unwrap();
+    let pending = PendingHandshakes::default();
+    let attempts = ConnectionAttempts::new(&limits);
+    commands.insert_resource(attempts.clone());
 
-    match endpoint.local_addr() {
-        Ok(address) => info!("Server listening on '{address}'..."),
-        Err(e) => error!("{}", e),
-    }
+    for (address, v6_only) in config.listen_addresses() {
+        let mut endpoint = match create_endpoint(quinn_config.config.clone(), address, v6_only) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Could not listen on '{address}': {e}");
+                exit.send(AppExit);
+                return;
+            }
+        };
 
-    IoTaskPool::get()
-        .spawn(accept_connections(endpoint.clone(), sender))
-        .detach();
+        match endpoint.local_addr() {
+            Ok(address) => info!("Server listening on '{address}'..."),
+            Err(e) => error!("{}", e),
+        }
+
+        // Endpoints are also used to connect to the master server
+        let client_config = client::create_config(session_cache(), None)
+            .expect("Client configs without an identity are always valid");
+        endpoint.set_default_client_config(client_config);
+        endpoints.0.push(endpoint.clone());
 
-    commands.insert_resource(AppEndpoint(endpoint));
+        IoTaskPool::get()
+            .spawn(accept_connections(
+                endpoint,
+                sender.clone(),
+                access.clone(),
+                limits.clone(),
+                attempts.clone(),
+                pending.clone(),
+            ))
+            .detach();
+    }
 }
 
-async fn accept_connections(endpoint: Endpoint, sender: Sender<ClientConnection>) {
+async fn accept_connections(
+    endpoint: Endpoint,
+    sender: Sender<NewClientConnection>,
+    access: AccessControl,
+    limits: RateLimits,
+    attempts: ConnectionAttempts,
+    pending: PendingHandshakes,
+) {
     while let Some(connecting) = endpoint.accept().await {
         let address = connecting.remote_address();
+
+        // Dropping a `Connecting` immediately closes it, which is the cheapest way to turn away floods of connections
+        if !attempts.attempt(address.ip()) {
+            continue;
+        }
+
+        let Some(guard) = pending.try_start(limits.max_pending_handshakes) else {
+            warn!("Too many handshakes in progress, ignoring incoming connection from '{address}'");
+            continue;
+        };
+
         if let Some(local_ip) = connecting.local_ip() {
             info!("Incoming connection from '{address}' with local IP '{local_ip}'...");
         } else {
             info!("Incoming connection from '{address}'...");
         }
+
+        if let Err(reason) = access.check_ip(address.ip()) {
+            info!("Rejecting incoming connection from '{address}': {reason}");
+            IoTaskPool::get()
+                .spawn(reject_connection(
+                    connecting,
+                    reason,
+                    guard,
+                    limits.handshake_timeout,
+                ))
+                .detach();
+            continue;
+        }
+
         IoTaskPool::get()
-            .spawn(handle_connection(connecting, sender.clone()))
+            .spawn(handle_connection(
+                connecting,
+                sender.clone(),
+                guard,
+                limits.handshake_timeout,
+            ))
             .detach();
     }
 }
 
-async fn handle_connection(connecting: Connecting, sender: Sender<ClientConnection>) {
+/// Finish the QUIC handshake only so that the client can be told why it isn't allowed to connect. This counts as a
+/// pending handshake and is cut off after the same timeout as any other, so banned clients can't use it to tie the server up
+async fn reject_connection(
+    connecting: Connecting,
+    reason: DisconnectReason,
+    _guard: PendingHandshakeGuard,
+    timeout: Duration,
+) {
+    let connection = future::or(async { connecting.await.ok() }, async {
+        async_io::Timer::after(timeout).await;
+        None
+    })
+    .await;
+
+    if let Some(connection) = connection {
+        disconnect(&connection, reason);
+    }
+}
+
+async fn handle_connection(
+    connecting: Connecting,
+    sender: Sender<NewClientConnection>,
+    _guard: PendingHandshakeGuard,
+    timeout: Duration,
+) {
     let address = connecting.remote_address();
     let local_ip = connecting.local_ip();
-    if let Err(e) = try_handle_connection(connecting, sender).await {
+    let accepted_at = Instant::now();
+
+    let result = future::or(
+        try_handle_connection(connecting, sender, accepted_at),
+        async {
+            async_io::Timer::after(timeout).await;
+            Err(anyhow::anyhow!("Handshake timed out after {timeout:?}"))
+        },
+    )
+    .await;
+
+    if let Err(e) = result {
         if let Some(local_ip) = local_ip {
             error!("Error while handling incoming connection from '{address}' with local IP '{local_ip}': {e}");
         } else {
@@ -95,79 +345,207 @@ async fn handle_connection(connecting: Connecting, sender: Sender<ClientConnecti
 
 async fn try_handle_connection(
     connecting: Connecting,
-    sender: Sender<ClientConnection>,
+    sender: Sender<NewClientConnection>,
+    accepted_at: Instant,
 ) -> anyhow::Result<()> {
     let connection = connecting.await?;
     let (send, receive) = connection.accept_bi().await?;
 
-    let client_connection = ClientConnection {
-        connection,
-        handshake: default(),
-        send: SendStreamDriver::new(send),
-        receive: ReceiveStreamDriver::new(receive),
-    };
-
-    sender.send(client_connection)?;
-    Ok(())
+    // Never block here, as that would stall the task pool thread until the main loop catches up
+    match sender.try_send((QuinnConnection::new(connection, send, receive), accepted_at)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full((quinn, _))) => {
+            disconnect(quinn.connection(), DisconnectReason::ServerBusy);
+            Err(anyhow::anyhow!(
+                "Too many new connections are waiting to be processed"
+            ))
+        }
+        Err(TrySendError::Disconnected(_)) => Err(anyhow::anyhow!("The server is shutting down")),
+    }
 }
 
-fn poll_new_client_connections(mut commands: Commands, receiver: Res<NewClientConnectionReceiver>) {
-    for new_connection in receiver.0.try_iter() {
-        let id = new_connection.connection.stable_id();
-        let address = new_connection.connection.remote_address();
-        if let Some(local_ip) = new_connection.connection.local_ip() {
+fn poll_new_client_connections(
+    mut commands: Commands,
+    receiver: Res<NewClientConnectionReceiver>,
+    limits: Res<RateLimits>,
+) {
+    for (new_connection, accepted_at) in receiver.0.try_iter() {
+        let id = new_connection.connection().stable_id();
+        let address = new_connection.connection().remote_address();
+        if let Some(local_ip) = new_connection.connection().local_ip() {
             info!("Connection established with client ID '{id}', address '{address}' and local IP '{local_ip}'!");
         } else {
             info!("Connection established with client ID '{id}', address '{address}'!");
         }
-        commands.spawn(new_connection);
+        commands.spawn((
+            ClientConnection::default(),
+            new_connection,
+            ConnectionBundle::<Server>::default(),
+            InboundRateLimiter::new(&limits),
+            HandshakeDeadline(accepted_at + limits.handshake_timeout),
+        ));
     }
 }
 
-fn drive_send_streams(mut query: Query<&mut ClientConnection>) {
-    for mut client in query.iter_mut() {
-        if let Err(e) = client.send.drive() {
-            error!(
-                "Error while sending data to client ID '{}': {e}",
-                client.connection.stable_id()
-            );
-        }
-    }
-}
+fn handshake(
+    mut query: Query<(
+        Entity,
+        &mut ClientConnection,
+        &QuinnConnection,
+        &mut Received<Profile>,
+    )>,
+    config: Res<ServerConfig>,
+    access: Res<AccessControl>,
+    mut queue: ResMut<WaitingQueue>,
+    mut disconnect: EventWriter<DisconnectPeer>,
+) {
+    // Usernames are compared case-insensitively, so that players can't pass themselves off as someone else
+    let mut usernames: HashSet<String> = query
+        .iter()
+        .filter_map(|(_, client, ..)| client.handshake.profile())
+        .map(|profile| profile.username.to_lowercase())
+        .collect();
 
-fn handshake(mut query: Query<&mut ClientConnection>) {
-    for mut client in query.iter_mut() {
+    for (entity, mut client, quinn, mut profiles) in query.iter_mut() {
         match client.handshake {
-            ClientHandshakeState::ExpectingUsername => {
-                match client.receive.try_receive(256, true) {
-                    Some(Ok(Some(username))) => {
-                        let username = String::from_utf8_lossy(&username.bytes);
-                        let warn = matches!(username, Cow::Owned(_));
-                        let username = username.into_owned();
-
-                        if warn {
-                            warn!(
-                                "Received non-UTF8 username from client ID '{}': '{username}'",
-                                client.connection.stable_id()
-                            );
-                        } else {
-                            info!(
-                                "Received username from client ID '{}': '{username}'",
-                                client.connection.stable_id()
-                            );
-                        }
-
-                        client.handshake =
-                            ClientHandshakeState::Finished(ClientProfile { username });
-                    }
-                    Some(Err(e)) => error!(
-                        "Error while receiving username from client ID '{}': {e}",
-                        client.connection.stable_id()
-                    ),
-                    Some(Ok(None)) | None => continue,
+            ClientHandshakeState::ExpectingProfile => {
+                let Some(Profile {
+                    protocol_version,
+                    username,
+                    password,
+                }) = profiles.buffer.drain(..).next()
+                else {
+                    continue;
+                };
+
+                let id = quinn.connection().stable_id();
+                if protocol_version != PROTOCOL_VERSION {
+                    info!("Rejecting client ID '{id}' with username '{username}': Protocol version {protocol_version} is incompatible");
+                    disconnect.send(DisconnectPeer {
+                        entity,
+                        reason: DisconnectReason::IncompatibleVersion {
+                            server_version: PROTOCOL_VERSION,
+                        },
+                    });
+                    continue;
                 }
+
+                let Some(fingerprint) = peer_fingerprint(quinn.connection()) else {
+                    info!("Rejecting client ID '{id}' with username '{username}': No identity");
+                    disconnect.send(DisconnectPeer {
+                        entity,
+                        reason: DisconnectReason::MissingIdentity,
+                    });
+                    continue;
+                };
+                let player = format_fingerprint(&fingerprint);
+                info!("Received username from client ID '{id}' with player ID '{player}': '{username}'");
+
+                if config.password.is_some() && password != config.password {
+                    info!(
+                        "Rejecting client ID '{id}' with username '{username}': Incorrect password"
+                    );
+                    disconnect.send(DisconnectPeer {
+                        entity,
+                        reason: DisconnectReason::WrongPassword,
+                    });
+                    continue;
+                }
+
+                if let Err(reason) = access.check_player(&player) {
+                    info!("Rejecting client ID '{id}' with username '{username}': {reason}");
+                    disconnect.send(DisconnectPeer { entity, reason });
+                    continue;
+                }
+
+                if !usernames.insert(username.to_lowercase()) {
+                    info!("Rejecting client ID '{id}' with username '{username}': Username taken");
+                    disconnect.send(DisconnectPeer {
+                        entity,
+                        reason: DisconnectReason::UsernameTaken,
+                    });
+                    continue;
+                }
+
+                // Everyone goes through the queue, even if there are free slots, so that admins are always let in first
+                let is_admin = config
+                    .admins
+                    .iter()
+                    .any(|admin| admin.eq_ignore_ascii_case(&player));
+                queue.push(entity, is_admin);
+                client.handshake = ClientHandshakeState::Queued(ClientProfile { username, player });
             }
-            ClientHandshakeState::Finished(_) => continue,
+            ClientHandshakeState::Queried
+            | ClientHandshakeState::Queued(_)
+            | ClientHandshakeState::Finished(_) => continue,
+        }
+    }
+}
+
+/// Start sending replicated entities to players once they've been let in
+fn start_replication(
+    mut commands: Commands,
+    query: Query<
+        (Entity, &ClientConnection),
+        (Without<ReplicationClient>, Without<AwaitingReconnect>),
+    >,
+    config: Res<ServerConfig>,
+) {
+    for (entity, client) in query.iter() {
+        if matches!(client.handshake, ClientHandshakeState::Finished(_)) {
+            commands.entity(entity).insert((
+                ReplicationClient::default(),
+                config.relevance.interest(),
+                PlayerInputs::default(),
+            ));
+        }
+    }
+}
+
+/// Log what players' attacks hit. The server doesn't simulate the game, so it has no rules to apply the hits with, and
+/// this is where they would be applied
+fn log_attacks(mut events: EventReader<AttackResolved>, clients: Query<&ClientConnection>) {
+    for attack in events.read() {
+        let username = clients
+            .get(attack.attacker)
+            .ok()
+            .and_then(|client| client.handshake.profile())
+            .map_or("Unknown player", |profile| profile.username.as_str());
+        debug!(
+            "{username} attacked in '{}' as of tick {}, hitting {:?}",
+            attack.room.0, attack.tick.0, attack.hits
+        );
+    }
+}
+
+fn despawn_disconnected_clients(
+    mut commands: Commands,
+    mut events: EventReader<PeerDisconnected>,
+    resumable: Query<&Resumable>,
+    mut tokens: ResMut<ResumeTokens>,
+    config: Res<ServerConfig>,
+) {
+    for PeerDisconnected { entity, reason } in events.read() {
+        let resumable = resumable.get(*entity).ok();
+        if resume::is_held(resumable.is_some(), reason) {
+            info!(
+                "Client on entity {entity:?} disconnected: {reason}. Holding its place for {:?}...",
+                config.reconnect_grace_period
+            );
+            commands
+                .entity(*entity)
+                .insert(AwaitingReconnect {
+                    deadline: Instant::now() + config.reconnect_grace_period,
+                })
+                // Starts over with a full snapshot if they come back
+                .remove::<ReplicationClient>();
+            continue;
+        }
+
+        if let Some(resumable) = resumable {
+            resume::forget(&mut tokens, resumable);
         }
+        info!("Client on entity {entity:?} disconnected: {reason}");
+        commands.entity(*entity).despawn();
     }
 }
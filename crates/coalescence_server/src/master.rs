@@ -0,0 +1,319 @@
+//! Registers the server with a master server, so that players can find it in the public server list.
+//!
+//! Registration is opt-in, by setting the `master_server` config value, along with `master_fingerprint` to pin the master
+//! server's certificate. Requests are sent from the server's own endpoints, rather than a separate socket, so that the
+//! master server sees the same address that players will connect to.
+//!
+//! The connection to the master server is also used to receive notifications asking the server to punch holes through
+//! its NAT, either towards clients that want to connect, or towards relays on the master server
+
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use bevy::{
+    prelude::*,
+    tasks::{block_on, IoTaskPool, Task},
+};
+use coalescence_proto::{
+    master::{MasterNotification, MasterRequest, MasterResponse},
+    packet::DisconnectReason,
+};
+use coalescence_quinn::{
+    connection::disconnect,
+    master::{punch, read_message, request},
+    parse_fingerprint, peer_fingerprint,
+    quinn::{Connection, Endpoint},
+};
+use futures_lite::future::poll_once;
+
+use crate::{config::ServerConfig, status::current_status, ClientConnection, ClientHandshakeState};
+
+/// How long to wait before trying again after a request fails
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The endpoints that the server is listening on
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ServerEndpoints(pub Vec<Endpoint>);
+
+#[derive(Debug)]
+enum RegistrationState {
+    Unregistered,
+    Registered {
+        token: u64,
+        heartbeat_interval: Duration,
+    },
+}
+
+type MasterRequestTask = Task<anyhow::Result<(Connection, MasterResponse)>>;
+
+#[derive(Resource, Debug)]
+struct MasterRegistration {
+    /// The address of the master server, as `host:port`
+    master: String,
+    /// The fingerprint of the master server's certificate, which it's only trusted if it presents
+    fingerprint: [u8; 32],
+    state: RegistrationState,
+    /// Kept open between requests, so that each heartbeat doesn't need a new handshake
+    connection: Option<Connection>,
+    request: Option<MasterRequestTask>,
+    timer: Timer,
+}
+
+#[derive(Debug, Default)]
+pub struct MasterRegistrationPlugin;
+
+impl Plugin for MasterRegistrationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerEndpoints>()
+            .add_systems(Startup, start_registration)
+            .add_systems(
+                Update,
+                register_with_master.run_if(resource_exists::<MasterRegistration>()),
+            );
+    }
+}
+
+fn start_registration(mut commands: Commands, config: Res<ServerConfig>) {
+    let Some(master) = config.master_server.clone() else {
+        return;
+    };
+    let Some(fingerprint) = config
+        .master_fingerprint
+        .as_deref()
+        .and_then(parse_fingerprint)
+    else {
+        // The config was validated, so this can't happen
+        error!("The master server's fingerprint is missing or invalid, so the server won't be registered");
+        return;
+    };
+
+    info!("Registering the server with the master server at '{master}'...");
+    let mut timer = Timer::new(RETRY_INTERVAL, TimerMode::Once);
+    // Register straight away, rather than after the first interval
+    timer.tick(RETRY_INTERVAL);
+    commands.insert_resource(MasterRegistration {
+        master,
+        fingerprint,
+        state: RegistrationState::Unregistered,
+        connection: None,
+        request: None,
+        timer,
+    });
+}
+
+fn register_with_master(
+    time: Res<Time>,
+    mut registration: ResMut<MasterRegistration>,
+    endpoints: Res<ServerEndpoints>,
+    config: Res<ServerConfig>,
+    query: Query<&ClientConnection>,
+) {
+    if let Some(task) = &mut registration.request {
+        if let Some(result) = block_on(poll_once(task)) {
+            registration.request = None;
+            handle_response(&mut registration, result, &endpoints);
+        }
+        return;
+    }
+
+    if !registration.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let players = query
+        .iter()
+        .filter(|client| matches!(client.handshake, ClientHandshakeState::Finished(_)))
+        .count();
+    let status = current_status(&config, players);
+
+    let master_request = match registration.state {
+        RegistrationState::Unregistered => MasterRequest::Register {
+            port: config.port,
+            status,
+        },
+        RegistrationState::Registered { token, .. } => MasterRequest::Heartbeat { token, status },
+    };
+
+    let connection = registration.connection.clone();
+    let master = registration.master.clone();
+    let fingerprint = registration.fingerprint;
+    let endpoints = endpoints.0.clone();
+    registration.request = Some(IoTaskPool::get().spawn(async move {
+        let connection = match connection {
+            Some(connection) if connection.close_reason().is_none() => connection,
+            _ => connect(&master, fingerprint, &endpoints).await?,
+        };
+        let response = request(&connection, &master_request).await?;
+        Ok((connection, response))
+    }));
+}
+
+fn handle_response(
+    registration: &mut MasterRegistration,
+    result: anyhow::Result<(Connection, MasterResponse)>,
+    endpoints: &ServerEndpoints,
+) {
+    // Retry after the default interval unless the response says otherwise
+    let mut next_request = RETRY_INTERVAL;
+
+    match result {
+        Ok((connection, response)) => {
+            let is_new = registration
+                .connection
+                .as_ref()
+                .map_or(true, |old| old.stable_id() != connection.stable_id());
+            if is_new {
+                IoTaskPool::get()
+                    .spawn(receive_notifications(
+                        connection.clone(),
+                        endpoints.0.clone(),
+                    ))
+                    .detach();
+            }
+
+            registration.connection = Some(connection);
+            match response {
+                MasterResponse::Registered {
+                    token,
+                    heartbeat_interval,
+                } => {
+                    info!("Registered with the master server");
+                    registration.state = RegistrationState::Registered {
+                        token,
+                        heartbeat_interval,
+                    };
+                    next_request = heartbeat_interval;
+                }
+                MasterResponse::Ok => {
+                    if let RegistrationState::Registered {
+                        heartbeat_interval, ..
+                    } = registration.state
+                    {
+                        next_request = heartbeat_interval;
+                    }
+                }
+                MasterResponse::UnknownToken => {
+                    info!("The master server forgot about this server, registering again...");
+                    registration.state = RegistrationState::Unregistered;
+                    next_request = Duration::ZERO;
+                }
+                MasterResponse::Rejected(reason) => {
+                    warn!("The master server rejected this server: {reason}");
+                    registration.state = RegistrationState::Unregistered;
+                }
+                MasterResponse::ServerList { .. }
+                | MasterResponse::PunchStarted { .. }
+                | MasterResponse::RelayAllocated { .. } => {
+                    warn!("The master server sent an unexpected response");
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Error while communicating with the master server: {e:#}");
+            registration.connection = None;
+        }
+    }
+
+    registration.timer = Timer::new(next_request, TimerMode::Once);
+}
+
+/// Handle notifications from the master server until the connection to it closes
+async fn receive_notifications(connection: Connection, endpoints: Vec<Endpoint>) {
+    loop {
+        let mut receive = match connection.accept_uni().await {
+            Ok(receive) => receive,
+            Err(e) => {
+                debug!("Stopped receiving notifications from the master server: {e}");
+                return;
+            }
+        };
+
+        let notification = match read_message(&mut receive).await {
+            Ok(notification) => notification,
+            Err(e) => {
+                warn!("Error while receiving a notification from the master server: {e}");
+                continue;
+            }
+        };
+
+        let address = match notification {
+            MasterNotification::Punch { client } => {
+                info!("Punching a hole towards client '{client}'...");
+                client
+            }
+            MasterNotification::Relay { port } => {
+                let address = SocketAddr::new(connection.remote_address().ip(), port);
+                info!("Punching a hole towards relay '{address}'...");
+                address
+            }
+        };
+
+        let Some(endpoint) = endpoint_for(&endpoints, address) else {
+            warn!("None of the server's endpoints can reach '{address}'");
+            continue;
+        };
+
+        IoTaskPool::get()
+            .spawn(async move { punch(&endpoint, address).await })
+            .detach();
+    }
+}
+
+/// Find the endpoint with the same address family as the given address
+fn endpoint_for(endpoints: &[Endpoint], address: SocketAddr) -> Option<Endpoint> {
+    endpoints
+        .iter()
+        .find(|endpoint| {
+            endpoint
+                .local_addr()
+                .is_ok_and(|local| local.is_ipv6() == address.is_ipv6())
+        })
+        .cloned()
+}
+
+/// Connect to the master server from whichever of the server's endpoints can reach it, checking that it presented the
+/// certificate it's pinned to before anything is sent to it
+async fn connect(
+    master: &str,
+    fingerprint: [u8; 32],
+    endpoints: &[Endpoint],
+) -> anyhow::Result<Connection> {
+    // `to_socket_addrs` is blocking with no async alternative, so putting it in the task makes no difference
+    let addresses: Vec<SocketAddr> = master
+        .to_socket_addrs()
+        .with_context(|| format!("Could not resolve the master server address '{master}'"))?
+        .collect();
+
+    // The server name is only used for certificate verification, which is replaced by checking the fingerprint
+    let server_name = master
+        .rsplit_once(':')
+        .map_or(master, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    for address in addresses {
+        let Some(endpoint) = endpoint_for(endpoints, address) else {
+            continue;
+        };
+
+        match endpoint.connect(address, server_name)?.await {
+            Ok(connection) if peer_fingerprint(&connection) == Some(fingerprint) => {
+                return Ok(connection)
+            }
+            Ok(connection) => {
+                disconnect(&connection, DisconnectReason::Quit);
+                return Err(anyhow!(
+                    "The master server at '{address}' did not present the certificate with the configured fingerprint"
+                ));
+            }
+            Err(e) => debug!("Could not connect to the master server at '{address}': {e}"),
+        }
+    }
+
+    Err(anyhow!(
+        "Could not connect to the master server at '{master}'"
+    ))
+}
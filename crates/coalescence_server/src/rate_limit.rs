@@ -0,0 +1,322 @@
+//! Flood protection: limits on how often connections may be attempted, how long and how many handshakes may be in progress,
+//! and how much data each connection may send to the server.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use coalescence_proto::{packet::DisconnectReason, DisconnectPeer, PacketReceiver, ReceivePackets};
+use coalescence_quinn::{QuinnConnection, ReceiveBytes};
+use serde::Deserialize;
+
+use crate::{access::canonical_ip, ClientConnection};
+
+#[derive(Resource, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// How many connections a single IP address may attempt within `attempt_window`
+    pub attempts_per_ip: u32,
+    #[serde(with = "humantime_serde")]
+    pub attempt_window: Duration,
+    /// How many status queries a single IP address may make within `attempt_window`. These don't count towards
+    /// `attempts_per_ip` once they're known to be queries, so that refreshing a server browser doesn't stop the player
+    /// from joining
+    pub status_queries_per_ip: u32,
+    /// How long a client has to complete both the QUIC and application handshakes before it is disconnected
+    #[serde(with = "humantime_serde")]
+    pub handshake_timeout: Duration,
+    /// How many QUIC handshakes may be in progress at once
+    pub max_pending_handshakes: usize,
+    /// How many bytes each connection may send per second, on average
+    pub inbound_bytes_per_second: u32,
+    /// How many bytes each connection may send in a single burst
+    pub inbound_bytes_burst: u32,
+    /// How many stream chunks and datagrams each connection may send per second, on average
+    pub inbound_messages_per_second: u32,
+    /// How many stream chunks and datagrams each connection may send in a single burst
+    pub inbound_messages_burst: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            attempts_per_ip: 5,
+            attempt_window: Duration::from_secs(10),
+            status_queries_per_ip: 30,
+            handshake_timeout: Duration::from_secs(10),
+            max_pending_handshakes: 64,
+            inbound_bytes_per_second: 256 * 1024,
+            inbound_bytes_burst: 512 * 1024,
+            inbound_messages_per_second: 500,
+            inbound_messages_burst: 1000,
+        }
+    }
+}
+
+/// A token bucket: allows up to `capacity` units through at once, refilling at `rate` units per second
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, capacity: u32) -> Self {
+        Self {
+            capacity: capacity.into(),
+            rate: rate.into(),
+            tokens: capacity.into(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take the given amount of tokens from the bucket, returning false if there weren't enough
+    fn try_take(&mut self, amount: usize, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        let amount = amount as f64;
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            self.tokens = 0.0;
+            false
+        }
+    }
+}
+
+/// Counts connection attempts per IP address over a fixed window of time, shared between the tasks that accept incoming
+/// connections so that listening on both an IPv4 and an IPv6 endpoint doesn't double the limit, and with the ECS world
+/// so that status queries can be moved to their own limit
+#[derive(Resource, Debug, Clone)]
+pub struct ConnectionAttempts(Arc<Mutex<AttemptCounts>>);
+
+#[derive(Debug)]
+struct AttemptCounts {
+    limit: u32,
+    query_limit: u32,
+    window: Duration,
+    attempts: HashMap<IpAddr, AttemptWindow>,
+    last_prune: Instant,
+}
+
+#[derive(Debug)]
+struct AttemptWindow {
+    start: Instant,
+    attempts: u32,
+    queries: u32,
+}
+
+impl ConnectionAttempts {
+    pub fn new(limits: &RateLimits) -> Self {
+        Self(Arc::new(Mutex::new(AttemptCounts {
+            limit: limits.attempts_per_ip,
+            query_limit: limits.status_queries_per_ip,
+            window: limits.attempt_window,
+            attempts: HashMap::new(),
+            last_prune: Instant::now(),
+        })))
+    }
+
+    /// Record a connection attempt from the given address, returning false if it has made too many attempts recently
+    pub fn attempt(&self, ip: IpAddr) -> bool {
+        self.0.lock().unwrap().attempt(canonical_ip(ip))
+    }
+
+    /// Count a connection attempt from the given address as a status query instead, as long as it hasn't made too many
+    /// of them recently
+    pub fn count_as_query(&self, ip: IpAddr) {
+        self.0.lock().unwrap().count_as_query(canonical_ip(ip));
+    }
+}
+
+impl AttemptCounts {
+    fn attempt(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        // Forget addresses whose windows have ended, so the map can't grow forever
+        if now.duration_since(self.last_prune) >= self.window {
+            self.attempts
+                .retain(|_, window| now.duration_since(window.start) < self.window);
+            self.last_prune = now;
+        }
+
+        let window = self.attempts.entry(ip).or_insert(AttemptWindow {
+            start: now,
+            attempts: 0,
+            queries: 0,
+        });
+        if now.duration_since(window.start) >= self.window {
+            *window = AttemptWindow {
+                start: now,
+                attempts: 0,
+                queries: 0,
+            };
+        }
+
+        window.attempts += 1;
+        if window.attempts == self.limit + 1 {
+            warn!(
+                "'{ip}' exceeded {} connection attempts within {:?}, ignoring it for the rest of the window",
+                self.limit, self.window
+            );
+        }
+
+        window.attempts <= self.limit
+    }
+
+    fn count_as_query(&mut self, ip: IpAddr) {
+        // The window may have already ended, in which case the attempt was forgotten anyway
+        let Some(window) = self.attempts.get_mut(&ip) else {
+            return;
+        };
+        if window.queries < self.query_limit && window.attempts > 0 {
+            window.queries += 1;
+            window.attempts -= 1;
+        }
+    }
+}
+
+/// Counts QUIC handshakes that are in progress, shared between the tasks that perform them
+#[derive(Debug, Clone, Default)]
+pub struct PendingHandshakes(Arc<AtomicUsize>);
+
+impl PendingHandshakes {
+    /// Start a handshake, unless `limit` handshakes are already in progress.
+    /// The handshake counts as in progress until the returned guard is dropped
+    pub fn try_start(&self, limit: usize) -> Option<PendingHandshakeGuard> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (pending < limit).then_some(pending + 1)
+            })
+            .ok()
+            .map(|_| PendingHandshakeGuard(self.0.clone()))
+    }
+}
+
+#[derive(Debug)]
+pub struct PendingHandshakeGuard(Arc<AtomicUsize>);
+
+impl Drop for PendingHandshakeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A component limiting how much data a connection may send us
+#[derive(Component, Debug)]
+pub struct InboundRateLimiter {
+    bytes: TokenBucket,
+    messages: TokenBucket,
+}
+
+impl InboundRateLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            bytes: TokenBucket::new(limits.inbound_bytes_per_second, limits.inbound_bytes_burst),
+            messages: TokenBucket::new(
+                limits.inbound_messages_per_second,
+                limits.inbound_messages_burst,
+            ),
+        }
+    }
+}
+
+/// A component recording when a client must have finished the handshake by, or else be disconnected
+#[derive(Component, Debug)]
+pub struct HandshakeDeadline(pub Instant);
+
+#[derive(Debug, Default)]
+pub struct RateLimitPlugin;
+
+impl Plugin for RateLimitPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RateLimits>()
+            .add_systems(Startup, log_rate_limits)
+            .add_systems(
+                PreUpdate,
+                limit_inbound_traffic
+                    .after(ReceiveBytes)
+                    .before(ReceivePackets),
+            )
+            .add_systems(Update, time_out_handshakes);
+    }
+}
+
+fn log_rate_limits(limits: Res<RateLimits>) {
+    info!("Rate limits: {limits:?}");
+}
+
+/// Disconnect any clients that sent more data than they're allowed to, before any of it gets deserialized
+fn limit_inbound_traffic(
+    mut query: Query<(
+        Entity,
+        &QuinnConnection,
+        &mut InboundRateLimiter,
+        &mut PacketReceiver,
+    )>,
+    mut disconnect: EventWriter<DisconnectPeer>,
+) {
+    let now = Instant::now();
+    for (entity, quinn, mut limiter, mut receiver) in query.iter_mut() {
+        let traffic = quinn.received_this_update();
+        if traffic.bytes == 0 {
+            continue;
+        }
+
+        let bytes_ok = limiter.bytes.try_take(traffic.bytes, now);
+        let messages_ok = limiter.messages.try_take(traffic.messages, now);
+        if !(bytes_ok && messages_ok) {
+            warn!(
+                "Client ID '{}' exceeded its inbound rate limit, disconnecting it",
+                quinn.connection().stable_id()
+            );
+            receiver.clear();
+            disconnect.send(DisconnectPeer {
+                entity,
+                reason: DisconnectReason::RateLimited,
+            });
+        }
+    }
+}
+
+fn time_out_handshakes(
+    mut commands: Commands,
+    query: Query<(
+        Entity,
+        &ClientConnection,
+        &QuinnConnection,
+        &HandshakeDeadline,
+    )>,
+    mut disconnect: EventWriter<DisconnectPeer>,
+) {
+    let now = Instant::now();
+    for (entity, client, quinn, deadline) in query.iter() {
+        if client.handshake.profile().is_some() {
+            commands.entity(entity).remove::<HandshakeDeadline>();
+        } else if now >= deadline.0 {
+            commands.entity(entity).remove::<HandshakeDeadline>();
+            info!(
+                "Client ID '{}' took too long to finish the handshake, disconnecting it",
+                quinn.connection().stable_id()
+            );
+            disconnect.send(DisconnectPeer {
+                entity,
+                reason: DisconnectReason::Timeout,
+            });
+        }
+    }
+}
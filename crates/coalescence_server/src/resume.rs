@@ -0,0 +1,200 @@
+//! Letting players whose connection dropped reconnect and take their place back.
+//!
+//! Players are given a resume token once they've been let in. If their connection drops by accident, their entity and
+//! player slot are held for a grace period, during which they can reconnect and send the token instead of a profile to
+//! pick up where they left off. Once the grace period is over, they're treated as having left
+
+use std::{collections::HashMap, time::Instant};
+
+use bevy::prelude::*;
+use coalescence_proto::{
+    packet::{DisconnectReason, Lobby, Received, Resume, ResumeToken, SessionResumable},
+    peer::Server,
+    ConnectionBundle, DisconnectPeer, PacketSender,
+};
+use coalescence_quinn::QuinnConnection;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{
+    access::AccessControl,
+    config::ServerConfig,
+    rate_limit::{InboundRateLimiter, RateLimits},
+    slots::{admit_players, announce_departure},
+    ClientConnection, ClientHandshakeState,
+};
+
+/// The players that can be resumed, by their resume token
+#[derive(Resource, Debug, Default)]
+pub struct ResumeTokens(HashMap<ResumeToken, Entity>);
+
+/// A component on players that have been given a resume token
+#[derive(Component, Debug)]
+pub struct Resumable {
+    pub token: ResumeToken,
+}
+
+/// A component on players whose connection dropped, holding their place until the deadline
+#[derive(Component, Debug)]
+pub struct AwaitingReconnect {
+    pub deadline: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct ResumePlugin;
+
+impl Plugin for ResumePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ResumeTokens>().add_systems(
+            Update,
+            (
+                issue_resume_tokens.after(admit_players),
+                resume_sessions,
+                expire_held_players,
+            ),
+        );
+    }
+}
+
+/// Whether a player that disconnected for the given reason has their place held for them to reconnect
+pub fn is_held(resumable: bool, reason: &DisconnectReason) -> bool {
+    resumable && reason.is_resumable()
+}
+
+/// Forget a player's resume token once they've left for good
+pub fn forget(tokens: &mut ResumeTokens, resumable: &Resumable) {
+    tokens.0.remove(&resumable.token);
+}
+
+fn issue_resume_tokens(
+    mut commands: Commands,
+    mut clients: Query<(Entity, &ClientConnection, &mut PacketSender<Server>), Without<Resumable>>,
+    mut tokens: ResMut<ResumeTokens>,
+    config: Res<ServerConfig>,
+) {
+    // A grace period of zero turns resuming off
+    if config.reconnect_grace_period.is_zero() {
+        return;
+    }
+
+    let random = SystemRandom::new();
+    for (entity, client, mut sender) in clients.iter_mut() {
+        if !matches!(client.handshake, ClientHandshakeState::Finished(_)) {
+            continue;
+        }
+
+        let mut token = [0; 16];
+        if random.fill(&mut token).is_err() {
+            error!("Could not generate a resume token for the client on entity {entity:?}");
+            continue;
+        }
+        let token = ResumeToken(token);
+
+        let packet = SessionResumable {
+            token,
+            grace_period: config.reconnect_grace_period,
+        };
+        if let Err(e) = sender.send(packet) {
+            error!("Error while sending resume token: {e}");
+            continue;
+        }
+
+        tokens.0.insert(token, entity);
+        commands.entity(entity).insert(Resumable { token });
+    }
+}
+
+// Needs to be an exclusive system to be able to move the new connection onto the held player's entity
+fn resume_sessions(world: &mut World) {
+    let requests: Vec<_> = world
+        .query::<(Entity, &ClientConnection, &mut Received<Resume>)>()
+        .iter_mut(world)
+        .filter_map(|(entity, client, mut received)| {
+            let request = received.buffer.drain(..).last()?;
+            matches!(client.handshake, ClientHandshakeState::ExpectingProfile)
+                .then_some((entity, request.token))
+        })
+        .collect();
+
+    for (new, token) in requests {
+        let held = world.resource::<ResumeTokens>().0.get(&token).copied();
+        let profile = held.and_then(|held| {
+            let client = world.get::<ClientConnection>(held)?;
+            client
+                .handshake
+                .profile()
+                .map(|profile| (profile.username.clone(), profile.player.clone()))
+        });
+        let (Some(held), Some((username, player))) = (held, profile) else {
+            info!("Rejecting client on entity {new:?}: Its resume token is unknown or has expired");
+            world.send_event(DisconnectPeer {
+                entity: new,
+                reason: DisconnectReason::SessionExpired,
+            });
+            continue;
+        };
+
+        // Players may have been banned while they were away
+        if let Err(reason) = world.resource::<AccessControl>().check_player(&player) {
+            info!("Rejecting client on entity {new:?} resuming as '{username}': {reason}");
+            world.send_event(DisconnectPeer {
+                entity: new,
+                reason,
+            });
+            continue;
+        }
+
+        let Some(quinn) = world.entity_mut(new).take::<QuinnConnection>() else {
+            continue;
+        };
+        world.despawn(new);
+
+        info!("'{username}' reconnected, resuming their session on entity {held:?}");
+
+        let limiter = InboundRateLimiter::new(world.resource::<RateLimits>());
+        let mut entity = world.entity_mut(held);
+        // The old connection may not have been noticed dropping yet
+        if let Some(mut old) = entity.take::<QuinnConnection>() {
+            old.disconnect(DisconnectReason::Quit);
+        }
+        entity.remove::<AwaitingReconnect>();
+        entity.insert((quinn, ConnectionBundle::<Server>::default(), limiter));
+
+        // Anything sent while they were away was lost along with the old connection, so start them over with the lobby
+        let usernames = world
+            .query::<&ClientConnection>()
+            .iter(world)
+            .filter_map(|client| match &client.handshake {
+                ClientHandshakeState::Finished(profile) => Some(profile.username.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut sender = world.get_mut::<PacketSender<Server>>(held).unwrap();
+        if let Err(e) = sender.send(Lobby { usernames }) {
+            error!("Error while sending the lobby to a resumed player: {e}");
+        }
+    }
+}
+
+fn expire_held_players(
+    mut commands: Commands,
+    held: Query<(Entity, &AwaitingReconnect, &Resumable)>,
+    mut clients: Query<(Entity, &ClientConnection, &mut PacketSender<Server>)>,
+    mut tokens: ResMut<ResumeTokens>,
+) {
+    let now = Instant::now();
+    for (entity, awaiting, resumable) in held.iter() {
+        if now < awaiting.deadline {
+            continue;
+        }
+
+        forget(&mut tokens, resumable);
+        if let Ok((_, ClientConnection { handshake }, _)) = clients.get(entity) {
+            if let Some(profile) = handshake.profile() {
+                let username = profile.username.clone();
+                info!("'{username}' didn't reconnect in time, giving their place away");
+                announce_departure(entity, &username, &mut clients);
+            }
+        }
+        commands.entity(entity).despawn();
+    }
+}
@@ -0,0 +1,249 @@
+//! Player slots: the cap on how many players may be connected at once, the queue of clients waiting for a slot to free up,
+//! and the slots reserved for admins.
+//!
+//! Clients enter the queue once they've finished the handshake. Admins are queued ahead of everyone else, but are still
+//! served first-come-first-served amongst themselves. Non-admins may only take slots that aren't reserved.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use coalescence_proto::{
+    packet::{DisconnectReason, Lobby, PlayerJoined, PlayerLeft, QueuePosition},
+    peer::Server,
+    DisconnectPeer, PacketSender, PeerDisconnected,
+};
+use serde::Deserialize;
+
+use crate::{
+    config::ServerConfig,
+    resume::{self, Resumable},
+    ClientConnection, ClientHandshakeState, ClientProfile,
+};
+
+/// What to do with clients that finish the handshake while all player slots are taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FullBehaviour {
+    /// Disconnect the client, telling it that the server is full
+    Reject,
+    /// Put the client in a queue until a slot frees up
+    #[default]
+    Queue,
+}
+
+#[derive(Debug)]
+struct QueueEntry {
+    entity: Entity,
+    is_admin: bool,
+    /// The position that the client was last told it was at, so it's only told again when it changes
+    last_sent_position: Option<usize>,
+}
+
+/// Clients waiting for a player slot to free up, in the order they'll be admitted
+#[derive(Resource, Debug, Default)]
+pub struct WaitingQueue {
+    entries: VecDeque<QueueEntry>,
+}
+
+impl WaitingQueue {
+    /// Add a client to the back of the queue, or if it's an admin, behind any admins that are already queued
+    pub fn push(&mut self, entity: Entity, is_admin: bool) {
+        let entry = QueueEntry {
+            entity,
+            is_admin,
+            last_sent_position: None,
+        };
+
+        if is_admin {
+            let index = self
+                .entries
+                .iter()
+                .position(|entry| !entry.is_admin)
+                .unwrap_or(self.entries.len());
+            self.entries.insert(index, entry);
+        } else {
+            self.entries.push_back(entry);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SlotsPlugin;
+
+impl Plugin for SlotsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<WaitingQueue>()
+            .add_systems(Update, announce_departures);
+    }
+}
+
+/// Admit queued clients into any free slots, then either tell the rest where they are in the queue, or turn them away
+pub fn admit_players(
+    mut queue: ResMut<WaitingQueue>,
+    config: Res<ServerConfig>,
+    mut clients: Query<(Entity, &mut ClientConnection, &mut PacketSender<Server>)>,
+    mut disconnect: EventWriter<DisconnectPeer>,
+) {
+    // Forget any clients that disconnected while they were waiting
+    queue.entries.retain(|entry| {
+        clients
+            .get(entry.entity)
+            .is_ok_and(|(_, client, _)| matches!(client.handshake, ClientHandshakeState::Queued(_)))
+    });
+
+    let mut players = clients
+        .iter()
+        .filter(|(_, client, _)| matches!(client.handshake, ClientHandshakeState::Finished(_)))
+        .count();
+
+    let mut admitted = Vec::new();
+    while let Some(front) = queue.entries.front() {
+        let slots = if front.is_admin {
+            config.max_players
+        } else {
+            config.max_players.saturating_sub(config.reserved_slots)
+        };
+
+        if players >= slots {
+            break;
+        }
+
+        let entry = queue.entries.pop_front().unwrap();
+        let (_, mut client, _) = clients.get_mut(entry.entity).unwrap();
+        let ClientHandshakeState::Queued(profile) = std::mem::take(&mut client.handshake) else {
+            unreachable!("Clients that aren't queued were removed from the queue above");
+        };
+
+        info!(
+            "Admitting '{}' into the server ({}/{} players)",
+            profile.username,
+            players + 1,
+            config.max_players
+        );
+        admitted.push((entry.entity, profile.username.clone()));
+        client.handshake = ClientHandshakeState::Finished(profile);
+        players += 1;
+    }
+
+    if !admitted.is_empty() {
+        announce_arrivals(&admitted, &mut clients);
+    }
+
+    let length = queue.entries.len();
+    for (position, entry) in queue.entries.iter_mut().enumerate() {
+        let rejected = match config.full_behaviour {
+            FullBehaviour::Reject => true,
+            FullBehaviour::Queue => position >= config.max_queue_length,
+        };
+
+        if rejected {
+            disconnect.send(DisconnectPeer {
+                entity: entry.entity,
+                reason: DisconnectReason::ServerFull,
+            });
+            continue;
+        }
+
+        if entry.last_sent_position == Some(position) {
+            continue;
+        }
+        entry.last_sent_position = Some(position);
+
+        let (_, _, mut sender) = clients.get_mut(entry.entity).unwrap();
+        let packet = QueuePosition {
+            position: position as u32 + 1,
+            length: length.min(config.max_queue_length) as u32,
+        };
+        if let Err(e) = sender.send(packet) {
+            error!("Error while sending queue position: {e}");
+        }
+    }
+
+    // Rejected clients are disconnected, so they don't need to stay in the queue
+    match config.full_behaviour {
+        FullBehaviour::Reject => queue.entries.clear(),
+        FullBehaviour::Queue => queue.entries.truncate(config.max_queue_length),
+    }
+}
+
+/// Send the lobby to newly admitted players, and tell everyone else that they joined
+fn announce_arrivals(
+    admitted: &[(Entity, String)],
+    clients: &mut Query<(Entity, &mut ClientConnection, &mut PacketSender<Server>)>,
+) {
+    let usernames: Vec<_> = clients
+        .iter()
+        .filter_map(|(_, client, _)| match &client.handshake {
+            ClientHandshakeState::Finished(ClientProfile { username, .. }) => {
+                Some(username.clone())
+            }
+            _ => None,
+        })
+        .collect();
+
+    for (entity, client, mut sender) in clients.iter_mut() {
+        if !matches!(client.handshake, ClientHandshakeState::Finished(_)) {
+            continue;
+        }
+
+        let result = if admitted.iter().any(|(admitted, _)| *admitted == entity) {
+            sender.send(Lobby {
+                usernames: usernames.clone(),
+            })
+        } else {
+            admitted.iter().try_for_each(|(_, username)| {
+                sender.send(PlayerJoined {
+                    username: username.clone(),
+                })
+            })
+        };
+
+        if let Err(e) = result {
+            error!("Error while announcing new players: {e}");
+        }
+    }
+}
+
+/// Tell everyone when a player leaves
+fn announce_departures(
+    mut events: EventReader<PeerDisconnected>,
+    mut clients: Query<(Entity, &ClientConnection, &mut PacketSender<Server>)>,
+    resumable: Query<(), With<Resumable>>,
+) {
+    for PeerDisconnected { entity, reason } in events.read() {
+        // Players whose place is being held are only announced as leaving if they don't come back in time
+        if resume::is_held(resumable.contains(*entity), reason) {
+            continue;
+        }
+
+        let Ok((_, client, _)) = clients.get(*entity) else {
+            continue;
+        };
+
+        let ClientHandshakeState::Finished(profile) = &client.handshake else {
+            continue;
+        };
+        let username = profile.username.clone();
+        announce_departure(*entity, &username, &mut clients);
+    }
+}
+
+/// Tell everyone except the departed player that they left
+pub fn announce_departure(
+    departed: Entity,
+    username: &str,
+    clients: &mut Query<(Entity, &ClientConnection, &mut PacketSender<Server>)>,
+) {
+    for (other, client, mut sender) in clients.iter_mut() {
+        if other == departed || !matches!(client.handshake, ClientHandshakeState::Finished(_)) {
+            continue;
+        }
+
+        let packet = PlayerLeft {
+            username: username.to_owned(),
+        };
+        if let Err(e) = sender.send(packet) {
+            error!("Error while announcing departed player: {e}");
+        }
+    }
+}
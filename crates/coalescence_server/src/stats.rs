@@ -0,0 +1,66 @@
+//! Lets players see how well everyone's connection to the server is doing
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use coalescence_proto::{
+    packet::{PlayerConnection, PlayerStats},
+    peer::Server,
+    NetworkStats, PacketSender,
+};
+
+use crate::{ClientConnection, ClientHandshakeState};
+
+/// How often players are sent everyone's connection statistics
+const PLAYER_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Resource, Debug, Deref, DerefMut)]
+struct PlayerStatsTimer(Timer);
+
+#[derive(Debug, Default)]
+pub struct PlayerStatsPlugin;
+
+impl Plugin for PlayerStatsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PlayerStatsTimer(Timer::new(
+            PLAYER_STATS_INTERVAL,
+            TimerMode::Repeating,
+        )))
+        .add_systems(Update, send_player_stats);
+    }
+}
+
+fn send_player_stats(
+    time: Res<Time>,
+    mut timer: ResMut<PlayerStatsTimer>,
+    mut clients: Query<(&ClientConnection, &NetworkStats, &mut PacketSender<Server>)>,
+) {
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let players: Vec<_> = clients
+        .iter()
+        .filter_map(|(client, stats, _)| match &client.handshake {
+            ClientHandshakeState::Finished(profile) => Some(PlayerConnection {
+                username: profile.username.clone(),
+                ping: stats.rtt,
+                quality: stats.quality(),
+            }),
+            _ => None,
+        })
+        .collect();
+
+    for (client, _, mut sender) in clients.iter_mut() {
+        if !matches!(client.handshake, ClientHandshakeState::Finished(_)) {
+            continue;
+        }
+
+        let packet = PlayerStats {
+            players: players.clone(),
+        };
+        if let Err(e) = sender.send(packet) {
+            error!("Error while sending player stats: {e}");
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Answers status queries from clients that want to know about the server before joining it, such as from a server browser.
+//!
+//! A status query is a normal connection whose first packet is a [`StatusRequest`] instead of a [`Profile`].
+//! Once the server has answered it with a [`ServerStatus`], the client closes the connection itself.
+//! Queries are still subject to the handshake timeout, so clients that never close the connection are disconnected anyway.
+//! They count towards `rate_limits.status_queries_per_ip` instead of the limit on connection attempts, so that players
+//! who keep refreshing a server browser can still join.
+//!
+//! [`Profile`]: coalescence_proto::packet::Profile
+
+use bevy::prelude::*;
+use coalescence_proto::{
+    packet::{Received, ServerStatus, StatusRequest},
+    peer::Server,
+    PacketSender, PROTOCOL_VERSION,
+};
+use coalescence_quinn::QuinnConnection;
+
+use crate::{
+    config::ServerConfig, rate_limit::ConnectionAttempts, ClientConnection, ClientHandshakeState,
+};
+
+pub fn answer_status_queries(
+    config: Res<ServerConfig>,
+    attempts: Res<ConnectionAttempts>,
+    mut query: Query<(
+        &mut ClientConnection,
+        &QuinnConnection,
+        &mut Received<StatusRequest>,
+        &mut PacketSender<Server>,
+    )>,
+) {
+    if query
+        .iter()
+        .all(|(_, _, requests, _)| requests.buffer.is_empty())
+    {
+        return;
+    }
+
+    let players = query
+        .iter()
+        .filter(|(client, ..)| matches!(client.handshake, ClientHandshakeState::Finished(_)))
+        .count();
+    let status = current_status(&config, players);
+
+    for (mut client, quinn, mut requests, mut sender) in query.iter_mut() {
+        let Some(request) = requests.buffer.drain(..).last() else {
+            continue;
+        };
+
+        // Clients that have already sent their profile are joining, not querying
+        if !matches!(client.handshake, ClientHandshakeState::ExpectingProfile) {
+            continue;
+        }
+
+        debug!(
+            "Answering status query from client ID '{}' with protocol version {}",
+            quinn.connection().stable_id(),
+            request.protocol_version
+        );
+
+        client.handshake = ClientHandshakeState::Queried;
+        attempts.count_as_query(quinn.connection().remote_address().ip());
+        if let Err(e) = sender.send(status.clone()) {
+            error!("Error while sending server status: {e}");
+        }
+    }
+}
+
+/// The server's status, given how many players are currently connected
+pub fn current_status(config: &ServerConfig, players: usize) -> ServerStatus {
+    ServerStatus {
+        name: config.name.clone(),
+        motd: config.motd.clone(),
+        players: players as u32,
+        max_players: config.max_players as u32,
+        protocol_version: PROTOCOL_VERSION,
+        game_mode: config.game_mode,
+        password_required: config.password.is_some(),
+    }
+}
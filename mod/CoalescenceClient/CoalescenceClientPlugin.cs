@@ -77,6 +77,14 @@ namespace CoalescenceClient
 			return $"{NativeAssemblyDirectory()}\\{NATIVE_ASSEMBLY_NAME}.dll";
 		}
 
+		/// <summary>
+		/// Where the certificate that servers know the player by is kept
+		/// </summary>
+		public static string IdentityPath()
+		{
+			return $"{PluginDirectory()}\\identity.pem";
+		}
+
 		/// <summary>
 		/// Creates a mapping from the identifier used in the DllImports to the actual location of the native library.
 		///
@@ -14,6 +14,7 @@ namespace CoalescenceClient.Menu
 	public unsafe class ServerBrowserMenu : SinglePageMenu
 	{
 		public const string CONNECT_BUTTON_SIGNAL = "CONNECT";
+		public const string QUERY_BUTTON_SIGNAL = "QUERY";
 		public const string MAIN_MENU_BUTTON_SIGNAL = "MULTIPLAYER";
 
 		public static readonly ProcessManager.ProcessID ProcessId = new(nameof(ServerBrowserMenu), true);
@@ -26,14 +27,31 @@ namespace CoalescenceClient.Menu
 
 		private OpUpdown ServerPort;
 
+		private OpTextBox ServerPassword;
+
 		private HoldButton ConnectButton;
 
+		private SimpleButton QueryButton;
+
+		private OpLabel ServerStatusLabel;
+
+		private OpLabel LanServersLabel;
+
+		private static readonly List<string> DiscoveredServerLines = [];
+
+		private float LanServersRefreshTimer;
+
 		private SafeAppHandle? appHandle;
 
 		private ClientProfile Profile;
 
 		private bool WaitingForConnection;
 
+		/// <summary>
+		/// What the native app last reported it's doing while connecting, shown in the info label
+		/// </summary>
+		private string ConnectionProgress = string.Empty;
+
 		public override bool FreezeMenuFunctions => WaitingForConnection || base.FreezeMenuFunctions;
 
 #pragma warning disable CS8618 // The fields get assigned to in the yield methods, which are called by the builder
@@ -41,6 +59,10 @@ namespace CoalescenceClient.Menu
 		{
 			Instance = this;
 			Profile = new();
+
+			// The app is needed to listen for servers on the LAN, even before connecting to one
+			appHandle = NewAppHandle();
+			appHandle.StartLanDiscovery();
 			new CustomMenuBuilder()
 				.WithBackgroundArt(true)
 				.WithTitleIllustration("MultiplayerTitle")
@@ -50,6 +72,17 @@ namespace CoalescenceClient.Menu
 		}
 #pragma warning restore CS8618
 
+		private static SafeAppHandle NewAppHandle()
+		{
+			SafeAppHandle handle = new(Interop.new_app());
+			if (!handle.LoadIdentity(Plugin.IdentityPath()))
+			{
+				Plugin.Logger.LogWarning("Could not load the player's identity, servers won't recognise the player after restarting");
+			}
+
+			return handle;
+		}
+
 		public override IEnumerable<MenuObject> YieldMenuObjects()
 		{
 			// Connect to server button
@@ -57,6 +90,11 @@ namespace CoalescenceClient.Menu
 			ConnectButton = new(this, Page, Translate(CONNECT_BUTTON_SIGNAL), CONNECT_BUTTON_SIGNAL, new(ScreenDimensions.ScreenCenter.x, ScreenDimensions.ScreenSize.y * 0.3f), 30);
 			ConnectButton.GetButtonBehavior.greyedOut = true;
 			yield return ConnectButton;
+
+			// Shows the server's status before joining, below the address and password
+			QueryButton = new(this, Page, Translate(QUERY_BUTTON_SIGNAL), QUERY_BUTTON_SIGNAL, new(ScreenDimensions.ScreenCenter.x - 55, ScreenDimensions.ScreenSize.y * 0.45f), new(110, 30));
+			QueryButton.buttonBehav.greyedOut = true;
+			yield return QueryButton;
 		}
 
 		public override IEnumerable<UIelement> YieldMixedUiElements()
@@ -80,7 +118,11 @@ namespace CoalescenceClient.Menu
 				 serverIpAddressWidth);
 
 			// Disable connect button when the IP address textbox is empty
-			ServerIpAddress.OnValueUpdate += (_, value, _) => ConnectButton.GetButtonBehavior.greyedOut = string.IsNullOrEmpty(value);
+			ServerIpAddress.OnValueUpdate += (_, value, _) =>
+			{
+				ConnectButton.GetButtonBehavior.greyedOut = string.IsNullOrEmpty(value);
+				QueryButton.buttonBehav.greyedOut = string.IsNullOrEmpty(value);
+			};
 
 			OpLabel serverPortLabel = new(
 				serverSocketAddressAnchor,
@@ -111,6 +153,41 @@ namespace CoalescenceClient.Menu
 			yield return ServerIpAddress;
 			yield return serverPortLabel;
 			yield return ServerPort;
+
+			// Optional password, on the line below and aligned with the IP address textbox
+			Vector2 serverPasswordAnchor = new(ServerIpAddress.PosX, serverSocketAddressAnchor.y - 40);
+
+			OpLabel serverPasswordLabel = new(
+				serverPasswordAnchor,
+				new(20, 24),
+				Translate("Password:"),
+				FLabelAlignment.Left);
+			serverPasswordLabel.PosX = serverPasswordAnchor.x - serverPasswordLabel.label.textRect.width - padding;
+
+			ServerPassword = new(
+				new Configurable<string>(""),
+				serverPasswordAnchor,
+				serverIpAddressWidth);
+
+			yield return serverPasswordLabel;
+			yield return ServerPassword;
+
+			ServerStatusLabel = new(
+				new(ScreenDimensions.ScreenCenter.x, ScreenDimensions.ScreenSize.y * 0.4f),
+				new(20, 24),
+				string.Empty,
+				FLabelAlignment.Center);
+
+			yield return ServerStatusLabel;
+
+			// Servers on the local network, listed along the bottom of the screen
+			LanServersLabel = new(
+				new(ScreenDimensions.ScreenCenter.x, ScreenDimensions.ScreenSize.y * 0.15f),
+				new(20, 24),
+				string.Empty,
+				FLabelAlignment.Center);
+
+			yield return LanServersLabel;
 		}
 
 		internal static void SetupHooks()
@@ -127,6 +204,12 @@ namespace CoalescenceClient.Menu
 			if (WaitingForConnection)
 			{
 				infoLabelFade = 1;
+
+				// The menu is frozen while connecting, so cancelling is done with the keyboard rather than a button
+				if (Input.GetKeyDown(KeyCode.Escape))
+				{
+					appHandle?.CancelConnect();
+				}
 			}
 		}
 
@@ -141,16 +224,51 @@ namespace CoalescenceClient.Menu
 					appHandle.Close();
 					appHandle = null;
 				}
+				else
+				{
+					RefreshLanServers(dt);
+				}
 			}
 
 			base.RawUpdate(dt);
 		}
 
+		private void RefreshLanServers(float dt)
+		{
+			LanServersRefreshTimer -= dt;
+			if (LanServersRefreshTimer > 0 || appHandle == null)
+			{
+				return;
+			}
+			LanServersRefreshTimer = 1;
+
+			DiscoveredServerLines.Clear();
+			appHandle.DiscoveredServers(&DiscoveredServerCallback);
+			LanServersLabel.text = DiscoveredServerLines.Count == 0
+				? Translate("No servers found on the LAN")
+				: Translate("Servers on the LAN:") + "\n" + string.Join("\n", DiscoveredServerLines);
+		}
+
+		[UnmanagedCallersOnly(CallConvs = [typeof(CallConvCdecl)])]
+		private static void DiscoveredServerCallback(DiscoveredServerInfo* server)
+		{
+			// Only show a handful, so the list doesn't run off the screen
+			if (DiscoveredServerLines.Count >= 5)
+			{
+				return;
+			}
+
+			string address = new((char*)server->address);
+			string name = new((char*)server->name);
+			string compatibility = server->compatible ? string.Empty : " - Incompatible version";
+			DiscoveredServerLines.Add($"{name} - {address} port {server->port} - {server->players}/{server->max_players} players{compatibility}");
+		}
+
 		public override string UpdateInfoText()
 		{
 			if (WaitingForConnection)
 			{
-				return "Connecting...";
+				return $"Connecting...{ConnectionProgress} Press Escape to cancel";
 			}
 			else
 			{
@@ -171,19 +289,21 @@ namespace CoalescenceClient.Menu
 		{
 			string address = ServerIpAddress.value;
 			ushort port = (ushort)ServerPort.valueInt;
+			string? password = string.IsNullOrEmpty(ServerPassword.value) ? null : ServerPassword.value;
 			Plugin.Logger.LogInfo($"Connecting to: {address} on port: {port}");
 
 			if (appHandle == null || appHandle.IsInvalid || appHandle.IsClosed)
 			{
-				appHandle = new(Interop.new_app());
+				appHandle = NewAppHandle();
 			}
 
-			AppConnectToServerResult result = appHandle.ConnectToServer(address, port, Profile.Username, &ConnectedToServerCallback, &NativeErrorCallback);
+			AppConnectToServerResult result = appHandle.ConnectToServer(address, port, Profile.Username, password, &ConnectProgressCallback, &ConnectedToServerCallback, &NativeErrorCallback);
 
 			switch (result.tag)
 			{
 				case AppConnectToServerResult.Tag.Ok:
 					WaitingForConnection = true;
+					ConnectionProgress = string.Empty;
 					DisableTypeables();
 					infoLabel.text = UpdateInfoText();
 					break;
@@ -199,9 +319,70 @@ namespace CoalescenceClient.Menu
 			}
 		}
 
+		private void Query()
+		{
+			string address = ServerIpAddress.value;
+			ushort port = (ushort)ServerPort.valueInt;
+
+			if (appHandle == null || appHandle.IsInvalid || appHandle.IsClosed)
+			{
+				appHandle = NewAppHandle();
+			}
+
+			AppQueryServerResult result = appHandle.QueryServer(address, port, &ServerStatusCallback, &QueryErrorCallback);
+
+			switch (result.tag)
+			{
+				case AppQueryServerResult.Tag.Ok:
+					ServerStatusLabel.text = Translate("Querying server...");
+					break;
+				case AppQueryServerResult.Tag.AppPointerIsNull:
+					DisplayNativeError("appHandle is null");
+					break;
+				case AppQueryServerResult.Tag.AddressPointerIsNull:
+					DisplayNativeError("addressPointer is null");
+					break;
+				case AppQueryServerResult.Tag.Err:
+					DisplayNativeError(InteropUtils.FormatNativeError(result.err._0));
+					break;
+			}
+		}
+
+		[UnmanagedCallersOnly(CallConvs = [typeof(CallConvCdecl)])]
+		private static void ServerStatusCallback(ServerStatusInfo* status)
+		{
+			string name = new((char*)status->name);
+			string motd = new((char*)status->motd);
+			string password = status->password_required ? " - Password required" : string.Empty;
+			string compatibility = status->compatible ? string.Empty : " - Incompatible version";
+			Instance.ServerStatusLabel.text = $"{name} ({status->game_mode})\n{motd}\n{status->players}/{status->max_players} players - {status->ping_milliseconds}ms{password}{compatibility}";
+		}
+
+		[UnmanagedCallersOnly(CallConvs = [typeof(CallConvCdecl)])]
+		private static void QueryErrorCallback(Error* error)
+		{
+			Instance.ServerStatusLabel.text = string.Empty;
+			Instance.DisplayNativeError(InteropUtils.FormatNativeError(error));
+		}
+
+		[UnmanagedCallersOnly(CallConvs = [typeof(CallConvCdecl)])]
+		private static void ConnectProgressCallback(ConnectProgressInfo* progress)
+		{
+			string address = new((char*)progress->address);
+			Instance.ConnectionProgress = progress->stage switch
+			{
+				ConnectStage.Trying => $" Trying {address} ({progress->attempt}/{progress->addresses}).",
+				ConnectStage.Punching => $" Trying {address} through NAT.",
+				ConnectStage.Relaying => $" Trying {address} through a relay.",
+				_ => string.Empty,
+			};
+			Instance.infoLabel.text = Instance.UpdateInfoText();
+		}
+
 		[UnmanagedCallersOnly(CallConvs = [typeof(CallConvCdecl)])]
 		private static void ConnectedToServerCallback()
 		{
+			Instance.appHandle?.StopLanDiscovery();
 			Instance.PlaySound(SoundID.MENU_Start_New_Game);
 			Instance.manager.musicPlayer?.FadeOutAllSongs(100f);
 			Instance.SwitchMainProcess(ServerLobbyMenu.ProcessId);
@@ -234,6 +415,9 @@ namespace CoalescenceClient.Menu
 				case CONNECT_BUTTON_SIGNAL:
 					Connect();
 					break;
+				case QUERY_BUTTON_SIGNAL:
+					Query();
+					break;
 				case BACK_BUTTON_SIGNAL:
 					ExitToMainMenu();
 					break;
@@ -43,19 +43,326 @@ namespace CoalescenceClient.NativeInterop
 		/// <param name="address">The IP address or DNS name of the server</param>
 		/// <param name="port">The port to connect to</param>
 		/// <param name="username">This client's username</param>
+		/// <param name="password">The server's password, or null if it doesn't need one</param>
+		/// <param name="asyncProgressHandler">Callback when a new address is being tried. The progress' address is only valid during the callback</param>
 		/// <param name="asyncOkHandler">Callback if the connection succeeded</param>
 		/// <param name="asyncErrorHandler">Callback if the connection failed</param>
 		/// <returns>Synchronous errors are returned directly, async errors invoke the <paramref name="asyncErrorHandler"/></returns>
-		public unsafe AppConnectToServerResult ConnectToServer(string address, ushort port, string username, delegate* unmanaged[Cdecl]<void> asyncOkHandler, delegate* unmanaged[Cdecl]<Error*, void> asyncErrorHandler)
+		public unsafe AppConnectToServerResult ConnectToServer(string address, ushort port, string username, string? password, delegate* unmanaged[Cdecl]<ConnectProgressInfo*, void> asyncProgressHandler, delegate* unmanaged[Cdecl]<void> asyncOkHandler, delegate* unmanaged[Cdecl]<Error*, void> asyncErrorHandler)
 		{
 			IntPtr addressPointer = Marshal.StringToHGlobalUni(address);
+			IntPtr progressCallbackPointer = (IntPtr)asyncProgressHandler;
 			IntPtr okCallbackPointer = (IntPtr)asyncOkHandler;
 			IntPtr errorCallbackPointer = (IntPtr)asyncErrorHandler;
 			IntPtr usernamePointer = Marshal.StringToHGlobalUni(username);
-			AppConnectToServerResult result = Interop.app_connect_to_server(AppHandle, (ushort*)addressPointer, port, (ushort*)usernamePointer, okCallbackPointer, errorCallbackPointer);
+			// StringToHGlobalUni returns IntPtr.Zero for null strings, which the native code treats as no password
+			IntPtr passwordPointer = Marshal.StringToHGlobalUni(password);
+			AppConnectToServerResult result = Interop.app_connect_to_server(AppHandle, (ushort*)addressPointer, port, (ushort*)usernamePointer, (ushort*)passwordPointer, progressCallbackPointer, okCallbackPointer, errorCallbackPointer);
 			Marshal.FreeHGlobal(addressPointer);
 			Marshal.FreeHGlobal(usernamePointer);
+			Marshal.FreeHGlobal(passwordPointer);
 			return result;
 		}
+
+		/// <summary>
+		/// Cancels the connection attempt in progress, which invokes its error handler with a "cancelled" error
+		/// </summary>
+		/// <returns>Whether a connection attempt was in progress</returns>
+		public bool CancelConnect()
+		{
+			unsafe
+			{
+				return Convert.ToBoolean(Interop.app_cancel_connect(AppHandle));
+			}
+		}
+
+		/// <summary>
+		/// Sets the callbacks for when the connection to the server drops and is automatically resumed
+		/// </summary>
+		/// <param name="reconnectingHandler">Callback before every reconnection attempt, with the attempt number</param>
+		/// <param name="reconnectedHandler">Callback once the server has given the player's place back</param>
+		/// <param name="failedHandler">Callback if the server gave the player's place away or couldn't be reached in time</param>
+		public unsafe void SetReconnectHandlers(delegate* unmanaged[Cdecl]<uint, void> reconnectingHandler, delegate* unmanaged[Cdecl]<void> reconnectedHandler, delegate* unmanaged[Cdecl]<Error*, void> failedHandler)
+		{
+			Interop.app_set_reconnect_handlers(AppHandle, (IntPtr)reconnectingHandler, (IntPtr)reconnectedHandler, (IntPtr)failedHandler);
+		}
+
+		/// <summary>
+		/// Asks a server for its status without joining it
+		/// </summary>
+		/// <param name="address">The IP address or DNS name of the server</param>
+		/// <param name="port">The port to connect to</param>
+		/// <param name="asyncOkHandler">Callback with the server's status. The status' strings are only valid during the callback</param>
+		/// <param name="asyncErrorHandler">Callback if the query failed</param>
+		/// <returns>Synchronous errors are returned directly, async errors invoke the <paramref name="asyncErrorHandler"/></returns>
+		public unsafe AppQueryServerResult QueryServer(string address, ushort port, delegate* unmanaged[Cdecl]<ServerStatusInfo*, void> asyncOkHandler, delegate* unmanaged[Cdecl]<Error*, void> asyncErrorHandler)
+		{
+			IntPtr addressPointer = Marshal.StringToHGlobalUni(address);
+			IntPtr okCallbackPointer = (IntPtr)asyncOkHandler;
+			IntPtr errorCallbackPointer = (IntPtr)asyncErrorHandler;
+			AppQueryServerResult result = Interop.app_query_server(AppHandle, (ushort*)addressPointer, port, okCallbackPointer, errorCallbackPointer);
+			Marshal.FreeHGlobal(addressPointer);
+			return result;
+		}
+
+		/// <summary>
+		/// Starts listening for servers announcing themselves on the local network
+		/// </summary>
+		/// <returns>Whether listening started successfully. If not, the reason is in the native log</returns>
+		public bool StartLanDiscovery()
+		{
+			unsafe
+			{
+				return Convert.ToBoolean(Interop.app_start_lan_discovery(AppHandle));
+			}
+		}
+
+		/// <summary>
+		/// Stops listening for servers on the local network, forgetting any that were discovered
+		/// </summary>
+		public void StopLanDiscovery()
+		{
+			unsafe
+			{
+				Interop.app_stop_lan_discovery(AppHandle);
+			}
+		}
+
+		/// <summary>
+		/// Invokes the handler once for each server discovered on the local network, most recently seen first.
+		/// The server info's strings are only valid during the handler
+		/// </summary>
+		public unsafe void DiscoveredServers(delegate* unmanaged[Cdecl]<DiscoveredServerInfo*, void> handler)
+		{
+			Interop.app_discovered_servers(AppHandle, (IntPtr)handler);
+		}
+
+		/// <summary>
+		/// Fetches a page of the public server list from a master server
+		/// </summary>
+		/// <param name="masterAddress">The IP address or DNS name of the master server</param>
+		/// <param name="port">The port the master server listens on</param>
+		/// <param name="masterFingerprint">The fingerprint of the master server's certificate as colon-separated hex bytes, which it's only trusted if it presents</param>
+		/// <param name="filter">Which servers to include, or null to include every server</param>
+		/// <param name="page">The page to fetch, starting from 0</param>
+		/// <param name="asyncOkHandler">Callback with the array of servers, its length, the page, and the total amount of pages. The array is only valid during the callback</param>
+		/// <param name="asyncErrorHandler">Callback if the request failed</param>
+		/// <returns>Synchronous errors are returned directly, async errors invoke the <paramref name="asyncErrorHandler"/></returns>
+		public unsafe AppFetchServerListResult FetchServerList(string masterAddress, ushort port, string masterFingerprint, ServerListFilter* filter, uint page, delegate* unmanaged[Cdecl]<ListedServerInfo*, nuint, uint, uint, void> asyncOkHandler, delegate* unmanaged[Cdecl]<Error*, void> asyncErrorHandler)
+		{
+			IntPtr addressPointer = Marshal.StringToHGlobalUni(masterAddress);
+			IntPtr fingerprintPointer = Marshal.StringToHGlobalUni(masterFingerprint);
+			IntPtr okCallbackPointer = (IntPtr)asyncOkHandler;
+			IntPtr errorCallbackPointer = (IntPtr)asyncErrorHandler;
+			AppFetchServerListResult result = Interop.app_fetch_server_list(AppHandle, (ushort*)addressPointer, port, (ushort*)fingerprintPointer, filter, page, okCallbackPointer, errorCallbackPointer);
+			Marshal.FreeHGlobal(addressPointer);
+			Marshal.FreeHGlobal(fingerprintPointer);
+			return result;
+		}
+
+		/// <summary>
+		/// Sets how often heartbeats are sent to the server, and how long it may go silent for before the connection is
+		/// considered lost
+		/// </summary>
+		/// <returns>False if the timeout isn't longer than the interval</returns>
+		public bool SetHeartbeatSettings(uint intervalMs, uint timeoutMs)
+		{
+			unsafe
+			{
+				return Interop.app_set_heartbeat_settings(AppHandle, intervalMs, timeoutMs);
+			}
+		}
+
+		/// <summary>
+		/// Loads the identity that servers know the player by, creating it if it doesn't exist yet
+		/// </summary>
+		/// <param name="path">The PEM file holding the identity's certificate and private key</param>
+		/// <returns>False if the identity couldn't be loaded or created</returns>
+		public bool LoadIdentity(string path)
+		{
+			IntPtr pathPointer = Marshal.StringToHGlobalUni(path);
+			bool loaded;
+			unsafe
+			{
+				loaded = Convert.ToBoolean(Interop.app_load_identity(AppHandle, (ushort*)pathPointer));
+			}
+
+			Marshal.FreeHGlobal(pathPointer);
+			return loaded;
+		}
+
+		/// <summary>
+		/// Sets what the local player is pressing, which is used from the next tick on until it's set again
+		/// </summary>
+		public bool SetInput(InputInfo input)
+		{
+			unsafe
+			{
+				return Convert.ToBoolean(Interop.app_set_input(AppHandle, &input));
+			}
+		}
+
+		/// <summary>
+		/// Sets the room the player is in, and the rooms it connects to, so that the server sends what's relevant to it
+		/// </summary>
+		/// <param name="room">The room's name, or null if the player isn't in any room, e.g. because they're in the menus</param>
+		/// <param name="neighbours">The names of the rooms that the room connects to</param>
+		public void SetRoom(string? room, string[] neighbours)
+		{
+			IntPtr roomPointer = Marshal.StringToHGlobalUni(room);
+			IntPtr[] neighbourPointers = new IntPtr[neighbours.Length];
+			for (int i = 0; i < neighbours.Length; i++)
+			{
+				neighbourPointers[i] = Marshal.StringToHGlobalUni(neighbours[i]);
+			}
+
+			unsafe
+			{
+				fixed (IntPtr* neighboursPointer = neighbourPointers)
+				{
+					Interop.app_set_room(AppHandle, (ushort*)roomPointer, (ushort**)neighboursPointer, (nuint)neighbours.Length);
+				}
+			}
+
+			Marshal.FreeHGlobal(roomPointer);
+			foreach (IntPtr pointer in neighbourPointers)
+			{
+				Marshal.FreeHGlobal(pointer);
+			}
+		}
+
+		/// <summary>
+		/// Tells the server that the player attacked, so that it checks what was hit as of what the player was seeing
+		/// </summary>
+		/// <param name="room">The name of the room the attack happened in</param>
+		/// <param name="attack">The area that the attack reaches</param>
+		/// <returns>False if the attack's shape is unknown or the client isn't playing on a server</returns>
+		public bool ReportAttack(string room, AttackInfo attack)
+		{
+			IntPtr roomPointer = Marshal.StringToHGlobalUni(room);
+			bool reported;
+			unsafe
+			{
+				reported = Convert.ToBoolean(Interop.app_report_attack(AppHandle, (ushort*)roomPointer, &attack));
+			}
+
+			Marshal.FreeHGlobal(roomPointer);
+			return reported;
+		}
+
+		/// <summary>
+		/// Sets how connection attempts to a server's resolved addresses are raced against each other
+		/// </summary>
+		/// <param name="attemptDelayMs">How many milliseconds each attempt is given before the next one is started alongside it</param>
+		/// <param name="attemptTimeoutMs">How many milliseconds each attempt may take before it's given up on</param>
+		public void SetConnectTimeouts(uint attemptDelayMs, uint attemptTimeoutMs)
+		{
+			unsafe
+			{
+				Interop.app_set_connect_timeouts(AppHandle, attemptDelayMs, attemptTimeoutMs);
+			}
+		}
+
+		/// <summary>
+		/// Sets the QUIC transport parameters used for new connections.
+		/// Start from <see cref="Interop.default_transport_settings"/> and change only what's needed
+		/// </summary>
+		/// <returns>Whether the parameters were valid. If not, the reason is in the native log</returns>
+		public bool SetTransportSettings(TransportSettingsInfo settings)
+		{
+			unsafe
+			{
+				return Convert.ToBoolean(Interop.app_set_transport_settings(AppHandle, &settings));
+			}
+		}
+
+		/// <summary>
+		/// Sets the master server used to reach servers that can't be connected to directly
+		/// </summary>
+		/// <param name="masterAddress">The IP address or DNS name of the master server, or null to only connect to servers directly</param>
+		/// <param name="port">The port the master server listens on</param>
+		/// <param name="masterFingerprint">The fingerprint of the master server's certificate as colon-separated hex bytes, which it's only trusted if it presents</param>
+		/// <returns>False if the fingerprint is invalid, in which case the master server isn't changed</returns>
+		public bool SetMasterServer(string? masterAddress, ushort port, string? masterFingerprint)
+		{
+			unsafe
+			{
+				// StringToHGlobalUni returns IntPtr.Zero for null strings, which the native code treats as no master server
+				IntPtr addressPointer = Marshal.StringToHGlobalUni(masterAddress);
+				IntPtr fingerprintPointer = Marshal.StringToHGlobalUni(masterFingerprint);
+				bool set = Convert.ToBoolean(Interop.app_set_master_server(AppHandle, (ushort*)addressPointer, port, (ushort*)fingerprintPointer));
+				Marshal.FreeHGlobal(addressPointer);
+				Marshal.FreeHGlobal(fingerprintPointer);
+				return set;
+			}
+		}
+
+		/// <summary>
+		/// Gets how often connections have managed to resume a previous session with 0-RTT early data
+		/// </summary>
+		public ResumptionStatsInfo ResumptionStats()
+		{
+			unsafe
+			{
+				ResumptionStatsInfo stats;
+				Interop.app_resumption_stats(AppHandle, &stats);
+				return stats;
+			}
+		}
+
+		/// <summary>
+		/// Gets statistics about the connection to the server
+		/// </summary>
+		/// <returns>False if not connected to a server</returns>
+		public bool NetworkStats(out NetworkStatsInfo stats)
+		{
+			unsafe
+			{
+				NetworkStatsInfo info;
+				bool connected = Interop.app_network_stats(AppHandle, &info);
+				stats = info;
+				return connected;
+			}
+		}
+
+		/// <summary>
+		/// Calls the handler for every player on the server, with their ping and connection quality.
+		/// The player's username is only valid during the handler
+		/// </summary>
+		public unsafe void PlayerStats(delegate* unmanaged[Cdecl]<PlayerStatsInfo*, void> handler)
+		{
+			Interop.app_player_stats(AppHandle, (IntPtr)handler);
+		}
+
+		/// <summary>
+		/// Gets the tick the server is estimated to be on
+		/// </summary>
+		/// <returns>False if the clock hasn't been synchronised with the server's yet</returns>
+		public bool NetworkTick(out uint tick)
+		{
+			unsafe
+			{
+				uint estimate;
+				bool synced = Interop.app_network_tick(AppHandle, &estimate);
+				tick = estimate;
+				return synced;
+			}
+		}
+
+		/// <summary>
+		/// Gets where this client is in the server's queue, if the server was full when it joined
+		/// </summary>
+		/// <param name="length">The length of the queue, or 0 if not queued</param>
+		/// <returns>The 1-based position in the queue, or 0 if not queued</returns>
+		public uint QueuePosition(out uint length)
+		{
+			unsafe
+			{
+				uint queueLength;
+				uint position = Interop.app_queue_position(AppHandle, &queueLength);
+				length = queueLength;
+				return position;
+			}
+		}
 	}
 }